@@ -1,10 +1,7 @@
-use ecat::{
-    InitState, PdoConfig, PdoMapping, PdoObject, SdoRead, SdoWrite, TxBuf, TxIndex,
-    user::ControlFlow,
-};
-use ethercrab::{SubDevice, error::Error};
+use ecat::{Driver, Error, PdoConfig, PdoMapping, PdoObject, SdoRead, SdoWrite, user::ControlFlow};
+use ethercrab::SubDevice;
 
-use ecat::io::{TIMEOUT_CLEAR_MASK, TIMEOUT_MASK, WRITE_MASK};
+use ecat::io::IoCtx;
 
 use io_uring::types::Timespec;
 
@@ -13,10 +10,7 @@ const MAX_FRAMES: usize = 64;
 
 static PDU_STORAGE: ethercrab::PduStorage<MAX_FRAMES, MAX_PDU_DATA> = ethercrab::PduStorage::new();
 
-use std::collections::BTreeMap;
-
 use ethercrab::MainDevice;
-use ethercrab::std::RawSocketDesc;
 
 fn setup_process_priority() -> Result<(), Box<dyn std::error::Error>> {
     use thread_priority::*;
@@ -38,9 +32,9 @@ fn setup_process_priority() -> Result<(), Box<dyn std::error::Error>> {
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     setup_process_priority()?;
 
-    let (_tx, mut rx, pdu_loop) = PDU_STORAGE.try_split().expect("cannot split pdu");
+    let (_tx, rx, pdu_loop) = PDU_STORAGE.try_split().expect("cannot split pdu");
 
-    let mut maindevice = MainDevice::new(
+    let maindevice = MainDevice::new(
         pdu_loop,
         ethercrab::Timeouts::default(),
         ethercrab::MainDeviceConfig {
@@ -49,65 +43,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         },
     );
 
-    let mut ring = io_uring::IoUring::new(64)?;
-
-    let mut probe = io_uring::register::Probe::new();
-    ring.submitter().register_probe(&mut probe)?;
-
-    use io_uring::{opcode, types};
-
-    if !probe.is_supported(opcode::RecvMulti::CODE) || !probe.is_supported(opcode::Write::CODE) {
-        panic!("readmulti/write opcodes are not supported");
-    }
-
-    let mut sock = RawSocketDesc::new("enxf8edfcadd5c6")?;
-    //let mut sock = RawSocketDesc::new("enxf8e43bcd2609")?;
-    let mtu = sock.interface_mtu()?;
-
-    let mtu = mtu + 18;
-    const ENTRIES: usize = 256;
-
-    let mut tx_bufs = BTreeMap::new();
-
-    let idx = 0;
-
-    let mut rx_bufs = io_uring_buf_ring::BufRing::new(ENTRIES as _, mtu as _, idx)?
-        .register(&ring.submitter())
-        .map_err(|(err, _)| err)?
-        .init();
-
-    use std::os::fd::AsRawFd;
-    let rx_multi_entry =
-        opcode::RecvMulti::new(types::Fd(sock.as_raw_fd()), rx_bufs.bgid()).build();
-
-    while unsafe { ring.submission().push(&rx_multi_entry).is_err() } {
-        ring.submit().expect("could not submit ops");
-    }
-    ring.submit().expect("could not submit ops");
-
     let retries = 5;
 
     //TODO: update to actual timeout duration.
     let timeout = Timespec::new().sec(1);
 
-    let mut state = InitState::<16, _, _, _>::new();
-
-    let write_entry = |id| id | WRITE_MASK;
-    let timeout_entry = |id| id | TIMEOUT_MASK;
-
-    state.start(
-        &maindevice,
-        retries,
-        &timeout,
-        &mut tx_bufs,
-        &sock,
-        &mut ring,
-        &write_entry,
-        &timeout_entry,
-    )?;
-
-    let mut pdi_offset = ethercrab::PdiOffset::default();
-
     let config = PdoConfig::new(
         // inputs
         [PdoMapping::new(
@@ -145,123 +85,23 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         ],
     );
 
-    loop {
-        let cqueue_entry = ring.completion().next();
-        if let Some(entry) = cqueue_entry {
-            let udata = entry.user_data();
-            if udata & TIMEOUT_CLEAR_MASK == TIMEOUT_CLEAR_MASK {
-                let key = udata & 0xFFFFFF;
-                let res = tx_bufs.remove(&key).expect("could not get received entry");
-
-                if let Some((header, pdu)) = res.received {
-                    let _ = state.update(
-                        pdu,
-                        header,
-                        &mut maindevice,
-                        retries,
-                        &timeout,
-                        &mut tx_bufs,
-                        &sock,
-                        &mut ring,
-                        res.configured_addr,
-                        res.identifier,
-                        &mut pdi_offset,
-                        |_maindev, subdev| (User::new(subdev), &config),
-                        |maindev, dev, received, entries, ring, index, identifier, output_buf| {
-                            let flow = dev
-                                .update(
-                                    received,
-                                    maindev,
-                                    retries,
-                                    &timeout,
-                                    entries,
-                                    &sock,
-                                    ring,
-                                    index,
-                                    identifier,
-                                    output_buf,
-                                    &write_entry,
-                                    &timeout_entry,
-                                )
-                                .unwrap();
-
-                            Ok(flow)
-                        },
-                        &write_entry,
-                        &timeout_entry,
-                    );
-                } else {
-                    println!("actually timed out");
-                }
-                continue;
-            } else if udata & WRITE_MASK == WRITE_MASK {
-                continue;
-            } else if udata & TIMEOUT_MASK == TIMEOUT_MASK {
-                if !matches!(-entry.result(), libc::ECANCELED) {
-                    let key = entry.user_data() & 0xFFFFFF;
-
-                    let Some(entry) = tx_bufs.get_mut(&key) else {
-                        println!("could not find entry :(");
-                        continue;
-                    };
-
-                    println!("timeout");
-
-                    if entry.retries_remaining == 0 {
-                        let timeout_clear =
-                            io_uring::opcode::TimeoutRemove::new(key | TIMEOUT_MASK)
-                                .build()
-                                .user_data(key | TIMEOUT_CLEAR_MASK);
-
-                        while unsafe { ring.submission().push(&timeout_clear).is_err() } {
-                            ring.submit().expect("could not submit ops");
-                        }
-                        ring.submit().unwrap();
-                    } else {
-                        while unsafe { ring.submission().push(entry.entry()).is_err() } {
-                            ring.submit().expect("could not submit ops");
-                        }
-                        ring.submit().unwrap();
-                        entry.retries_remaining -= 1;
-                    }
-                }
-                continue;
-            }
-
-            let Ok(Some(id)) = rx_bufs.buffer_id_from_cqe(&entry) else {
-                continue;
-            };
-
-            let buf = id.buffer();
+    let mut driver =
+        Driver::<16, _, _, _>::new("enxf8edfcadd5c6", maindevice, rx, retries, timeout)?;
+    //let mut driver = Driver::<16, _, _, _>::new("enxf8e43bcd2609", maindevice, rx, retries, timeout)?;
 
-            let Some(recv_frame) = rx.receive_frame_io_uring(buf).unwrap() else {
-                continue;
-            };
+    driver.start()?;
 
-            let frame: ethercrab::received_frame::ReceivedFrame = recv_frame.into();
+    driver.run(
+        |_maindev, subdev| (User::new(subdev), &config),
+        |ctx, dev, received, index, identifier, output_buf| {
+            let flow = dev
+                .update(received, ctx, index, identifier, output_buf)
+                .unwrap();
 
-            for (idx, res) in frame.into_pdu_iter_with_headers().enumerate() {
-                let Ok((pdu, header)) = res else {
-                    continue;
-                };
-                let rx_idx = (idx, header).idx();
-
-                if let Some(entry) = tx_bufs.get_mut(&rx_idx) {
-                    entry.received = Some((header, pdu));
-
-                    let timeout_clear = io_uring::opcode::TimeoutRemove::new(rx_idx | TIMEOUT_MASK)
-                        .build()
-                        .user_data(rx_idx | TIMEOUT_CLEAR_MASK);
-
-                    while unsafe { ring.submission().push(&timeout_clear).is_err() } {
-                        ring.submit().expect("could not submit ops");
-                    }
-
-                    ring.submit().unwrap();
-                }
-            }
-        }
-    }
+            Ok(flow)
+        },
+    )?;
+    Ok(())
 }
 
 struct User {
@@ -277,37 +117,21 @@ impl User {
         }
     }
 
-    #[allow(clippy::too_many_arguments)]
     fn update(
         &mut self,
         received: Option<ecat::DeviceResponse<'_, '_>>,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut io_uring::IoUring,
+        ctx: &mut IoCtx,
         idx: u16,
         identifier: Option<u8>,
         output_buf: &mut [u8],
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<Option<ControlFlow>, Error> {
-        self.state.update(
-            received,
-            maindevice,
-            retry_count,
-            timeout_duration,
-            tx_entries,
-            sock,
-            ring,
-            &mut self.device,
-            idx,
-            identifier,
-            output_buf,
-            write_entry,
-            timeout_entry,
-        )
+        if let Some(ecat::DeviceResponse::Emergency(emergency)) = received {
+            println!("{emergency}");
+            return Ok(None);
+        }
+
+        self.state
+            .update(received, ctx, &mut self.device, idx, identifier, output_buf)
     }
 }
 
@@ -397,22 +221,14 @@ impl core::fmt::Debug for RecvObj {
 }
 
 impl UserState {
-    #[allow(clippy::too_many_arguments)]
     fn update(
         &mut self,
         received: Option<ecat::DeviceResponse<'_, '_>>,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut io_uring::IoUring,
+        ctx: &mut IoCtx,
         subdev: &mut SubDevice,
         idx: u16,
         identifier: Option<u8>,
         output_buf: &mut [u8],
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<Option<ControlFlow>, Error> {
         match self {
             Self::Idle => {
@@ -446,19 +262,12 @@ impl UserState {
                     let configured_addr = subdev.configured_address();
 
                     read_err.start(
-                        maindevice,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
+                        ctx,
                         &subdev.config.mailbox.write.unwrap(),
                         &subdev.config.mailbox.read.unwrap(),
                         configured_addr,
                         identifier,
                         idx,
-                        &write_entry,
-                        &timeout_entry,
                     )?;
 
                     *self = Self::Error(read_err);
@@ -515,19 +324,12 @@ impl UserState {
                         if let Some(_err_code) = err.update(
                             received,
                             header,
-                            maindevice,
-                            retry_count,
-                            timeout_duration,
-                            tx_entries,
-                            sock,
-                            ring,
+                            ctx,
                             &subdev.config.mailbox.write.unwrap(),
                             &subdev.config.mailbox.read.unwrap(),
                             subdev.configured_address(),
                             identifier,
                             idx,
-                            &write_entry,
-                            &timeout_entry,
                         )? {
 
                             /*
@@ -544,15 +346,11 @@ impl UserState {
 
                                 // lmao no way thats all it is
                                 let (frame, handle) =
-                                    unsafe { maindevice.prep_rx_tx(0, &buf).unwrap().unwrap() };
+                                    unsafe { ctx.maindevice.prep_rx_tx(0, &buf).unwrap().unwrap() };
                                 ecat::setup::setup_write(
                                     frame,
                                     handle,
-                                    retry_count,
-                                    timeout_duration,
-                                    tx_entries,
-                                    sock,
-                                    ring,
+                                    &mut ctx.tx,
                                     Some(idx),
                                     None,
                                 )?;
@@ -564,6 +362,11 @@ impl UserState {
                             */
                         }
                     }
+                    DeviceResponse::Emergency(_)
+                    | DeviceResponse::Mailbox(_)
+                    | DeviceResponse::StateChange(_)
+                    | DeviceResponse::Timeout
+                    | DeviceResponse::Completion(_) => (),
                 }
                 Ok(None)
             }
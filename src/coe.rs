@@ -0,0 +1,318 @@
+use crate::error::Error;
+
+// ETG.1000.6 mailbox and CoE framing, built by hand so that requests
+// can be sized to the subdevice's mailbox instead of a fixed ethercrab type.
+
+pub(crate) const MBX_HEADER_LEN: usize = 6;
+pub(crate) const COE_HEADER_LEN: usize = 2;
+// command specifier + index + subindex
+pub(crate) const SDO_HEADER_LEN: usize = 4;
+// opcode + reserved + fragments left
+pub(crate) const SDO_INFO_HEADER_LEN: usize = 4;
+// segments always carry at least this many data bytes, unused ones are padding
+pub(crate) const SDO_SEGMENT_MIN_DATA: usize = 7;
+
+pub(crate) const MBX_TYPE_EOE: u8 = 0x02;
+pub(crate) const MBX_TYPE_COE: u8 = 0x03;
+pub(crate) const MBX_TYPE_FOE: u8 = 0x04;
+pub(crate) const MBX_TYPE_SOE: u8 = 0x05;
+pub(crate) const MBX_TYPE_VOE: u8 = 0x0F;
+
+pub(crate) const COE_EMERGENCY: u8 = 0x01;
+pub(crate) const COE_SDO_REQUEST: u8 = 0x02;
+pub(crate) const COE_SDO_RESPONSE: u8 = 0x03;
+pub(crate) const COE_SDO_INFO: u8 = 0x08;
+
+// client command specifiers (bits 5..7 of the sdo command byte)
+pub(crate) const CCS_DOWNLOAD_SEGMENT: u8 = 0;
+pub(crate) const CCS_INITIATE_DOWNLOAD: u8 = 1;
+pub(crate) const CCS_INITIATE_UPLOAD: u8 = 2;
+pub(crate) const CCS_UPLOAD_SEGMENT: u8 = 3;
+
+// server command specifiers
+pub(crate) const SCS_UPLOAD_SEGMENT: u8 = 0;
+pub(crate) const SCS_DOWNLOAD_SEGMENT: u8 = 1;
+pub(crate) const SCS_INITIATE_UPLOAD: u8 = 2;
+pub(crate) const SCS_INITIATE_DOWNLOAD: u8 = 3;
+
+// either side can abort a transfer at any point
+pub(crate) const SDO_ABORT: u8 = 4;
+
+pub(crate) type MbxBuf = smallvec::SmallVec<[u8; 64]>;
+
+pub(crate) fn mailbox_header(buf: &mut MbxBuf, len: usize, ty: u8, counter: u8) {
+    buf.extend_from_slice(&(len as u16).to_le_bytes());
+    // station address
+    buf.extend_from_slice(&0u16.to_le_bytes());
+    // channel + priority
+    buf.push(0);
+    buf.push((ty & 0x0F) | ((counter & 0x07) << 4));
+}
+
+fn coe_header(buf: &mut MbxBuf, service: u8) {
+    buf.extend_from_slice(&(u16::from(service) << 12).to_le_bytes());
+}
+
+// an sdo request addressing `index`:`subindex`, eg. initiate upload / download
+pub(crate) fn sdo_request(
+    counter: u8,
+    command: u8,
+    index: u16,
+    subindex: u8,
+    data: &[u8],
+) -> MbxBuf {
+    let mut buf = MbxBuf::new();
+    mailbox_header(
+        &mut buf,
+        COE_HEADER_LEN + SDO_HEADER_LEN + data.len(),
+        MBX_TYPE_COE,
+        counter,
+    );
+    coe_header(&mut buf, COE_SDO_REQUEST);
+    buf.push(command);
+    buf.extend_from_slice(&index.to_le_bytes());
+    buf.push(subindex);
+    buf.extend_from_slice(data);
+    buf
+}
+
+// an sdo segment request, these have no index/subindex
+pub(crate) fn sdo_segment(counter: u8, command: u8, data: &[u8]) -> MbxBuf {
+    let data_len = core::cmp::max(data.len(), SDO_SEGMENT_MIN_DATA);
+
+    let mut buf = MbxBuf::new();
+    mailbox_header(
+        &mut buf,
+        COE_HEADER_LEN + 1 + data_len,
+        MBX_TYPE_COE,
+        counter,
+    );
+    coe_header(&mut buf, COE_SDO_REQUEST);
+    buf.push(command);
+    buf.extend_from_slice(data);
+    buf.resize(MBX_HEADER_LEN + COE_HEADER_LEN + 1 + data_len, 0);
+    buf
+}
+
+// an sdo information request, `opcode` selects the service
+pub(crate) fn sdo_info_request(counter: u8, opcode: u8, data: &[u8]) -> MbxBuf {
+    let mut buf = MbxBuf::new();
+    mailbox_header(
+        &mut buf,
+        COE_HEADER_LEN + SDO_INFO_HEADER_LEN + data.len(),
+        MBX_TYPE_COE,
+        counter,
+    );
+    coe_header(&mut buf, COE_SDO_INFO);
+    buf.push(opcode);
+    // reserved + fragments left
+    buf.extend_from_slice(&[0; 3]);
+    buf.extend_from_slice(data);
+    buf
+}
+
+// the next counter that follows `counter`, 0 is reserved so it cycles through 1..=7
+pub(crate) fn next_counter(counter: u8) -> u8 {
+    if counter >= 7 { 1 } else { counter + 1 }
+}
+
+// the counter of a message, none if it is too short to have a mailbox header
+pub(crate) fn counter(bytes: &[u8]) -> Option<u8> {
+    bytes.get(5).map(|b| (b >> 4) & 0x07)
+}
+
+pub(crate) fn set_counter(bytes: &mut [u8], counter: u8) {
+    if let Some(b) = bytes.get_mut(5) {
+        *b = (*b & 0x8F) | ((counter & 0x07) << 4);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SdoResponse<'a> {
+    pub(crate) command: u8,
+    // everything after the command byte, up to the length given in the mailbox header
+    pub(crate) body: &'a [u8],
+}
+
+// everything after the mailbox header in the raw contents of the read mailbox,
+// up to the length given in the mailbox header
+pub(crate) fn mailbox_body(bytes: &[u8], ty: u8) -> Result<&[u8], Error> {
+    let (header, rest) = bytes
+        .split_first_chunk::<MBX_HEADER_LEN>()
+        .ok_or(Error::InvalidMailbox)?;
+
+    let len = usize::from(u16::from_le_bytes([header[0], header[1]]));
+    let found = header[5] & 0x0F;
+
+    if found != ty {
+        return Err(Error::UnexpectedMailboxType(found));
+    }
+
+    rest.get(..len).ok_or(Error::InvalidMailbox)
+}
+
+// everything after the coe header in the raw contents of the read mailbox
+pub(crate) fn coe_body(bytes: &[u8], service: u8) -> Result<&[u8], Error> {
+    let coe = mailbox_body(bytes, MBX_TYPE_COE)?;
+    let (coe_header, body) = coe
+        .split_first_chunk::<COE_HEADER_LEN>()
+        .ok_or(Error::InvalidMailbox)?;
+
+    let found = (u16::from_le_bytes(*coe_header) >> 12) as u8;
+    if found != service {
+        return Err(Error::UnexpectedCoeService(found));
+    }
+    Ok(body)
+}
+
+impl<'a> SdoResponse<'a> {
+    // parses the raw contents of the read mailbox
+    pub(crate) fn parse(bytes: &'a [u8]) -> Result<Self, Error> {
+        let sdo = coe_body(bytes, COE_SDO_RESPONSE)?;
+        let (&command, body) = sdo.split_first().ok_or(Error::InvalidMailbox)?;
+
+        if command >> 5 == SDO_ABORT {
+            let (index, rest) = body.split_first_chunk::<2>().ok_or(Error::InvalidMailbox)?;
+            let (&subindex, rest) = rest.split_first().ok_or(Error::InvalidMailbox)?;
+            let (code, _) = rest.split_first_chunk::<4>().ok_or(Error::InvalidMailbox)?;
+
+            return Err(Error::SdoAbort(crate::sdo::SdoAbort {
+                index: u16::from_le_bytes(*index),
+                subindex,
+                code: u32::from_le_bytes(*code),
+            }));
+        }
+
+        Ok(Self { command, body })
+    }
+
+    pub(crate) fn specifier(&self) -> u8 {
+        self.command >> 5
+    }
+
+    pub(crate) fn toggle(&self) -> bool {
+        self.command & 0x10 != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sdo_request_layout() {
+        let command = (CCS_INITIATE_DOWNLOAD << 5) | 0x0F;
+        let buf = sdo_request(3, command, 0x6040, 0x01, &[0xAA, 0xBB]);
+
+        // length, station address, channel + priority, type + counter
+        assert_eq!(buf[..6], [0x08, 0x00, 0x00, 0x00, 0x00, 0x33]);
+        // sdo request
+        assert_eq!(buf[6..8], [0x00, 0x20]);
+        // command, index, subindex
+        assert_eq!(buf[8..12], [0x2F, 0x40, 0x60, 0x01]);
+        assert_eq!(buf[12..], [0xAA, 0xBB]);
+    }
+
+    #[test]
+    fn sdo_segment_is_padded() {
+        let buf = sdo_segment(1, CCS_DOWNLOAD_SEGMENT, &[1, 2, 3]);
+
+        // coe header + command + the minimum of 7 data bytes
+        assert_eq!(buf[..2], 10u16.to_le_bytes());
+        assert_eq!(
+            buf.len(),
+            MBX_HEADER_LEN + COE_HEADER_LEN + 1 + SDO_SEGMENT_MIN_DATA
+        );
+        assert_eq!(
+            &buf[MBX_HEADER_LEN + COE_HEADER_LEN + 1..],
+            &[1, 2, 3, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn sdo_segment_longer_than_minimum() {
+        let data = [0x11; 12];
+        let buf = sdo_segment(1, CCS_DOWNLOAD_SEGMENT, &data);
+
+        assert_eq!(buf[..2], 15u16.to_le_bytes());
+        assert_eq!(&buf[MBX_HEADER_LEN + COE_HEADER_LEN + 1..], &data);
+    }
+
+    // a response from the subdevice at 0x1001, padded out to the size of its read mailbox
+    fn response(coe: &[u8]) -> [u8; 32] {
+        let mut bytes = [0xEE; 32];
+        bytes[..2].copy_from_slice(&(coe.len() as u16).to_le_bytes());
+        bytes[2..4].copy_from_slice(&0x1001u16.to_le_bytes());
+        bytes[4] = 0;
+        bytes[5] = MBX_TYPE_COE | (2 << 4);
+        bytes[6..6 + coe.len()].copy_from_slice(coe);
+        bytes
+    }
+
+    #[test]
+    fn sdo_response_expedited_upload() {
+        let bytes = response(&[0x00, 0x30, 0x4B, 0x41, 0x60, 0x00, 0x37, 0x06, 0x00, 0x00]);
+        let res = SdoResponse::parse(&bytes).unwrap();
+
+        assert_eq!(res.specifier(), SCS_INITIATE_UPLOAD);
+        assert!(!res.toggle());
+        // up to the length in the mailbox header, the rest of the mailbox is ignored
+        assert_eq!(res.body, &[0x41, 0x60, 0x00, 0x37, 0x06, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn sdo_response_segment_toggle() {
+        let bytes = response(&[0x00, 0x30, 0x10, 1, 2, 3, 4, 5, 6, 7]);
+        let res = SdoResponse::parse(&bytes).unwrap();
+
+        assert_eq!(res.specifier(), SCS_UPLOAD_SEGMENT);
+        assert!(res.toggle());
+        assert_eq!(res.body, &[1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn sdo_response_abort() {
+        let bytes = response(&[0x00, 0x30, 0x80, 0x00, 0x10, 0x02, 0x00, 0x00, 0x02, 0x06]);
+
+        match SdoResponse::parse(&bytes) {
+            Err(Error::SdoAbort(abort)) => assert_eq!(
+                abort,
+                crate::sdo::SdoAbort {
+                    index: 0x1000,
+                    subindex: 2,
+                    code: 0x0602_0000,
+                }
+            ),
+            res => panic!("expected an abort, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn sdo_response_truncated_abort() {
+        let bytes = response(&[0x00, 0x30, 0x80, 0x00, 0x10, 0x02, 0x00]);
+        assert!(matches!(
+            SdoResponse::parse(&bytes),
+            Err(Error::InvalidMailbox)
+        ));
+    }
+
+    #[test]
+    fn sdo_response_wrong_service() {
+        // an emergency
+        let bytes = response(&[0x00, 0x10, 0x00, 0x00, 0x00, 0, 0, 0, 0, 0]);
+        assert!(matches!(
+            SdoResponse::parse(&bytes),
+            Err(Error::UnexpectedCoeService(COE_EMERGENCY))
+        ));
+    }
+
+    #[test]
+    fn sdo_response_length_out_of_bounds() {
+        let mut bytes = response(&[0x00, 0x30, 0x60, 0x00, 0x10, 0x00]);
+        bytes[..2].copy_from_slice(&64u16.to_le_bytes());
+        assert!(matches!(
+            SdoResponse::parse(&bytes),
+            Err(Error::InvalidMailbox)
+        ));
+    }
+}
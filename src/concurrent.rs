@@ -0,0 +1,84 @@
+use core::ops::Range;
+
+// paces the per subdevice state machines of a stage, so that at most `limit` of them have
+// pdus in flight at once. the subdevices are started in order of their index
+#[derive(Debug)]
+pub(crate) struct Concurrent {
+    len: u16,
+    started: u16,
+    finished: u16,
+}
+
+impl Concurrent {
+    // along with the subdevices to start right away
+    pub(crate) fn new(len: u16, limit: u16) -> (Self, Range<u16>) {
+        let started = len.min(limit.max(1));
+        let concurrent = Self {
+            len,
+            started,
+            finished: 0,
+        };
+        (concurrent, 0..started)
+    }
+
+    // a subdevice is done, returns the next one to start, if any
+    pub(crate) fn finish(&mut self) -> Option<u16> {
+        self.finished += 1;
+        if self.started == self.len {
+            return None;
+        }
+
+        self.started += 1;
+        Some(self.started - 1)
+    }
+
+    // whether every subdevice is done
+    pub(crate) fn done(&self) -> bool {
+        self.finished == self.len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_up_to_the_limit() {
+        let (concurrent, start) = Concurrent::new(5, 2);
+        assert_eq!(start, 0..2);
+        assert!(!concurrent.done());
+    }
+
+    #[test]
+    fn limit_above_len() {
+        let (_, start) = Concurrent::new(3, 8);
+        assert_eq!(start, 0..3);
+    }
+
+    #[test]
+    fn limit_of_zero_starts_one() {
+        let (_, start) = Concurrent::new(3, 0);
+        assert_eq!(start, 0..1);
+    }
+
+    #[test]
+    fn finish_starts_the_rest_in_order() {
+        let (mut concurrent, start) = Concurrent::new(4, 2);
+        assert_eq!(start, 0..2);
+
+        assert_eq!(concurrent.finish(), Some(2));
+        assert_eq!(concurrent.finish(), Some(3));
+        assert_eq!(concurrent.finish(), None);
+        assert!(!concurrent.done());
+
+        assert_eq!(concurrent.finish(), None);
+        assert!(concurrent.done());
+    }
+
+    #[test]
+    fn no_subdevices() {
+        let (concurrent, start) = Concurrent::new(0, 4);
+        assert!(start.is_empty());
+        assert!(concurrent.done());
+    }
+}
@@ -1,10 +1,7 @@
+use crate::error::Error;
+use crate::io::IoCtx;
 use crate::setup::setup_write;
-use crate::txbuf::TxBuf;
-use ethercrab::{
-    MainDevice, PduHeader, SubDevice, error::Error, received_frame::ReceivedPdu, std::RawSocketDesc,
-};
-use io_uring::{IoUring, types::Timespec};
-use std::collections::BTreeMap;
+use ethercrab::{PduHeader, SubDevice, received_frame::ReceivedPdu};
 
 use heapless::Deque;
 
@@ -38,78 +35,45 @@ impl<const N: usize> Dc<N> {
         }
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub(crate) fn start(
-        &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
-    ) -> Result<(), Error> {
-        let (frame, handle) = maindevice.prep_latch_receive_times().unwrap().unwrap();
+    pub(crate) fn start(&mut self, ctx: &mut IoCtx) -> Result<(), Error> {
+        let (frame, handle) = ctx
+            .maindevice
+            .prep_latch_receive_times()?
+            .ok_or(Error::NoFrame)?;
 
-        setup_write(
-            frame,
-            handle,
-            retry_count,
-            timeout,
-            tx_entries,
-            sock,
-            ring,
-            None,
-            None,
-            write_entry,
-            timeout_entry,
-        )?;
+        setup_write(frame, handle, &mut ctx.tx, None, None)?;
         Ok(())
     }
 
-    #[allow(clippy::too_many_arguments)]
     pub(crate) fn update(
         &mut self,
         received: ReceivedPdu<'_>,
         header: PduHeader,
-        maindevice: &mut MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut io_uring::IoUring,
+        ctx: &mut IoCtx,
         idx: Option<u16>,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<Option<Deque<SubDevice, N>>, Error> {
         match &mut self.state {
             DcState::LatchReceive => {
                 if header.command_code != 8 {
-                    unreachable!()
+                    return Err(Error::UnexpectedCommand(header.command_code));
                 }
 
-                let dc_supported_devices = maindevice
-                    .prep_latch_dc_times(self.subdevices.as_mut_slices().0, |res, id| {
-                        let (frame, handle) = res.unwrap().unwrap();
+                let dc_supported_devices = ctx.maindevice.prep_latch_dc_times(
+                    self.subdevices.as_mut_slices().0,
+                    |res, id| {
+                        let (frame, handle) = res?.ok_or(ethercrab::error::Error::Internal)?;
+
+                        setup_write(frame, handle, &mut ctx.tx, Some(id), None)
+                            .map_err(|_| ethercrab::error::Error::Internal)
+                    },
+                )?;
 
-                        setup_write(
-                            frame,
-                            handle,
-                            retry_count,
-                            timeout,
-                            tx_entries,
-                            sock,
-                            ring,
-                            Some(id),
-                            None,
-                            &write_entry,
-                            &timeout_entry,
-                        )?;
-                        Ok(())
-                    })
-                    .unwrap();
+                // eg. a bus of simple io couplers, nothing was read so there is nothing to wait for
+                if dc_supported_devices == 0 {
+                    return Ok(Some(self.skip(ctx)));
+                }
 
+                // the subdevices without dc are left out of the reads and the configuration below
                 self.state = DcState::LatchTimes {
                     supported_devices: dc_supported_devices,
                     attr_count: 0,
@@ -119,28 +83,35 @@ impl<const N: usize> Dc<N> {
                 supported_devices,
                 attr_count,
             } => {
-                assert_eq!(header.command_code, 4);
+                if header.command_code != 4 {
+                    return Err(Error::UnexpectedCommand(header.command_code));
+                }
 
                 let addr = (u32::from_le_bytes(header.command_raw) >> 16) as u16;
-                let id = idx.unwrap() as usize;
+                let id = idx.ok_or(Error::MissingIndex)? as usize;
 
                 use ethercrab::EtherCrabWireRead;
-                match (self.subdevices.get_mut(id), addr) {
+                let dev = self
+                    .subdevices
+                    .get_mut(id)
+                    .ok_or(Error::UnknownDevice(id as _))?;
+
+                match addr {
                     // dc receive time
-                    (Some(dev), 0x0918) => {
+                    0x0918 => {
                         let bytes = &*received;
-                        let dc_receive_time = u64::unpack_from_slice(bytes).unwrap();
+                        let dc_receive_time = u64::unpack_from_slice(bytes)?;
                         dev.set_dc_receive_time(dc_receive_time)
                     }
                     // dc time port 0
-                    (Some(dev), 0x0900) => {
+                    0x0900 => {
                         let bytes = &*received;
                         let [time_p0, time_p1, time_p2, time_p3] =
-                            <[u32; 4]>::unpack_from_slice(bytes).unwrap();
+                            <[u32; 4]>::unpack_from_slice(bytes)?;
                         dev.ports
                             .set_receive_times(time_p0, time_p3, time_p1, time_p2);
                     }
-                    _ => unreachable!(),
+                    reg => return Err(Error::UnexpectedRegister(reg)),
                 }
 
                 *attr_count += 1;
@@ -149,30 +120,16 @@ impl<const N: usize> Dc<N> {
                     return Ok(None);
                 }
 
-                let master_dc_device = maindevice
-                    .prep_configure_dc(
-                        self.subdevices.as_mut_slices().0,
-                        ethercrab::std::ethercat_now,
-                        |res, id| {
-                            let (frame, handle) = res.unwrap().unwrap();
+                let master_dc_device = ctx.maindevice.prep_configure_dc(
+                    self.subdevices.as_mut_slices().0,
+                    ethercrab::std::ethercat_now,
+                    |res, id| {
+                        let (frame, handle) = res?.ok_or(ethercrab::error::Error::Internal)?;
 
-                            setup_write(
-                                frame,
-                                handle,
-                                retry_count,
-                                timeout,
-                                tx_entries,
-                                sock,
-                                ring,
-                                Some(id),
-                                None,
-                                &write_entry,
-                                &timeout_entry,
-                            )?;
-                            Ok(())
-                        },
-                    )
-                    .unwrap();
+                        setup_write(frame, handle, &mut ctx.tx, Some(id), None)
+                            .map_err(|_| ethercrab::error::Error::Internal)
+                    },
+                )?;
 
                 self.state = DcState::Configure {
                     supported_devices: *supported_devices,
@@ -185,12 +142,14 @@ impl<const N: usize> Dc<N> {
                 master_dc,
                 attr_count,
             } => {
-                assert_eq!(header.command_code, 5);
+                if header.command_code != 5 {
+                    return Err(Error::UnexpectedCommand(header.command_code));
+                }
 
                 let addr = (u32::from_le_bytes(header.command_raw) >> 16) as u16;
                 // write cmds so theres nothing to read
                 if !matches!(addr, 0x0920 | 0x0928) {
-                    unreachable!()
+                    return Err(Error::UnexpectedRegister(addr));
                 }
 
                 *attr_count += 1;
@@ -200,62 +159,52 @@ impl<const N: usize> Dc<N> {
                 }
 
                 if let Some(master_idx) = master_dc {
-                    let master = self.subdevices.get(*master_idx).unwrap();
+                    let master = self
+                        .subdevices
+                        .get(*master_idx)
+                        .ok_or(Error::UnknownDevice(*master_idx as _))?;
 
-                    maindevice.dc_reference_configured_address.store(
+                    ctx.maindevice.dc_reference_configured_address.store(
                         master.configured_address(),
                         std::sync::atomic::Ordering::Relaxed,
                     );
 
-                    let (frame, handle) = maindevice.prep_dc_static_sync(master).unwrap().unwrap();
-                    setup_write(
-                        frame,
-                        handle,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
-                        None,
-                        None,
-                        &write_entry,
-                        &timeout_entry,
-                    )?;
+                    let (frame, handle) = ctx
+                        .maindevice
+                        .prep_dc_static_sync(master)?
+                        .ok_or(Error::NoFrame)?;
+                    setup_write(frame, handle, &mut ctx.tx, None, None)?;
 
-                    let remaining_iterations = maindevice.config.dc_static_sync_iterations;
+                    let remaining_iterations = ctx.maindevice.config.dc_static_sync_iterations;
 
                     self.state = DcState::StaticSync {
                         master_dc: *master_idx,
                         remaining_iterations,
                     }
                 } else {
-                    todo!("non dc supported devices");
+                    return Ok(Some(self.skip(ctx)));
                 }
             }
             DcState::StaticSync {
                 master_dc,
                 remaining_iterations,
             } => {
-                assert_eq!(header.command_code, 14);
+                if header.command_code != 14 {
+                    return Err(Error::UnexpectedCommand(header.command_code));
+                }
 
                 if *remaining_iterations > 0 {
                     *remaining_iterations -= 1;
-                    let master = self.subdevices.get(*master_dc).unwrap();
-
-                    let (frame, handle) = maindevice.prep_dc_static_sync(master).unwrap().unwrap();
-                    setup_write(
-                        frame,
-                        handle,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
-                        None,
-                        None,
-                        &write_entry,
-                        &timeout_entry,
-                    )?;
+                    let master = self
+                        .subdevices
+                        .get(*master_dc)
+                        .ok_or(Error::UnknownDevice(*master_dc as _))?;
+
+                    let (frame, handle) = ctx
+                        .maindevice
+                        .prep_dc_static_sync(master)?
+                        .ok_or(Error::NoFrame)?;
+                    setup_write(frame, handle, &mut ctx.tx, None, None)?;
                 } else {
                     return Ok(Some(core::mem::take(&mut self.subdevices)));
                 }
@@ -263,4 +212,13 @@ impl<const N: usize> Dc<N> {
         }
         Ok(None)
     }
+
+    // there is no reference clock, so the subdevices are left to run freely
+    fn skip(&mut self, ctx: &mut IoCtx) -> Deque<SubDevice, N> {
+        // a previous bus may have had one
+        ctx.maindevice
+            .dc_reference_configured_address
+            .store(0, std::sync::atomic::Ordering::Relaxed);
+        core::mem::take(&mut self.subdevices)
+    }
 }
@@ -0,0 +1,235 @@
+use crate::error::Error;
+use crate::io::IoCtx;
+use ethercrab::{PduHeader, SubDevice, SubDeviceState, received_frame::ReceivedPdu};
+
+use crate::mbx_config::MailboxConfigState;
+use crate::pdo::PdoConfig;
+use crate::pdo_config::PdoMappingConfig;
+use crate::state_transition::StateChange;
+
+// tags the transitions and the mailbox sync manager setup, so that the ones still in flight when
+// the bus is shut down are ignored. the mailbox transfers never use 0b11 in the low bits
+const STATE_CHANGE_IDENTIFIER: u8 = 0x83;
+
+// what the pdo mapping is started with, its responses carry it above the tags, ie. in id >> 4
+const PDO_REMAP_IDENTIFIER: u8 = 0xD;
+
+// a change that op makes to a single subdevice while the rest of the bus keeps cycling,
+// see `ControlFlow::RestartDevice` / `ControlFlow::Reconfigure`
+pub(crate) enum DeviceChange<'a> {
+    // along with what to do once the subdevice is in the requested state
+    State(StateChange, Then),
+    // setting up the mailbox sync managers again in init, they are reset along with the state
+    Mailbox(MailboxConfigState),
+    // writing the pdo mapping again while in preop
+    Pdos(PdoMappingConfig<'a>),
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Then {
+    Done,
+    // coming back up from init
+    PreOp,
+    // mapping the pdos again before going back up to op
+    Pdos,
+}
+
+impl<'a> DeviceChange<'a> {
+    // returns `None` if the subdevice is already in `target` and there is nothing else to do
+    pub(crate) fn start_new<const I: usize, const O: usize>(
+        current: SubDeviceState,
+        target: SubDeviceState,
+        then: Then,
+        ctx: &mut IoCtx,
+        subdev: &SubDevice,
+        config: &'a PdoConfig<'a, I, O>,
+        idx: u16,
+    ) -> Result<Option<Self>, Error> {
+        let mut change = StateChange::new(current, target);
+        if change.start(
+            ctx,
+            subdev.configured_address(),
+            Some(STATE_CHANGE_IDENTIFIER),
+            idx,
+        )? {
+            return Self::then(then, target, ctx, subdev, config, idx);
+        }
+        Ok(Some(Self::State(change, then)))
+    }
+
+    fn then<const I: usize, const O: usize>(
+        then: Then,
+        current: SubDeviceState,
+        ctx: &mut IoCtx,
+        subdev: &SubDevice,
+        config: &'a PdoConfig<'a, I, O>,
+        idx: u16,
+    ) -> Result<Option<Self>, Error> {
+        match then {
+            Then::Done => Ok(None),
+            Then::PreOp => {
+                // the subdevice starts counting its mailbox messages from the beginning again
+                ctx.mailbox_counters.reset(subdev.configured_address());
+
+                if crate::mbx::mailboxes(subdev).is_ok() {
+                    let mut mbx = MailboxConfigState::new();
+                    mbx.start(
+                        ctx,
+                        subdev.configured_address(),
+                        idx,
+                        Some(STATE_CHANGE_IDENTIFIER),
+                    )?;
+                    return Ok(Some(Self::Mailbox(mbx)));
+                }
+
+                Self::start_new(
+                    current,
+                    SubDeviceState::PreOp,
+                    Then::Pdos,
+                    ctx,
+                    subdev,
+                    config,
+                    idx,
+                )
+            }
+            Then::Pdos => {
+                // the mapping of subdevices without a mailbox is fixed
+                let Ok((write_mbx, read_mbx)) = crate::mbx::mailboxes(subdev) else {
+                    return Self::start_new(
+                        current,
+                        SubDeviceState::Op,
+                        Then::Done,
+                        ctx,
+                        subdev,
+                        config,
+                        idx,
+                    );
+                };
+
+                let mut pdos = PdoMappingConfig::new(config, subdev)?;
+                pdos.start(
+                    ctx,
+                    &write_mbx,
+                    &read_mbx,
+                    subdev.configured_address(),
+                    Some(PDO_REMAP_IDENTIFIER),
+                    idx,
+                )?;
+                Ok(Some(Self::Pdos(pdos)))
+            }
+        }
+    }
+
+    // whether a response belongs to the change, the others are the user's
+    pub(crate) fn owns(&self, identifier: Option<u8>) -> bool {
+        match self {
+            Self::State(..) | Self::Mailbox(_) => identifier == Some(STATE_CHANGE_IDENTIFIER),
+            Self::Pdos(_) => identifier.is_some_and(|id| id >> 4 == PDO_REMAP_IDENTIFIER),
+        }
+    }
+
+    // returns the state the subdevice ended up in once the change is done
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn update<const I: usize, const O: usize>(
+        &mut self,
+        received: ReceivedPdu<'_>,
+        header: PduHeader,
+        ctx: &mut IoCtx,
+        subdev: &mut SubDevice,
+        config: &'a PdoConfig<'a, I, O>,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<Option<SubDeviceState>, Error> {
+        let configured_addr = subdev.configured_address();
+
+        let (next, state) = match self {
+            Self::State(change, then) => {
+                if !change.update(
+                    received,
+                    header,
+                    ctx,
+                    configured_addr,
+                    Some(STATE_CHANGE_IDENTIFIER),
+                    idx,
+                )? {
+                    return Ok(None);
+                }
+
+                let state = change.state();
+                (Self::then(*then, state, ctx, subdev, config, idx)?, state)
+            }
+            Self::Mailbox(mbx) => {
+                // the eeprom was handed back to the pdi, which is as far as the mailbox
+                // configuration goes here. the transition is made by the change itself
+                if !matches!(mbx, MailboxConfigState::SetEepromPdi) {
+                    mbx.update(
+                        received,
+                        header,
+                        ctx,
+                        configured_addr,
+                        idx,
+                        subdev,
+                        identifier,
+                    )?;
+                    return Ok(None);
+                }
+
+                let next = Self::start_new(
+                    SubDeviceState::Init,
+                    SubDeviceState::PreOp,
+                    Then::Pdos,
+                    ctx,
+                    subdev,
+                    config,
+                    idx,
+                )?;
+                (next, SubDeviceState::Init)
+            }
+            Self::Pdos(pdos) => {
+                let (write_mbx, read_mbx) = crate::mbx::mailboxes(subdev)?;
+                if !pdos.update(
+                    received,
+                    header,
+                    ctx,
+                    &write_mbx,
+                    &read_mbx,
+                    configured_addr,
+                    identifier,
+                    idx,
+                    subdev,
+                    config,
+                )? {
+                    return Ok(None);
+                }
+
+                let next = Self::start_new(
+                    SubDeviceState::PreOp,
+                    SubDeviceState::Op,
+                    Then::Done,
+                    ctx,
+                    subdev,
+                    config,
+                    idx,
+                )?;
+                (next, SubDeviceState::Op)
+            }
+        };
+
+        match next {
+            Some(next) => {
+                *self = next;
+                Ok(None)
+            }
+            None => Ok(Some(state)),
+        }
+    }
+
+    // the state the subdevice was last brought to
+    pub(crate) fn state(&self) -> SubDeviceState {
+        match self {
+            Self::State(change, _) => change.state(),
+            Self::Mailbox(_) => SubDeviceState::Init,
+            Self::Pdos(_) => SubDeviceState::PreOp,
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use crate::emergency::EmergencyQueue;
+use crate::eoe::{Tap, Taps};
+use crate::error::Error;
+use crate::gateway::Gateway;
+use crate::io::{GATEWAY_MASK, IoCtx, TAP_MASK, TIMEOUT_CLEAR_MASK, TIMEOUT_MASK, WRITE_MASK};
+use crate::mbx::{MailboxCounters, MailboxStatus};
+use crate::pdo::PdoConfig;
+use crate::state::{InitState, Settings};
+use crate::txbuf::{TxBuf, TxIndex};
+use ethercrab::{MainDevice, PduRx, std::RawSocketDesc};
+use io_uring::{IoUring, opcode, types::Timespec};
+use std::collections::BTreeMap;
+
+const RING_ENTRIES: u32 = 64;
+const RX_BUF_ENTRIES: u16 = 256;
+const RX_BUF_GROUP: u16 = 0;
+
+// ethernet header + fcs on top of the interface mtu
+const ETH_OVERHEAD: usize = 18;
+
+// how many subdevices are configured at once by default, see `Driver::set_concurrency`
+const DEFAULT_CONCURRENCY: u16 = 8;
+
+type RxBufRing = io_uring_buf_ring::BufRing<io_uring_buf_ring::Initialized>;
+
+fn write_entry(id: u64) -> u64 {
+    id | WRITE_MASK
+}
+
+fn timeout_entry(id: u64) -> u64 {
+    id | TIMEOUT_MASK
+}
+
+// owns everything needed to drive the io_uring completion loop,
+// so that applications only need to supply the pdo config and the per device callback.
+pub struct Driver<'a, 'sto, const N: usize, const I: usize, const O: usize, U> {
+    maindevice: MainDevice<'sto>,
+    rx: PduRx<'sto>,
+    ring: IoUring,
+    sock: RawSocketDesc,
+    rx_bufs: RxBufRing,
+    tx_entries: BTreeMap<u64, TxBuf<'sto>>,
+    emergencies: EmergencyQueue,
+    taps: Taps,
+    mailbox_counters: MailboxCounters,
+    mailbox_status: MailboxStatus,
+    gateway: Option<Gateway>,
+    settings: Settings,
+    retry_count: usize,
+    timeout: Timespec,
+    pdi_offset: ethercrab::PdiOffset,
+    state: InitState<'a, N, I, O, U>,
+}
+
+impl<'a, 'sto, const N: usize, const I: usize, const O: usize, U: crate::user::UserDevice>
+    Driver<'a, 'sto, N, I, O, U>
+{
+    pub fn new(
+        interface: &str,
+        maindevice: MainDevice<'sto>,
+        rx: PduRx<'sto>,
+        retry_count: usize,
+        timeout: Timespec,
+    ) -> std::io::Result<Self> {
+        let ring = IoUring::new(RING_ENTRIES)?;
+
+        let mut probe = io_uring::register::Probe::new();
+        ring.submitter().register_probe(&mut probe)?;
+
+        if !probe.is_supported(opcode::RecvMulti::CODE) || !probe.is_supported(opcode::Write::CODE)
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "readmulti/write opcodes are not supported",
+            ));
+        }
+
+        let mut sock = RawSocketDesc::new(interface)?;
+        let mtu = sock.interface_mtu()? + ETH_OVERHEAD;
+
+        let rx_bufs = io_uring_buf_ring::BufRing::new(RX_BUF_ENTRIES as _, mtu as _, RX_BUF_GROUP)?
+            .register(&ring.submitter())
+            .map_err(|(err, _)| err)?
+            .init();
+
+        let mut driver = Self {
+            maindevice,
+            rx,
+            ring,
+            sock,
+            rx_bufs,
+            tx_entries: BTreeMap::new(),
+            emergencies: EmergencyQueue::default(),
+            taps: Taps::default(),
+            mailbox_counters: MailboxCounters::default(),
+            mailbox_status: MailboxStatus::default(),
+            gateway: None,
+            settings: Settings {
+                concurrency: DEFAULT_CONCURRENCY,
+                broadcast_transitions: false,
+            },
+            retry_count,
+            timeout,
+            pdi_offset: ethercrab::PdiOffset::default(),
+            state: InitState::new(),
+        };
+        driver.submit_recv()?;
+        Ok(driver)
+    }
+
+    fn submit_recv(&mut self) -> std::io::Result<()> {
+        use std::os::fd::AsRawFd;
+        let rx_multi_entry = opcode::RecvMulti::new(
+            io_uring::types::Fd(self.sock.as_raw_fd()),
+            self.rx_bufs.bgid(),
+        )
+        .build();
+
+        while unsafe { self.ring.submission().push(&rx_multi_entry).is_err() } {
+            self.ring.submit()?;
+        }
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    // bridges `tap` with the eoe traffic of the device at `idx`, see `Eoe`
+    pub fn add_tap(&mut self, idx: u16, tap: Tap) -> std::io::Result<()> {
+        self.taps.insert(idx, tap);
+        self.submit_tap_poll(idx)
+    }
+
+    // whether the device at `idx` is still bridged with a tap, taps are dropped once they fail
+    pub fn has_tap(&self, idx: u16) -> bool {
+        self.taps.contains(idx)
+    }
+
+    fn submit_tap_poll(&mut self, idx: u16) -> std::io::Result<()> {
+        let Some(poll) = self.taps.poll_entry(idx) else {
+            return Ok(());
+        };
+
+        while unsafe { self.ring.submission().push(&poll).is_err() } {
+            self.ring.submit()?;
+        }
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    // forwards the mailbox requests that arrive on `gateway` while the bus is in op
+    pub fn set_gateway(&mut self, gateway: Gateway) -> std::io::Result<()> {
+        self.gateway = Some(gateway);
+        self.submit_gateway_poll()
+    }
+
+    fn submit_gateway_poll(&mut self) -> std::io::Result<()> {
+        let Some(poll) = self.gateway.as_ref().map(Gateway::poll_entry) else {
+            return Ok(());
+        };
+
+        while unsafe { self.ring.submission().push(&poll).is_err() } {
+            self.ring.submit()?;
+        }
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    // how many subdevices have their eeprom read, mailboxes configured, pdos mapped and
+    // transitions requested at once while the bus is brought up, 1 does them one after another
+    pub fn set_concurrency(&mut self, limit: u16) {
+        self.settings.concurrency = limit.max(1);
+    }
+
+    // whether op is requested of every subdevice with a single broadcast, instead of one after
+    // another. the subdevices that do not get there are checked one by one and reported together
+    // in `Error::BroadcastTransition`
+    pub fn set_broadcast_transitions(&mut self, enabled: bool) {
+        self.settings.broadcast_transitions = enabled;
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn split(
+        &mut self,
+    ) -> (
+        IoCtx<'_, 'sto>,
+        &mut InitState<'a, N, I, O, U>,
+        &mut ethercrab::PdiOffset,
+    ) {
+        let ctx = IoCtx::new(
+            &mut self.maindevice,
+            self.retry_count,
+            &self.timeout,
+            &mut self.tx_entries,
+            &self.sock,
+            &mut self.ring,
+            &write_entry,
+            &timeout_entry,
+            &mut self.emergencies,
+            &mut self.taps,
+            &mut self.mailbox_counters,
+            &mut self.mailbox_status,
+            self.gateway.as_mut(),
+        );
+        (ctx, &mut self.state, &mut self.pdi_offset)
+    }
+
+    // resets the bus and starts bringing the subdevices up to op
+    pub fn start(&mut self) -> Result<(), Error> {
+        let (mut ctx, state, pdi_offset) = self.split();
+        *pdi_offset = ethercrab::PdiOffset::default();
+        state.start(&mut ctx)
+    }
+
+    // brings the subdevices back down to init, `run` returns once they are there
+    pub fn shutdown(&mut self) -> Result<(), Error> {
+        let (mut ctx, state, _) = self.split();
+        state.shutdown(&mut ctx)
+    }
+
+    pub fn state(&self) -> &InitState<'a, N, I, O, U> {
+        &self.state
+    }
+
+    pub fn maindevice(&self) -> &MainDevice<'sto> {
+        &self.maindevice
+    }
+
+    /// processes a single completion queue entry if one is available
+    /// - returns `Ok(true)` if an entry was processed
+    #[allow(clippy::type_complexity)]
+    pub fn run_once(
+        &mut self,
+        config: impl FnMut(&MainDevice, ethercrab::SubDevice) -> (U, &'a PdoConfig<'a, I, O>),
+        user_cb: impl FnMut(
+            &mut IoCtx,
+            &mut U,
+            Option<crate::op::DeviceResponse<'_, '_>>,
+            u16,
+            Option<u8>,
+            &mut [u8],
+        ) -> std::io::Result<Option<crate::user::ControlFlow>>,
+    ) -> Result<bool, Error> {
+        let Some(entry) = self.ring.completion().next() else {
+            return Ok(false);
+        };
+
+        let udata = entry.user_data();
+        if udata & TIMEOUT_CLEAR_MASK == TIMEOUT_CLEAR_MASK {
+            let key = udata & 0xFFFFFF;
+            let Some(res) = self.tx_entries.remove(&key) else {
+                return Ok(true);
+            };
+
+            let Some((header, pdu)) = res.received else {
+                // ran out of retries without getting a response
+                let (mut ctx, state, _) = self.split();
+                state.timeout(
+                    &mut ctx,
+                    (key >> 16) as u8,
+                    res.configured_addr,
+                    res.identifier,
+                    user_cb,
+                )?;
+                return Ok(true);
+            };
+
+            let settings = self.settings;
+            let (mut ctx, state, pdi_offset) = self.split();
+            state.update(
+                pdu,
+                header,
+                &mut ctx,
+                res.configured_addr,
+                res.identifier,
+                pdi_offset,
+                settings,
+                config,
+                user_cb,
+            )?;
+            return Ok(true);
+        } else if udata & WRITE_MASK == WRITE_MASK {
+            return Ok(true);
+        } else if udata & TIMEOUT_MASK == TIMEOUT_MASK {
+            if matches!(-entry.result(), libc::ECANCELED) {
+                return Ok(true);
+            }
+
+            let key = udata & 0xFFFFFF;
+
+            let Some(tx_entry) = self.tx_entries.get_mut(&key) else {
+                return Ok(true);
+            };
+
+            if tx_entry.retries_remaining == 0 {
+                let timeout_clear = opcode::TimeoutRemove::new(key | TIMEOUT_MASK)
+                    .build()
+                    .user_data(key | TIMEOUT_CLEAR_MASK);
+
+                while unsafe { self.ring.submission().push(&timeout_clear).is_err() } {
+                    self.ring.submit()?;
+                }
+            } else {
+                while unsafe { self.ring.submission().push(tx_entry.entry()).is_err() } {
+                    self.ring.submit()?;
+                }
+                tx_entry.retries_remaining -= 1;
+            }
+            self.ring.submit()?;
+            return Ok(true);
+        } else if udata & TAP_MASK == TAP_MASK {
+            let idx = udata as u16;
+            match entry.result() {
+                res if res >= 0 => self.taps.receive(idx),
+                err if -err == libc::EINTR || -err == libc::EAGAIN => (),
+                // the bus keeps running without it
+                _ => self.taps.remove(idx),
+            }
+
+            self.submit_tap_poll(idx)?;
+            return Ok(true);
+        } else if udata & GATEWAY_MASK == GATEWAY_MASK {
+            match entry.result() {
+                res if res >= 0 => {
+                    if let Some(gateway) = &mut self.gateway {
+                        gateway.receive()?;
+                    }
+                }
+                err if -err == libc::EINTR || -err == libc::EAGAIN => (),
+                err => return Err(std::io::Error::from_raw_os_error(-err).into()),
+            }
+
+            self.submit_gateway_poll()?;
+            return Ok(true);
+        }
+
+        let Ok(Some(id)) = self.rx_bufs.buffer_id_from_cqe(&entry) else {
+            return Ok(true);
+        };
+
+        let Some(recv_frame) = self.rx.receive_frame_io_uring(id.buffer())? else {
+            return Ok(true);
+        };
+
+        let frame: ethercrab::received_frame::ReceivedFrame = recv_frame.into();
+
+        for (idx, res) in frame.into_pdu_iter_with_headers().enumerate() {
+            let Ok((pdu, header)) = res else {
+                continue;
+            };
+            let rx_idx = (idx, header).idx();
+
+            if let Some(tx_entry) = self.tx_entries.get_mut(&rx_idx) {
+                tx_entry.received = Some((header, pdu));
+
+                let timeout_clear = opcode::TimeoutRemove::new(rx_idx | TIMEOUT_MASK)
+                    .build()
+                    .user_data(rx_idx | TIMEOUT_CLEAR_MASK);
+
+                while unsafe { self.ring.submission().push(&timeout_clear).is_err() } {
+                    self.ring.submit()?;
+                }
+
+                self.ring.submit()?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// drives the completion loop until the bus was shut down or stopped, see `ControlFlow`
+    #[allow(clippy::type_complexity)]
+    pub fn run(
+        &mut self,
+        mut config: impl FnMut(&MainDevice, ethercrab::SubDevice) -> (U, &'a PdoConfig<'a, I, O>),
+        mut user_cb: impl FnMut(
+            &mut IoCtx,
+            &mut U,
+            Option<crate::op::DeviceResponse<'_, '_>>,
+            u16,
+            Option<u8>,
+            &mut [u8],
+        ) -> std::io::Result<Option<crate::user::ControlFlow>>,
+    ) -> Result<(), Error> {
+        while !self.state.is_stopped() {
+            self.run_once(&mut config, &mut user_cb)?;
+        }
+        Ok(())
+    }
+}
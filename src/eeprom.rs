@@ -1,10 +1,7 @@
+use crate::error::Error;
+use crate::io::IoCtx;
 use crate::setup::setup_write;
-use crate::txbuf::TxBuf;
-use ethercrab::{
-    MainDevice, PduHeader, error::Error, received_frame::ReceivedPdu, std::RawSocketDesc,
-};
-use io_uring::{IoUring, types::Timespec};
-use std::collections::BTreeMap;
+use ethercrab::{PduHeader, received_frame::ReceivedPdu};
 
 use range::RangeReader;
 use read_state::RegisterReadState;
@@ -24,78 +21,40 @@ pub(crate) mod read_state {
     }
 
     impl RegisterReadState {
-        #[allow(clippy::too_many_arguments)]
         pub(crate) fn start(
             &mut self,
-            maindevice: &MainDevice,
-            retry_count: usize,
-            timeout_duration: &Timespec,
-            tx_entries: &mut BTreeMap<u64, TxBuf>,
-            sock: &RawSocketDesc,
-            ring: &mut IoUring,
+            ctx: &mut IoCtx,
             configured_addr: u16,
             start_addr: u16,
             idx: u16,
             identifier: u8,
-            write_entry: impl Fn(u64) -> u64,
-            timeout_entry: impl Fn(u64) -> u64,
         ) -> Result<(), Error> {
-            let (frame, handle) = maindevice
+            let (frame, handle) = ctx
+                .maindevice
                 .prep_read_eeprom_chunk(configured_addr, start_addr)?
-                .unwrap();
-            setup_write(
-                frame,
-                handle,
-                retry_count,
-                timeout_duration,
-                tx_entries,
-                sock,
-                ring,
-                Some(idx),
-                Some(identifier),
-                &write_entry,
-                &timeout_entry,
-            )?;
+                .ok_or(Error::NoFrame)?;
+            setup_write(frame, handle, &mut ctx.tx, Some(idx), Some(identifier))?;
             *self = Self::RequestRead;
             Ok(())
         }
 
-        #[allow(clippy::too_many_arguments)]
         pub(crate) fn update<'p>(
             &mut self,
             received: ReceivedPdu<'p>,
             _header: PduHeader,
-            maindevice: &MainDevice,
-            retry_count: usize,
-            timeout_duration: &Timespec,
-            tx_entries: &mut BTreeMap<u64, TxBuf>,
-            sock: &RawSocketDesc,
-            ring: &mut IoUring,
+            ctx: &mut IoCtx,
             configured_addr: u16,
             index: u16,
             identifier: u8,
-            write_entry: impl Fn(u64) -> u64,
-            timeout_entry: impl Fn(u64) -> u64,
         ) -> Result<Option<ReceivedPdu<'p>>, Error> {
             match self {
                 Self::RequestRead => {
-                    let (frame, handle) = maindevice
+                    let (frame, handle) = ctx
+                        .maindevice
                         .prep_wait_for_eeprom_chunk(configured_addr)?
-                        .unwrap();
-
-                    setup_write(
-                        frame,
-                        handle,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
-                        Some(index),
-                        Some(identifier),
-                        &write_entry,
-                        &timeout_entry,
-                    )?;
+                        .ok_or(Error::NoFrame)?;
+
+                    setup_write(frame, handle, &mut ctx.tx, Some(index), Some(identifier))?;
                     *self = Self::WaitForDevice;
                 }
                 Self::WaitForDevice => {
@@ -103,27 +62,16 @@ pub(crate) mod read_state {
                     let ctrl = ethercrab::SiiControl::unpack_from_slice(&received)?;
                     let (frame, handle) = if ctrl.busy {
                         // resubmit the wait req
-                        maindevice.prep_wait_for_eeprom_chunk(configured_addr)
+                        ctx.maindevice.prep_wait_for_eeprom_chunk(configured_addr)
                     } else {
                         *self = Self::ReadData;
                         // send a read req
-                        maindevice.prep_read_available_eeprom_chunk(configured_addr, ctrl)
+                        ctx.maindevice
+                            .prep_read_available_eeprom_chunk(configured_addr, ctrl)
                     }?
-                    .unwrap();
-
-                    setup_write(
-                        frame,
-                        handle,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
-                        Some(index),
-                        Some(identifier),
-                        &write_entry,
-                        &timeout_entry,
-                    )?;
+                    .ok_or(Error::NoFrame)?;
+
+                    setup_write(frame, handle, &mut ctx.tx, Some(index), Some(identifier))?;
                 }
                 Self::ReadData => return Ok(Some(received)),
             }
@@ -155,71 +103,41 @@ pub mod range {
             }
         }
 
-        #[allow(clippy::too_many_arguments)]
         pub fn start(
             &mut self,
-            maindevice: &MainDevice,
-            retry_count: usize,
-            timeout_duration: &Timespec,
-            tx_entries: &mut BTreeMap<u64, TxBuf>,
-            sock: &RawSocketDesc,
-            ring: &mut IoUring,
+            ctx: &mut IoCtx,
             configured_addr: u16,
             idx: u16,
-            write_entry: impl Fn(u64) -> u64,
-            timeout_entry: impl Fn(u64) -> u64,
         ) -> Result<(), Error> {
             self.state.start(
-                maindevice,
-                retry_count,
-                timeout_duration,
-                tx_entries,
-                sock,
-                ring,
+                ctx,
                 configured_addr,
                 self.start + (self.offset / 2),
                 idx,
                 self.identifier,
-                &write_entry,
-                &timeout_entry,
             )
         }
 
-        #[allow(clippy::too_many_arguments)]
         pub fn update(
             &mut self,
             received: ReceivedPdu<'_>,
             header: PduHeader,
-            maindevice: &MainDevice,
-            retry_count: usize,
-            timeout_duration: &Timespec,
-            tx_entries: &mut BTreeMap<u64, TxBuf>,
-            sock: &RawSocketDesc,
-            ring: &mut IoUring,
+            ctx: &mut IoCtx,
             configured_addr: u16,
             index: u16,
-            write_entry: impl Fn(u64) -> u64,
-            timeout_entry: impl Fn(u64) -> u64,
         ) -> Result<bool, Error> {
             if let Some(buf) = self.state.update(
                 received,
                 header,
-                maindevice,
-                retry_count,
-                timeout_duration,
-                tx_entries,
-                sock,
-                ring,
+                ctx,
                 configured_addr,
                 index,
                 self.identifier,
-                &write_entry,
-                &timeout_entry,
             )? {
                 let bytes = &*buf;
 
                 let skip = usize::from(self.offset % 2);
-                let chunk = bytes.get(skip..).unwrap();
+                let chunk = bytes.get(skip..).ok_or(Error::Sii)?;
 
                 let min_len = core::cmp::min(usize::from(self.len), self.buffer.len());
                 let remaining_buf = &mut self.buffer
@@ -236,18 +154,7 @@ pub mod range {
                 let buf = &mut remaining_buf[..chunk.len()];
                 buf.copy_from_slice(chunk);
                 // continue to read.
-                self.start(
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    configured_addr,
-                    index,
-                    &write_entry,
-                    &timeout_entry,
-                )?;
+                self.start(ctx, configured_addr, index)?;
             }
             Ok(false)
         }
@@ -278,66 +185,31 @@ pub mod category {
             }
         }
 
-        #[allow(clippy::too_many_arguments)]
         pub fn start(
             &mut self,
-            maindevice: &MainDevice,
-            retry_count: usize,
-            timeout_duration: &Timespec,
-            tx_entries: &mut BTreeMap<u64, TxBuf>,
-            sock: &RawSocketDesc,
-            ring: &mut IoUring,
+            ctx: &mut IoCtx,
             configured_addr: u16,
             idx: u16,
-            write_entry: impl Fn(u64) -> u64,
-            timeout_entry: impl Fn(u64) -> u64,
         ) -> Result<(), Error> {
-            self.state.start(
-                maindevice,
-                retry_count,
-                timeout_duration,
-                tx_entries,
-                sock,
-                ring,
-                configured_addr,
-                self.addr,
-                idx,
-                self.identifier,
-                &write_entry,
-                &timeout_entry,
-            )
+            self.state
+                .start(ctx, configured_addr, self.addr, idx, self.identifier)
         }
 
-        #[allow(clippy::too_many_arguments)]
         pub fn update(
             &mut self,
             received: ReceivedPdu<'_>,
             header: PduHeader,
-            maindevice: &MainDevice,
-            retry_count: usize,
-            timeout_duration: &Timespec,
-            tx_entries: &mut BTreeMap<u64, TxBuf>,
-            sock: &RawSocketDesc,
-            ring: &mut IoUring,
+            ctx: &mut IoCtx,
             configured_addr: u16,
             index: u16,
-            write_entry: impl Fn(u64) -> u64,
-            timeout_entry: impl Fn(u64) -> u64,
         ) -> Result<bool, Error> {
             if let Some(buf) = self.state.update(
                 received,
                 header,
-                maindevice,
-                retry_count,
-                timeout_duration,
-                tx_entries,
-                sock,
-                ring,
+                ctx,
                 configured_addr,
                 index,
                 self.identifier,
-                &write_entry,
-                &timeout_entry,
             )? {
                 let bytes = &*buf;
 
@@ -348,8 +220,8 @@ pub mod category {
                 }
 
                 let (ty, len) = {
-                    let (ty, rem) = bytes.split_first_chunk::<2>().unwrap();
-                    let (len, _) = rem.split_first_chunk::<2>().unwrap();
+                    let (ty, rem) = bytes.split_first_chunk::<2>().ok_or(Error::Sii)?;
+                    let (len, _) = rem.split_first_chunk::<2>().ok_or(Error::Sii)?;
                     (ty, len)
                 };
 
@@ -386,18 +258,7 @@ pub mod category {
 
                 self.addr += len_words;
                 // did not find desired category, continue
-                self.start(
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    configured_addr,
-                    index,
-                    &write_entry,
-                    &timeout_entry,
-                )?;
+                self.start(ctx, configured_addr, index)?;
             }
             Ok(false)
         }
@@ -413,125 +274,53 @@ pub mod category {
             Self::Categories(CategoryReader::new(category, identifier))
         }
 
-        #[allow(clippy::too_many_arguments)]
         pub fn start(
             &mut self,
-            maindevice: &MainDevice,
-            retry_count: usize,
-            timeout_duration: &Timespec,
-            tx_entries: &mut BTreeMap<u64, TxBuf>,
-            sock: &RawSocketDesc,
-            ring: &mut IoUring,
+            ctx: &mut IoCtx,
             configured_addr: u16,
             idx: u16,
-            write_entry: impl Fn(u64) -> u64,
-            timeout_entry: impl Fn(u64) -> u64,
         ) -> Result<(), Error> {
             match self {
-                Self::Categories(cat) => cat.start(
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    configured_addr,
-                    idx,
-                    write_entry,
-                    timeout_entry,
-                ),
-                _ => unreachable!(),
+                Self::Categories(cat) => cat.start(ctx, configured_addr, idx),
+                _ => Err(Error::UnexpectedState {
+                    expected: ethercrab::SubDeviceState::Init,
+                    found: None,
+                }),
             }
         }
 
         // returns Some(..) if iteration is ready, Some(true) if more expected to follow, otherwise
         // Some(false)
-        #[allow(clippy::too_many_arguments)]
         pub fn update(
             &mut self,
             received: ReceivedPdu<'_>,
             header: PduHeader,
-            maindevice: &MainDevice,
-            retry_count: usize,
-            timeout_duration: &Timespec,
-            tx_entries: &mut BTreeMap<u64, TxBuf>,
-            sock: &RawSocketDesc,
-            ring: &mut IoUring,
+            ctx: &mut IoCtx,
             configured_addr: u16,
             idx: u16,
-            write_entry: impl Fn(u64) -> u64,
-            timeout_entry: impl Fn(u64) -> u64,
         ) -> Result<Option<bool>, Error> {
             match self {
                 Self::Categories(cat) => {
-                    if cat.update(
-                        received,
-                        header,
-                        maindevice,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
-                        configured_addr,
-                        idx,
-                        &write_entry,
-                        &timeout_entry,
-                    )? {
+                    if cat.update(received, header, ctx, configured_addr, idx)? {
                         match core::mem::take(&mut cat.found) {
                             // could not find category :(
                             None => return Ok(Some(false)),
                             Some(found) => {
                                 let mut reader =
                                     RangeReader::new(found.start, N as _, [0; N], cat.identifier);
-                                reader.start(
-                                    maindevice,
-                                    retry_count,
-                                    timeout_duration,
-                                    tx_entries,
-                                    sock,
-                                    ring,
-                                    configured_addr,
-                                    idx,
-                                    &write_entry,
-                                    &timeout_entry,
-                                )?;
+                                reader.start(ctx, configured_addr, idx)?;
                                 *self = Self::Category(reader, found);
                             }
                         }
                     }
                 }
                 Self::Category(cat, found) => {
-                    if cat.update(
-                        received,
-                        header,
-                        maindevice,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
-                        configured_addr,
-                        idx,
-                        &write_entry,
-                        &timeout_entry,
-                    )? {
+                    if cat.update(received, header, ctx, configured_addr, idx)? {
                         if cat.start + (cat.offset / 2) >= found.start + (found.len / 2) {
                             return Ok(Some(false));
                         }
 
-                        cat.start(
-                            maindevice,
-                            retry_count,
-                            timeout_duration,
-                            tx_entries,
-                            sock,
-                            ring,
-                            configured_addr,
-                            idx,
-                            &write_entry,
-                            &timeout_entry,
-                        )?;
+                        cat.start(ctx, configured_addr, idx)?;
 
                         return Ok(Some(true));
                     }
@@ -574,75 +363,45 @@ pub mod string {
             }
         }
 
-        #[allow(clippy::too_many_arguments)]
         pub fn start(
             &mut self,
-            maindevice: &MainDevice,
-            retry_count: usize,
-            timeout_duration: &Timespec,
-            tx_entries: &mut BTreeMap<u64, TxBuf>,
-            sock: &RawSocketDesc,
-            ring: &mut IoUring,
+            ctx: &mut IoCtx,
             configured_addr: u16,
             idx: u16,
-            write_entry: impl Fn(u64) -> u64,
-            timeout_entry: impl Fn(u64) -> u64,
         ) -> Result<(), Error> {
             self.state.start(
-                maindevice,
-                retry_count,
-                timeout_duration,
-                tx_entries,
-                sock,
-                ring,
+                ctx,
                 configured_addr,
                 self.start + (self.offset / 2),
                 idx,
                 self.identifier,
-                write_entry,
-                timeout_entry,
             )
         }
 
-        #[allow(clippy::too_many_arguments)]
         pub fn update(
             &mut self,
             received: ReceivedPdu<'_>,
             header: PduHeader,
-            maindevice: &MainDevice,
-            retry_count: usize,
-            timeout_duration: &Timespec,
-            tx_entries: &mut BTreeMap<u64, TxBuf>,
-            sock: &RawSocketDesc,
-            ring: &mut IoUring,
+            ctx: &mut IoCtx,
             configured_addr: u16,
             index: u16,
-            write_entry: impl Fn(u64) -> u64,
-            timeout_entry: impl Fn(u64) -> u64,
         ) -> Result<bool, Error> {
             if let Some(buf) = self.state.update(
                 received,
                 header,
-                maindevice,
-                retry_count,
-                timeout_duration,
-                tx_entries,
-                sock,
-                ring,
+                ctx,
                 configured_addr,
                 index,
                 self.identifier,
-                &write_entry,
-                &timeout_entry,
             )? {
                 let bytes = &*buf;
 
                 let skip = usize::from(self.offset % 2);
-                let bytes = bytes.get(skip..).unwrap();
+                let bytes = bytes.get(skip..).ok_or(Error::Sii)?;
 
                 let len = if self.first {
                     self.first = false;
-                    let count = bytes[0];
+                    let count = *bytes.first().ok_or(Error::Sii)?;
                     if count < self.string_offset {
                         return Ok(true);
                     }
@@ -667,18 +426,7 @@ pub mod string {
                 self.offset += len as u16;
 
                 // continue to read.
-                self.start(
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    configured_addr,
-                    index,
-                    &write_entry,
-                    &timeout_entry,
-                )?;
+                self.start(ctx, configured_addr, index)?;
             }
             Ok(false)
         }
@@ -0,0 +1,122 @@
+use crate::coe;
+use heapless::Deque;
+use std::collections::BTreeMap;
+
+// emergencies kept per subdevice until they are handed to the user callback,
+// the oldest ones are dropped once this many pile up
+const QUEUE_LEN: usize = 8;
+
+// error code + error register + manufacturer specific data
+const EMERGENCY_LEN: usize = 8;
+
+// sent unsolicited by the subdevice through its read mailbox when an error occurs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Emergency {
+    pub error_code: u16,
+    pub error_register: u8,
+    pub data: [u8; 5],
+}
+
+impl Emergency {
+    // the emergency in the raw contents of the read mailbox, or None if it holds something else
+    pub(crate) fn parse(bytes: &[u8]) -> Option<Self> {
+        // only up to the length in the mailbox header, the rest of the mailbox is left over
+        let body = coe::coe_body(bytes, coe::COE_EMERGENCY).ok()?;
+        let body = body.first_chunk::<EMERGENCY_LEN>()?;
+        let mut data = [0; 5];
+        data.copy_from_slice(&body[3..]);
+
+        Some(Self {
+            error_code: u16::from_le_bytes([body[0], body[1]]),
+            error_register: body[2],
+            data,
+        })
+    }
+}
+
+impl core::fmt::Display for Emergency {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "emergency {:#06x} (error register {:#04x}, data {:02x?})",
+            self.error_code, self.error_register, self.data
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct EmergencyQueue {
+    queues: BTreeMap<u16, Deque<Emergency, QUEUE_LEN>>,
+}
+
+impl EmergencyQueue {
+    pub(crate) fn push(&mut self, idx: u16, emergency: Emergency) {
+        let queue = self.queues.entry(idx).or_default();
+        if queue.is_full() {
+            let _ = queue.pop_front();
+        }
+        let _ = queue.push_back(emergency);
+    }
+
+    pub(crate) fn pop(&mut self, idx: u16) -> Option<Emergency> {
+        self.queues.get_mut(&idx)?.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an emergency from the subdevice at 0x1001, padded out to the size of its read mailbox
+    fn mailbox(len: u16, coe: &[u8]) -> [u8; 32] {
+        let mut bytes = [0xEE; 32];
+        bytes[..2].copy_from_slice(&len.to_le_bytes());
+        bytes[2..4].copy_from_slice(&0x1001u16.to_le_bytes());
+        bytes[4] = 0;
+        bytes[5] = coe::MBX_TYPE_COE | (1 << 4);
+        bytes[6..6 + coe.len()].copy_from_slice(coe);
+        bytes
+    }
+
+    const EMERGENCY: [u8; 10] = [0x00, 0x10, 0x10, 0x82, 0x11, 1, 2, 3, 4, 5];
+
+    #[test]
+    fn parse() {
+        let bytes = mailbox(10, &EMERGENCY);
+
+        assert_eq!(
+            Emergency::parse(&bytes),
+            Some(Emergency {
+                error_code: 0x8210,
+                error_register: 0x11,
+                data: [1, 2, 3, 4, 5],
+            })
+        );
+    }
+
+    #[test]
+    fn length_too_short() {
+        // the rest of the emergency is whatever was left in the mailbox
+        let bytes = mailbox(6, &EMERGENCY);
+        assert_eq!(Emergency::parse(&bytes), None);
+    }
+
+    #[test]
+    fn length_out_of_bounds() {
+        let bytes = mailbox(64, &EMERGENCY);
+        assert_eq!(Emergency::parse(&bytes), None);
+    }
+
+    #[test]
+    fn not_an_emergency() {
+        // an sdo response
+        let mut coe = EMERGENCY;
+        coe[1] = 0x30;
+        assert_eq!(Emergency::parse(&mailbox(10, &coe)), None);
+
+        // an eoe frame
+        let mut bytes = mailbox(10, &EMERGENCY);
+        bytes[5] = coe::MBX_TYPE_EOE;
+        assert_eq!(Emergency::parse(&bytes), None);
+    }
+}
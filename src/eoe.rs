@@ -0,0 +1,734 @@
+use crate::coe;
+use crate::error::Error;
+use crate::io::{IoCtx, TAP_MASK};
+use crate::mbx::{MbxPoll, MbxWriteRead, Polled};
+use ethercrab::{PduHeader, received_frame::ReceivedPdu};
+use io_uring::{opcode, squeue, types::Fd};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+// ETG.1000.6 ethernet over ethercat, frames are split into fragments that fit the mailbox
+
+// frame type / port / flags + fragment / offset / frame number
+const EOE_HEADER_LEN: usize = 4;
+
+const FRAME_FRAGMENT: u16 = 0x00;
+const SET_IP_REQUEST: u16 = 0x02;
+const SET_IP_RESPONSE: u16 = 0x03;
+
+const LAST_FRAGMENT: u16 = 1 << 8;
+
+// fragment offsets and frame sizes are given in these units
+const FRAGMENT_UNIT: usize = 32;
+
+// frames from the tap that have not been sent to the subdevice yet,
+// the oldest ones are dropped once this many pile up
+const TAP_QUEUE_LEN: usize = 16;
+
+// ethernet header + vlan tag + payload, the tap is opened without packet info
+const TAP_FRAME_LEN: usize = 1518;
+
+// everything that can be set through a set ip parameter request, fields that are None are left as is
+#[derive(Debug, Clone, Default)]
+pub struct IpParams {
+    pub mac: Option<[u8; 6]>,
+    pub ip: Option<Ipv4Addr>,
+    pub subnet: Option<Ipv4Addr>,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns: Option<Ipv4Addr>,
+    // at most 32 bytes, anything longer is cut off
+    pub dns_name: Option<String>,
+}
+
+impl IpParams {
+    fn request(&self, counter: u8, port: u8) -> coe::MbxBuf {
+        let mut flags = 0u32;
+        let mut body = coe::MbxBuf::new();
+
+        // every field is always there, the flags say which of them are used
+        let mut ip = |set: Option<Ipv4Addr>, flag: u32, body: &mut coe::MbxBuf| {
+            if set.is_some() {
+                flags |= flag;
+            }
+            body.extend_from_slice(&u32::from(set.unwrap_or(Ipv4Addr::UNSPECIFIED)).to_le_bytes());
+        };
+
+        body.extend_from_slice(&self.mac.unwrap_or_default());
+        ip(self.ip, 1 << 1, &mut body);
+        ip(self.subnet, 1 << 2, &mut body);
+        ip(self.gateway, 1 << 3, &mut body);
+        ip(self.dns, 1 << 4, &mut body);
+
+        let mut name = [0; 32];
+        if let Some(dns_name) = &self.dns_name {
+            let len = core::cmp::min(dns_name.len(), name.len());
+            name[..len].copy_from_slice(&dns_name.as_bytes()[..len]);
+            flags |= 1 << 5;
+        }
+        body.extend_from_slice(&name);
+
+        if self.mac.is_some() {
+            flags |= 1 << 0;
+        }
+
+        let mut buf = coe::MbxBuf::new();
+        coe::mailbox_header(
+            &mut buf,
+            EOE_HEADER_LEN + 4 + body.len(),
+            coe::MBX_TYPE_EOE,
+            counter,
+        );
+        buf.extend_from_slice(
+            &(SET_IP_REQUEST | (u16::from(port) << 4) | LAST_FRAGMENT).to_le_bytes(),
+        );
+        buf.extend_from_slice(&0u16.to_le_bytes());
+        buf.extend_from_slice(&flags.to_le_bytes());
+        buf.extend_from_slice(&body);
+        buf
+    }
+}
+
+// an outgoing frame that is partway through being sent
+#[derive(Debug)]
+struct TxFrame {
+    data: Vec<u8>,
+    offset: usize,
+    fragment: u8,
+    // 4 bit frame number the fragments are sent with
+    number: u8,
+}
+
+impl TxFrame {
+    // the next fragment that fits a write mailbox of `mbx_len` bytes, along with whether it is the
+    // last one
+    fn next_fragment(
+        &mut self,
+        mbx_len: usize,
+        port: u8,
+        counter: u8,
+    ) -> Result<(coe::MbxBuf, bool), Error> {
+        // every fragment but the last one has to be a multiple of 32 bytes
+        let max_len =
+            mbx_len.saturating_sub(coe::MBX_HEADER_LEN + EOE_HEADER_LEN) & !(FRAGMENT_UNIT - 1);
+        if max_len == 0 {
+            return Err(Error::InvalidMailbox);
+        }
+
+        let end = core::cmp::min(self.offset + max_len, self.data.len());
+        let last = end == self.data.len();
+
+        // the first fragment carries the size of the whole frame, the others their offset
+        let units = if self.fragment == 0 {
+            self.data.len().div_ceil(FRAGMENT_UNIT)
+        } else {
+            self.offset / FRAGMENT_UNIT
+        } as u16;
+
+        let word0 = FRAME_FRAGMENT | (u16::from(port) << 4) | if last { LAST_FRAGMENT } else { 0 };
+        let word1 = u16::from(self.fragment & 0x3F)
+            | ((units & 0x3F) << 6)
+            | (u16::from(self.number) << 12);
+
+        let mut buf = coe::MbxBuf::new();
+        coe::mailbox_header(
+            &mut buf,
+            EOE_HEADER_LEN + end - self.offset,
+            coe::MBX_TYPE_EOE,
+            counter,
+        );
+        buf.extend_from_slice(&word0.to_le_bytes());
+        buf.extend_from_slice(&word1.to_le_bytes());
+        buf.extend_from_slice(&self.data[self.offset..end]);
+
+        self.offset = end;
+        self.fragment += 1;
+        Ok((buf, last))
+    }
+}
+
+// an incoming frame that is being put back together
+#[derive(Debug, Default)]
+struct RxFrame {
+    data: Vec<u8>,
+    // frame number and last fragment that was received
+    fragment: Option<(u8, u8)>,
+}
+
+impl RxFrame {
+    // takes the fragment with the given header words, returns true once the frame is complete
+    fn fragment(&mut self, word0: u16, word1: u16, data: &[u8]) -> bool {
+        let fragment = (word1 & 0x3F) as u8;
+        let offset = usize::from((word1 >> 6) & 0x3F) * FRAGMENT_UNIT;
+        let frame_number = (word1 >> 12) as u8;
+
+        if fragment == 0 {
+            self.data.clear();
+        } else {
+            match self.fragment {
+                Some((number, last))
+                    if number == frame_number
+                        && last + 1 == fragment
+                        && offset == self.data.len() => {}
+                // a fragment went missing, so the whole frame is dropped
+                _ => {
+                    self.fragment = None;
+                    return false;
+                }
+            }
+        }
+
+        self.data.extend_from_slice(data);
+        self.fragment = Some((frame_number, fragment));
+
+        if word0 & LAST_FRAGMENT == 0 {
+            return false;
+        }
+        self.fragment = None;
+        true
+    }
+}
+
+// exchanges ethernet frames with a single subdevice, and with the tap that was added for it if there is one
+pub struct Eoe {
+    port: u8,
+    counter: u8,
+    // 4 bit frame number of the last frame that was sent
+    frame_number: u8,
+    set_ip: Option<IpParams>,
+    tx: Option<TxFrame>,
+    rx: RxFrame,
+    state: EoeState,
+}
+
+enum EoeState {
+    Idle,
+    // writing a fragment or a set ip request
+    Send(MbxWriteRead),
+    // checking if the subdevice sent anything
+    Poll(MbxPoll),
+}
+
+#[derive(Debug)]
+pub enum EoeResponse<'a> {
+    // a complete frame from the subdevice, it was already written to the tap if there is one
+    Frame(&'a [u8]),
+    // the subdevice took the ip parameters
+    IpSet,
+}
+
+impl Eoe {
+    pub fn new(subdev: &ethercrab::SubDevice, port: u8) -> Self {
+        Self {
+            port: port & 0x0F,
+            counter: subdev.mailbox_counter(),
+            frame_number: 0,
+            set_ip: None,
+            tx: None,
+            rx: RxFrame::default(),
+            state: EoeState::Idle,
+        }
+    }
+
+    // sent ahead of any frames that were not started yet, the response comes back as
+    // `EoeResponse::IpSet`
+    pub fn set_ip(&mut self, params: IpParams) {
+        self.set_ip = Some(params);
+    }
+
+    pub fn idle(&self) -> bool {
+        matches!(self.state, EoeState::Idle)
+    }
+
+    // starts the next exchange with the subdevice if none is running, this needs to be called
+    // regularly (eg. for every pdi) as the subdevice can send frames at any time
+    pub fn poll(
+        &mut self,
+        ctx: &mut IoCtx,
+        write_mbx: &ethercrab::Mailbox,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<(), Error> {
+        if !self.idle() {
+            return Ok(());
+        }
+
+        if self.tx.is_none() {
+            self.tx = ctx.taps.pop(idx).map(|data| {
+                self.frame_number = (self.frame_number + 1) & 0x0F;
+                TxFrame {
+                    data,
+                    offset: 0,
+                    fragment: 0,
+                    number: self.frame_number,
+                }
+            });
+        }
+
+        let Some(req) = self.next_request(usize::from(write_mbx.len))? else {
+            let mut poll = MbxPoll::new();
+            poll.start(ctx, read_mbx, configured_addr, identifier, idx)?;
+            self.state = EoeState::Poll(poll);
+            return Ok(());
+        };
+
+        let mut inner = MbxWriteRead::write_only(&req);
+        inner.start(ctx, write_mbx, read_mbx, configured_addr, identifier, idx)?;
+        self.state = EoeState::Send(inner);
+        Ok(())
+    }
+
+    // the set ip request or the next fragment of the frame that is being sent, if there is either
+    fn next_request(&mut self, mbx_len: usize) -> Result<Option<coe::MbxBuf>, Error> {
+        // the subdevice takes the fragments of a frame back to back, so a set ip request has to
+        // wait until the frame that was started was sent completely
+        let set_ip = match &self.tx {
+            Some(tx) if tx.fragment > 0 => None,
+            _ => self.set_ip.take(),
+        };
+
+        if let Some(params) = set_ip {
+            self.counter = coe::next_counter(self.counter);
+            return Ok(Some(params.request(self.counter, self.port)));
+        }
+
+        let Some(tx) = &mut self.tx else {
+            return Ok(None);
+        };
+
+        let counter = coe::next_counter(self.counter);
+        let (req, last) = tx.next_fragment(mbx_len, self.port, counter)?;
+        self.counter = counter;
+        if last {
+            self.tx = None;
+        }
+        Ok(Some(req))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &mut self,
+        received: ReceivedPdu<'_>,
+        header: PduHeader,
+        ctx: &mut IoCtx,
+        write_mbx: &ethercrab::Mailbox,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<Option<EoeResponse<'_>>, Error> {
+        match &mut self.state {
+            EoeState::Send(inner) => {
+                if inner
+                    .update(
+                        received,
+                        header,
+                        ctx,
+                        write_mbx,
+                        read_mbx,
+                        configured_addr,
+                        identifier,
+                        idx,
+                    )?
+                    .is_some()
+                {
+                    self.state = EoeState::Idle;
+                }
+                Ok(None)
+            }
+            EoeState::Poll(poll) => {
+                match poll.update(received, ctx, read_mbx, configured_addr, identifier, idx)? {
+                    None => Ok(None),
+                    Some(Polled::Empty) => {
+                        self.state = EoeState::Idle;
+                        Ok(None)
+                    }
+                    Some(Polled::Message(bytes)) => {
+                        self.state = EoeState::Idle;
+                        self.receive(&bytes, ctx, idx)
+                    }
+                }
+            }
+            EoeState::Idle => Ok(None),
+        }
+    }
+
+    // takes a message that was read out of the mailbox, this is done by `update` for the
+    // messages it polls for, but can also be used for `DeviceResponse::Mailbox`
+    pub fn receive(
+        &mut self,
+        bytes: &[u8],
+        ctx: &mut IoCtx,
+        idx: u16,
+    ) -> Result<Option<EoeResponse<'_>>, Error> {
+        let body = match coe::mailbox_body(bytes, coe::MBX_TYPE_EOE) {
+            Ok(body) => body,
+            // not for us, the subdevice can answer other protocols in the same mailbox
+            Err(Error::UnexpectedMailboxType(_)) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let (header, data) = body
+            .split_first_chunk::<EOE_HEADER_LEN>()
+            .ok_or(Error::InvalidMailbox)?;
+        let word0 = u16::from_le_bytes([header[0], header[1]]);
+        let word1 = u16::from_le_bytes([header[2], header[3]]);
+
+        match word0 & 0x0F {
+            FRAME_FRAGMENT => {
+                if !self.rx.fragment(word0, word1, data) {
+                    return Ok(None);
+                }
+
+                ctx.taps.write(idx, &self.rx.data);
+                Ok(Some(EoeResponse::Frame(&self.rx.data)))
+            }
+            SET_IP_RESPONSE => match word1 {
+                0 => Ok(Some(EoeResponse::IpSet)),
+                code => Err(Error::EoeSetIp(code)),
+            },
+            // timestamps and address filters are not used
+            _ => Ok(None),
+        }
+    }
+}
+
+// a linux tap interface to bridge the frames of a subdevice with
+#[derive(Debug)]
+pub struct Tap {
+    fd: OwnedFd,
+}
+
+impl Tap {
+    pub fn open(name: &str) -> std::io::Result<Self> {
+        let mut ifr: libc::ifreq = unsafe { core::mem::zeroed() };
+        if name.len() >= ifr.ifr_name.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "tap name is too long",
+            ));
+        }
+
+        let fd = unsafe {
+            libc::open(
+                c"/dev/net/tun".as_ptr(),
+                libc::O_RDWR | libc::O_CLOEXEC | libc::O_NONBLOCK,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        for (dst, src) in ifr.ifr_name.iter_mut().zip(name.as_bytes()) {
+            *dst = *src as _;
+        }
+        ifr.ifr_ifru.ifru_flags = (libc::IFF_TAP | libc::IFF_NO_PI) as _;
+
+        if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETIFF, &mut ifr) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Self { fd })
+    }
+
+    // none if there is nothing to read
+    fn read(&self, buf: &mut [u8]) -> std::io::Result<Option<usize>> {
+        let res = unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr() as _, buf.len()) };
+        if res < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+            return Err(err);
+        }
+        Ok(Some(res as usize))
+    }
+
+    // the tap is non blocking, so the frame is dropped if its queue is full
+    fn write(&self, frame: &[u8]) -> std::io::Result<()> {
+        let res = unsafe { libc::write(self.fd.as_raw_fd(), frame.as_ptr() as _, frame.len()) };
+        if res < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::WouldBlock {
+                return Ok(());
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct TapEntry {
+    tap: Tap,
+    frames: VecDeque<Vec<u8>>,
+}
+
+// taps keyed by the index of the device they are bridged with.
+// a tap that fails to be read from / written to is dropped, the bus keeps running without it
+#[derive(Debug, Default)]
+pub struct Taps {
+    taps: BTreeMap<u16, TapEntry>,
+}
+
+impl Taps {
+    pub(crate) fn insert(&mut self, idx: u16, tap: Tap) {
+        self.taps.insert(
+            idx,
+            TapEntry {
+                tap,
+                frames: VecDeque::new(),
+            },
+        );
+    }
+
+    pub(crate) fn contains(&self, idx: u16) -> bool {
+        self.taps.contains_key(&idx)
+    }
+
+    pub(crate) fn remove(&mut self, idx: u16) {
+        self.taps.remove(&idx);
+    }
+
+    // waits for the tap of the device at `idx` to become readable, the ring does not read from
+    // it directly
+    pub(crate) fn poll_entry(&self, idx: u16) -> Option<squeue::Entry> {
+        let entry = self.taps.get(&idx)?;
+        let poll = opcode::PollAdd::new(Fd(entry.tap.fd.as_raw_fd()), libc::POLLIN as _)
+            .build()
+            .user_data(TAP_MASK | u64::from(idx));
+        Some(poll)
+    }
+
+    // reads every frame that is waiting on the tap of the device at `idx`
+    pub(crate) fn receive(&mut self, idx: u16) {
+        let Some(entry) = self.taps.get_mut(&idx) else {
+            return;
+        };
+
+        let mut buf = [0; TAP_FRAME_LEN];
+        loop {
+            let len = match entry.tap.read(&mut buf) {
+                Ok(Some(len)) => len,
+                Ok(None) => return,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    self.taps.remove(&idx);
+                    return;
+                }
+            };
+
+            if entry.frames.len() == TAP_QUEUE_LEN {
+                entry.frames.pop_front();
+            }
+            entry.frames.push_back(buf[..len].to_vec());
+        }
+    }
+
+    pub(crate) fn pop(&mut self, idx: u16) -> Option<Vec<u8>> {
+        self.taps.get_mut(&idx)?.frames.pop_front()
+    }
+
+    pub(crate) fn write(&mut self, idx: u16, frame: &[u8]) {
+        let Some(entry) = self.taps.get(&idx) else {
+            return;
+        };
+        if entry.tap.write(frame).is_err() {
+            self.taps.remove(&idx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(len: usize) -> Vec<u8> {
+        (0..len).map(|b| b as u8).collect()
+    }
+
+    // splits `data` for a write mailbox of `mbx_len` bytes
+    fn split(data: &[u8], mbx_len: usize) -> Vec<coe::MbxBuf> {
+        let mut tx = TxFrame {
+            data: data.to_vec(),
+            offset: 0,
+            fragment: 0,
+            number: 5,
+        };
+
+        let mut fragments = Vec::new();
+        loop {
+            let (buf, last) = tx.next_fragment(mbx_len, 1, 1).unwrap();
+            fragments.push(buf);
+            if last {
+                return fragments;
+            }
+        }
+    }
+
+    fn words(fragment: &[u8]) -> (u16, u16) {
+        let header = &fragment[coe::MBX_HEADER_LEN..];
+        (
+            u16::from_le_bytes([header[0], header[1]]),
+            u16::from_le_bytes([header[2], header[3]]),
+        )
+    }
+
+    #[test]
+    fn split_into_32_byte_units() {
+        let data = frame(200);
+        // 118 bytes after the headers, cut down to 96
+        let fragments = split(&data, 128);
+        assert_eq!(fragments.len(), 3);
+
+        let lens: Vec<_> = fragments
+            .iter()
+            .map(|f| f.len() - coe::MBX_HEADER_LEN - EOE_HEADER_LEN)
+            .collect();
+        assert_eq!(lens, [96, 96, 8]);
+
+        // the mailbox header holds the eoe header + data
+        assert_eq!(fragments[0][..2], 100u16.to_le_bytes());
+
+        let (word0, word1) = words(&fragments[0]);
+        // frame fragment on port 1, more to follow
+        assert_eq!(word0, 0x0010);
+        // fragment 0, the size of the frame in units (200 / 32 rounded up), frame number 5
+        assert_eq!(word1, (5 << 12) | (7 << 6));
+
+        let (word0, word1) = words(&fragments[1]);
+        assert_eq!(word0, 0x0010);
+        // fragment 1 at 96 bytes
+        assert_eq!(word1, (5 << 12) | (3 << 6) | 1);
+
+        let (word0, word1) = words(&fragments[2]);
+        assert_eq!(word0, 0x0010 | LAST_FRAGMENT);
+        assert_eq!(word1, (5 << 12) | (6 << 6) | 2);
+    }
+
+    #[test]
+    fn single_fragment() {
+        let fragments = split(&frame(60), 128);
+        assert_eq!(fragments.len(), 1);
+
+        let (word0, word1) = words(&fragments[0]);
+        assert_eq!(word0, 0x0010 | LAST_FRAGMENT);
+        assert_eq!(word1, (5 << 12) | (2 << 6));
+    }
+
+    #[test]
+    fn mailbox_too_small() {
+        let mut tx = TxFrame {
+            data: frame(60),
+            offset: 0,
+            fragment: 0,
+            number: 0,
+        };
+        assert!(matches!(
+            tx.next_fragment(coe::MBX_HEADER_LEN + EOE_HEADER_LEN + 31, 0, 1),
+            Err(Error::InvalidMailbox)
+        ));
+    }
+
+    fn reassemble(rx: &mut RxFrame, fragment: &[u8]) -> bool {
+        let (word0, word1) = words(fragment);
+        rx.fragment(
+            word0,
+            word1,
+            &fragment[coe::MBX_HEADER_LEN + EOE_HEADER_LEN..],
+        )
+    }
+
+    #[test]
+    fn split_and_reassemble() {
+        let data = frame(1514);
+        let fragments = split(&data, 256);
+
+        let mut rx = RxFrame::default();
+        let (last, rest) = fragments.split_last().unwrap();
+        for fragment in rest {
+            assert!(!reassemble(&mut rx, fragment));
+        }
+        assert!(reassemble(&mut rx, last));
+        assert_eq!(rx.data, data);
+    }
+
+    #[test]
+    fn missing_fragment_drops_frame() {
+        let fragments = split(&frame(200), 128);
+
+        let mut rx = RxFrame::default();
+        assert!(!reassemble(&mut rx, &fragments[0]));
+        assert!(!reassemble(&mut rx, &fragments[2]));
+        assert_eq!(rx.fragment, None);
+
+        // the next frame starts over
+        let fragments = split(&frame(60), 128);
+        assert!(reassemble(&mut rx, &fragments[0]));
+        assert_eq!(rx.data, frame(60));
+    }
+
+    #[test]
+    fn set_ip_waits_for_the_frame() {
+        let mut eoe = Eoe {
+            port: 1,
+            counter: 1,
+            frame_number: 5,
+            set_ip: None,
+            tx: Some(TxFrame {
+                data: frame(200),
+                offset: 0,
+                fragment: 0,
+                number: 5,
+            }),
+            rx: RxFrame::default(),
+            state: EoeState::Idle,
+        };
+
+        let frame_type = |req: &coe::MbxBuf| words(req).0 & 0x0F;
+
+        let first = eoe.next_request(128).unwrap().unwrap();
+        assert_eq!(frame_type(&first), FRAME_FRAGMENT);
+
+        // the rest of the frame goes first
+        eoe.set_ip(IpParams::default());
+        let mut fragments = 1;
+        while eoe.tx.is_some() {
+            let req = eoe.next_request(128).unwrap().unwrap();
+            assert_eq!(frame_type(&req), FRAME_FRAGMENT);
+            fragments += 1;
+        }
+        assert_eq!(fragments, split(&frame(200), 128).len());
+
+        let req = eoe.next_request(128).unwrap().unwrap();
+        assert_eq!(frame_type(&req), SET_IP_REQUEST);
+        assert!(eoe.next_request(128).unwrap().is_none());
+    }
+
+    #[test]
+    fn set_ip_ahead_of_a_new_frame() {
+        let mut eoe = Eoe {
+            port: 1,
+            counter: 1,
+            frame_number: 5,
+            set_ip: Some(IpParams::default()),
+            tx: Some(TxFrame {
+                data: frame(60),
+                offset: 0,
+                fragment: 0,
+                number: 5,
+            }),
+            rx: RxFrame::default(),
+            state: EoeState::Idle,
+        };
+
+        let req = eoe.next_request(128).unwrap().unwrap();
+        assert_eq!(words(&req).0 & 0x0F, SET_IP_REQUEST);
+        let req = eoe.next_request(128).unwrap().unwrap();
+        assert_eq!(words(&req).0 & 0x0F, FRAME_FRAGMENT);
+        assert!(eoe.tx.is_none());
+    }
+}
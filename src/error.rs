@@ -0,0 +1,222 @@
+use ethercrab::SubDeviceState;
+
+#[derive(Debug)]
+pub enum Error {
+    // errors from ethercrab itself (pdu loop, wire (un)packing, ...)
+    Ethercrab(ethercrab::error::Error),
+    // io_uring submission or user callback failures
+    Io(std::io::Error),
+    // ethercrab had no frame available to prep the pdu into
+    NoFrame,
+    // no subdevices were found on the bus
+    NoSubDevices,
+    // a pdu ran out of retries without a response, along with the device index and identifier
+    // it was sent with
+    Timeout {
+        idx: Option<u16>,
+        identifier: Option<u8>,
+    },
+    // a response came back without the device index it was sent with
+    MissingIndex,
+    // a response came back for a device that is not being tracked
+    UnknownDevice(u16),
+    // the subdevice raised the error flag in its al status while transitioning,
+    // `code` is read from the al status code register
+    Transition {
+        configured_addr: u16,
+        requested: SubDeviceState,
+        code: ethercrab::AlStatusCode,
+    },
+    // some of the subdevices refused the state that was requested of the whole bus,
+    // along with the configured address and al status code of each of them
+    BroadcastTransition {
+        requested: SubDeviceState,
+        failed: Vec<(u16, ethercrab::AlStatusCode)>,
+    },
+    // a subdevice was not in the state the bus was expected to be in
+    UnexpectedState {
+        expected: SubDeviceState,
+        found: Option<SubDeviceState>,
+    },
+    // a pdu with a command code that the current state does not send
+    UnexpectedCommand(u8),
+    // a pdu that read from / wrote to a register that the current state does not use
+    UnexpectedRegister(u16),
+    // a response tagged with an identifier that the current state does not use
+    UnexpectedIdentifier(Option<u8>),
+    // the subdevice needs a mailbox that was not found in its sii
+    MissingMailbox(u16),
+    // the sii does not describe a sync manager of the given type
+    MissingSyncManager(ethercrab::SyncManagerType),
+    // the sii does not describe an fmmu with the given usage
+    MissingFmmu(ethercrab::FmmuUsage),
+    // the sii contents could not be parsed
+    Sii,
+    // the mailbox contents were too short or their length was out of bounds
+    InvalidMailbox,
+    // the mailbox held a different protocol than the one that was requested
+    UnexpectedMailboxType(u8),
+    // the coe header held a service that does not answer the request
+    UnexpectedCoeService(u8),
+    // the sdo response had a command specifier that does not answer the request
+    UnexpectedSdoCommand(u8),
+    // the device aborted the sdo transfer
+    SdoAbort(crate::sdo::SdoAbort),
+    // the subdevice refused the foe transfer
+    Foe(crate::foe::FoeError),
+    // the foe message had an opcode that does not answer the last packet
+    UnexpectedFoeOpcode(u8),
+    // the foe data / ack did not carry the packet number that was next in line
+    FoePacket {
+        expected: u32,
+        received: u32,
+    },
+    // the subdevice refused to read / write the idn
+    Soe {
+        idn: u16,
+        code: u16,
+    },
+    // the soe response had an opcode that does not answer the request
+    UnexpectedSoeOpcode(u8),
+    // the idn element that was read is larger than the requested type
+    SoeSize {
+        idn: u16,
+        expected: usize,
+        received: usize,
+    },
+    // the subdevice did not take the eoe ip parameters, with the result code it gave
+    EoeSetIp(u16),
+    // a complete sdo write had more entries than subindex 0 can count
+    SdoEntryCount(usize),
+    // the entries of a complete sdo write were not all the same size
+    SdoEntrySize {
+        expected: usize,
+        received: usize,
+    },
+    // the size of an sdo response did not match the requested type
+    SdoSize {
+        expected: usize,
+        received: usize,
+    },
+    // some of the subdevices did not make it down to init during a shutdown, along with the
+    // configured address of each of them and what went wrong. the rest of the bus was shut down
+    Shutdown(Vec<(u16, Error)>),
+    // a mailbox transfer was started again after it had finished
+    TransferFinished,
+    // the subdevice at the configured address never acknowledged the repeat request
+    // of its read mailbox
+    MailboxRepeat(u16),
+}
+
+impl From<ethercrab::error::Error> for Error {
+    fn from(err: ethercrab::error::Error) -> Self {
+        Self::Ethercrab(err)
+    }
+}
+
+impl From<ethercrab_wire::WireError> for Error {
+    fn from(err: ethercrab_wire::WireError) -> Self {
+        Self::Ethercrab(err.into())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Ethercrab(err) => write!(f, "{err}"),
+            Self::Io(err) => write!(f, "io: {err}"),
+            Self::NoFrame => f.write_str("no frame available"),
+            Self::NoSubDevices => f.write_str("no subdevices found"),
+            Self::Timeout { idx, identifier } => {
+                write!(
+                    f,
+                    "pdu for device {idx:?} with identifier {identifier:?} timed out"
+                )
+            }
+            Self::MissingIndex => f.write_str("response is missing its device index"),
+            Self::UnknownDevice(idx) => write!(f, "no device at index {idx}"),
+            Self::Transition {
+                configured_addr,
+                requested,
+                code,
+            } => write!(
+                f,
+                "subdevice {configured_addr:#06x} failed to transition to {requested:?}: {code}"
+            ),
+            Self::BroadcastTransition { requested, failed } => {
+                write!(f, "subdevices failed to transition to {requested:?}:")?;
+                for (configured_addr, code) in failed {
+                    write!(f, " {configured_addr:#06x} ({code})")?;
+                }
+                Ok(())
+            }
+            Self::UnexpectedState { expected, found } => {
+                write!(f, "expected subdevice in {expected:?}, found {found:?}")
+            }
+            Self::UnexpectedCommand(code) => write!(f, "unexpected command code {code}"),
+            Self::UnexpectedRegister(reg) => write!(f, "unexpected register {reg:#06x}"),
+            Self::UnexpectedIdentifier(id) => write!(f, "unexpected identifier {id:?}"),
+            Self::MissingMailbox(addr) => {
+                write!(f, "subdevice {addr:#06x} is missing a mailbox")
+            }
+            Self::MissingSyncManager(ty) => write!(f, "could not find a {ty:?} sync manager"),
+            Self::MissingFmmu(usage) => write!(f, "could not find an {usage:?} fmmu"),
+            Self::Sii => f.write_str("could not parse sii"),
+            Self::InvalidMailbox => f.write_str("malformed mailbox response"),
+            Self::UnexpectedMailboxType(ty) => write!(f, "unexpected mailbox type {ty:#04x}"),
+            Self::UnexpectedCoeService(service) => {
+                write!(f, "unexpected coe service {service:#04x}")
+            }
+            Self::UnexpectedSdoCommand(cmd) => write!(f, "unexpected sdo command {cmd:#04x}"),
+            Self::SdoAbort(abort) => write!(f, "{abort}"),
+            Self::Foe(err) => write!(f, "{err}"),
+            Self::UnexpectedFoeOpcode(op) => write!(f, "unexpected foe opcode {op:#04x}"),
+            Self::FoePacket { expected, received } => {
+                write!(f, "expected foe packet {expected}, got {received}")
+            }
+            Self::Soe { idn, code } => write!(f, "soe error {code:#06x} for idn {idn:#06x}"),
+            Self::UnexpectedSoeOpcode(op) => write!(f, "unexpected soe opcode {op:#04x}"),
+            Self::SoeSize {
+                idn,
+                expected,
+                received,
+            } => write!(
+                f,
+                "expected at most {expected} bytes from idn {idn:#06x}, got {received}"
+            ),
+            Self::EoeSetIp(code) => write!(f, "eoe set ip parameter failed with {code:#06x}"),
+            Self::SdoEntryCount(count) => write!(f, "{count} sdo entries do not fit in a u8"),
+            Self::SdoEntrySize { expected, received } => {
+                write!(
+                    f,
+                    "expected sdo entries of {expected} bytes, got {received}"
+                )
+            }
+            Self::SdoSize { expected, received } => {
+                write!(f, "expected {expected} bytes from sdo, got {received}")
+            }
+            Self::Shutdown(failed) => {
+                f.write_str("subdevices failed to shut down:")?;
+                for (configured_addr, err) in failed {
+                    write!(f, " {configured_addr:#06x} ({err})")?;
+                }
+                Ok(())
+            }
+            Self::TransferFinished => f.write_str("mailbox transfer already finished"),
+            Self::MailboxRepeat(addr) => {
+                write!(
+                    f,
+                    "subdevice {addr:#06x} did not acknowledge the mailbox repeat"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -1,11 +1,7 @@
+use crate::error::Error;
+use crate::io::IoCtx;
 use crate::setup::setup_write;
-use crate::txbuf::TxBuf;
-use ethercrab::{
-    EtherCrabWireSized, MainDevice, PduHeader, SubDevice, error::Error,
-    received_frame::ReceivedPdu, std::RawSocketDesc,
-};
-use io_uring::{IoUring, types::Timespec};
-use std::collections::BTreeMap;
+use ethercrab::{EtherCrabWireSized, PduHeader, SubDevice, received_frame::ReceivedPdu};
 
 use crate::eeprom::category::CategoryIter;
 
@@ -30,6 +26,9 @@ pub enum ConfigureFmmus {
         current_output: Option<ConfigureFmmu>,
         input_len: Option<usize>,
         output_len: Option<usize>,
+        // the fmmu the sii sets aside for the mailbox status, and where it was mapped to
+        status_fmmu: Option<u8>,
+        mailbox_status: Option<usize>,
     },
 }
 
@@ -41,52 +40,83 @@ impl ConfigureFmmus {
         )
     }
 
-    #[allow(clippy::too_many_arguments)]
     pub(crate) fn start(
         &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         configured_addr: u16,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
-    ) {
+    ) -> Result<(), Error> {
         match self {
-            Self::SyncManagers(s, _) => {
-                let _ = s.start(
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    configured_addr,
-                    idx,
-                    write_entry,
-                    timeout_entry,
-                );
+            Self::SyncManagers(s, _) => s.start(ctx, configured_addr, idx),
+            _ => Err(Error::UnexpectedState {
+                expected: ethercrab::SubDeviceState::PreOp,
+                found: None,
+            }),
+        }
+    }
+
+    // whether the sii was read and the inputs were not configured yet
+    pub(crate) fn ready(&self) -> bool {
+        matches!(
+            self,
+            Self::Configure {
+                current_input: None,
+                input_len: None,
+                ..
+            }
+        )
+    }
+
+    // returns false if the subdevice has no inputs, in which case there is nothing to wait for
+    pub(crate) fn start_input(
+        &mut self,
+        ctx: &mut IoCtx,
+        configured_addr: u16,
+        idx: u16,
+        pdi_offset: &ethercrab::PdiOffset,
+    ) -> Result<bool, Error> {
+        match self {
+            Self::Configure {
+                input_iter,
+                current_input,
+                input_len,
+                ..
+            } => {
+                *current_input = input_iter
+                    .next()
+                    .map(|(sm_idx, mapping)| {
+                        ConfigureFmmu::start_new(
+                            sm_idx,
+                            mapping,
+                            ethercrab::SyncManagerType::ProcessDataRead,
+                            ctx,
+                            configured_addr,
+                            Some(1),
+                            idx,
+                        )
+                    })
+                    .transpose()?;
+
+                if current_input.is_none() {
+                    *input_len = Some(pdi_offset.start_address as _);
+                    return Ok(false);
+                }
+            }
+            _ => {
+                return Err(Error::UnexpectedState {
+                    expected: ethercrab::SubDeviceState::PreOp,
+                    found: None,
+                });
             }
-            _ => unreachable!(),
         }
+        Ok(true)
     }
 
-    #[allow(clippy::too_many_arguments)]
     pub(crate) fn start_output(
         &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         configured_addr: u16,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<(), Error> {
         match self {
             Self::Configure {
@@ -101,22 +131,20 @@ impl ConfigureFmmus {
                             sm_idx,
                             mapping,
                             ethercrab::SyncManagerType::ProcessDataWrite,
-                            maindevice,
-                            retry_count,
-                            timeout_duration,
-                            tx_entries,
-                            sock,
-                            ring,
+                            ctx,
                             configured_addr,
                             Some(2),
                             idx,
-                            write_entry,
-                            timeout_entry,
                         )
                     })
                     .transpose()?;
             }
-            _ => unreachable!(),
+            _ => {
+                return Err(Error::UnexpectedState {
+                    expected: ethercrab::SubDeviceState::PreOp,
+                    found: None,
+                });
+            }
         }
         Ok(())
     }
@@ -126,60 +154,29 @@ impl ConfigureFmmus {
         &mut self,
         received: ReceivedPdu<'_>,
         header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         configured_addr: u16,
         idx: u16,
         subdev: &mut ethercrab::SubDevice,
         identifier: Option<u8>,
         config: &PdoConfig<'_, I, O>,
         pdi_offset: &mut ethercrab::PdiOffset,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
-    ) -> Result<Option<FmmuMappingOutput<(usize, usize)>>, Error> {
+    ) -> Result<Option<FmmuMappingOutput<(usize, usize, Option<usize>)>>, Error> {
         match self {
             Self::SyncManagers(managers, collected) => {
-                if let Some(more) = managers.update(
-                    received,
-                    header,
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    configured_addr,
-                    idx,
-                    &write_entry,
-                    &timeout_entry,
-                )? {
+                if let Some(more) = managers.update(received, header, ctx, configured_addr, idx)? {
                     if let Some(buf) = managers.buffer() {
                         use ethercrab::EtherCrabWireRead;
-                        let mgr = ethercrab::SyncManager::unpack_from_slice(buf).unwrap();
+                        let mgr = ethercrab::SyncManager::unpack_from_slice(buf)?;
 
                         let _ = collected.push(mgr);
                     } else {
-                        panic!("could not find sync manager")
+                        return Err(Error::Sii);
                     }
 
                     if !more {
                         let mut fmmus = CategoryIter::new(ethercrab::CategoryType::Fmmu, 0);
-                        fmmus.start(
-                            maindevice,
-                            retry_count,
-                            timeout_duration,
-                            tx_entries,
-                            sock,
-                            ring,
-                            configured_addr,
-                            idx,
-                            &write_entry,
-                            &timeout_entry,
-                        )?;
+                        fmmus.start(ctx, configured_addr, idx)?;
 
                         *self =
                             Self::Mappings(core::mem::take(collected), fmmus, Default::default());
@@ -187,27 +184,14 @@ impl ConfigureFmmus {
                 }
             }
             Self::Mappings(managers, fmmus, collected) => {
-                if let Some(more) = fmmus.update(
-                    received,
-                    header,
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    configured_addr,
-                    idx,
-                    &write_entry,
-                    &timeout_entry,
-                )? {
+                if let Some(more) = fmmus.update(received, header, ctx, configured_addr, idx)? {
                     if let Some(buf) = fmmus.buffer() {
                         use ethercrab::EtherCrabWireRead;
-                        let fmmu = ethercrab::FmmuUsage::unpack_from_slice(buf).unwrap();
+                        let fmmu = ethercrab::FmmuUsage::unpack_from_slice(buf)?;
 
                         let _ = collected.push(fmmu);
                     } else {
-                        panic!("could not find sync manager")
+                        return Err(Error::Sii);
                     }
 
                     if !more {
@@ -216,47 +200,32 @@ impl ConfigureFmmus {
                             ethercrab::PdoDirection::MasterRead,
                             managers,
                             collected,
-                        );
+                        )?;
                         let outputs = FmmuMapping::from_config(
                             config,
                             ethercrab::PdoDirection::MasterWrite,
                             managers,
                             collected,
-                        );
-
-                        let mut input_iter = inputs.into_iter();
-                        let output_iter = outputs.into_iter();
-
-                        let current_input = input_iter
-                            .next()
-                            .map(|(sm_idx, mapping)| {
-                                ConfigureFmmu::start_new(
-                                    sm_idx,
-                                    mapping,
-                                    ethercrab::SyncManagerType::ProcessDataRead,
-                                    maindevice,
-                                    retry_count,
-                                    timeout_duration,
-                                    tx_entries,
-                                    sock,
-                                    ring,
-                                    configured_addr,
-                                    Some(1),
-                                    idx,
-                                    &write_entry,
-                                    &timeout_entry,
-                                )
-                            })
-                            .transpose()?;
+                        )?;
+
+                        let status_fmmu = collected
+                            .iter()
+                            .position(|&usage| usage == ethercrab::FmmuUsage::SyncManagerStatus)
+                            .map(|pos| pos as u8);
 
+                        // the inputs are configured once it is the subdevice's turn,
+                        // see `start_input`
                         *self = Self::Configure {
-                            input_iter,
-                            output_iter,
-                            current_input,
+                            input_iter: inputs.into_iter(),
+                            output_iter: outputs.into_iter(),
+                            current_input: None,
                             current_output: None,
                             input_len: None,
                             output_len: None,
+                            status_fmmu,
+                            mailbox_status: None,
                         };
+                        return Ok(Some(FmmuMappingOutput::Ready));
                     }
                 }
             }
@@ -267,28 +236,25 @@ impl ConfigureFmmus {
                 current_output,
                 input_len,
                 output_len,
+                status_fmmu,
+                mailbox_status,
             } => {
                 match identifier.map(|id| (id >> 2) & 0b11) {
                     Some(1) => {
-                        let input = current_input.as_mut().unwrap();
+                        let input = current_input
+                            .as_mut()
+                            .ok_or(Error::UnexpectedIdentifier(identifier))?;
 
                         if input.update(
                             received,
                             header,
-                            maindevice,
-                            retry_count,
-                            timeout_duration,
-                            tx_entries,
-                            sock,
-                            ring,
+                            ctx,
                             configured_addr,
                             identifier,
                             idx,
                             pdi_offset,
                             subdev,
                             ethercrab::PdoDirection::MasterRead,
-                            &write_entry,
-                            &timeout_entry,
                         )? {
                             *current_input = input_iter
                                 .next()
@@ -297,17 +263,10 @@ impl ConfigureFmmus {
                                         sm_idx,
                                         mapping,
                                         ethercrab::SyncManagerType::ProcessDataRead,
-                                        maindevice,
-                                        retry_count,
-                                        timeout_duration,
-                                        tx_entries,
-                                        sock,
-                                        ring,
+                                        ctx,
                                         configured_addr,
                                         Some(1),
                                         idx,
-                                        &write_entry,
-                                        &timeout_entry,
                                     )
                                 })
                                 .transpose()?;
@@ -316,6 +275,7 @@ impl ConfigureFmmus {
                             // due to needing sequential access to the pdi
                             if current_input.is_none() {
                                 *input_len = Some(pdi_offset.start_address as _);
+                                return Ok(Some(FmmuMappingOutput::Input));
                                 /*
                                 *current_output = output_iter
                                     .next()
@@ -324,12 +284,7 @@ impl ConfigureFmmus {
                                             sm_idx,
                                             mapping,
                                             ethercrab::SyncManagerType::ProcessDataWrite,
-                                            maindevice,
-                                            retry_count,
-                                            timeout_duration,
-                                            tx_entries,
-                                            sock,
-                                            ring,
+                                            ctx,
                                             configured_addr,
                                             Some(2),
                                             idx,
@@ -338,7 +293,6 @@ impl ConfigureFmmus {
                                     .transpose()?;
                                 */
                             }
-                            return Ok(Some(FmmuMappingOutput::Input));
 
                             /*
                             if current_input.is_none() && current_output.is_none() {
@@ -352,25 +306,20 @@ impl ConfigureFmmus {
                         }
                     }
                     Some(2) => {
-                        let output = current_output.as_mut().unwrap();
+                        let output = current_output
+                            .as_mut()
+                            .ok_or(Error::UnexpectedIdentifier(identifier))?;
 
                         if output.update(
                             received,
                             header,
-                            maindevice,
-                            retry_count,
-                            timeout_duration,
-                            tx_entries,
-                            sock,
-                            ring,
+                            ctx,
                             configured_addr,
                             identifier,
                             idx,
                             pdi_offset,
                             subdev,
                             ethercrab::PdoDirection::MasterWrite,
-                            &write_entry,
-                            &timeout_entry,
                         )? {
                             *current_output = output_iter
                                 .next()
@@ -379,34 +328,47 @@ impl ConfigureFmmus {
                                         sm_idx,
                                         mapping,
                                         ethercrab::SyncManagerType::ProcessDataWrite,
-                                        maindevice,
-                                        retry_count,
-                                        timeout_duration,
-                                        tx_entries,
-                                        sock,
-                                        ring,
+                                        ctx,
                                         configured_addr,
                                         Some(2),
                                         idx,
-                                        &write_entry,
-                                        &timeout_entry,
                                     )
                                 })
                                 .transpose()?;
 
                             if current_output.is_none() {
+                                *mailbox_status = start_mailbox_status(
+                                    ctx,
+                                    configured_addr,
+                                    idx,
+                                    *status_fmmu,
+                                    subdev,
+                                    pdi_offset,
+                                )?;
                                 *output_len = Some(pdi_offset.start_address as _);
+
+                                if mailbox_status.is_some() {
+                                    return Ok(None);
+                                }
                             }
 
                             if current_input.is_none() && current_output.is_none() {
                                 return Ok(Some(FmmuMappingOutput::Output((
                                     input_len.unwrap_or_default(),
                                     output_len.unwrap_or_default(),
+                                    None,
                                 ))));
                             }
                         }
                     }
-                    _ => unreachable!(),
+                    Some(3) => {
+                        return Ok(Some(FmmuMappingOutput::Output((
+                            input_len.unwrap_or_default(),
+                            output_len.unwrap_or_default(),
+                            *mailbox_status,
+                        ))));
+                    }
+                    _ => return Err(Error::UnexpectedIdentifier(identifier)),
                 }
             }
         }
@@ -414,6 +376,43 @@ impl ConfigureFmmus {
     }
 }
 
+// maps the status register of the read mailbox into the pdi after the outputs, so that op
+// sees the mailbox fill up as part of the cyclic frame. returns where it was mapped to
+fn start_mailbox_status(
+    ctx: &mut IoCtx,
+    configured_addr: u16,
+    idx: u16,
+    status_fmmu: Option<u8>,
+    subdev: &SubDevice,
+    pdi_offset: &mut ethercrab::PdiOffset,
+) -> Result<Option<usize>, Error> {
+    let (Some(fmmu_idx), Some(read_mbx)) = (status_fmmu, subdev.config.mailbox.read) else {
+        return Ok(None);
+    };
+
+    let fmmu = ethercrab::Fmmu {
+        logical_start_address: pdi_offset.start_address,
+        length_bytes: 1,
+        logical_start_bit: 0,
+        logical_end_bit: 7,
+        physical_start_address: crate::mbx::sm_status_register(read_mbx.sync_manager),
+        physical_start_bit: 0,
+        read_enable: true,
+        write_enable: false,
+        enable: true,
+    };
+
+    let (frame, handle) = ctx
+        .maindevice
+        .prep_write_fmmu(configured_addr, fmmu_idx, fmmu)?
+        .ok_or(Error::NoFrame)?;
+    setup_write(frame, handle, &mut ctx.tx, Some(idx), Some(3 << 2))?;
+
+    let offset = pdi_offset.start_address as usize;
+    *pdi_offset = pdi_offset.increment(1);
+    Ok(Some(offset))
+}
+
 #[derive(Debug)]
 pub struct FmmuMapping {
     sync_manager: ethercrab::SyncManager,
@@ -427,7 +426,7 @@ impl FmmuMapping {
         direction: ethercrab::PdoDirection,
         sync_managers: &[ethercrab::SyncManager],
         fmmus: &[ethercrab::FmmuUsage],
-    ) -> heapless::index_map::FnvIndexMap<u8, Self, N> {
+    ) -> Result<heapless::index_map::FnvIndexMap<u8, Self, N>, Error> {
         use ethercrab::{PdoDirection, SyncManagerType};
         let objects = match direction {
             PdoDirection::MasterRead => config.inputs.as_slice(),
@@ -453,13 +452,13 @@ impl FmmuMapping {
                 .enumerate()
                 .find(|(_, sm)| sm.usage_type() == ty)
                 .map(|(idx, sm)| (idx as u8, sm))
-                .unwrap();
+                .ok_or(Error::MissingSyncManager(ty))?;
 
             let fmmu_index = fmmus
                 .iter()
                 .position(|&usage| usage == fmmu_usage)
                 .map(|pos| pos as u8)
-                .unwrap();
+                .ok_or(Error::MissingFmmu(fmmu_usage))?;
 
             let len = assignment.len_bytes();
 
@@ -477,7 +476,7 @@ impl FmmuMapping {
                 }
             }
         }
-        config
+        Ok(config)
     }
 }
 
@@ -498,59 +497,38 @@ impl ConfigureFmmu {
 
     /// both creates and starts self
     /// - this is because the fmmu instantiation depends on the config given from writing to the sync manager
-    #[allow(clippy::too_many_arguments)]
     fn start_new(
         sm_idx: u8,
         mapping: FmmuMapping,
         sm_type: ethercrab::SyncManagerType,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         configured_addr: u16,
         identifier: Option<u8>,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<Self, Error> {
-        let ((frame, handle), config) = maindevice
+        let ((frame, handle), config) = ctx
+            .maindevice
             .prep_write_sm_config(
                 configured_addr,
                 sm_idx,
                 &mapping.sync_manager,
                 mapping.length,
-            )
-            .unwrap()
-            .unwrap();
+            )?
+            .ok_or(Error::NoFrame)?;
         setup_write(
             frame,
             handle,
-            retry_count,
-            timeout_duration,
-            tx_entries,
-            sock,
-            ring,
+            &mut ctx.tx,
             Some(idx),
             Some(1 | (identifier.unwrap_or(0) << 2)),
-            &write_entry,
-            &timeout_entry,
         )?;
 
         let mut fmmu = FmmuConfig::new(mapping.fmmu_index, sm_type, &config);
         fmmu.start(
-            maindevice,
-            retry_count,
-            timeout_duration,
-            tx_entries,
-            sock,
-            ring,
+            ctx,
             configured_addr,
             Some(2 | (identifier.unwrap_or(0) << 2)),
             idx,
-            write_entry,
-            timeout_entry,
         )?;
 
         Ok(Self::from_fmmu(fmmu))
@@ -561,20 +539,13 @@ impl ConfigureFmmu {
         &mut self,
         received: ReceivedPdu<'_>,
         header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         configured_addr: u16,
         identifier: Option<u8>,
         idx: u16,
         pdi_offset: &mut ethercrab::PdiOffset,
         subdev: &mut SubDevice,
         direction: ethercrab::PdoDirection,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<bool, Error> {
         // first 2 bits to identify
         match identifier.map(|id| id & 0b11) {
@@ -588,18 +559,11 @@ impl ConfigureFmmu {
                 if let Some(segment) = self.fmmu.update(
                     received,
                     header,
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
+                    ctx,
                     configured_addr,
                     identifier,
                     idx,
                     pdi_offset,
-                    write_entry,
-                    timeout_entry,
                 )? {
                     use ethercrab::PdoDirection;
                     match direction {
@@ -613,7 +577,12 @@ impl ConfigureFmmu {
                     }
                 }
             }
-            _ => unreachable!(),
+            _ => {
+                return Err(Error::UnexpectedState {
+                    expected: ethercrab::SubDeviceState::PreOp,
+                    found: None,
+                });
+            }
         }
         Ok(false)
     }
@@ -644,43 +613,28 @@ impl FmmuConfig {
         }
     }
 
-    #[allow(clippy::too_many_arguments)]
     fn start(
         &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         configured_addr: u16,
         identifier: Option<u8>,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<(), Error> {
         match self {
             Self::ReadFmmu { fmmu_idx, .. } => {
-                let (frame, handle) = maindevice
-                    .prep_read_fmmu(configured_addr, *fmmu_idx)
-                    .unwrap()
-                    .unwrap();
-
-                setup_write(
-                    frame,
-                    handle,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    Some(idx),
-                    identifier,
-                    write_entry,
-                    timeout_entry,
-                )?;
+                let (frame, handle) = ctx
+                    .maindevice
+                    .prep_read_fmmu(configured_addr, *fmmu_idx)?
+                    .ok_or(Error::NoFrame)?;
+
+                setup_write(frame, handle, &mut ctx.tx, Some(idx), identifier)?;
+            }
+            _ => {
+                return Err(Error::UnexpectedState {
+                    expected: ethercrab::SubDeviceState::PreOp,
+                    found: None,
+                });
             }
-            _ => unreachable!(),
         }
         Ok(())
     }
@@ -690,18 +644,11 @@ impl FmmuConfig {
         &mut self,
         received: ReceivedPdu<'_>,
         _header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         configured_addr: u16,
         identifier: Option<u8>,
         idx: u16,
         pdi_offset: &mut ethercrab::PdiOffset,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<Option<ethercrab::PdiSegment>, Error> {
         match self {
             Self::ReadFmmu {
@@ -711,7 +658,7 @@ impl FmmuConfig {
                 sm_physical_start_addr,
             } => {
                 use ethercrab::EtherCrabWireRead;
-                let mut fmmu = ethercrab::Fmmu::unpack_from_slice(&received).unwrap();
+                let mut fmmu = ethercrab::Fmmu::unpack_from_slice(&received)?;
 
                 let fmmu = if fmmu.enable {
                     fmmu.length_bytes += *sm_length_bytes;
@@ -733,24 +680,12 @@ impl FmmuConfig {
                     }
                 };
 
-                let (frame, handle) = maindevice
-                    .prep_write_fmmu(configured_addr, *fmmu_idx, fmmu)
-                    .unwrap()
-                    .unwrap();
-
-                setup_write(
-                    frame,
-                    handle,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    Some(idx),
-                    identifier,
-                    write_entry,
-                    timeout_entry,
-                )?;
+                let (frame, handle) = ctx
+                    .maindevice
+                    .prep_write_fmmu(configured_addr, *fmmu_idx, fmmu)?
+                    .ok_or(Error::NoFrame)?;
+
+                setup_write(frame, handle, &mut ctx.tx, Some(idx), identifier)?;
                 *self = Self::WriteConfig(fmmu, *sm_length_bytes, *fmmu_idx);
             }
             Self::WriteConfig(_fmmu, len, fmmu_idx) => {
@@ -761,30 +696,18 @@ impl FmmuConfig {
                     bytes: starting_pdi.up_to(*pdi_offset),
                 };
 
-                let (frame, handle) = maindevice
-                    .prep_read_fmmu(configured_addr, *fmmu_idx)
-                    .unwrap()
-                    .unwrap();
-
-                setup_write(
-                    frame,
-                    handle,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    Some(idx),
-                    identifier,
-                    write_entry,
-                    timeout_entry,
-                )?;
+                let (frame, handle) = ctx
+                    .maindevice
+                    .prep_read_fmmu(configured_addr, *fmmu_idx)?
+                    .ok_or(Error::NoFrame)?;
+
+                setup_write(frame, handle, &mut ctx.tx, Some(idx), identifier)?;
 
                 *self = Self::CheckFmmu(segment);
             }
             Self::CheckFmmu(segment) => {
                 use ethercrab::EtherCrabWireRead;
-                let _fmmu = ethercrab::Fmmu::unpack_from_slice(&received).unwrap();
+                let _fmmu = ethercrab::Fmmu::unpack_from_slice(&received)?;
 
                 return Ok(Some(segment.clone()));
             }
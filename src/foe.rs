@@ -0,0 +1,630 @@
+use crate::coe;
+use crate::error::Error;
+use crate::io::IoCtx;
+use crate::mbx::MbxWriteRead;
+use ethercrab::{PduHeader, received_frame::ReceivedPdu};
+
+// ETG.1000.6 file access over ethercat. the mailboxes that are passed in can also be the
+// bootstrap mailboxes, so that firmware can be written while the subdevice is in bootstrap.
+
+// opcode + reserved + password / packet number / error code
+const FOE_HEADER_LEN: usize = 6;
+
+const OP_READ: u8 = 0x01;
+const OP_WRITE: u8 = 0x02;
+const OP_DATA: u8 = 0x03;
+const OP_ACK: u8 = 0x04;
+const OP_ERROR: u8 = 0x05;
+const OP_BUSY: u8 = 0x06;
+
+fn request(counter: u8, opcode: u8, value: u32, data: &[u8]) -> coe::MbxBuf {
+    let mut buf = coe::MbxBuf::new();
+    coe::mailbox_header(
+        &mut buf,
+        FOE_HEADER_LEN + data.len(),
+        coe::MBX_TYPE_FOE,
+        counter,
+    );
+    buf.extend_from_slice(&[opcode, 0]);
+    buf.extend_from_slice(&value.to_le_bytes());
+    buf.extend_from_slice(data);
+    buf
+}
+
+// the opcode, the value after it and whatever data follows
+fn parse(bytes: &[u8]) -> Result<(u8, u32, &[u8]), Error> {
+    let body = coe::mailbox_body(bytes, coe::MBX_TYPE_FOE)?;
+    let (header, data) = body
+        .split_first_chunk::<FOE_HEADER_LEN>()
+        .ok_or(Error::InvalidMailbox)?;
+
+    let value = u32::from_le_bytes([header[2], header[3], header[4], header[5]]);
+    if header[0] == OP_ERROR {
+        return Err(Error::Foe(FoeError { code: value }));
+    }
+
+    Ok((header[0], value, data))
+}
+
+// the most file data that fits into a single message of a mailbox that is `mbx_len` long
+fn max_data(mbx_len: u16) -> Result<usize, Error> {
+    match usize::from(mbx_len).saturating_sub(coe::MBX_HEADER_LEN + FOE_HEADER_LEN) {
+        0 => Err(Error::InvalidMailbox),
+        len => Ok(len),
+    }
+}
+
+pub struct FoeRead {
+    counter: u8,
+    // the last packet that was received
+    packet: u32,
+    data: Vec<u8>,
+    req: coe::MbxBuf,
+    state: FoeReadState,
+}
+
+enum FoeReadState {
+    Idle,
+    // waiting on the next data packet, after the read request or an ack
+    Data(MbxWriteRead),
+    // acknowledging the last packet, which the subdevice does not answer
+    Ack(MbxWriteRead),
+    Done,
+}
+
+// what a read does after a message from the subdevice
+#[derive(Debug)]
+enum ReadStep {
+    // acknowledges a data packet and waits for the next one
+    Ack(coe::MbxBuf),
+    // acknowledges the last data packet, which the subdevice does not answer
+    AckLast(coe::MbxBuf),
+    // the subdevice is busy, it sends the data once it is ready
+    Wait,
+}
+
+impl FoeRead {
+    pub fn new(subdev: &ethercrab::SubDevice, name: &str, password: u32) -> Self {
+        Self::with_counter(subdev.mailbox_counter(), name, password)
+    }
+
+    fn with_counter(counter: u8, name: &str, password: u32) -> Self {
+        Self {
+            counter,
+            packet: 0,
+            data: Vec::new(),
+            req: request(counter, OP_READ, password, name.as_bytes()),
+            state: FoeReadState::Idle,
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        matches!(self.state, FoeReadState::Done)
+    }
+
+    pub fn start(
+        &mut self,
+        ctx: &mut IoCtx,
+        write_mbx: &ethercrab::Mailbox,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<(), Error> {
+        // the file name has to fit into a single message
+        if self.req.len() > usize::from(write_mbx.len) {
+            return Err(Error::InvalidMailbox);
+        }
+
+        let mut inner = MbxWriteRead::new(&self.req);
+        inner.start(ctx, write_mbx, read_mbx, configured_addr, identifier, idx)?;
+        self.state = FoeReadState::Data(inner);
+        Ok(())
+    }
+
+    // hands back the whole file once the last packet was acknowledged
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &mut self,
+        received: ReceivedPdu<'_>,
+        header: PduHeader,
+        ctx: &mut IoCtx,
+        write_mbx: &ethercrab::Mailbox,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<Option<&[u8]>, Error> {
+        match &mut self.state {
+            FoeReadState::Data(inner) => {
+                let Some(bytes) = inner.update(
+                    received,
+                    header,
+                    ctx,
+                    write_mbx,
+                    read_mbx,
+                    configured_addr,
+                    identifier,
+                    idx,
+                )?
+                else {
+                    return Ok(None);
+                };
+
+                let mut inner = match self.receive(&bytes, read_mbx.len)? {
+                    ReadStep::Ack(ack) => MbxWriteRead::new(&ack),
+                    ReadStep::AckLast(ack) => {
+                        let mut inner = MbxWriteRead::write_only(&ack);
+                        inner.start(
+                            ctx,
+                            write_mbx,
+                            read_mbx,
+                            configured_addr,
+                            identifier.map(|id| id >> 2),
+                            idx,
+                        )?;
+                        self.state = FoeReadState::Ack(inner);
+                        return Ok(None);
+                    }
+                    ReadStep::Wait => MbxWriteRead::read_only(),
+                };
+
+                inner.start(
+                    ctx,
+                    write_mbx,
+                    read_mbx,
+                    configured_addr,
+                    identifier.map(|id| id >> 2),
+                    idx,
+                )?;
+                self.state = FoeReadState::Data(inner);
+            }
+            FoeReadState::Ack(inner) => {
+                if inner
+                    .update(
+                        received,
+                        header,
+                        ctx,
+                        write_mbx,
+                        read_mbx,
+                        configured_addr,
+                        identifier,
+                        idx,
+                    )?
+                    .is_some()
+                {
+                    self.state = FoeReadState::Done;
+                    return Ok(Some(&self.data));
+                }
+            }
+            FoeReadState::Idle | FoeReadState::Done => (),
+        }
+        Ok(None)
+    }
+
+    // takes in a message that was read from the read mailbox, which is `read_len` long
+    fn receive(&mut self, bytes: &[u8], read_len: u16) -> Result<ReadStep, Error> {
+        let (opcode, value, data) = parse(bytes)?;
+        match opcode {
+            OP_DATA => {
+                if value != self.packet + 1 {
+                    return Err(Error::FoePacket {
+                        expected: self.packet + 1,
+                        received: value,
+                    });
+                }
+
+                self.packet = value;
+                self.data.extend_from_slice(data);
+
+                // a packet shorter than the mailbox allows for is the last one
+                let last = data.len() < max_data(read_len)?;
+
+                self.counter = coe::next_counter(self.counter);
+                let ack = request(self.counter, OP_ACK, self.packet, &[]);
+
+                Ok(if last {
+                    ReadStep::AckLast(ack)
+                } else {
+                    ReadStep::Ack(ack)
+                })
+            }
+            // the subdevice is not ready yet, wait for it to send the data
+            OP_BUSY => Ok(ReadStep::Wait),
+            _ => Err(Error::UnexpectedFoeOpcode(opcode)),
+        }
+    }
+}
+
+crate::mbx_queue::mailbox_transfer!(
+    impl[] for FoeRead,
+    |res| update => res.is_some(),
+    |this| crate::mbx_queue::MailboxResponse::Data(&this.data),
+);
+
+pub struct FoeWrite {
+    counter: u8,
+    // the last packet that was sent, 0 being the write request
+    packet: u32,
+    // the range of data that was sent in the last packet
+    sent: core::ops::Range<usize>,
+    data: Vec<u8>,
+    req: coe::MbxBuf,
+    state: FoeWriteState,
+}
+
+enum FoeWriteState {
+    Idle,
+    // waiting on the ack for the last packet
+    Pending(MbxWriteRead),
+    Done,
+}
+
+// what a write does after a message from the subdevice
+#[derive(Debug, PartialEq, Eq)]
+enum WriteStep {
+    // sends the data in `sent` as packet `packet`, again if the subdevice was busy with it
+    Send,
+    // the subdevice is busy with the write request, it acks it once it is ready
+    Wait,
+    Done,
+}
+
+impl FoeWrite {
+    pub fn new(subdev: &ethercrab::SubDevice, name: &str, password: u32, data: &[u8]) -> Self {
+        Self::with_counter(subdev.mailbox_counter(), name, password, data)
+    }
+
+    fn with_counter(counter: u8, name: &str, password: u32, data: &[u8]) -> Self {
+        Self {
+            counter,
+            packet: 0,
+            sent: 0..0,
+            data: data.to_vec(),
+            req: request(counter, OP_WRITE, password, name.as_bytes()),
+            state: FoeWriteState::Idle,
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        matches!(self.state, FoeWriteState::Done)
+    }
+
+    pub fn start(
+        &mut self,
+        ctx: &mut IoCtx,
+        write_mbx: &ethercrab::Mailbox,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<(), Error> {
+        // the file name has to fit into a single message
+        if self.req.len() > usize::from(write_mbx.len) {
+            return Err(Error::InvalidMailbox);
+        }
+
+        let mut inner = MbxWriteRead::new(&self.req);
+        inner.start(ctx, write_mbx, read_mbx, configured_addr, identifier, idx)?;
+        self.state = FoeWriteState::Pending(inner);
+        Ok(())
+    }
+
+    // sends the data in `self.sent` as packet `self.packet`
+    fn send(
+        &mut self,
+        ctx: &mut IoCtx,
+        write_mbx: &ethercrab::Mailbox,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<(), Error> {
+        let req = self.data_request();
+
+        let mut inner = MbxWriteRead::new(&req);
+        inner.start(ctx, write_mbx, read_mbx, configured_addr, identifier, idx)?;
+        self.state = FoeWriteState::Pending(inner);
+        Ok(())
+    }
+
+    // returns true once the subdevice acknowledged the last packet
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &mut self,
+        received: ReceivedPdu<'_>,
+        header: PduHeader,
+        ctx: &mut IoCtx,
+        write_mbx: &ethercrab::Mailbox,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<bool, Error> {
+        let FoeWriteState::Pending(inner) = &mut self.state else {
+            return Ok(false);
+        };
+
+        let Some(bytes) = inner.update(
+            received,
+            header,
+            ctx,
+            write_mbx,
+            read_mbx,
+            configured_addr,
+            identifier,
+            idx,
+        )?
+        else {
+            return Ok(false);
+        };
+
+        match self.receive(&bytes, write_mbx.len)? {
+            WriteStep::Send => (),
+            WriteStep::Wait => {
+                let mut inner = MbxWriteRead::read_only();
+                inner.start(
+                    ctx,
+                    write_mbx,
+                    read_mbx,
+                    configured_addr,
+                    identifier.map(|id| id >> 2),
+                    idx,
+                )?;
+                self.state = FoeWriteState::Pending(inner);
+                return Ok(false);
+            }
+            WriteStep::Done => {
+                self.state = FoeWriteState::Done;
+                return Ok(true);
+            }
+        }
+
+        self.send(
+            ctx,
+            write_mbx,
+            read_mbx,
+            configured_addr,
+            identifier.map(|id| id >> 2),
+            idx,
+        )?;
+        Ok(false)
+    }
+
+    // takes in a message that answers the last packet, the write mailbox is `write_len` long
+    fn receive(&mut self, bytes: &[u8], write_len: u16) -> Result<WriteStep, Error> {
+        let max_len = max_data(write_len)?;
+        let (opcode, value, _) = parse(bytes)?;
+
+        match opcode {
+            OP_ACK => {
+                if value != self.packet {
+                    return Err(Error::FoePacket {
+                        expected: self.packet,
+                        received: value,
+                    });
+                }
+
+                // a packet shorter than the mailbox allows for was the last one,
+                // if the data ends on a full packet an empty one follows it
+                if self.packet > 0 && self.sent.len() < max_len {
+                    return Ok(WriteStep::Done);
+                }
+
+                let start = self.sent.end;
+                self.sent = start..core::cmp::min(self.data.len(), start + max_len);
+                self.packet += 1;
+                Ok(WriteStep::Send)
+            }
+            // the subdevice could not take the last packet yet, so it is sent again
+            OP_BUSY if self.packet > 0 => Ok(WriteStep::Send),
+            OP_BUSY => Ok(WriteStep::Wait),
+            _ => Err(Error::UnexpectedFoeOpcode(opcode)),
+        }
+    }
+
+    // the data in `sent` as packet `packet`
+    fn data_request(&mut self) -> coe::MbxBuf {
+        self.counter = coe::next_counter(self.counter);
+        request(
+            self.counter,
+            OP_DATA,
+            self.packet,
+            &self.data[self.sent.clone()],
+        )
+    }
+}
+
+crate::mbx_queue::mailbox_transfer!(
+    impl[] for FoeWrite,
+    |done| update => done,
+    |_| crate::mbx_queue::MailboxResponse::Done,
+);
+
+// sent by the subdevice instead of a data packet or ack when it refuses the transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FoeError {
+    pub code: u32,
+}
+
+impl FoeError {
+    // error codes as listed in ETG.1000.6
+    pub fn description(&self) -> &'static str {
+        match self.code {
+            0x8000 => "not defined",
+            0x8001 => "not found",
+            0x8002 => "access denied",
+            0x8003 => "disk full",
+            0x8004 => "illegal",
+            0x8005 => "packet number wrong",
+            0x8006 => "already exists",
+            0x8007 => "no user",
+            0x8008 => "bootstrap only",
+            0x8009 => "not bootstrap",
+            0x800A => "no rights",
+            0x800B => "program error",
+            _ => "unknown foe error",
+        }
+    }
+}
+
+impl core::fmt::Display for FoeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "foe error {:#06x}: {}", self.code, self.description())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // room for 8 bytes of file data per message
+    const MBX_LEN: u16 = (coe::MBX_HEADER_LEN + FOE_HEADER_LEN + 8) as u16;
+
+    #[test]
+    fn request_layout() {
+        let buf = request(1, OP_READ, 0x1234_5678, b"fw");
+
+        // length, station address, channel + priority, type + counter
+        assert_eq!(buf[..6], [0x08, 0x00, 0x00, 0x00, 0x00, 0x14]);
+        // opcode, reserved, password
+        assert_eq!(buf[6..12], [OP_READ, 0x00, 0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(buf[12..], *b"fw");
+    }
+
+    #[test]
+    fn parse_error() {
+        let bytes = request(2, OP_ERROR, 0x8001, b"not found");
+        assert!(matches!(
+            parse(&bytes),
+            Err(Error::Foe(FoeError { code: 0x8001 }))
+        ));
+    }
+
+    #[test]
+    fn parse_wrong_type() {
+        let mut bytes = request(2, OP_DATA, 1, &[]);
+        bytes[5] = coe::MBX_TYPE_COE | (2 << 4);
+        assert!(matches!(
+            parse(&bytes),
+            Err(Error::UnexpectedMailboxType(coe::MBX_TYPE_COE))
+        ));
+    }
+
+    #[test]
+    fn read_until_short_packet() {
+        let mut read = FoeRead::with_counter(1, "fw", 0);
+
+        let packet = request(2, OP_DATA, 1, &[1; 8]);
+        let ReadStep::Ack(ack) = read.receive(&packet, MBX_LEN).unwrap() else {
+            panic!("a full packet is not the last one");
+        };
+        assert_eq!(ack[6..12], [OP_ACK, 0, 1, 0, 0, 0]);
+        assert_eq!(coe::counter(&ack), Some(2));
+
+        let packet = request(3, OP_DATA, 2, &[2; 3]);
+        let ReadStep::AckLast(ack) = read.receive(&packet, MBX_LEN).unwrap() else {
+            panic!("a short packet is the last one");
+        };
+        assert_eq!(ack[6..12], [OP_ACK, 0, 2, 0, 0, 0]);
+        assert_eq!(read.data, [1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2]);
+    }
+
+    #[test]
+    fn read_checks_against_the_read_mailbox() {
+        let mut read = FoeRead::with_counter(1, "fw", 0);
+
+        // full for a read mailbox that is as long as the packet, short for a longer one
+        let packet = request(2, OP_DATA, 1, &[1; 8]);
+        assert!(matches!(
+            read.receive(&packet, MBX_LEN + 4),
+            Ok(ReadStep::AckLast(_))
+        ));
+    }
+
+    #[test]
+    fn read_busy() {
+        let mut read = FoeRead::with_counter(1, "fw", 0);
+
+        let busy = request(2, OP_BUSY, 0, &[]);
+        assert!(matches!(read.receive(&busy, MBX_LEN), Ok(ReadStep::Wait)));
+        assert_eq!(read.packet, 0);
+    }
+
+    #[test]
+    fn read_out_of_order() {
+        let mut read = FoeRead::with_counter(1, "fw", 0);
+
+        let packet = request(2, OP_DATA, 2, &[1; 8]);
+        assert!(matches!(
+            read.receive(&packet, MBX_LEN),
+            Err(Error::FoePacket {
+                expected: 1,
+                received: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn write_in_packets() {
+        let data: Vec<u8> = (1..=12).collect();
+        let mut write = FoeWrite::with_counter(1, "fw", 0, &data);
+
+        // the write request is acked with packet 0
+        let ack = request(2, OP_ACK, 0, &[]);
+        assert_eq!(write.receive(&ack, MBX_LEN).unwrap(), WriteStep::Send);
+        assert_eq!((write.packet, write.sent.clone()), (1, 0..8));
+
+        let req = write.data_request();
+        assert_eq!(req[6..12], [OP_DATA, 0, 1, 0, 0, 0]);
+        assert_eq!(req[12..], data[..8]);
+
+        let ack = request(3, OP_ACK, 1, &[]);
+        assert_eq!(write.receive(&ack, MBX_LEN).unwrap(), WriteStep::Send);
+        assert_eq!((write.packet, write.sent.clone()), (2, 8..12));
+
+        let ack = request(4, OP_ACK, 2, &[]);
+        assert_eq!(write.receive(&ack, MBX_LEN).unwrap(), WriteStep::Done);
+    }
+
+    #[test]
+    fn write_ending_on_a_full_packet() {
+        let mut write = FoeWrite::with_counter(1, "fw", 0, &[1; 8]);
+
+        write.receive(&request(2, OP_ACK, 0, &[]), MBX_LEN).unwrap();
+        write.receive(&request(3, OP_ACK, 1, &[]), MBX_LEN).unwrap();
+        // an empty packet marks the end
+        assert_eq!((write.packet, write.sent.clone()), (2, 8..8));
+
+        let ack = request(4, OP_ACK, 2, &[]);
+        assert_eq!(write.receive(&ack, MBX_LEN).unwrap(), WriteStep::Done);
+    }
+
+    #[test]
+    fn write_busy() {
+        let mut write = FoeWrite::with_counter(1, "fw", 0, &[1; 12]);
+
+        // busy with the write request, wait for the ack
+        let busy = request(2, OP_BUSY, 0, &[]);
+        assert_eq!(write.receive(&busy, MBX_LEN).unwrap(), WriteStep::Wait);
+
+        // busy with a data packet, send it again
+        write.receive(&request(3, OP_ACK, 0, &[]), MBX_LEN).unwrap();
+        assert_eq!(write.receive(&busy, MBX_LEN).unwrap(), WriteStep::Send);
+        assert_eq!((write.packet, write.sent.clone()), (1, 0..8));
+    }
+
+    #[test]
+    fn write_wrong_ack() {
+        let mut write = FoeWrite::with_counter(1, "fw", 0, &[1; 12]);
+
+        let ack = request(2, OP_ACK, 1, &[]);
+        assert!(matches!(
+            write.receive(&ack, MBX_LEN),
+            Err(Error::FoePacket {
+                expected: 0,
+                received: 1
+            })
+        ));
+    }
+}
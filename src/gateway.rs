@@ -0,0 +1,264 @@
+use crate::coe;
+use crate::error::Error;
+use crate::io::{GATEWAY_MASK, IoCtx};
+use crate::mbx::MbxWriteRead;
+use ethercrab::{PduHeader, received_frame::ReceivedPdu};
+use io_uring::{opcode, squeue, types::Fd};
+
+use std::collections::VecDeque;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::os::fd::AsRawFd;
+
+// ETG.8200 mailbox gateway, tools send mailbox messages wrapped in an ethercat header over udp
+// and get the response of the addressed subdevice back the same way
+pub const GATEWAY_PORT: u16 = 0x88A4;
+
+// the device index that gateway exchanges are sent with, so their responses can be told apart
+pub(crate) const GATEWAY_IDX: u16 = u16::MAX;
+
+// length + type
+const ECAT_HEADER_LEN: usize = 2;
+const ECAT_TYPE_MAILBOX: u16 = 5;
+
+// requests that have not been forwarded yet, the oldest ones are dropped once this many pile up
+const QUEUE_LEN: usize = 8;
+
+// large enough for any mailbox
+const RECV_LEN: usize = 2048;
+
+#[derive(Debug)]
+struct Request {
+    peer: SocketAddr,
+    // the station address the request is for
+    address: u16,
+    mailbox: coe::MbxBuf,
+}
+
+#[derive(Debug)]
+struct Exchange {
+    peer: SocketAddr,
+    // the device index of the subdevice, the exchange itself is sent with `GATEWAY_IDX`
+    idx: u16,
+    configured_addr: u16,
+    write_mbx: ethercrab::Mailbox,
+    read_mbx: ethercrab::Mailbox,
+    inner: MbxWriteRead,
+}
+
+// forwards mailbox requests from a udp socket to the subdevices while the bus is in op,
+// one request at a time
+#[derive(Debug)]
+pub struct Gateway {
+    socket: UdpSocket,
+    requests: VecDeque<Request>,
+    current: Option<Exchange>,
+}
+
+impl Gateway {
+    // eg. `Gateway::bind(("0.0.0.0", GATEWAY_PORT))`
+    pub fn bind(addr: impl ToSocketAddrs) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket,
+            requests: VecDeque::new(),
+            current: None,
+        })
+    }
+
+    // waits for the socket to become readable, the ring does not read from it directly
+    pub(crate) fn poll_entry(&self) -> squeue::Entry {
+        opcode::PollAdd::new(Fd(self.socket.as_raw_fd()), libc::POLLIN as _)
+            .build()
+            .user_data(GATEWAY_MASK)
+    }
+
+    // reads every request that is waiting on the socket
+    pub(crate) fn receive(&mut self) -> std::io::Result<()> {
+        let mut buf = [0; RECV_LEN];
+        loop {
+            let (len, peer) = match self.socket.recv_from(&mut buf) {
+                Ok(res) => res,
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err),
+            };
+
+            // anything that is not a mailbox message is ignored
+            let Some((header, mailbox)) = buf[..len].split_first_chunk::<ECAT_HEADER_LEN>() else {
+                continue;
+            };
+            let header = u16::from_le_bytes(*header);
+            if header >> 12 != ECAT_TYPE_MAILBOX {
+                continue;
+            }
+
+            let Some(mailbox) = mailbox.get(..usize::from(header & 0x07FF)) else {
+                continue;
+            };
+            if mailbox.len() < coe::MBX_HEADER_LEN {
+                continue;
+            }
+
+            if self.requests.len() == QUEUE_LEN {
+                self.requests.pop_front();
+            }
+            self.requests.push_back(Request {
+                peer,
+                address: u16::from_le_bytes([mailbox[2], mailbox[3]]),
+                mailbox: mailbox.into(),
+            });
+        }
+    }
+
+    // whether the exchange in flight is with the subdevice at `configured_addr`
+    pub(crate) fn exchanging(&self, configured_addr: u16) -> bool {
+        self.current
+            .as_ref()
+            .is_some_and(|exchange| exchange.configured_addr == configured_addr)
+    }
+
+    // drops the exchange that is in flight, eg. when the bus is restarted
+    pub(crate) fn reset(&mut self) {
+        self.current = None;
+    }
+}
+
+// forwards the next request if nothing is in flight, `devices` are the subdevices in op along
+// with their device index
+pub(crate) fn start_next<'d>(
+    ctx: &mut IoCtx,
+    devices: impl Iterator<Item = (u16, &'d ethercrab::SubDevice)> + Clone,
+) -> Result<(), Error> {
+    let Some(gateway) = ctx.gateway.as_deref_mut() else {
+        return Ok(());
+    };
+    if gateway.current.is_some() {
+        return Ok(());
+    }
+
+    let (req, idx, subdev) = loop {
+        let Some(req) = gateway.requests.front() else {
+            return Ok(());
+        };
+
+        // requests for subdevices that are not on the bus or have no mailbox go unanswered
+        let Some((idx, subdev)) = devices.clone().find(|(_, subdev)| {
+            subdev.configured_address() == req.address && crate::mbx::mailboxes(subdev).is_ok()
+        }) else {
+            gateway.requests.pop_front();
+            continue;
+        };
+
+        // the request waits while the subdevice is in the middle of a mailbox transfer, which
+        // is always sent with an identifier, so the messages of the two do not get mixed up
+        let busy = ctx
+            .tx
+            .tx_entries
+            .values()
+            .any(|tx| tx.configured_addr == Some(idx) && tx.identifier.is_some())
+            || ctx.mailbox_status.waiting(idx);
+        if busy {
+            return Ok(());
+        }
+
+        if let Some(req) = gateway.requests.pop_front() {
+            break (req, idx, subdev);
+        }
+    };
+
+    let (write_mbx, read_mbx) = crate::mbx::mailboxes(subdev)?;
+    let configured_addr = subdev.configured_address();
+
+    // the address is the sender's, which is the maindevice
+    let mut mailbox = req.mailbox;
+    mailbox[2..4].copy_from_slice(&0u16.to_le_bytes());
+
+    let mut inner = MbxWriteRead::new(&mailbox);
+    inner.start(
+        ctx,
+        &write_mbx,
+        &read_mbx,
+        configured_addr,
+        None,
+        GATEWAY_IDX,
+    )?;
+
+    if let Some(gateway) = ctx.gateway.as_deref_mut() {
+        gateway.current = Some(Exchange {
+            peer: req.peer,
+            idx,
+            configured_addr,
+            write_mbx,
+            read_mbx,
+            inner,
+        });
+    }
+    Ok(())
+}
+
+// a response for the exchange in flight, the reply is sent back once it is complete.
+// a failed exchange is dropped without a reply and the tool asks again, it does not stop the bus
+pub(crate) fn update(
+    received: ReceivedPdu<'_>,
+    header: PduHeader,
+    ctx: &mut IoCtx,
+    identifier: Option<u8>,
+) -> Result<(), Error> {
+    let Some(mut exchange) = ctx
+        .gateway
+        .as_deref_mut()
+        .and_then(|gateway| gateway.current.take())
+    else {
+        // stale responses from an exchange that was dropped
+        return Ok(());
+    };
+
+    let res = exchange.inner.update(
+        received,
+        header,
+        ctx,
+        &exchange.write_mbx,
+        &exchange.read_mbx,
+        exchange.configured_addr,
+        identifier,
+        GATEWAY_IDX,
+    );
+
+    // emergencies that were read instead of the response belong to the subdevice
+    while let Some(emergency) = ctx.emergencies.pop(GATEWAY_IDX) {
+        ctx.emergencies.push(exchange.idx, emergency);
+    }
+
+    let Ok(res) = res else {
+        return Ok(());
+    };
+
+    let Some(gateway) = ctx.gateway.as_deref_mut() else {
+        return Ok(());
+    };
+
+    let Some(bytes) = res else {
+        gateway.current = Some(exchange);
+        return Ok(());
+    };
+
+    let Some(len) = bytes.first_chunk::<2>() else {
+        return Ok(());
+    };
+    let len = usize::from(u16::from_le_bytes(*len)) + coe::MBX_HEADER_LEN;
+    let Some(mailbox) = bytes.get(..len) else {
+        return Ok(());
+    };
+
+    let mut reply = coe::MbxBuf::new();
+    reply.extend_from_slice(&(len as u16 | (ECAT_TYPE_MAILBOX << 12)).to_le_bytes());
+    reply.extend_from_slice(mailbox);
+    // the address is the sender's, which is the subdevice
+    reply[ECAT_HEADER_LEN + 2..ECAT_HEADER_LEN + 4]
+        .copy_from_slice(&exchange.configured_addr.to_le_bytes());
+
+    // a reply that could not be sent is dropped as well, the tool will ask again
+    let _ = gateway.socket.send_to(&reply, exchange.peer);
+    Ok(())
+}
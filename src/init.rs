@@ -1,12 +1,11 @@
+use crate::concurrent::Concurrent;
+use crate::error::Error;
+use crate::io::IoCtx;
 use crate::setup::setup_write;
-use crate::txbuf::TxBuf;
 use ethercrab::{
-    ConfigureDevices, DeviceProperties, EtherCrabWireSized, MainDevice, PduHeader,
-    PrepConfigureDevices, PrepDeviceProperties, error::Error, received_frame::ReceivedPdu,
-    std::RawSocketDesc,
+    ConfigureDevices, DeviceProperties, EtherCrabWireSized, PduHeader, PrepConfigureDevices,
+    PrepDeviceProperties, received_frame::ReceivedPdu,
 };
-use io_uring::{IoUring, types::Timespec};
-use std::collections::BTreeMap;
 
 use crate::eeprom::{category::CategoryReader, range::RangeReader, string::StringReader};
 
@@ -14,187 +13,121 @@ use heapless::Deque;
 
 pub struct Init<const N: usize> {
     subdevices: Deque<SubdevState, N>,
+    // how many subdevices have their eeprom read at once
+    concurrency: u16,
     state: InitState,
 }
 
 impl<const N: usize> Init<N> {
-    #[allow(clippy::too_many_arguments)]
     pub(crate) fn start_new(
         subdev_count: u16,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
+        ctx: &mut IoCtx,
+        concurrency: u16,
     ) -> Result<Self, Error> {
         let mut subdevices = Deque::new();
 
         let mut addr_state = PrepConfigureDevices::new(subdev_count);
 
-        let (res, id, addr) = ConfigureDevices::iter(maindevice, &mut addr_state).unwrap();
-        let (frame, handle) = res?.unwrap();
-
-        setup_write(
-            frame,
-            handle,
-            retry_count,
-            timeout,
-            tx_entries,
-            sock,
-            ring,
-            Some(id),
-            None,
-            write_entry,
-            timeout_entry,
-        )?;
+        let (res, id, addr) =
+            ConfigureDevices::iter(ctx.maindevice, &mut addr_state).ok_or(Error::NoSubDevices)?;
+        let (frame, handle) = res?.ok_or(Error::NoFrame)?;
+
+        setup_write(frame, handle, &mut ctx.tx, Some(id), None)?;
 
         let _ = subdevices.push_back(SubdevState::new(addr));
         let state = InitState::ConfigureAddresses(addr_state);
 
-        Ok(Self { subdevices, state })
+        Ok(Self {
+            subdevices,
+            concurrency,
+            state,
+        })
     }
 
-    #[allow(clippy::too_many_arguments)]
     pub(crate) fn update(
         &mut self,
         received: ReceivedPdu<'_>,
         header: PduHeader,
-        maindevice: &mut MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut io_uring::IoUring,
+        ctx: &mut IoCtx,
         idx: Option<u16>,
         identifier: Option<u8>,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<Option<Deque<ethercrab::SubDevice, N>>, Error> {
         match &mut self.state {
             InitState::ConfigureAddresses(addr_state) => {
                 if header.command_code != 2 {
-                    unreachable!()
+                    return Err(Error::UnexpectedCommand(header.command_code));
                 }
 
-                if let Some((res, id, addr)) = ConfigureDevices::iter(maindevice, addr_state) {
-                    let (frame, handle) = res?.unwrap();
-
-                    setup_write(
-                        frame,
-                        handle,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
-                        Some(id),
-                        None,
-                        &write_entry,
-                        &timeout_entry,
-                    )?;
+                if let Some((res, id, addr)) = ConfigureDevices::iter(ctx.maindevice, addr_state) {
+                    let (frame, handle) = res?.ok_or(Error::NoFrame)?;
+
+                    setup_write(frame, handle, &mut ctx.tx, Some(id), None)?;
 
                     let _ = self.subdevices.push_back(SubdevState::new(addr));
 
                     return Ok(None);
                 }
 
-                let (frame, handle) = maindevice
+                let (frame, handle) = ctx
+                    .maindevice
                     .prep_wait_for_state(ethercrab::SubDeviceState::Init)?
-                    .unwrap();
-                setup_write(
-                    frame,
-                    handle,
-                    retry_count,
-                    timeout,
-                    tx_entries,
-                    sock,
-                    ring,
-                    None,
-                    None,
-                    &write_entry,
-                    &timeout_entry,
-                )?;
+                    .ok_or(Error::NoFrame)?;
+                setup_write(frame, handle, &mut ctx.tx, None, None)?;
 
                 self.state = InitState::SyncInit;
             }
             InitState::SyncInit => {
                 if header.command_code != 7 {
-                    unreachable!()
+                    return Err(Error::UnexpectedCommand(header.command_code));
                 }
 
                 use ethercrab::EtherCrabWireRead;
-                let ctrl = ethercrab::AlControl::unpack_from_slice(&received);
+                let state = ethercrab::AlControl::unpack_from_slice(&received)
+                    .ok()
+                    .map(|ctrl| ctrl.state);
+
+                if state != Some(ethercrab::SubDeviceState::Init) {
+                    return Err(Error::UnexpectedState {
+                        expected: ethercrab::SubDeviceState::Init,
+                        found: state,
+                    });
+                }
 
-                if !matches!(
-                    ctrl.map(|ctrl| ctrl.state),
-                    Ok(ethercrab::SubDeviceState::Init)
-                ) {
-                    todo!("handle devices that are not in init state during sync");
+                // every subdevice has its own eeprom, so they can be read at the same time
+                let (concurrent, start) =
+                    Concurrent::new(self.subdevices.len() as _, self.concurrency);
+                for id in start {
+                    let subdev = self
+                        .subdevices
+                        .get_mut(usize::from(id))
+                        .ok_or(Error::NoSubDevices)?;
+                    subdev.start(ctx, id)?;
                 }
 
-                let subdev = self.subdevices.front_mut().unwrap();
-
-                subdev.start(
-                    maindevice,
-                    retry_count,
-                    timeout,
-                    tx_entries,
-                    sock,
-                    ring,
-                    0,
-                    &write_entry,
-                    &timeout_entry,
-                )?;
-
-                self.state = InitState::ConfigureSubdevices(0);
+                self.state = InitState::ConfigureSubdevices(concurrent);
             }
-            InitState::ConfigureSubdevices(configured_idx) => {
-                let id = idx.expect("no index set :(");
+            InitState::ConfigureSubdevices(concurrent) => {
+                let id = idx.ok_or(Error::MissingIndex)?;
 
                 let subdev = self
                     .subdevices
                     .get_mut(usize::from(id))
-                    .expect("could not get subdev");
-
-                if !subdev.update(
-                    received,
-                    header,
-                    maindevice,
-                    retry_count,
-                    timeout,
-                    tx_entries,
-                    sock,
-                    ring,
-                    id,
-                    identifier,
-                    &write_entry,
-                    &timeout_entry,
-                )? {
+                    .ok_or(Error::UnknownDevice(id))?;
+
+                if !subdev.update(received, header, ctx, id, identifier)? {
                     return Ok(None);
                 }
 
-                *configured_idx += 1;
-
-                if usize::from(*configured_idx) != self.subdevices.len() {
+                if let Some(next) = concurrent.finish() {
                     let subdev = self
                         .subdevices
-                        .get_mut(usize::from(*configured_idx))
-                        .unwrap();
-
-                    subdev.start(
-                        maindevice,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
-                        *configured_idx,
-                        &write_entry,
-                        &timeout_entry,
-                    )?;
+                        .get_mut(usize::from(next))
+                        .ok_or(Error::UnknownDevice(next))?;
+
+                    subdev.start(ctx, next)?;
+                }
+
+                if !concurrent.done() {
                     return Ok(None);
                 }
 
@@ -206,7 +139,12 @@ impl<const N: usize> Init<N> {
                         SubdevState::Init(dev) => {
                             let _ = subdevs.push_back(dev);
                         }
-                        _ => unreachable!(),
+                        SubdevState::Initializing { .. } => {
+                            return Err(Error::UnexpectedState {
+                                expected: ethercrab::SubDeviceState::Init,
+                                found: None,
+                            });
+                        }
                     }
                 }
                 return Ok(Some(subdevs));
@@ -219,7 +157,7 @@ impl<const N: usize> Init<N> {
 enum InitState {
     ConfigureAddresses(PrepConfigureDevices),
     SyncInit,
-    ConfigureSubdevices(u16),
+    ConfigureSubdevices(Concurrent),
 }
 
 enum SubdevState {
@@ -238,60 +176,33 @@ impl SubdevState {
         }
     }
 
-    #[allow(clippy::too_many_arguments)]
-    fn start(
-        &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut io_uring::IoUring,
-        idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
-    ) -> Result<(), Error> {
+    fn start(&mut self, ctx: &mut IoCtx, idx: u16) -> Result<(), Error> {
         let configured_addr = match self {
             Self::Initializing {
                 configured_addr, ..
             } => configured_addr,
-            _ => unreachable!(),
+            _ => {
+                return Err(Error::UnexpectedState {
+                    expected: ethercrab::SubDeviceState::Init,
+                    found: None,
+                });
+            }
         };
-        let (frame, handle) = maindevice
-            .prep_clear_eeprom(*configured_addr)
-            .unwrap()
-            .unwrap();
-
-        setup_write(
-            frame,
-            handle,
-            retry_count,
-            timeout,
-            tx_entries,
-            sock,
-            ring,
-            Some(idx),
-            None,
-            write_entry,
-            timeout_entry,
-        )
+        let (frame, handle) = ctx
+            .maindevice
+            .prep_clear_eeprom(*configured_addr)?
+            .ok_or(Error::NoFrame)?;
+
+        setup_write(frame, handle, &mut ctx.tx, Some(idx), None)
     }
 
-    #[allow(clippy::too_many_arguments)]
     fn update(
         &mut self,
         received: ReceivedPdu<'_>,
         header: PduHeader,
-        maindevice: &mut MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut io_uring::IoUring,
+        ctx: &mut IoCtx,
         idx: u16,
         identifier: Option<u8>,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<bool, Error> {
         match self {
             Self::Initializing {
@@ -300,54 +211,30 @@ impl SubdevState {
             } => match state {
                 SubdevInitState::ClearEeprom => {
                     if header.command_code != 5 {
-                        unreachable!()
+                        return Err(Error::UnexpectedCommand(header.command_code));
                     }
 
-                    let (frame, handle) = maindevice
+                    let (frame, handle) = ctx
+                        .maindevice
                         .prep_set_eeprom(*configured_addr, ethercrab::SiiOwner::Master)?
-                        .unwrap();
-
-                    setup_write(
-                        frame,
-                        handle,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
-                        Some(idx),
-                        None,
-                        write_entry,
-                        timeout_entry,
-                    )?;
+                        .ok_or(Error::NoFrame)?;
+
+                    setup_write(frame, handle, &mut ctx.tx, Some(idx), None)?;
 
                     *state = SubdevInitState::SetEeprom;
                 }
                 SubdevInitState::SetEeprom => {
                     if header.command_code != 5 {
-                        unreachable!()
+                        return Err(Error::UnexpectedCommand(header.command_code));
                     }
 
                     let mut prep_state = PrepDeviceProperties::new(*configured_addr);
 
-                    let (frame, handle) = DeviceProperties::iter(maindevice, &mut prep_state)
-                        .unwrap()
-                        .unwrap()
-                        .unwrap();
-
-                    setup_write(
-                        frame,
-                        handle,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
-                        Some(idx),
-                        None,
-                        write_entry,
-                        timeout_entry,
-                    )?;
+                    let (frame, handle) = DeviceProperties::iter(ctx.maindevice, &mut prep_state)
+                        .ok_or(Error::NoFrame)??
+                        .ok_or(Error::NoFrame)?;
+
+                    setup_write(frame, handle, &mut ctx.tx, Some(idx), None)?;
 
                     let identity_state = RangeReader::new(
                         0x0008,
@@ -380,24 +267,12 @@ impl SubdevState {
                     complete_access,
                 } => {
                     if !matches!(header.command_code, 4 | 5) {
-                        unreachable!("{}", header.command_code)
+                        return Err(Error::UnexpectedCommand(header.command_code));
                     }
 
-                    if let Some(res) = DeviceProperties::iter(maindevice, prep_state) {
-                        let (frame, handle) = res.unwrap().unwrap();
-                        setup_write(
-                            frame,
-                            handle,
-                            retry_count,
-                            timeout,
-                            tx_entries,
-                            sock,
-                            ring,
-                            Some(idx),
-                            None,
-                            &write_entry,
-                            &timeout_entry,
-                        )?;
+                    if let Some(res) = DeviceProperties::iter(ctx.maindevice, prep_state) {
+                        let (frame, handle) = res?.ok_or(Error::NoFrame)?;
+                        setup_write(frame, handle, &mut ctx.tx, Some(idx), None)?;
                     }
 
                     // only care about upper 16 bits as thats what stores the register
@@ -409,15 +284,13 @@ impl SubdevState {
                     match addr {
                         // support flags
                         0x0008 => {
-                            *flags = Some(
-                                ethercrab::SupportFlags::unpack_from_slice(&received).unwrap(),
-                            );
+                            *flags = Some(ethercrab::SupportFlags::unpack_from_slice(&received)?);
                         }
                         // station alias
-                        0x0012 => *alias_address = Some(u16::unpack_from_slice(&received).unwrap()),
+                        0x0012 => *alias_address = Some(u16::unpack_from_slice(&received)?),
                         // dl status
                         0x0110 => {
-                            let status = ethercrab::DlStatus::unpack_from_slice(&received).unwrap();
+                            let status = ethercrab::DlStatus::unpack_from_slice(&received)?;
                             *ports = Some(ethercrab::Ports::new(
                                 status.link_port0,
                                 status.link_port3,
@@ -425,75 +298,39 @@ impl SubdevState {
                                 status.link_port2,
                             ));
 
-                            identity_state.start(
-                                maindevice,
-                                retry_count,
-                                timeout,
-                                tx_entries,
-                                sock,
-                                ring,
-                                *configured_addr,
-                                idx,
-                                &write_entry,
-                                &timeout_entry,
-                            )?;
+                            identity_state.start(ctx, *configured_addr, idx)?;
                         }
                         0x0502 | 0x0508 => match identifier {
                             Some(1) => {
                                 if identity_state.update(
                                     received,
                                     header,
-                                    maindevice,
-                                    retry_count,
-                                    timeout,
-                                    tx_entries,
-                                    sock,
-                                    ring,
+                                    ctx,
                                     *configured_addr,
                                     idx,
-                                    &write_entry,
-                                    &timeout_entry,
                                 )? {
-                                    *identity =
-                                        Some(ethercrab::SubDeviceIdentity::unpack_from_slice(
+                                    *identity = Some(
+                                        ethercrab::SubDeviceIdentity::unpack_from_slice(
                                             &identity_state.buffer,
-                                        )?);
-                                    name_state.start(
-                                        maindevice,
-                                        retry_count,
-                                        timeout,
-                                        tx_entries,
-                                        sock,
-                                        ring,
-                                        *configured_addr,
-                                        idx,
-                                        &write_entry,
-                                        &timeout_entry,
-                                    )?;
+                                        )
+                                        .map_err(|_| Error::Sii)?,
+                                    );
+                                    name_state.start(ctx, *configured_addr, idx)?;
                                 }
                             }
                             Some(2) => {
-                                if name_state.update(
+                                name_state.update(
                                     received,
                                     header,
-                                    maindevice,
-                                    retry_count,
-                                    timeout,
-                                    tx_entries,
-                                    sock,
-                                    ring,
+                                    ctx,
                                     *configured_addr,
                                     idx,
                                     complete_access,
-                                    &write_entry,
-                                    &timeout_entry,
-                                )? {
-                                    todo!()
-                                }
+                                )?;
                             }
-                            _ => unreachable!(),
+                            id => return Err(Error::UnexpectedIdentifier(id)),
                         },
-                        reg => unreachable!("{:#x}", reg),
+                        reg => return Err(Error::UnexpectedRegister(reg)),
                     }
 
                     if let (
@@ -521,7 +358,8 @@ impl SubdevState {
                     }
                 }
             },
-            _ => unreachable!(),
+            // already initialized, a stray response is not a reason to move on again
+            Self::Init(_) => (),
         }
         Ok(false)
     }
@@ -561,71 +399,34 @@ mod name {
             ))
         }
 
-        #[allow(clippy::too_many_arguments)]
         pub(crate) fn start(
             &mut self,
-            maindevice: &MainDevice,
-            retry_count: usize,
-            timeout_duration: &Timespec,
-            tx_entries: &mut BTreeMap<u64, TxBuf>,
-            sock: &RawSocketDesc,
-            ring: &mut io_uring::IoUring,
+            ctx: &mut IoCtx,
             configured_addr: u16,
             idx: u16,
-            write_entry: impl Fn(u64) -> u64,
-            timeout_entry: impl Fn(u64) -> u64,
         ) -> Result<(), Error> {
             match self {
-                Self::FindingCategory(state) => state.start(
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    configured_addr,
-                    idx,
-                    write_entry,
-                    timeout_entry,
-                ),
-                _ => unreachable!(),
+                Self::FindingCategory(state) => state.start(ctx, configured_addr, idx),
+                _ => Err(Error::UnexpectedState {
+                    expected: ethercrab::SubDeviceState::Init,
+                    found: None,
+                }),
             }
         }
 
-        #[allow(clippy::too_many_arguments)]
         pub(crate) fn update(
             &mut self,
             received: ReceivedPdu<'_>,
             header: PduHeader,
-            maindevice: &mut MainDevice,
-            retry_count: usize,
-            timeout_duration: &Timespec,
-            tx_entries: &mut BTreeMap<u64, TxBuf>,
-            sock: &RawSocketDesc,
-            ring: &mut io_uring::IoUring,
+            ctx: &mut IoCtx,
             configured_addr: u16,
             index: u16,
             complete_access: &mut bool,
-            write_entry: impl Fn(u64) -> u64,
-            timeout_entry: impl Fn(u64) -> u64,
         ) -> Result<bool, Error> {
             use ethercrab::EtherCrabWireRead;
             match self {
                 Self::FindingCategory(cat) => {
-                    if cat.update(
-                        received,
-                        header,
-                        maindevice,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
-                        configured_addr,
-                        index,
-                        &write_entry,
-                        &timeout_entry,
-                    )? {
+                    if cat.update(received, header, ctx, configured_addr, index)? {
                         match core::mem::take(&mut cat.found) {
                             None => *self = Self::Name(None),
                             Some(found) => {
@@ -636,18 +437,7 @@ mod name {
                                     cat.identifier,
                                 );
 
-                                reader.start(
-                                    maindevice,
-                                    retry_count,
-                                    timeout_duration,
-                                    tx_entries,
-                                    sock,
-                                    ring,
-                                    configured_addr,
-                                    index,
-                                    &write_entry,
-                                    &timeout_entry,
-                                )?;
+                                reader.start(ctx, configured_addr, index)?;
 
                                 *self = Self::ReadingCategory(reader)
                             }
@@ -655,75 +445,27 @@ mod name {
                     }
                 }
                 Self::ReadingCategory(cat) => {
-                    if cat.update(
-                        received,
-                        header,
-                        maindevice,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
-                        configured_addr,
-                        index,
-                        &write_entry,
-                        &timeout_entry,
-                    )? {
-                        let general_info =
-                            ethercrab::SiiGeneral::unpack_from_slice(&cat.buffer).unwrap();
+                    if cat.update(received, header, ctx, configured_addr, index)? {
+                        let general_info = ethercrab::SiiGeneral::unpack_from_slice(&cat.buffer)
+                            .map_err(|_| Error::Sii)?;
                         *complete_access = general_info
                             .coe_details
                             .contains(ethercrab::CoeDetails::ENABLE_COMPLETE_ACCESS);
                         let mut reader =
                             CategoryReader::new(ethercrab::CategoryType::Strings, cat.identifier);
-                        reader.start(
-                            maindevice,
-                            retry_count,
-                            timeout_duration,
-                            tx_entries,
-                            sock,
-                            ring,
-                            configured_addr,
-                            index,
-                            &write_entry,
-                            &timeout_entry,
-                        )?;
+                        reader.start(ctx, configured_addr, index)?;
 
                         *self = Self::FindingStrings(reader, general_info.name_string_idx);
                     }
                 }
                 Self::FindingStrings(cat, name_idx) => {
-                    if cat.update(
-                        received,
-                        header,
-                        maindevice,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
-                        configured_addr,
-                        index,
-                        &write_entry,
-                        &timeout_entry,
-                    )? {
+                    if cat.update(received, header, ctx, configured_addr, index)? {
                         match core::mem::take(&mut cat.found) {
                             None => *self = Self::Name(None),
                             Some(found) => {
                                 let mut reader =
                                     StringReader::new(*name_idx, found.start, cat.identifier);
-                                reader.start(
-                                    maindevice,
-                                    retry_count,
-                                    timeout_duration,
-                                    tx_entries,
-                                    sock,
-                                    ring,
-                                    configured_addr,
-                                    index,
-                                    &write_entry,
-                                    &timeout_entry,
-                                )?;
+                                reader.start(ctx, configured_addr, index)?;
 
                                 *self = Self::ReadingStrings(reader);
                             }
@@ -731,20 +473,7 @@ mod name {
                     }
                 }
                 Self::ReadingStrings(strings) => {
-                    if strings.update(
-                        received,
-                        header,
-                        maindevice,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
-                        configured_addr,
-                        index,
-                        &write_entry,
-                        &timeout_entry,
-                    )? {
+                    if strings.update(received, header, ctx, configured_addr, index)? {
                         match core::mem::take(&mut strings.found) {
                             None => *self = Self::Name(None),
                             Some(found) => {
@@ -754,18 +483,7 @@ mod name {
                                     [0; N],
                                     strings.identifier,
                                 );
-                                reader.start(
-                                    maindevice,
-                                    retry_count,
-                                    timeout_duration,
-                                    tx_entries,
-                                    sock,
-                                    ring,
-                                    configured_addr,
-                                    index,
-                                    &write_entry,
-                                    &timeout_entry,
-                                )?;
+                                reader.start(ctx, configured_addr, index)?;
 
                                 *self = Self::ReadingName(reader);
                             }
@@ -773,20 +491,7 @@ mod name {
                     }
                 }
                 Self::ReadingName(name) => {
-                    if name.update(
-                        received,
-                        header,
-                        maindevice,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
-                        configured_addr,
-                        index,
-                        &write_entry,
-                        &timeout_entry,
-                    )? {
+                    if name.update(received, header, ctx, configured_addr, index)? {
                         let mut buf = [0; N];
                         core::mem::swap(&mut name.buffer, &mut buf);
                         let mut v = heapless::Vec::from_array(buf);
@@ -799,7 +504,8 @@ mod name {
                         }
                     }
                 }
-                _ => todo!(),
+                // the name has already been read
+                Self::Name(_) => (),
             }
             Ok(false)
         }
@@ -1,3 +1,82 @@
+use crate::emergency::EmergencyQueue;
+use crate::eoe::Taps;
+use crate::gateway::Gateway;
+use crate::mbx::{MailboxCounters, MailboxStatus};
+use crate::txbuf::TxBuf;
+use ethercrab::{MainDevice, std::RawSocketDesc};
+use io_uring::{IoUring, types::Timespec};
+use std::collections::BTreeMap;
+
 pub const WRITE_MASK: u64 = 1 << 63;
 pub const TIMEOUT_MASK: u64 = 1 << 62;
 pub const TIMEOUT_CLEAR_MASK: u64 = WRITE_MASK | TIMEOUT_MASK;
+// a tap became readable, the lower bits hold the index of the device it is bridged with
+pub const TAP_MASK: u64 = 1 << 61;
+// the mailbox gateway socket became readable
+pub const GATEWAY_MASK: u64 = 1 << 60;
+
+// everything that is needed to send a pdu and later get its response
+pub struct IoCtx<'a, 'sto> {
+    pub maindevice: &'a mut MainDevice<'sto>,
+    pub tx: TxCtx<'a, 'sto>,
+    // emergencies that were read out of the mailboxes, keyed by device index
+    pub emergencies: &'a mut EmergencyQueue,
+    // frames read from the taps that were added to the driver, keyed by device index
+    pub taps: &'a mut Taps,
+    // the mailbox counters of each subdevice, keyed by configured address
+    pub mailbox_counters: &'a mut MailboxCounters,
+    // the read mailboxes whose status is part of the cyclic frame in op, keyed by device index
+    pub mailbox_status: &'a mut MailboxStatus,
+    // mailbox requests from outside tools, forwarded while the bus is in op
+    pub gateway: Option<&'a mut Gateway>,
+}
+
+// kept separate from the maindevice so frames can be prepped while they are being submitted
+pub struct TxCtx<'a, 'sto> {
+    pub retry_count: usize,
+    pub timeout: &'a Timespec,
+    pub tx_entries: &'a mut BTreeMap<u64, TxBuf<'sto>>,
+    pub sock: &'a RawSocketDesc,
+    pub ring: &'a mut IoUring,
+    pub write_entry: &'a dyn Fn(u64) -> u64,
+    pub timeout_entry: &'a dyn Fn(u64) -> u64,
+}
+
+impl<'a, 'sto> IoCtx<'a, 'sto> {
+    // only built by the driver, which lends out the parts of itself that sending and receiving
+    // needs for the length of a single completion
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        maindevice: &'a mut MainDevice<'sto>,
+        retry_count: usize,
+        timeout: &'a Timespec,
+        tx_entries: &'a mut BTreeMap<u64, TxBuf<'sto>>,
+        sock: &'a RawSocketDesc,
+        ring: &'a mut IoUring,
+        write_entry: &'a dyn Fn(u64) -> u64,
+        timeout_entry: &'a dyn Fn(u64) -> u64,
+        emergencies: &'a mut EmergencyQueue,
+        taps: &'a mut Taps,
+        mailbox_counters: &'a mut MailboxCounters,
+        mailbox_status: &'a mut MailboxStatus,
+        gateway: Option<&'a mut Gateway>,
+    ) -> Self {
+        Self {
+            maindevice,
+            tx: TxCtx {
+                retry_count,
+                timeout,
+                tx_entries,
+                sock,
+                ring,
+                write_entry,
+                timeout_entry,
+            },
+            emergencies,
+            taps,
+            mailbox_counters,
+            mailbox_status,
+            gateway,
+        }
+    }
+}
@@ -1,10 +1,20 @@
+mod coe;
+mod concurrent;
 mod dc;
+mod device_change;
+mod driver;
 mod eeprom;
+mod emergency;
+mod eoe;
+mod error;
 mod fmmu;
+mod foe;
+mod gateway;
 mod init;
 pub mod io;
 mod mbx;
 mod mbx_config;
+mod mbx_queue;
 mod op;
 mod pdo;
 mod pdo_config;
@@ -12,15 +22,30 @@ mod preop;
 mod reset;
 mod safeop;
 mod sdo;
+mod sdo_info;
 pub mod setup;
+mod shutdown;
+mod soe;
 mod state;
 pub mod state_transition;
 mod txbuf;
 pub mod user;
 
+pub use driver::Driver;
+pub use emergency::{Emergency, EmergencyQueue};
+pub use eoe::{Eoe, EoeResponse, IpParams, Tap, Taps};
+pub use error::Error;
+pub use foe::{FoeError, FoeRead, FoeWrite};
+pub use gateway::{GATEWAY_PORT, Gateway};
+pub use mbx::{MailboxCounters, MailboxExchange, MailboxStatus};
+pub use mbx_queue::{MailboxCompletion, MailboxQueue, MailboxResponse, MailboxTransfer};
 pub use op::DeviceResponse;
 pub use pdo::{PdoConfig, PdoMapping, PdoObject};
-pub use sdo::{SdoRead, SdoWrite};
+pub use sdo::{SdoAbort, SdoRead, SdoWrite};
+pub use sdo_info::{
+    EntryDescription, ObjectDescription, ObjectList, ObjectListType, SdoInfo, SdoInfoResponse,
+};
+pub use soe::{SoeElement, SoeRead, SoeWrite};
 pub use state::InitState;
 pub use txbuf::{TxBuf, TxIndex};
 
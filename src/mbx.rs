@@ -1,57 +1,373 @@
+use crate::emergency::Emergency;
+use crate::error::Error;
+use crate::io::IoCtx;
 use crate::setup::setup_write;
-use crate::txbuf::TxBuf;
-use ethercrab::{
-    MainDevice, PduHeader, error::Error, received_frame::ReceivedPdu, std::RawSocketDesc,
-};
-use io_uring::{IoUring, types::Timespec};
-use std::collections::BTreeMap;
+use ethercrab::{Mailbox, PduHeader, SubDevice, received_frame::ReceivedPdu};
 
+// the write and read mailboxes that were found in the subdevice's sii
+pub(crate) fn mailboxes(subdev: &SubDevice) -> Result<(Mailbox, Mailbox), Error> {
+    match (subdev.config.mailbox.write, subdev.config.mailbox.read) {
+        (Some(write), Some(read)) => Ok((write, read)),
+        _ => Err(Error::MissingMailbox(subdev.configured_address())),
+    }
+}
+
+// the mailbox counters of each subdevice, keyed by configured address.
+// every message that is written gets the next counter so that the subdevice can tell a
+// retried write apart from a new message, and messages that are read with the same counter
+// as the one before are repeats that were already handled
+#[derive(Debug, Default)]
+pub struct MailboxCounters {
+    counters: std::collections::BTreeMap<u16, Counters>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counters {
+    sent: u8,
+    received: Option<u8>,
+}
+
+impl MailboxCounters {
+    // the counter for the next message written to the subdevice at `configured_addr`
+    pub(crate) fn next(&mut self, configured_addr: u16) -> u8 {
+        let counters = self.counters.entry(configured_addr).or_default();
+        counters.sent = crate::coe::next_counter(counters.sent);
+        counters.sent
+    }
+
+    // keeps track of the counter of a message that was read, returns true if it was a repeat
+    pub(crate) fn duplicate(&mut self, configured_addr: u16, message: &[u8]) -> bool {
+        // 0 is used by subdevices that do not count their messages
+        let Some(counter) = crate::coe::counter(message).filter(|c| *c != 0) else {
+            return false;
+        };
+
+        let counters = self.counters.entry(configured_addr).or_default();
+        if counters.received == Some(counter) {
+            return true;
+        }
+        counters.received = Some(counter);
+        false
+    }
+
+    // subdevices start counting from the beginning after their mailboxes were set up again
+    pub(crate) fn clear(&mut self) {
+        self.counters.clear();
+    }
+
+    // the same for a single subdevice, eg. one that was restarted through init
+    pub(crate) fn reset(&mut self, configured_addr: u16) {
+        self.counters.remove(&configured_addr);
+    }
+}
+
+// the identifier of the reads that op starts on its own, for messages that were sent
+// while nothing was waiting on them. the mailbox transfers only use 0b01 and 0b10 in the low
+// bits, see `RESERVED_IDENTIFIERS`
+pub(crate) const CYCLIC_READ_IDENTIFIER: u8 = 0x80;
+
+// the read mailboxes whose status is mapped into the cyclic frame, keyed by device index.
+// reads that find their mailbox empty wait for the cyclic frame to show it full again
+// instead of polling the status on their own
+#[derive(Debug, Default)]
+pub struct MailboxStatus {
+    devices: std::collections::BTreeMap<u16, CyclicStatus>,
+}
+
+#[derive(Debug)]
+struct CyclicStatus {
+    // where the status register ended up in the pdi
+    offset: usize,
+    configured_addr: u16,
+    read_mbx: Mailbox,
+    full: bool,
+    // the identifier of the read that is waiting on the mailbox
+    waiting: Option<Option<u8>>,
+}
+
+impl MailboxStatus {
+    pub(crate) fn insert(
+        &mut self,
+        idx: u16,
+        offset: usize,
+        configured_addr: u16,
+        read_mbx: Mailbox,
+    ) {
+        self.devices.insert(
+            idx,
+            CyclicStatus {
+                offset,
+                configured_addr,
+                read_mbx,
+                full: false,
+                waiting: None,
+            },
+        );
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.devices.clear();
+    }
+
+    // whether the mailbox of the device at `idx` was full in the last cyclic frame,
+    // none if its status is not part of it
+    pub fn full(&self, idx: u16) -> Option<bool> {
+        self.devices.get(&idx).map(|status| status.full)
+    }
+
+    // whether a read of the device at `idx` is parked until its mailbox is full
+    pub(crate) fn waiting(&self, idx: u16) -> bool {
+        self.devices
+            .get(&idx)
+            .is_some_and(|status| status.waiting.is_some())
+    }
+
+    // parks a read until the mailbox is full, returns false if the status is not mapped
+    fn wait(&mut self, idx: u16, identifier: Option<u8>) -> bool {
+        let Some(status) = self.devices.get_mut(&idx) else {
+            return false;
+        };
+        status.waiting = Some(identifier);
+        true
+    }
+
+    // picks the status of every device out of the pdi that came back with the cyclic frame
+    pub(crate) fn update(&mut self, pdi: &[u8]) {
+        for status in self.devices.values_mut() {
+            if let Some(byte) = pdi.get(status.offset) {
+                status.full = byte & SM_MAILBOX_FULL != 0;
+            }
+        }
+    }
+}
+
+// reads every mailbox that the last cyclic frame showed as full, either for the read that is
+// waiting on it or, if there is no mailbox traffic with the device at all, for op itself
+pub(crate) fn read_full_mailboxes(ctx: &mut IoCtx) -> Result<(), Error> {
+    for (idx, status) in ctx.mailbox_status.devices.iter_mut() {
+        if !status.full {
+            continue;
+        }
+
+        let identifier = match status.waiting.take() {
+            Some(identifier) => identifier,
+            None => {
+                // mailbox traffic is always sent with an identifier, unlike the cyclic frame
+                let busy = ctx
+                    .tx
+                    .tx_entries
+                    .values()
+                    .any(|tx| tx.configured_addr == Some(*idx) && tx.identifier.is_some())
+                    || ctx
+                        .gateway
+                        .as_deref()
+                        .is_some_and(|gateway| gateway.exchanging(status.configured_addr));
+                if busy {
+                    continue;
+                }
+                Some(CYCLIC_READ_IDENTIFIER)
+            }
+        };
+
+        let (frame, handle) = unsafe {
+            ctx.maindevice
+                .prep_read(
+                    status.configured_addr,
+                    status.read_mbx.address,
+                    status.read_mbx.len,
+                )?
+                .ok_or(Error::NoFrame)?
+        };
+        setup_write(frame, handle, &mut ctx.tx, Some(*idx), identifier)?;
+
+        // not full until the next cyclic frame says so, the read above is in flight
+        status.full = false;
+    }
+    Ok(())
+}
+
+// sends a message of any mailbox type and hands back the payload of the response,
+// so that eg. vendor specific protocols can be implemented outside of this crate
 #[derive(Debug)]
-pub(crate) struct MbxWriteRead<R> {
-    req: R,
+pub struct MailboxExchange {
+    ty: u8,
+    response: bool,
+    inner: MbxWriteRead,
+    // the length of the request, header included
+    len: usize,
+    // the payload of the response, without the mailbox header
+    data: crate::coe::MbxBuf,
+    done: bool,
+}
+
+impl MailboxExchange {
+    pub const EOE: u8 = crate::coe::MBX_TYPE_EOE;
+    pub const COE: u8 = crate::coe::MBX_TYPE_COE;
+    pub const FOE: u8 = crate::coe::MBX_TYPE_FOE;
+    pub const SOE: u8 = crate::coe::MBX_TYPE_SOE;
+    pub const VOE: u8 = crate::coe::MBX_TYPE_VOE;
+
+    // the response has to be of the same mailbox type as the request,
+    // only the low 4 bits of `ty` fit in the mailbox header
+    pub fn new(subdev: &SubDevice, ty: u8, payload: &[u8]) -> Self {
+        let req = Self::request(subdev, ty, payload);
+        Self {
+            ty: ty & 0x0F,
+            response: true,
+            inner: MbxWriteRead::new(&req),
+            len: req.len(),
+            data: Default::default(),
+            done: false,
+        }
+    }
+
+    // for messages that the subdevice does not answer, `update` hands back an empty payload
+    // once the message was written
+    pub fn send_only(subdev: &SubDevice, ty: u8, payload: &[u8]) -> Self {
+        let req = Self::request(subdev, ty, payload);
+        Self {
+            ty: ty & 0x0F,
+            response: false,
+            inner: MbxWriteRead::write_only(&req),
+            len: req.len(),
+            data: Default::default(),
+            done: false,
+        }
+    }
+
+    fn request(subdev: &SubDevice, ty: u8, payload: &[u8]) -> crate::coe::MbxBuf {
+        let mut req = crate::coe::MbxBuf::new();
+        crate::coe::mailbox_header(&mut req, payload.len(), ty, subdev.mailbox_counter());
+        req.extend_from_slice(payload);
+        req
+    }
+
+    pub fn finished(&self) -> bool {
+        self.done
+    }
+
+    pub fn start(
+        &mut self,
+        ctx: &mut IoCtx,
+        write_mbx: &Mailbox,
+        read_mbx: &Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<(), Error> {
+        if self.len > usize::from(write_mbx.len) {
+            return Err(Error::InvalidMailbox);
+        }
+
+        self.inner
+            .start(ctx, write_mbx, read_mbx, configured_addr, identifier, idx)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &mut self,
+        received: ReceivedPdu<'_>,
+        header: PduHeader,
+        ctx: &mut IoCtx,
+        write_mbx: &Mailbox,
+        read_mbx: &Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<Option<&[u8]>, Error> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let Some(bytes) = self.inner.update(
+            received,
+            header,
+            ctx,
+            write_mbx,
+            read_mbx,
+            configured_addr,
+            identifier,
+            idx,
+        )?
+        else {
+            return Ok(None);
+        };
+
+        if self.response {
+            let payload = crate::coe::mailbox_body(&bytes, self.ty)?;
+            self.data.extend_from_slice(payload);
+        }
+
+        self.done = true;
+        Ok(Some(&self.data))
+    }
+}
+
+crate::mbx_queue::mailbox_transfer!(
+    impl[] for MailboxExchange,
+    |res| update => res.is_some(),
+    |this| crate::mbx_queue::MailboxResponse::Data(&this.data),
+);
+
+// writes a request into the write mailbox and hands back the raw contents of the read mailbox
+#[derive(Debug)]
+pub(crate) struct MbxWriteRead {
+    req: crate::coe::MbxBuf,
     state: MbxWriteReadState,
 }
 
-impl<R: ethercrab::coe::services::CoeServiceRequest> MbxWriteRead<R> {
-    pub(crate) fn new(request: R) -> Self {
+impl MbxWriteRead {
+    pub(crate) fn new(request: &[u8]) -> Self {
         Self {
-            req: request,
+            req: request.into(),
             state: MbxWriteReadState::MailboxFull(CoeMailboxState::new()),
         }
     }
 
-    #[allow(clippy::too_many_arguments)]
+    // only reads the next message out of the read mailbox, for responses
+    // that are split over several messages without a request for each of them
+    pub(crate) fn read_only() -> Self {
+        Self {
+            req: Default::default(),
+            state: MbxWriteReadState::Read(CoeRead::new()),
+        }
+    }
+
+    // only writes the request, for messages that the subdevice does not answer.
+    // `update` hands back the written pdu once the write went through
+    pub(crate) fn write_only(request: &[u8]) -> Self {
+        Self {
+            req: request.into(),
+            state: MbxWriteReadState::Write(WriteMbxState::new()),
+        }
+    }
+
     pub(crate) fn start(
         &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         write_mbx: &ethercrab::Mailbox,
         read_mbx: &ethercrab::Mailbox,
         configured_addr: u16,
         identifier: Option<u8>,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<(), Error> {
         match &mut self.state {
-            MbxWriteReadState::MailboxFull(m) => m.start(
-                maindevice,
-                retry_count,
-                timeout,
-                tx_entries,
-                sock,
-                ring,
+            MbxWriteReadState::Write(tx) => tx.start(
+                ctx,
                 write_mbx,
+                configured_addr,
+                1 | (identifier.unwrap_or(0) << 2),
+                idx,
+            ),
+            MbxWriteReadState::MailboxFull(m) => {
+                m.start(ctx, write_mbx, read_mbx, configured_addr, identifier, idx)
+            }
+            MbxWriteReadState::Read(read) => read.start(
+                ctx,
                 read_mbx,
                 configured_addr,
-                identifier,
                 idx,
-                write_entry,
-                timeout_entry,
+                Some(1 | (identifier.unwrap_or(0) << 2)),
             ),
             _ => unreachable!(),
         }
@@ -62,74 +378,44 @@ impl<R: ethercrab::coe::services::CoeServiceRequest> MbxWriteRead<R> {
         &mut self,
         received: ReceivedPdu<'p>,
         header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         write_mbx: &ethercrab::Mailbox,
         read_mbx: &ethercrab::Mailbox,
         configured_addr: u16,
         identifier: Option<u8>,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
-    ) -> Result<Option<(R, ReceivedPdu<'p>)>, Error> {
+    ) -> Result<Option<ReceivedPdu<'p>>, Error> {
         match &mut self.state {
             MbxWriteReadState::MailboxFull(m) => {
                 if m.update(
                     received,
                     header,
-                    maindevice,
-                    retry_count,
-                    timeout,
-                    tx_entries,
-                    sock,
-                    ring,
+                    ctx,
                     write_mbx,
                     read_mbx,
                     configured_addr,
                     identifier,
                     idx,
-                    &write_entry,
-                    &timeout_entry,
                 )? {
                     let mut read = CoeRead::new();
                     let mut write = CoeWrite::new();
                     //NOTE: these are the only places that are allowed to have this identifier mask
 
                     read.start(
-                        maindevice,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
+                        ctx,
                         read_mbx,
                         configured_addr,
                         idx,
                         Some(1 | ((!0b11) & identifier.unwrap_or(0))),
-                        &write_entry,
-                        &timeout_entry,
                     )?;
 
-                    let bytes = self.req.pack();
-
                     write.start(
-                        maindevice,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
+                        ctx,
                         write_mbx,
                         configured_addr,
                         idx,
                         Some(2 | ((!0b11) & identifier.unwrap_or(0))),
-                        bytes.as_ref(),
-                        &write_entry,
-                        &timeout_entry,
+                        &mut self.req,
                     )?;
 
                     self.state = MbxWriteReadState::WriteRead { read, write };
@@ -156,36 +442,188 @@ impl<R: ethercrab::coe::services::CoeServiceRequest> MbxWriteRead<R> {
                     if let Some(bytes) = read.update(
                         received,
                         header,
-                        maindevice,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
+                        ctx,
                         read_mbx,
                         configured_addr,
                         identifier,
                         idx,
-                        &write_entry,
-                        &timeout_entry,
                     )? {
-                        let res = ethercrab::SubDevice::parse_coe_service_reponse(bytes, &self.req)
-                            .unwrap();
-
-                        return Ok(Some(res));
+                        return Ok(Some(bytes));
                     }
                 }
-                o => unreachable!("{o:?}"),
+                _ => return Err(Error::UnexpectedIdentifier(identifier)),
+            },
+            MbxWriteReadState::Write(tx) => match identifier.map(|id| id & 0b11) {
+                Some(1) => {
+                    if tx.update(
+                        received,
+                        header,
+                        ctx,
+                        write_mbx,
+                        configured_addr,
+                        identifier.unwrap_or(0),
+                        idx,
+                    )? {
+                        // same masking as the write in the write/read above
+                        let mut write = CoeWrite::new();
+                        write.start(
+                            ctx,
+                            write_mbx,
+                            configured_addr,
+                            idx,
+                            Some(2 | ((!0b11) & identifier.unwrap_or(0))),
+                            &mut self.req,
+                        )?;
+                        self.state = MbxWriteReadState::Written(write);
+                    }
+                }
+                _ => return Err(Error::UnexpectedIdentifier(identifier)),
+            },
+            MbxWriteReadState::Written(write) => match identifier.map(|id| id & 0b11) {
+                Some(2) => {
+                    write.update();
+                    return Ok(Some(received));
+                }
+                _ => return Err(Error::UnexpectedIdentifier(identifier)),
+            },
+            MbxWriteReadState::Read(read) => match identifier.map(|id| id & 0b11) {
+                Some(1) => {
+                    return read.update(
+                        received,
+                        header,
+                        ctx,
+                        read_mbx,
+                        configured_addr,
+                        identifier,
+                        idx,
+                    );
+                }
+                _ => return Err(Error::UnexpectedIdentifier(identifier)),
             },
         }
         Ok(None)
     }
 }
 
+// checks the read mailbox once, for messages that the subdevice sends without being asked
+#[derive(Debug)]
+pub(crate) enum MbxPoll {
+    Status,
+    Read,
+    Repeat(Repeat),
+}
+
+pub(crate) enum Polled<'p> {
+    Empty,
+    Message(ReceivedPdu<'p>),
+}
+
+impl MbxPoll {
+    pub(crate) fn new() -> Self {
+        Self::Status
+    }
+
+    pub(crate) fn start(
+        &mut self,
+        ctx: &mut IoCtx,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<(), Error> {
+        self.status(
+            ctx,
+            read_mbx,
+            configured_addr,
+            Some(1 | (identifier.unwrap_or(0) << 2)),
+            idx,
+        )
+    }
+
+    fn status(
+        &mut self,
+        ctx: &mut IoCtx,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<(), Error> {
+        let (frame, handle) = ctx
+            .maindevice
+            .prep_mailbox_sync_manager_status(configured_addr, read_mbx.sync_manager)?
+            .ok_or(Error::NoFrame)?;
+
+        *self = Self::Status;
+        setup_write(frame, handle, &mut ctx.tx, Some(idx), identifier)
+    }
+
+    pub(crate) fn update<'p>(
+        &mut self,
+        received: ReceivedPdu<'p>,
+        ctx: &mut IoCtx,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<Option<Polled<'p>>, Error> {
+        if identifier.map(|id| id & 0b11) != Some(1) {
+            return Err(Error::UnexpectedIdentifier(identifier));
+        }
+
+        match self {
+            Self::Status => {
+                use ethercrab::EtherCrabWireRead;
+                let status = ethercrab::sync_manager_channel::Status::unpack_from_slice(&received)?;
+
+                if !status.mailbox_full {
+                    return Ok(Some(Polled::Empty));
+                }
+
+                let (frame, handle) = unsafe {
+                    ctx.maindevice
+                        .prep_read(configured_addr, read_mbx.address, read_mbx.len)?
+                        .ok_or(Error::NoFrame)?
+                };
+                setup_write(frame, handle, &mut ctx.tx, Some(idx), identifier)?;
+
+                *self = Self::Read;
+                Ok(None)
+            }
+            Self::Read => {
+                // same as for the responses of a write / read
+                if received.working_counter == 0 {
+                    let repeat = Repeat::start(ctx, read_mbx, configured_addr, idx, identifier)?;
+                    *self = Self::Repeat(repeat);
+                    return Ok(None);
+                }
+
+                if ctx.mailbox_counters.duplicate(configured_addr, &received) {
+                    return Ok(Some(Polled::Empty));
+                }
+
+                if let Some(emergency) = Emergency::parse(&received) {
+                    ctx.emergencies.push(idx, emergency);
+                    return Ok(Some(Polled::Empty));
+                }
+                Ok(Some(Polled::Message(received)))
+            }
+            Self::Repeat(repeat) => {
+                if repeat.update(received, ctx, read_mbx, configured_addr, idx, identifier)? {
+                    self.status(ctx, read_mbx, configured_addr, identifier, idx)?;
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 enum MbxWriteReadState {
     MailboxFull(CoeMailboxState),
     WriteRead { write: CoeWrite, read: CoeRead },
+    Read(CoeRead),
+    Write(WriteMbxState),
+    Written(CoeWrite),
 }
 
 #[derive(Debug)]
@@ -202,50 +640,28 @@ impl CoeMailboxState {
         }
     }
 
-    #[allow(clippy::too_many_arguments)]
     fn start(
         &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         write_mbx: &ethercrab::Mailbox,
         read_mbx: &ethercrab::Mailbox,
         configured_addr: u16,
         identifier: Option<u8>,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<(), Error> {
         self.tx.start(
-            maindevice,
-            retry_count,
-            timeout,
-            tx_entries,
-            sock,
-            ring,
+            ctx,
             write_mbx,
             configured_addr,
             1 | (identifier.unwrap_or(0) << 2),
             idx,
-            &write_entry,
-            &timeout_entry,
         )?;
         self.rx.start(
-            maindevice,
-            retry_count,
-            timeout,
-            tx_entries,
-            sock,
-            ring,
+            ctx,
             read_mbx,
             configured_addr,
             2 | (identifier.unwrap_or(0) << 2),
             idx,
-            write_entry,
-            timeout_entry,
         )?;
         Ok(())
     }
@@ -255,19 +671,12 @@ impl CoeMailboxState {
         &mut self,
         received: ReceivedPdu<'_>,
         header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         write_mbx: &ethercrab::Mailbox,
         read_mbx: &ethercrab::Mailbox,
         configured_addr: u16,
         identifier: Option<u8>,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<bool, Error> {
         // get only the first 2 bits that are used in the read
         match identifier.map(|id| id & 0b11) {
@@ -275,18 +684,11 @@ impl CoeMailboxState {
                 if self.tx.update(
                     received,
                     header,
-                    maindevice,
-                    retry_count,
-                    timeout,
-                    tx_entries,
-                    sock,
-                    ring,
+                    ctx,
                     write_mbx,
                     configured_addr,
-                    1,
+                    identifier.unwrap_or(0),
                     idx,
-                    write_entry,
-                    timeout_entry,
                 )? && matches!(self.rx, ReadMbxState::Ready)
                 {
                     return Ok(true);
@@ -296,24 +698,17 @@ impl CoeMailboxState {
                 if self.rx.update(
                     received,
                     header,
-                    maindevice,
-                    retry_count,
-                    timeout,
-                    tx_entries,
-                    sock,
-                    ring,
+                    ctx,
                     read_mbx,
                     configured_addr,
-                    2,
+                    identifier.unwrap_or(0),
                     idx,
-                    write_entry,
-                    timeout_entry,
                 )? && matches!(self.tx, WriteMbxState::Ready)
                 {
                     return Ok(true);
                 }
             }
-            _ => unreachable!(),
+            _ => return Err(Error::UnexpectedIdentifier(identifier)),
         }
         Ok(false)
     }
@@ -330,43 +725,27 @@ impl CoeWrite {
         Self::Sent
     }
 
-    #[allow(clippy::too_many_arguments)]
     fn start(
         &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         write_mbx: &ethercrab::Mailbox,
         configured_addr: u16,
         idx: u16,
         identifier: Option<u8>,
-        bytes: &[u8],
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
+        bytes: &mut [u8],
     ) -> Result<(), Error> {
+        // whatever counter the message was built with is replaced by the one that is tracked.
+        // retries of this write resend the same frame, so they keep the counter
+        let counter = ctx.mailbox_counters.next(configured_addr);
+        crate::coe::set_counter(bytes, counter);
+
         let (frame, handle) = unsafe {
-            maindevice
-                .prep_write(configured_addr, write_mbx.address, write_mbx.len, bytes)
-                .unwrap()
-                .unwrap()
+            ctx.maindevice
+                .prep_write(configured_addr, write_mbx.address, write_mbx.len, bytes)?
+                .ok_or(Error::NoFrame)?
         };
 
-        setup_write(
-            frame,
-            handle,
-            retry_count,
-            timeout,
-            tx_entries,
-            sock,
-            ring,
-            Some(idx),
-            identifier,
-            write_entry,
-            timeout_entry,
-        )
+        setup_write(frame, handle, &mut ctx.tx, Some(idx), identifier)
     }
 
     fn update(&mut self) {
@@ -378,6 +757,8 @@ impl CoeWrite {
 enum CoeRead {
     Empty,
     Ready,
+    // the response was lost, waiting on the subdevice to put it back into the mailbox
+    Repeat(Repeat),
 }
 
 impl CoeRead {
@@ -385,39 +766,19 @@ impl CoeRead {
         Self::Empty
     }
 
-    #[allow(clippy::too_many_arguments)]
     fn start(
         &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         read_mbx: &ethercrab::Mailbox,
         configured_addr: u16,
         idx: u16,
         identifier: Option<u8>,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<(), Error> {
-        let (frame, handle) = maindevice
-            .prep_mailbox_sync_manager_status(configured_addr, read_mbx.sync_manager)
-            .unwrap()
-            .unwrap();
-        setup_write(
-            frame,
-            handle,
-            retry_count,
-            timeout,
-            tx_entries,
-            sock,
-            ring,
-            Some(idx),
-            identifier,
-            write_entry,
-            timeout_entry,
-        )
+        let (frame, handle) = ctx
+            .maindevice
+            .prep_mailbox_sync_manager_status(configured_addr, read_mbx.sync_manager)?
+            .ok_or(Error::NoFrame)?;
+        setup_write(frame, handle, &mut ctx.tx, Some(idx), identifier)
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -425,71 +786,185 @@ impl CoeRead {
         &mut self,
         received: ReceivedPdu<'p>,
         _header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         read_mbx: &ethercrab::Mailbox,
         configured_addr: u16,
         identifier: Option<u8>,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<Option<ReceivedPdu<'p>>, Error> {
         match self {
             Self::Empty => {
                 use ethercrab::EtherCrabWireRead;
-                let status =
-                    ethercrab::sync_manager_channel::Status::unpack_from_slice(&received).unwrap();
+                let status = ethercrab::sync_manager_channel::Status::unpack_from_slice(&received)?;
 
                 if !status.mailbox_full {
-                    self.start(
-                        maindevice,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
-                        read_mbx,
-                        configured_addr,
-                        idx,
-                        identifier,
-                        write_entry,
-                        timeout_entry,
-                    )?;
+                    // the read is started by op once the cyclic frame shows the mailbox full
+                    if ctx.mailbox_status.wait(idx, identifier) {
+                        *self = Self::Ready;
+                        return Ok(None);
+                    }
+
+                    self.start(ctx, read_mbx, configured_addr, idx, identifier)?;
                     return Ok(None);
                 }
 
                 let (frame, handle) = unsafe {
-                    maindevice
-                        .prep_read(configured_addr, read_mbx.address, read_mbx.len)
-                        .unwrap()
-                        .unwrap()
+                    ctx.maindevice
+                        .prep_read(configured_addr, read_mbx.address, read_mbx.len)?
+                        .ok_or(Error::NoFrame)?
                 };
-                setup_write(
-                    frame,
-                    handle,
-                    retry_count,
-                    timeout,
-                    tx_entries,
-                    sock,
-                    ring,
-                    Some(idx),
-                    identifier,
-                    write_entry,
-                    timeout_entry,
-                )?;
+                setup_write(frame, handle, &mut ctx.tx, Some(idx), identifier)?;
 
                 *self = Self::Ready;
             }
-            Self::Ready => return Ok(Some(received)),
+            Self::Ready => {
+                // the mailbox was emptied by a read whose response never made it back,
+                // the retry of it finds nothing
+                if received.working_counter == 0 {
+                    let repeat = Repeat::start(ctx, read_mbx, configured_addr, idx, identifier)?;
+                    *self = Self::Repeat(repeat);
+                    return Ok(None);
+                }
+
+                // a message that was already read, eg. when the subdevice repeated one that did
+                // make it back. it is dropped and the mailbox is checked again
+                if ctx.mailbox_counters.duplicate(configured_addr, &received) {
+                    *self = Self::Empty;
+                    self.start(ctx, read_mbx, configured_addr, idx, identifier)?;
+                    return Ok(None);
+                }
+
+                // emergencies can show up ahead of the response, so keep
+                // them for the user and wait for the mailbox to fill again
+                if let Some(emergency) = Emergency::parse(&received) {
+                    ctx.emergencies.push(idx, emergency);
+
+                    *self = Self::Empty;
+                    self.start(ctx, read_mbx, configured_addr, idx, identifier)?;
+                    return Ok(None);
+                }
+                return Ok(Some(received));
+            }
+            Self::Repeat(repeat) => {
+                if repeat.update(received, ctx, read_mbx, configured_addr, idx, identifier)? {
+                    *self = Self::Empty;
+                    self.start(ctx, read_mbx, configured_addr, idx, identifier)?;
+                }
+            }
         }
         Ok(None)
     }
 }
 
+// the status register of a sync manager, ETG.1000.4 0x0805
+pub(crate) const fn sm_status_register(sync_manager: u8) -> u16 {
+    0x0805 + (sync_manager as u16) * 8
+}
+
+const SM_MAILBOX_FULL: u8 = 1 << 3;
+
+// the activate and pdi control registers of a sync manager, ETG.1000.4 0x0806 and 0x0807
+const fn sm_activate_register(sync_manager: u8) -> u16 {
+    0x0806 + (sync_manager as u16) * 8
+}
+
+// repeat request in the activate register, and its acknowledge in the pdi control register
+const SM_REPEAT: u8 = 1 << 1;
+
+// how often the pdi control register is read before the repeat request is given up on
+const REPEAT_POLLS: u16 = 1000;
+
+// asks the subdevice to put the last message it sent back into the read mailbox, by toggling
+// the repeat request of the mailbox's sync manager and waiting for it to be acknowledged
+#[derive(Debug)]
+pub(crate) enum Repeat {
+    Activate,
+    // the toggled repeat request was written, holding whether it is now set
+    Request(bool),
+    // holding the same, and how often the acknowledge was polled so far
+    Ack(bool, u16),
+}
+
+impl Repeat {
+    fn start(
+        ctx: &mut IoCtx,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        idx: u16,
+        identifier: Option<u8>,
+    ) -> Result<Self, Error> {
+        Self::read_registers(ctx, read_mbx, configured_addr, idx, identifier)?;
+        Ok(Self::Activate)
+    }
+
+    fn read_registers(
+        ctx: &mut IoCtx,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        idx: u16,
+        identifier: Option<u8>,
+    ) -> Result<(), Error> {
+        let (frame, handle) = unsafe {
+            ctx.maindevice
+                .prep_read(
+                    configured_addr,
+                    sm_activate_register(read_mbx.sync_manager),
+                    2,
+                )?
+                .ok_or(Error::NoFrame)?
+        };
+        setup_write(frame, handle, &mut ctx.tx, Some(idx), identifier)
+    }
+
+    // returns true once the subdevice acknowledged the repeat request
+    fn update(
+        &mut self,
+        received: ReceivedPdu<'_>,
+        ctx: &mut IoCtx,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        idx: u16,
+        identifier: Option<u8>,
+    ) -> Result<bool, Error> {
+        match *self {
+            Self::Activate => {
+                let activate = *received.first().ok_or(Error::InvalidMailbox)?;
+                let toggled = activate ^ SM_REPEAT;
+
+                let (frame, handle) = unsafe {
+                    ctx.maindevice
+                        .prep_write(
+                            configured_addr,
+                            sm_activate_register(read_mbx.sync_manager),
+                            1,
+                            &[toggled],
+                        )?
+                        .ok_or(Error::NoFrame)?
+                };
+                setup_write(frame, handle, &mut ctx.tx, Some(idx), identifier)?;
+
+                *self = Self::Request(toggled & SM_REPEAT != 0);
+            }
+            Self::Request(repeat) => {
+                Self::read_registers(ctx, read_mbx, configured_addr, idx, identifier)?;
+                *self = Self::Ack(repeat, 0);
+            }
+            Self::Ack(repeat, polls) => {
+                let pdi_control = *received.get(1).ok_or(Error::InvalidMailbox)?;
+                if (pdi_control & SM_REPEAT != 0) == repeat {
+                    return Ok(true);
+                }
+                if polls >= REPEAT_POLLS {
+                    return Err(Error::MailboxRepeat(configured_addr));
+                }
+                Self::read_registers(ctx, read_mbx, configured_addr, idx, identifier)?;
+                *self = Self::Ack(repeat, polls + 1);
+            }
+        }
+        Ok(false)
+    }
+}
+
 #[derive(Debug)]
 enum WriteMbxState {
     Full,
@@ -501,39 +976,20 @@ impl WriteMbxState {
         Self::Full
     }
 
-    #[allow(clippy::too_many_arguments)]
     fn start(
         &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         write_mbx: &ethercrab::Mailbox,
         configured_addr: u16,
         identifier: u8,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<(), Error> {
-        let (frame, handle) = maindevice
+        let (frame, handle) = ctx
+            .maindevice
             .prep_mailbox_sync_manager_status(configured_addr, write_mbx.sync_manager)?
-            .unwrap();
-
-        setup_write(
-            frame,
-            handle,
-            retry_count,
-            timeout,
-            tx_entries,
-            sock,
-            ring,
-            Some(idx),
-            Some(identifier),
-            write_entry,
-            timeout_entry,
-        )
+            .ok_or(Error::NoFrame)?;
+
+        setup_write(frame, handle, &mut ctx.tx, Some(idx), Some(identifier))
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -541,47 +997,27 @@ impl WriteMbxState {
         &mut self,
         received: ReceivedPdu<'_>,
         _header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         write_mbx: &ethercrab::Mailbox,
         configured_addr: u16,
         identifier: u8,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<bool, Error> {
         match self {
             Self::Full => {
                 use ethercrab::EtherCrabWireRead;
                 let sm_status =
-                    ethercrab::sync_manager_channel::Status::unpack_from_slice(&received).unwrap();
+                    ethercrab::sync_manager_channel::Status::unpack_from_slice(&received)?;
 
                 if sm_status.mailbox_full {
-                    self.start(
-                        maindevice,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
-                        write_mbx,
-                        configured_addr,
-                        identifier,
-                        idx,
-                        write_entry,
-                        timeout_entry,
-                    )?;
+                    self.start(ctx, write_mbx, configured_addr, identifier, idx)?;
                     Ok(false)
                 } else {
                     *self = Self::Ready;
                     Ok(true)
                 }
             }
-            _ => unreachable!(),
+            Self::Ready => Ok(true),
         }
     }
 }
@@ -598,39 +1034,20 @@ impl ReadMbxState {
         Self::Full
     }
 
-    #[allow(clippy::too_many_arguments)]
     fn start(
         &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         read_mbx: &ethercrab::Mailbox,
         configured_addr: u16,
         identifier: u8,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<(), Error> {
-        let (frame, handle) = maindevice
+        let (frame, handle) = ctx
+            .maindevice
             .prep_mailbox_sync_manager_status(configured_addr, read_mbx.sync_manager)?
-            .unwrap();
-
-        setup_write(
-            frame,
-            handle,
-            retry_count,
-            timeout,
-            tx_entries,
-            sock,
-            ring,
-            Some(idx),
-            Some(identifier),
-            write_entry,
-            timeout_entry,
-        )
+            .ok_or(Error::NoFrame)?;
+
+        setup_write(frame, handle, &mut ctx.tx, Some(idx), Some(identifier))
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -638,24 +1055,17 @@ impl ReadMbxState {
         &mut self,
         received: ReceivedPdu<'_>,
         _header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         read_mbx: &ethercrab::Mailbox,
         configured_addr: u16,
         identifier: u8,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<bool, Error> {
         match self {
             Self::Full => {
                 use ethercrab::EtherCrabWireRead;
                 let sm_status =
-                    ethercrab::sync_manager_channel::Status::unpack_from_slice(&received).unwrap();
+                    ethercrab::sync_manager_channel::Status::unpack_from_slice(&received)?;
 
                 if !sm_status.mailbox_full {
                     *self = Self::Ready;
@@ -664,45 +1074,19 @@ impl ReadMbxState {
 
                 // need to flush whatever is in the rx mailbox of the device
                 let (frame, handle) = unsafe {
-                    maindevice
-                        .prep_read(configured_addr, read_mbx.address, read_mbx.len)
-                        .unwrap()
-                        .unwrap()
+                    ctx.maindevice
+                        .prep_read(configured_addr, read_mbx.address, read_mbx.len)?
+                        .ok_or(Error::NoFrame)?
                 };
 
-                setup_write(
-                    frame,
-                    handle,
-                    retry_count,
-                    timeout,
-                    tx_entries,
-                    sock,
-                    ring,
-                    Some(idx),
-                    Some(identifier),
-                    write_entry,
-                    timeout_entry,
-                )?;
+                setup_write(frame, handle, &mut ctx.tx, Some(idx), Some(identifier))?;
                 *self = Self::Flush;
             }
             Self::Flush => {
-                self.start(
-                    maindevice,
-                    retry_count,
-                    timeout,
-                    tx_entries,
-                    sock,
-                    ring,
-                    read_mbx,
-                    configured_addr,
-                    identifier,
-                    idx,
-                    write_entry,
-                    timeout_entry,
-                )?;
+                self.start(ctx, read_mbx, configured_addr, identifier, idx)?;
                 *self = Self::Full;
             }
-            _ => unreachable!(),
+            Self::Ready => return Ok(true),
         }
         Ok(false)
     }
@@ -1,11 +1,8 @@
+use crate::concurrent::Concurrent;
+use crate::error::Error;
+use crate::io::IoCtx;
 use crate::setup::setup_write;
-use crate::txbuf::TxBuf;
-use ethercrab::{
-    EtherCrabWireSized, MainDevice, PduHeader, SubDevice, error::Error,
-    received_frame::ReceivedPdu, std::RawSocketDesc,
-};
-use io_uring::{IoUring, types::Timespec};
-use std::collections::BTreeMap;
+use ethercrab::{EtherCrabWireSized, PduHeader, SubDevice, received_frame::ReceivedPdu};
 
 use crate::eeprom::{category::CategoryIter, range::RangeReader};
 
@@ -18,21 +15,14 @@ use heapless::Deque;
 // configures the mailboxes on all of the ecat slaves to later setup fmmus and sync managers
 pub struct MailboxConfig<const N: usize> {
     subdevices: Deque<(SubDevice, MailboxConfigState), N>,
-    transition_idx: u16,
+    concurrent: Concurrent,
 }
 
 impl<const N: usize> MailboxConfig<N> {
-    #[allow(clippy::too_many_arguments)]
     pub(crate) fn start_new(
         subdevs: Deque<SubDevice, N>,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
+        ctx: &mut IoCtx,
+        concurrency: u16,
     ) -> Result<Self, Error> {
         let mut devs = heapless::Deque::new();
 
@@ -41,83 +31,59 @@ impl<const N: usize> MailboxConfig<N> {
             let _ = devs.push_back((subdev, state));
         }
 
-        let (subdev, state) = devs.front_mut().unwrap();
-
-        state.start(
-            maindevice,
-            retry_count,
-            timeout_duration,
-            tx_entries,
-            sock,
-            ring,
-            subdev.configured_address(),
-            0,
-            write_entry,
-            timeout_entry,
-        )?;
+        if devs.is_empty() {
+            return Err(Error::NoSubDevices);
+        }
+
+        let (concurrent, start) = Concurrent::new(devs.len() as _, concurrency);
+        for idx in start {
+            let (subdev, state) = devs
+                .get_mut(usize::from(idx))
+                .ok_or(Error::UnknownDevice(idx))?;
+            state.start(ctx, subdev.configured_address(), idx, None)?;
+        }
 
         Ok(Self {
             subdevices: devs,
-            transition_idx: 0,
+            concurrent,
         })
     }
 
-    #[allow(clippy::too_many_arguments)]
     pub(crate) fn update(
         &mut self,
         received: ReceivedPdu<'_>,
         header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         identifier: Option<u8>,
         idx: Option<u16>,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<Option<Deque<(SubDevice, MailboxConfigState), N>>, Error> {
-        let idx = idx.unwrap() as usize;
-        let (dev, state) = self.subdevices.get_mut(idx).unwrap();
+        let idx = idx.ok_or(Error::MissingIndex)? as usize;
+        let (dev, state) = self
+            .subdevices
+            .get_mut(idx)
+            .ok_or(Error::UnknownDevice(idx as _))?;
 
         if state.update(
             received,
             header,
-            maindevice,
-            retry_count,
-            timeout_duration,
-            tx_entries,
-            sock,
-            ring,
+            ctx,
             dev.configured_address(),
             idx as u16,
             dev,
             identifier,
-            &write_entry,
-            &timeout_entry,
         )? {
-            self.transition_idx += 1;
-
-            if usize::from(self.transition_idx) != self.subdevices.len() {
-                let (subdev, state) = self.subdevices.get_mut(self.transition_idx as _).unwrap();
-
-                state.start(
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    subdev.configured_address(),
-                    self.transition_idx,
-                    &write_entry,
-                    &timeout_entry,
-                )?;
-
-                return Ok(None);
+            if let Some(next) = self.concurrent.finish() {
+                let (subdev, state) = self
+                    .subdevices
+                    .get_mut(usize::from(next))
+                    .ok_or(Error::UnknownDevice(next))?;
+
+                state.start(ctx, subdev.configured_address(), next, None)?;
+            }
+
+            if self.concurrent.done() {
+                return Ok(Some(core::mem::take(&mut self.subdevices)));
             }
-            return Ok(Some(core::mem::take(&mut self.subdevices)));
         }
         Ok(None)
     }
@@ -141,102 +107,57 @@ pub(crate) enum MailboxConfigState {
 }
 
 impl MailboxConfigState {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self::SetEepromMaster
     }
 
-    #[allow(clippy::too_many_arguments)]
-    fn start(
+    // everything up to the pdi getting the eeprom back is sent with `identifier`, eg. when a
+    // single subdevice is restarted while the rest of the bus is in op
+    pub(crate) fn start(
         &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         configured_addr: u16,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
+        identifier: Option<u8>,
     ) -> Result<(), Error> {
-        let (frame, handle) = maindevice
-            .prep_set_eeprom(configured_addr, ethercrab::SiiOwner::Master)
-            .unwrap()
-            .unwrap();
-
-        setup_write(
-            frame,
-            handle,
-            retry_count,
-            timeout_duration,
-            tx_entries,
-            sock,
-            ring,
-            Some(idx),
-            None,
-            write_entry,
-            timeout_entry,
-        )
+        let (frame, handle) = ctx
+            .maindevice
+            .prep_set_eeprom(configured_addr, ethercrab::SiiOwner::Master)?
+            .ok_or(Error::NoFrame)?;
+
+        setup_write(frame, handle, &mut ctx.tx, Some(idx), identifier)
     }
 
     #[allow(clippy::too_many_arguments)]
-    fn update(
+    pub(crate) fn update(
         &mut self,
         received: ReceivedPdu<'_>,
         header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         configured_addr: u16,
         idx: u16,
         subdev: &mut ethercrab::SubDevice,
         identifier: Option<u8>,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<bool, Error> {
         match self {
             Self::SetEepromMaster => {
-                let mut state = CategoryIter::new(ethercrab::CategoryType::SyncManager, 0);
-                state.start(
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    configured_addr,
-                    idx,
-                    &write_entry,
-                    &timeout_entry,
-                )?;
+                let mut state = CategoryIter::new(
+                    ethercrab::CategoryType::SyncManager,
+                    identifier.unwrap_or(0),
+                );
+                state.start(ctx, configured_addr, idx)?;
 
                 *self = Self::SyncManagers(state, Default::default());
             }
             Self::SyncManagers(managers, collected) => {
-                if let Some(more) = managers.update(
-                    received,
-                    header,
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    configured_addr,
-                    idx,
-                    &write_entry,
-                    &timeout_entry,
-                )? {
+                if let Some(more) = managers.update(received, header, ctx, configured_addr, idx)? {
                     if let Some(buf) = managers.buffer() {
                         use ethercrab::EtherCrabWireRead;
-                        let mgr = ethercrab::SyncManager::unpack_from_slice(buf).unwrap();
+                        let mgr = ethercrab::SyncManager::unpack_from_slice(buf)?;
 
                         let _ = collected.push(mgr);
                     } else {
-                        panic!("could not find sync manager")
+                        return Err(Error::Sii);
                     }
 
                     if !more {
@@ -244,75 +165,29 @@ impl MailboxConfigState {
                             0x0018,
                             ethercrab::DefaultMailbox::PACKED_LEN as _,
                             Default::default(),
-                            0,
+                            identifier.unwrap_or(0),
                         );
 
-                        mbx_config.start(
-                            maindevice,
-                            retry_count,
-                            timeout_duration,
-                            tx_entries,
-                            sock,
-                            ring,
-                            configured_addr,
-                            idx,
-                            &write_entry,
-                            &timeout_entry,
-                        )?;
+                        mbx_config.start(ctx, configured_addr, idx)?;
 
                         *self = Self::GetMailboxConfig(core::mem::take(collected), mbx_config);
                     }
                 }
             }
             Self::GetMailboxConfig(sync_managers, config) => {
-                if config.update(
-                    received,
-                    header,
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    configured_addr,
-                    idx,
-                    &write_entry,
-                    &timeout_entry,
-                )? {
+                if config.update(received, header, ctx, configured_addr, idx)? {
                     use ethercrab::EtherCrabWireRead;
                     let cfg = ethercrab::DefaultMailbox::unpack_from_slice(&config.buffer)?;
 
                     let mut mbx_cfg =
                         SyncManagerMbxConfig::new(core::mem::take(sync_managers), cfg);
-                    mbx_cfg.update(
-                        maindevice,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
-                        configured_addr,
-                        idx,
-                        &write_entry,
-                        &timeout_entry,
-                    )?;
+                    mbx_cfg.update(ctx, configured_addr, idx, identifier)?;
 
                     *self = Self::ConfigureMailboxSms(mbx_cfg);
                 }
             }
             Self::ConfigureMailboxSms(cfg) => {
-                if cfg.update(
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    configured_addr,
-                    idx,
-                    &write_entry,
-                    &timeout_entry,
-                )? {
+                if cfg.update(ctx, configured_addr, idx, identifier)? {
                     let read_mbx = core::mem::take(&mut cfg.read_mbx);
                     let write_mbx = core::mem::take(&mut cfg.write_mbx);
 
@@ -327,128 +202,54 @@ impl MailboxConfigState {
 
                     subdev.config.mailbox.supported_protocols = cfg.default_mbx.supported_protocols;
 
-                    let (frame, handle) = maindevice
-                        .prep_set_eeprom(configured_addr, ethercrab::SiiOwner::Pdi)
-                        .unwrap()
-                        .unwrap();
-
-                    setup_write(
-                        frame,
-                        handle,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
-                        Some(idx),
-                        None,
-                        &write_entry,
-                        &timeout_entry,
-                    )?;
+                    let (frame, handle) = ctx
+                        .maindevice
+                        .prep_set_eeprom(configured_addr, ethercrab::SiiOwner::Pdi)?
+                        .ok_or(Error::NoFrame)?;
+
+                    setup_write(frame, handle, &mut ctx.tx, Some(idx), identifier)?;
 
                     *self = Self::SetEepromPdi;
                 }
             }
             Self::SetEepromPdi => {
                 let mut state = Transition::new(ethercrab::SubDeviceState::PreOp);
-                state.start(
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    configured_addr,
-                    idx,
-                    &write_entry,
-                    &timeout_entry,
-                )?;
+                state.start(ctx, configured_addr, idx)?;
                 *self = Self::PreOpTransition(state);
             }
             Self::PreOpTransition(transition) => {
-                if transition.update(
-                    received,
-                    header,
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    configured_addr,
-                    idx,
-                    &write_entry,
-                    &timeout_entry,
-                )? {
-                    // onto setting up stuff for coe
-                    if !subdev.config.mailbox.complete_access {
-                        todo!("support for non complete access devices");
-                    }
+                if transition.update(received, header, ctx, configured_addr, idx)? {
+                    // onto setting up stuff for coe, devices without complete access
+                    // get the sync manager types read one subindex at a time
+                    let mut sdo_read =
+                        SdoRead::complete(subdev, ethercrab::sync_manager_channel::SM_TYPE_ADDRESS);
 
-                    let mbx_count = subdev.mailbox_counter();
-                    let mut sdo_read = SdoRead::new(
-                        mbx_count,
-                        ethercrab::sync_manager_channel::SM_TYPE_ADDRESS,
-                        ethercrab::SubIndex::Complete,
-                    );
-
-                    sdo_read.start(
-                        maindevice,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
-                        &subdev.config.mailbox.write.unwrap(),
-                        &subdev.config.mailbox.read.unwrap(),
-                        configured_addr,
-                        identifier,
-                        idx,
-                        &write_entry,
-                        &timeout_entry,
-                    )?;
+                    let (write_mbx, read_mbx) = crate::mbx::mailboxes(subdev)?;
+                    sdo_read.start(ctx, &write_mbx, &read_mbx, configured_addr, identifier, idx)?;
 
                     *self = Self::CoeSyncManagers(sdo_read);
                 }
             }
             Self::CoeSyncManagers(s) => {
+                let (write_mbx, read_mbx) = crate::mbx::mailboxes(subdev)?;
                 if let Some(mgrs) = s.update(
                     received,
                     header,
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    &subdev.config.mailbox.write.unwrap(),
-                    &subdev.config.mailbox.read.unwrap(),
+                    ctx,
+                    &write_mbx,
+                    &read_mbx,
                     configured_addr,
                     identifier,
                     idx,
-                    &write_entry,
-                    &timeout_entry,
                 )? {
                     subdev.config.mailbox.coe_sync_manager_types = mgrs;
 
-                    let (frame, handle) = maindevice
-                        .prep_set_eeprom(configured_addr, ethercrab::SiiOwner::Master)
-                        .unwrap()
-                        .unwrap();
-
-                    setup_write(
-                        frame,
-                        handle,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
-                        Some(idx),
-                        None,
-                        &write_entry,
-                        &timeout_entry,
-                    )?;
+                    let (frame, handle) = ctx
+                        .maindevice
+                        .prep_set_eeprom(configured_addr, ethercrab::SiiOwner::Master)?
+                        .ok_or(Error::NoFrame)?;
+
+                    setup_write(frame, handle, &mut ctx.tx, Some(idx), None)?;
 
                     *self = Self::ResetEepromMaster;
                 }
@@ -482,47 +283,28 @@ impl<const N: usize> SyncManagerMbxConfig<N> {
         }
     }
 
-    #[allow(clippy::too_many_arguments)]
     fn update(
         &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         configured_addr: u16,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
+        identifier: Option<u8>,
     ) -> Result<bool, Error> {
         for (sm_idx, sync_manager) in self.iter.by_ref() {
             use ethercrab::SyncManagerType;
             match sync_manager.usage_type() {
                 SyncManagerType::MailboxWrite => {
-                    let ((frame, handle), _) = maindevice
+                    let ((frame, handle), _) = ctx
+                        .maindevice
                         .prep_write_sm_config(
                             configured_addr,
                             sm_idx as u8,
                             &sync_manager,
                             self.default_mbx.subdevice_receive_size,
-                        )
-                        .unwrap()
-                        .unwrap();
-
-                    setup_write(
-                        frame,
-                        handle,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
-                        Some(idx),
-                        None,
-                        write_entry,
-                        timeout_entry,
-                    )?;
+                        )?
+                        .ok_or(Error::NoFrame)?;
+
+                    setup_write(frame, handle, &mut ctx.tx, Some(idx), identifier)?;
 
                     self.write_mbx = Some(ethercrab::Mailbox {
                         address: sync_manager.start_addr,
@@ -532,28 +314,16 @@ impl<const N: usize> SyncManagerMbxConfig<N> {
                     return Ok(false);
                 }
                 SyncManagerType::MailboxRead => {
-                    let ((frame, handle), _) = maindevice
+                    let ((frame, handle), _) = ctx
+                        .maindevice
                         .prep_write_sm_config(
                             configured_addr,
                             sm_idx as u8,
                             &sync_manager,
                             self.default_mbx.subdevice_send_size,
-                        )
-                        .unwrap()
-                        .unwrap();
-                    setup_write(
-                        frame,
-                        handle,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
-                        Some(idx),
-                        None,
-                        write_entry,
-                        timeout_entry,
-                    )?;
+                        )?
+                        .ok_or(Error::NoFrame)?;
+                    setup_write(frame, handle, &mut ctx.tx, Some(idx), identifier)?;
 
                     self.read_mbx = Some(ethercrab::Mailbox {
                         address: sync_manager.start_addr,
@@ -0,0 +1,396 @@
+use crate::error::Error;
+use crate::io::IoCtx;
+use crate::sdo_info::SdoInfoResponse;
+use ethercrab::{Mailbox, PduHeader, SubDevice, received_frame::ReceivedPdu};
+
+use std::collections::VecDeque;
+
+// the identifiers that the transfers of a queue are started with, see `MailboxQueue::owns`.
+// each transfer takes the next one, so that late responses to a transfer that failed are not
+// taken for the one after it
+const QUEUE_IDENTIFIERS: core::ops::RangeInclusive<u8> = 0x38..=0x3F;
+
+// anything that is sent and received through a subdevice's mailbox, eg. `SdoRead` or `FoeWrite`
+pub trait MailboxTransfer {
+    fn start(
+        &mut self,
+        ctx: &mut IoCtx,
+        write_mbx: &Mailbox,
+        read_mbx: &Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<(), Error>;
+
+    // returns true once the transfer is done
+    #[allow(clippy::too_many_arguments)]
+    fn update(
+        &mut self,
+        received: ReceivedPdu<'_>,
+        header: PduHeader,
+        ctx: &mut IoCtx,
+        write_mbx: &Mailbox,
+        read_mbx: &Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<bool, Error>;
+
+    // what the transfer got back, only meaningful once it is done
+    fn response(&self) -> MailboxResponse<'_>;
+}
+
+// implements `MailboxTransfer` for a transfer by forwarding to its own `start` and to `$update`,
+// `$done` tells from what `$update` returned whether the transfer is done
+macro_rules! mailbox_transfer {
+    (
+        impl[$($generics:tt)*] for $ty:ty,
+        |$res:ident| $update:ident => $done:expr,
+        |$this:pat_param| $response:expr $(,)?
+    ) => {
+        impl<$($generics)*> crate::mbx_queue::MailboxTransfer for $ty {
+            fn start(
+                &mut self,
+                ctx: &mut crate::io::IoCtx,
+                write_mbx: &ethercrab::Mailbox,
+                read_mbx: &ethercrab::Mailbox,
+                configured_addr: u16,
+                identifier: Option<u8>,
+                idx: u16,
+            ) -> Result<(), crate::error::Error> {
+                <$ty>::start(
+                    self,
+                    ctx,
+                    write_mbx,
+                    read_mbx,
+                    configured_addr,
+                    identifier,
+                    idx,
+                )
+            }
+
+            fn update(
+                &mut self,
+                received: ethercrab::received_frame::ReceivedPdu<'_>,
+                header: ethercrab::PduHeader,
+                ctx: &mut crate::io::IoCtx,
+                write_mbx: &ethercrab::Mailbox,
+                read_mbx: &ethercrab::Mailbox,
+                configured_addr: u16,
+                identifier: Option<u8>,
+                idx: u16,
+            ) -> Result<bool, crate::error::Error> {
+                let $res = <$ty>::$update(
+                    self,
+                    received,
+                    header,
+                    ctx,
+                    write_mbx,
+                    read_mbx,
+                    configured_addr,
+                    identifier,
+                    idx,
+                )?;
+                Ok($done)
+            }
+
+            fn response(&self) -> crate::mbx_queue::MailboxResponse<'_> {
+                let $this = self;
+                $response
+            }
+        }
+    };
+}
+pub(crate) use mailbox_transfer;
+
+#[derive(Debug)]
+pub enum MailboxResponse<'a> {
+    // writes, which only get an acknowledgement
+    Done,
+    // the raw data of reads, eg. an sdo upload or a file
+    Data(&'a [u8]),
+    Info(SdoInfoResponse<'a>),
+}
+
+// a transfer of the queue that is done, either with its response or with what went wrong
+#[derive(Debug)]
+pub struct MailboxCompletion<'a> {
+    pub id: u32,
+    pub result: Result<MailboxResponse<'a>, Error>,
+}
+
+// queues up transfers for a single subdevice and runs them on its mailbox one after another,
+// so that eg. a batch of parameter writes can be pushed at once
+pub struct MailboxQueue {
+    write_mbx: Mailbox,
+    read_mbx: Mailbox,
+    configured_addr: u16,
+    // the identifier of the transfer in flight, or of the last one
+    identifier: u8,
+    next_id: u32,
+    pending: VecDeque<(u32, Box<dyn MailboxTransfer>)>,
+    current: Option<(u32, Box<dyn MailboxTransfer>)>,
+    // kept around until the next update, the completion borrows its response
+    finished: Option<(u32, Box<dyn MailboxTransfer>)>,
+}
+
+impl MailboxQueue {
+    pub fn new(subdev: &SubDevice) -> Result<Self, Error> {
+        let (write_mbx, read_mbx) = crate::mbx::mailboxes(subdev)?;
+        Ok(Self::with_mailboxes(
+            write_mbx,
+            read_mbx,
+            subdev.configured_address(),
+        ))
+    }
+
+    fn with_mailboxes(write_mbx: Mailbox, read_mbx: Mailbox, configured_addr: u16) -> Self {
+        Self {
+            write_mbx,
+            read_mbx,
+            configured_addr,
+            identifier: *QUEUE_IDENTIFIERS.end(),
+            next_id: 0,
+            pending: VecDeque::new(),
+            current: None,
+            finished: None,
+        }
+    }
+
+    // queues `transfer` and returns the id its completion is reported with
+    pub fn push(&mut self, transfer: impl MailboxTransfer + 'static) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.pending.push_back((id, Box::new(transfer)));
+        id
+    }
+
+    // queues all of `transfers` in order, returning the range of their ids
+    pub fn extend<T: MailboxTransfer + 'static>(
+        &mut self,
+        transfers: impl IntoIterator<Item = T>,
+    ) -> core::ops::Range<u32> {
+        let start = self.next_id;
+        for transfer in transfers {
+            self.push(transfer);
+        }
+        start..self.next_id
+    }
+
+    // the transfers that were not completed yet, including the one in flight
+    pub fn len(&self) -> usize {
+        self.pending.len() + usize::from(self.current.is_some())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // whether a response belongs to one of the queue's transfers, see `RESERVED_IDENTIFIERS`
+    pub fn owns(&self, identifier: Option<u8>) -> bool {
+        identifier.is_some_and(|id| QUEUE_IDENTIFIERS.contains(&(id >> 2)))
+    }
+
+    // starts the next transfer if none is in flight, eg. on every pdi
+    pub fn poll(&mut self, ctx: &mut IoCtx, idx: u16) -> Result<(), Error> {
+        if self.current.is_some() {
+            return Ok(());
+        }
+
+        let Some((id, mut transfer)) = self.take_next() else {
+            return Ok(());
+        };
+
+        transfer.start(
+            ctx,
+            &self.write_mbx,
+            &self.read_mbx,
+            self.configured_addr,
+            Some(self.identifier),
+            idx,
+        )?;
+        self.current = Some((id, transfer));
+        Ok(())
+    }
+
+    // takes the next pending transfer along with the identifier it is started with
+    fn take_next(&mut self) -> Option<(u32, Box<dyn MailboxTransfer>)> {
+        let next = self.pending.pop_front()?;
+        self.identifier = if self.identifier == *QUEUE_IDENTIFIERS.end() {
+            *QUEUE_IDENTIFIERS.start()
+        } else {
+            self.identifier + 1
+        };
+        Some(next)
+    }
+
+    // fails the transfer in flight once one of its pdus ran out of retries and starts the next
+    // one, the transfer cannot pick up where it stopped. op calls this for the queue that
+    // `UserDevice::mailbox_queue` hands out, other queues have to be timed out by the user
+    pub fn timeout(
+        &mut self,
+        ctx: &mut IoCtx,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<Option<MailboxCompletion<'static>>, Error> {
+        let completion = self.fail_current(identifier, idx);
+        if completion.is_some() {
+            self.poll(ctx, idx)?;
+        }
+        Ok(completion)
+    }
+
+    fn fail_current(
+        &mut self,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Option<MailboxCompletion<'static>> {
+        // left over from an earlier transfer
+        if identifier.map(|id| id >> 2) != Some(self.identifier) {
+            return None;
+        }
+
+        let (id, _) = self.current.take()?;
+        Some(MailboxCompletion {
+            id,
+            result: Err(Error::Timeout {
+                idx: Some(idx),
+                identifier,
+            }),
+        })
+    }
+
+    // feeds a response to the transfer in flight, once it is done the next one is started
+    pub fn update(
+        &mut self,
+        received: ReceivedPdu<'_>,
+        header: PduHeader,
+        ctx: &mut IoCtx,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<Option<MailboxCompletion<'_>>, Error> {
+        let Some((_, transfer)) = &mut self.current else {
+            return Ok(None);
+        };
+        // left over from an earlier transfer
+        if identifier.map(|id| id >> 2) != Some(self.identifier) {
+            return Ok(None);
+        }
+
+        let res = transfer.update(
+            received,
+            header,
+            ctx,
+            &self.write_mbx,
+            &self.read_mbx,
+            self.configured_addr,
+            identifier,
+            idx,
+        );
+        if let Ok(false) = res {
+            return Ok(None);
+        }
+
+        self.finished = self.current.take();
+        self.poll(ctx, idx)?;
+
+        let Some((id, transfer)) = &self.finished else {
+            return Ok(None);
+        };
+
+        Ok(Some(MailboxCompletion {
+            id: *id,
+            result: res.map(|_| transfer.response()),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // never gets a response, it is only ever timed out
+    struct Silent;
+
+    impl MailboxTransfer for Silent {
+        fn start(
+            &mut self,
+            _ctx: &mut IoCtx,
+            _write_mbx: &Mailbox,
+            _read_mbx: &Mailbox,
+            _configured_addr: u16,
+            _identifier: Option<u8>,
+            _idx: u16,
+        ) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn update(
+            &mut self,
+            _received: ReceivedPdu<'_>,
+            _header: PduHeader,
+            _ctx: &mut IoCtx,
+            _write_mbx: &Mailbox,
+            _read_mbx: &Mailbox,
+            _configured_addr: u16,
+            _identifier: Option<u8>,
+            _idx: u16,
+        ) -> Result<bool, Error> {
+            Ok(false)
+        }
+
+        fn response(&self) -> MailboxResponse<'_> {
+            MailboxResponse::Done
+        }
+    }
+
+    fn queue() -> MailboxQueue {
+        let mailbox = |address, sync_manager| Mailbox {
+            address,
+            len: 128,
+            sync_manager,
+        };
+        MailboxQueue::with_mailboxes(mailbox(0x1000, 0), mailbox(0x1080, 1), 0x1001)
+    }
+
+    // what `poll` does, without sending anything
+    fn start_next(queue: &mut MailboxQueue) -> u8 {
+        let next = queue.take_next().unwrap();
+        queue.current = Some(next);
+        queue.identifier
+    }
+
+    #[test]
+    fn timeout_then_next() {
+        let mut queue = queue();
+        assert_eq!(queue.extend([Silent, Silent]), 0..2);
+
+        let first = start_next(&mut queue);
+        // the read half of the mailbox exchange, as `MbxWriteRead` tags it
+        let identifier = Some(2 | (first << 2));
+        assert!(queue.owns(identifier));
+
+        let completion = queue.fail_current(identifier, 3).unwrap();
+        assert_eq!(completion.id, 0);
+        assert!(matches!(
+            completion.result,
+            Err(Error::Timeout {
+                idx: Some(3),
+                identifier: Some(_)
+            })
+        ));
+        assert!(queue.current.is_none());
+        assert_eq!(queue.len(), 1);
+
+        let second = start_next(&mut queue);
+        assert_ne!(first, second);
+
+        // a late timeout of the first transfer leaves the second one alone
+        assert!(queue.fail_current(identifier, 3).is_none());
+        assert_eq!(queue.current.as_ref().map(|(id, _)| *id), Some(1));
+
+        let completion = queue.fail_current(Some(1 | (second << 2)), 3).unwrap();
+        assert_eq!(completion.id, 1);
+        assert!(queue.is_empty());
+    }
+}
@@ -1,72 +1,63 @@
-use crate::txbuf::TxBuf;
-use ethercrab::{MainDevice, PduHeader, error::Error, received_frame::ReceivedPdu};
-use io_uring::IoUring;
-use std::collections::BTreeMap;
+use crate::error::Error;
+use crate::io::IoCtx;
+use ethercrab::{PduHeader, SubDeviceState, received_frame::ReceivedPdu};
+
+use crate::device_change::{DeviceChange, Then};
+use crate::pdo::PdoConfig;
+use crate::user::ControlFlow;
 
 use heapless::Deque;
 
-pub struct Op<const N: usize, U> {
-    subdevices: Deque<U, N>,
+pub struct Op<'a, const N: usize, const I: usize, const O: usize, U> {
+    // along with the state each subdevice is in and the change that is being made to it, if any
+    subdevices: Deque<
+        (
+            U,
+            &'a PdoConfig<'a, I, O>,
+            SubDeviceState,
+            Option<DeviceChange<'a>>,
+        ),
+        N,
+    >,
 }
 
-impl<const N: usize, U: crate::user::UserDevice> Op<N, U> {
-    #[allow(clippy::too_many_arguments)]
+impl<'a, const N: usize, const I: usize, const O: usize, U: crate::user::UserDevice>
+    Op<'a, N, I, O, U>
+{
     pub(crate) fn start_new<S>(
-        subdevs: Deque<(U, S), N>,
-        maindevice: &mut MainDevice,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        ring: &mut IoUring,
+        subdevs: Deque<(U, &'a PdoConfig<'a, I, O>, S, Option<usize>), N>,
+        ctx: &mut IoCtx,
         mut user_cb: impl FnMut(
-            &mut MainDevice,
+            &mut IoCtx,
             &mut U,
             Option<DeviceResponse<'_, '_>>,
-            &mut BTreeMap<u64, TxBuf>,
-            &mut IoUring,
             u16,
             Option<u8>,
             &mut [u8],
         ) -> std::io::Result<Option<crate::user::ControlFlow>>,
         output_buf: &mut [u8],
-        retry_count: usize,
-        timeout: &io_uring::types::Timespec,
-        sock: &ethercrab::std::RawSocketDesc,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<Self, Error> {
         let mut subdevices = Deque::new();
-        for (id, (mut subdev, _)) in subdevs.into_iter().enumerate() {
+        for (id, (mut subdev, config, _, mailbox_status)) in subdevs.into_iter().enumerate() {
+            if let (Some(offset), Some(read_mbx)) =
+                (mailbox_status, subdev.subdevice().config.mailbox.read)
+            {
+                let configured_addr = subdev.subdevice().configured_address();
+                ctx.mailbox_status
+                    .insert(id as _, offset, configured_addr, read_mbx);
+            }
+
             let buf_range = subdev.subdevice().config.io.output.bytes.clone();
             let user_output_buf = &mut output_buf[buf_range];
 
-            user_cb(
-                maindevice,
-                &mut subdev,
-                None,
-                tx_entries,
-                ring,
-                id as _,
-                None,
-                user_output_buf,
-            )
-            .map_err(|_| Error::Internal)?;
-            let _ = subdevices.push_back(subdev);
+            user_cb(ctx, &mut subdev, None, id as _, None, user_output_buf)?;
+            let _ = subdevices.push_back((subdev, config, SubDeviceState::Op, None));
         }
 
-        let (frame, handle) = unsafe { maindevice.prep_rx_tx(0, output_buf) }?.unwrap();
-
-        crate::setup::setup_write(
-            frame,
-            handle,
-            retry_count,
-            timeout,
-            tx_entries,
-            sock,
-            ring,
-            Some(0),
-            None,
-            write_entry,
-            timeout_entry,
-        )?;
+        let (frame, handle) =
+            unsafe { ctx.maindevice.prep_rx_tx(0, output_buf) }?.ok_or(Error::NoFrame)?;
+
+        crate::setup::setup_write(frame, handle, &mut ctx.tx, Some(0), None)?;
 
         Ok(Self { subdevices })
     }
@@ -76,40 +67,41 @@ impl<const N: usize, U: crate::user::UserDevice> Op<N, U> {
         &mut self,
         received: ReceivedPdu<'_>,
         header: PduHeader,
-        maindevice: &mut MainDevice,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         identifier: Option<u8>,
         idx: Option<u16>,
         mut user_cb: impl FnMut(
-            &mut MainDevice,
+            &mut IoCtx,
             &mut U,
             Option<crate::op::DeviceResponse<'_, '_>>,
-            &mut BTreeMap<u64, TxBuf>,
-            &mut IoUring,
             u16,
             Option<u8>,
             &mut [u8],
         ) -> std::io::Result<Option<crate::user::ControlFlow>>,
         input_end: usize,
         transmission_buf: &mut [u8],
-        retry_count: usize,
-        timeout: &io_uring::types::Timespec,
-        sock: &ethercrab::std::RawSocketDesc,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
-    ) -> Result<Option<crate::user::ControlFlow>, Error> {
-        let idx = idx.unwrap() as usize;
+    ) -> Result<Option<ControlFlow>, Error> {
+        if idx == Some(crate::gateway::GATEWAY_IDX) {
+            crate::gateway::update(received, header, ctx, identifier)?;
+            return Ok(None);
+        }
+
+        let idx = idx.ok_or(Error::MissingIndex)? as usize;
 
         if header.command_code == 12 {
             let received_bytes = &received[..];
             (transmission_buf[..received.len()]).copy_from_slice(received_bytes);
 
+            ctx.mailbox_status.update(transmission_buf);
+
             let (input_buf, output_buf) = transmission_buf.split_at_mut(input_end);
 
             let mut ctrl_flow = None;
 
-            for (id, subdev) in self.subdevices.iter_mut().enumerate() {
+            for id in 0..self.subdevices.len() {
+                let Some((subdev, ..)) = self.subdevices.get(id) else {
+                    continue;
+                };
                 let output_buf_range = subdev.subdevice().config.io.output.bytes.clone();
                 let output_buf_range =
                     output_buf_range.start - input_end..output_buf_range.end - input_end;
@@ -123,69 +115,302 @@ impl<const N: usize, U: crate::user::UserDevice> Op<N, U> {
                     continue;
                 };
 
-                if let (None, Some(f)) = (
-                    ctrl_flow,
-                    user_cb(
-                        maindevice,
+                while let Some(emergency) = ctx.emergencies.pop(id as _) {
+                    let subdev = self.subdev_mut(id).ok_or(Error::UnknownDevice(id as _))?;
+                    let flow = user_cb(
+                        ctx,
                         subdev,
-                        Some(DeviceResponse::Pdi(user_input_buf)),
-                        tx_entries,
-                        ring,
+                        Some(DeviceResponse::Emergency(emergency)),
                         id as _,
                         None,
                         user_output_buf,
-                    )
-                    .map_err(|_| Error::Internal)?,
-                ) {
-                    ctrl_flow = Some(f);
+                    )?;
+                    self.flow(flow, &mut ctrl_flow, ctx)?;
                 }
+
+                let subdev = self.subdev_mut(id).ok_or(Error::UnknownDevice(id as _))?;
+                let flow = user_cb(
+                    ctx,
+                    subdev,
+                    Some(DeviceResponse::Pdi(user_input_buf)),
+                    id as _,
+                    None,
+                    user_output_buf,
+                )?;
+                self.flow(flow, &mut ctrl_flow, ctx)?;
             }
 
-            let (frame, handle) = unsafe { maindevice.prep_rx_tx(0, transmission_buf) }?.unwrap();
-
-            crate::setup::setup_write(
-                frame,
-                handle,
-                retry_count,
-                timeout,
-                tx_entries,
-                sock,
-                ring,
-                Some(0),
-                None,
-                write_entry,
-                timeout_entry,
+            let (frame, handle) =
+                unsafe { ctx.maindevice.prep_rx_tx(0, transmission_buf) }?.ok_or(Error::NoFrame)?;
+
+            crate::setup::setup_write(frame, handle, &mut ctx.tx, Some(0), None)?;
+
+            crate::mbx::read_full_mailboxes(ctx)?;
+
+            // gateway requests go out alongside the cyclic frame, one at a time
+            crate::gateway::start_next(
+                ctx,
+                self.subdevices
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, (dev, ..))| (idx as u16, dev.subdevice())),
             )?;
 
             Ok(ctrl_flow)
         } else {
-            let dev = self.subdevices.get_mut(idx).unwrap();
+            let (dev, config, state, change) = self
+                .subdevices
+                .get_mut(idx)
+                .ok_or(Error::UnknownDevice(idx as _))?;
             let output_buf_range = dev.subdevice().config.io.output.bytes.clone();
 
             let Some(user_output_buf) = transmission_buf.get_mut(output_buf_range) else {
                 return Ok(None);
             };
 
-            user_cb(
-                maindevice,
+            // a message that op read on its own, nothing is waiting on it so messages that
+            // were lost are not repeated
+            let response = if identifier == Some(crate::mbx::CYCLIC_READ_IDENTIFIER) {
+                let configured_addr = dev.subdevice().configured_address();
+                if received.working_counter == 0
+                    || ctx.mailbox_counters.duplicate(configured_addr, &received)
+                {
+                    return Ok(None);
+                }
+
+                // kept with the others and delivered before the next pdi
+                if let Some(emergency) = crate::Emergency::parse(&received) {
+                    ctx.emergencies.push(idx as _, emergency);
+                    return Ok(None);
+                }
+                DeviceResponse::Mailbox(received)
+            } else if let Some(device_change) =
+                change.as_mut().filter(|change| change.owns(identifier))
+            {
+                let res = device_change.update(
+                    received,
+                    header,
+                    ctx,
+                    dev.subdevice_mut(),
+                    *config,
+                    identifier,
+                    idx as _,
+                );
+                match res {
+                    Ok(None) => return Ok(None),
+                    Ok(Some(new_state)) => {
+                        *state = new_state;
+                        *change = None;
+                        DeviceResponse::StateChange(Ok(new_state))
+                    }
+                    Err(err) => {
+                        *state = device_change.state();
+                        *change = None;
+                        DeviceResponse::StateChange(Err(err))
+                    }
+                }
+            } else {
+                DeviceResponse::Pdu(received, header)
+            };
+
+            // the identifier of a read that op started is of no use to the user
+            let identifier = match response {
+                DeviceResponse::Mailbox(_) => None,
+                _ => identifier,
+            };
+            let flow = user_cb(
+                ctx,
                 dev,
-                Some(DeviceResponse::Pdu(received, header)),
-                tx_entries,
-                ring,
+                Some(response),
                 idx as _,
                 identifier,
                 user_output_buf,
-            )
-            .map_err(|_| Error::Internal)
+            )?;
+
+            let mut ctrl_flow = None;
+            self.flow(flow, &mut ctrl_flow, ctx)?;
+            Ok(ctrl_flow)
+        }
+    }
+
+    // a pdu ran out of retries, see `InitState::timeout`
+    pub(crate) fn timeout(
+        &mut self,
+        ctx: &mut IoCtx,
+        command_code: u8,
+        idx: Option<u16>,
+        identifier: Option<u8>,
+        mut user_cb: impl FnMut(
+            &mut IoCtx,
+            &mut U,
+            Option<crate::op::DeviceResponse<'_, '_>>,
+            u16,
+            Option<u8>,
+            &mut [u8],
+        ) -> std::io::Result<Option<crate::user::ControlFlow>>,
+        transmission_buf: &mut [u8],
+    ) -> Result<Option<ControlFlow>, Error> {
+        // the next cyclic frame is only sent once the last one came back
+        if command_code == 12 {
+            let (frame, handle) =
+                unsafe { ctx.maindevice.prep_rx_tx(0, transmission_buf) }?.ok_or(Error::NoFrame)?;
+            crate::setup::setup_write(frame, handle, &mut ctx.tx, Some(0), None)?;
+            return Ok(None);
+        }
+
+        // the tool will ask again
+        if idx == Some(crate::gateway::GATEWAY_IDX) {
+            if let Some(gateway) = ctx.gateway.as_deref_mut() {
+                gateway.reset();
+            }
+            return Ok(None);
+        }
+
+        // nothing was waiting on it, the mailbox is read again once it shows up full
+        if identifier == Some(crate::mbx::CYCLIC_READ_IDENTIFIER) {
+            return Ok(None);
+        }
+
+        let idx = idx.ok_or(Error::MissingIndex)?;
+        let (dev, _, state, change) = self
+            .subdevices
+            .get_mut(usize::from(idx))
+            .ok_or(Error::UnknownDevice(idx))?;
+        let output_buf_range = dev.subdevice().config.io.output.bytes.clone();
+
+        let Some(user_output_buf) = transmission_buf.get_mut(output_buf_range) else {
+            return Ok(None);
+        };
+
+        let response = match change.take_if(|change| change.owns(identifier)) {
+            Some(device_change) => {
+                *state = device_change.state();
+                DeviceResponse::StateChange(Err(Error::Timeout {
+                    idx: Some(idx),
+                    identifier,
+                }))
+            }
+            None => match dev
+                .mailbox_queue()
+                .filter(|queue| queue.owns(identifier))
+                .map(|queue| queue.timeout(ctx, identifier, idx))
+                .transpose()?
+                .flatten()
+            {
+                Some(completion) => DeviceResponse::Completion(completion),
+                None => DeviceResponse::Timeout,
+            },
+        };
+
+        let flow = user_cb(ctx, dev, Some(response), idx, identifier, user_output_buf)?;
+
+        let mut ctrl_flow = None;
+        self.flow(flow, &mut ctrl_flow, ctx)?;
+        Ok(ctrl_flow)
+    }
+
+    // the first flow that concerns the whole bus is kept in `ctrl_flow`
+    fn flow(
+        &mut self,
+        flow: Option<ControlFlow>,
+        ctrl_flow: &mut Option<ControlFlow>,
+        ctx: &mut IoCtx,
+    ) -> Result<(), Error> {
+        let Some(flow) = flow else {
+            return Ok(());
+        };
+
+        if let (None, Some(flow)) = (*ctrl_flow, self.control(flow, ctx)?) {
+            *ctrl_flow = Some(flow);
         }
+        Ok(())
+    }
+
+    // starts the changes that only concern some of the subdevices, the ones that concern the
+    // whole bus are handed back
+    fn control(
+        &mut self,
+        flow: ControlFlow,
+        ctx: &mut IoCtx,
+    ) -> Result<Option<ControlFlow>, Error> {
+        match flow {
+            ControlFlow::RestartDevice(idx) => {
+                self.change(idx, SubDeviceState::Init, Then::PreOp, ctx)?
+            }
+            ControlFlow::Reconfigure(idx) => {
+                self.change(idx, SubDeviceState::PreOp, Then::Pdos, ctx)?
+            }
+            ControlFlow::EnterSafeOp => {
+                for idx in 0..self.subdevices.len() {
+                    self.change(idx as _, SubDeviceState::SafeOp, Then::Done, ctx)?;
+                }
+            }
+            ControlFlow::EnterOp => {
+                for idx in 0..self.subdevices.len() {
+                    self.change(idx as _, SubDeviceState::Op, Then::Done, ctx)?;
+                }
+            }
+            ControlFlow::Restart | ControlFlow::Shutdown | ControlFlow::Stop => {
+                return Ok(Some(flow));
+            }
+        }
+        Ok(None)
+    }
+
+    // subdevices that are already being changed are left alone
+    fn change(
+        &mut self,
+        idx: u16,
+        target: SubDeviceState,
+        then: Then,
+        ctx: &mut IoCtx,
+    ) -> Result<(), Error> {
+        let (dev, config, state, change) = self
+            .subdevices
+            .get_mut(usize::from(idx))
+            .ok_or(Error::UnknownDevice(idx))?;
+        if change.is_some() {
+            return Ok(());
+        }
+
+        *change =
+            DeviceChange::start_new(*state, target, then, ctx, dev.subdevice(), *config, idx)?;
+        Ok(())
+    }
+
+    // along with the state each one is in
+    pub(crate) fn into_subdevices(self) -> Deque<(U, SubDeviceState), N> {
+        let mut subdevices = Deque::new();
+        for (dev, _, state, change) in self.subdevices.into_iter() {
+            let state = change.as_ref().map_or(state, DeviceChange::state);
+            let _ = subdevices.push_back((dev, state));
+        }
+        subdevices
     }
 
     pub fn subdev_mut(&mut self, idx: usize) -> Option<&mut U> {
-        self.subdevices.get_mut(idx)
+        self.subdevices.get_mut(idx).map(|(dev, ..)| dev)
+    }
+
+    // the state the subdevice was last brought to, see `ControlFlow`
+    pub fn subdev_state(&self, idx: usize) -> Option<SubDeviceState> {
+        self.subdevices.get(idx).map(|(_, _, state, _)| *state)
     }
 }
 
 pub enum DeviceResponse<'a, 'b> {
     Pdu(ReceivedPdu<'a>, PduHeader),
     Pdi(&'b [u8]),
+    // an emergency the subdevice sent while its mailbox was being read, delivered before the next pdi
+    Emergency(crate::Emergency),
+    // a message the subdevice sent while nothing was waiting on it, eg. an eoe frame,
+    // picked up because the cyclic frame showed its mailbox full, handed over without an identifier
+    Mailbox(ReceivedPdu<'a>),
+    // a change requested through `ControlFlow` is done, with the state the subdevice ended up in
+    StateChange(Result<SubDeviceState, Error>),
+    // a pdu that was sent with the identifier ran out of retries without a response
+    Timeout,
+    // the transfer of the queue from `UserDevice::mailbox_queue` that timed out, the next one
+    // was already started
+    Completion(crate::MailboxCompletion<'static>),
 }
@@ -26,12 +26,15 @@ impl<'a> PdoMapping<'a> {
         self.objects.iter().map(|obj| obj.len_bytes()).sum()
     }
 
-    pub(crate) fn start_map(&'a self, subdev: &ethercrab::SubDevice) -> PdoMap<'a> {
-        PdoMap {
+    pub(crate) fn start_map(
+        &'a self,
+        subdev: &ethercrab::SubDevice,
+    ) -> Result<PdoMap<'a>, crate::error::Error> {
+        Ok(PdoMap {
             index: self.index,
             objects: self.objects,
-            state: PdoMapState::Clear(SdoWrite::new(subdev, self.index, 0, 0)),
-        }
+            state: PdoMapState::Clear(SdoWrite::new(subdev, self.index, 0, 0)?),
+        })
     }
 }
 
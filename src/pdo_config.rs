@@ -1,15 +1,17 @@
-use ethercrab::{
-    Mailbox, MainDevice, PduHeader, SubDevice, error::Error, received_frame::ReceivedPdu,
-    std::RawSocketDesc,
-};
+use crate::error::Error;
+use crate::io::IoCtx;
+use ethercrab::{Mailbox, PduHeader, SubDevice, received_frame::ReceivedPdu};
 
 use crate::pdo::{PdoConfig, PdoObject};
-use crate::txbuf::TxBuf;
-use io_uring::{IoUring, types::Timespec};
-use std::collections::BTreeMap;
 
 use crate::sdo::SdoWrite;
 
+// the identifier that the next write of the input (1) or output (2) mapping is started with,
+// keeping the bits above the tags that the whole config was started with
+fn next_identifier(identifier: Option<u8>, tag: u8) -> Option<u8> {
+    identifier.map(|id| tag | ((id >> 2) & !0b11))
+}
+
 pub(crate) struct PdoMappingConfig<'a> {
     state: PdoConfigState<'a>,
 }
@@ -18,76 +20,60 @@ impl<'a> PdoMappingConfig<'a> {
     pub(crate) fn new<const I: usize, const O: usize>(
         config: &'a PdoConfig<'a, I, O>,
         subdev: &SubDevice,
-    ) -> Self {
+    ) -> Result<Self, Error> {
         let input = config
             .inputs
             .first()
-            .map(|input| (input.start_map(subdev), 0));
+            .map(|input| input.start_map(subdev).map(|map| (map, 0)))
+            .transpose()?;
 
-        Self {
+        Ok(Self {
             state: PdoConfigState::Sdo {
                 input,
                 output: None,
             },
-        }
+        })
     }
 
-    #[allow(clippy::too_many_arguments)]
     pub(crate) fn start(
         &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         write_mbx: &Mailbox,
         read_mbx: &Mailbox,
         configured_addr: u16,
         identifier: Option<u8>,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<(), Error> {
         match &mut self.state {
             PdoConfigState::Sdo { input, output } => {
                 if let Some((input, _)) = input.as_mut() {
                     input.start(
-                        maindevice,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
+                        ctx,
                         write_mbx,
                         read_mbx,
                         configured_addr,
                         Some(1 | (identifier.unwrap_or(0) << 2)),
                         idx,
-                        &write_entry,
-                        &timeout_entry,
                     )?;
                 }
 
                 if let Some((output, _)) = output.as_mut() {
                     output.start(
-                        maindevice,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
+                        ctx,
                         write_mbx,
                         read_mbx,
                         configured_addr,
                         Some(2 | (identifier.unwrap_or(0) << 2)),
                         idx,
-                        &write_entry,
-                        &timeout_entry,
                     )?;
                 }
             }
-            _ => unreachable!(),
+            _ => {
+                return Err(Error::UnexpectedState {
+                    expected: ethercrab::SubDeviceState::PreOp,
+                    found: None,
+                });
+            }
         }
         Ok(())
     }
@@ -97,12 +83,7 @@ impl<'a> PdoMappingConfig<'a> {
         &mut self,
         received: ReceivedPdu<'_>,
         header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         write_mbx: &Mailbox,
         read_mbx: &Mailbox,
         configured_addr: u16,
@@ -110,8 +91,6 @@ impl<'a> PdoMappingConfig<'a> {
         idx: u16,
         subdev: &SubDevice,
         config: &'a PdoConfig<'a, I, O>,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<bool, Error> {
         // this is a mess.
         match &mut self.state {
@@ -119,21 +98,14 @@ impl<'a> PdoMappingConfig<'a> {
                 macro_rules! to_sync_managers {
                     () => {
                         let input = if !config.inputs.is_empty() {
-                            let mut write = SdoWrite::new(subdev, 0x1c10 + 2, 0, 0);
+                            let mut write = SdoWrite::new(subdev, 0x1c10 + 2, 0, 0)?;
                             write.start(
-                                maindevice,
-                                retry_count,
-                                timeout,
-                                tx_entries,
-                                sock,
-                                ring,
+                                ctx,
                                 write_mbx,
                                 read_mbx,
                                 configured_addr,
-                                Some(1),
+                                next_identifier(identifier, 1),
                                 idx,
-                                &write_entry,
-                                &timeout_entry,
                             )?;
                             Some((PdoMapState::Clear(write), 0))
                         } else {
@@ -149,44 +121,32 @@ impl<'a> PdoMappingConfig<'a> {
 
                 match identifier.map(|id| (id >> 2) & 0b11) {
                     Some(1) => {
-                        let (uinput, input_idx) = input.as_mut().unwrap();
+                        let (uinput, input_idx) = input
+                            .as_mut()
+                            .ok_or(Error::UnexpectedIdentifier(identifier))?;
                         if uinput.update(
                             received,
                             header,
-                            maindevice,
-                            retry_count,
-                            timeout,
-                            tx_entries,
-                            sock,
-                            ring,
+                            ctx,
                             write_mbx,
                             read_mbx,
                             configured_addr,
                             identifier,
                             idx,
                             subdev,
-                            &write_entry,
-                            &timeout_entry,
                         )? {
                             *input_idx += 1;
 
                             if let Some(cfg) = config.inputs.get(*input_idx as usize) {
-                                let mut map = cfg.start_map(subdev);
+                                let mut map = cfg.start_map(subdev)?;
 
                                 map.start(
-                                    maindevice,
-                                    retry_count,
-                                    timeout,
-                                    tx_entries,
-                                    sock,
-                                    ring,
+                                    ctx,
                                     write_mbx,
                                     read_mbx,
                                     configured_addr,
-                                    Some(1),
+                                    next_identifier(identifier, 1),
                                     idx,
-                                    &write_entry,
-                                    &timeout_entry,
                                 )?;
 
                                 *uinput = map;
@@ -196,25 +156,19 @@ impl<'a> PdoMappingConfig<'a> {
                                 *output = config
                                     .outputs
                                     .first()
-                                    .map(|output| (output.start_map(subdev), 0));
+                                    .map(|output| output.start_map(subdev).map(|map| (map, 0)))
+                                    .transpose()?;
                                 // NOTE: could not get it working in parallel, so this does it
                                 // sequentially
                                 match output {
                                     Some((o, _)) => {
                                         o.start(
-                                            maindevice,
-                                            retry_count,
-                                            timeout,
-                                            tx_entries,
-                                            sock,
-                                            ring,
+                                            ctx,
                                             write_mbx,
                                             read_mbx,
                                             configured_addr,
-                                            Some(2),
+                                            next_identifier(identifier, 2),
                                             idx,
-                                            &write_entry,
-                                            &timeout_entry,
                                         )?;
                                     }
                                     None => {
@@ -225,44 +179,32 @@ impl<'a> PdoMappingConfig<'a> {
                         }
                     }
                     Some(2) => {
-                        let (uoutput, output_idx) = output.as_mut().unwrap();
+                        let (uoutput, output_idx) = output
+                            .as_mut()
+                            .ok_or(Error::UnexpectedIdentifier(identifier))?;
                         if uoutput.update(
                             received,
                             header,
-                            maindevice,
-                            retry_count,
-                            timeout,
-                            tx_entries,
-                            sock,
-                            ring,
+                            ctx,
                             write_mbx,
                             read_mbx,
                             configured_addr,
                             identifier,
                             idx,
                             subdev,
-                            &write_entry,
-                            &timeout_entry,
                         )? {
                             *output_idx += 1;
 
                             if let Some(cfg) = config.inputs.get(*output_idx as usize) {
-                                let mut map = cfg.start_map(subdev);
+                                let mut map = cfg.start_map(subdev)?;
 
                                 map.start(
-                                    maindevice,
-                                    retry_count,
-                                    timeout,
-                                    tx_entries,
-                                    sock,
-                                    ring,
+                                    ctx,
                                     write_mbx,
                                     read_mbx,
                                     configured_addr,
-                                    Some(2),
+                                    next_identifier(identifier, 2),
                                     idx,
-                                    &write_entry,
-                                    &timeout_entry,
                                 )?;
 
                                 *uoutput = map;
@@ -275,51 +217,41 @@ impl<'a> PdoMappingConfig<'a> {
                             }
                         }
                     }
-                    _ => unreachable!(),
+                    _ => return Err(Error::UnexpectedIdentifier(identifier)),
                 }
             }
             PdoConfigState::SyncManagers { input, output } => {
                 match identifier.map(|id| (id >> 2) & 0b11) {
                     Some(1) => {
-                        let (uinput, input_idx) = input.as_mut().unwrap();
+                        let (uinput, input_idx) = input
+                            .as_mut()
+                            .ok_or(Error::UnexpectedIdentifier(identifier))?;
 
                         match uinput {
                             PdoMapState::Clear(c) => {
                                 if c.update(
                                     received,
                                     header,
-                                    maindevice,
-                                    retry_count,
-                                    timeout,
-                                    tx_entries,
-                                    sock,
-                                    ring,
+                                    ctx,
                                     write_mbx,
                                     read_mbx,
                                     configured_addr,
                                     identifier,
                                     idx,
-                                    &write_entry,
-                                    &timeout_entry,
-                                )?
-                                .is_some()
-                                {
-                                    let input = config.inputs.first().unwrap();
-                                    let mut s = SdoWrite::new(subdev, 0x1c10 + 2, 1, input.index);
+                                )? {
+                                    let input =
+                                        config.inputs.first().ok_or(Error::UnexpectedState {
+                                            expected: ethercrab::SubDeviceState::PreOp,
+                                            found: None,
+                                        })?;
+                                    let mut s = SdoWrite::new(subdev, 0x1c10 + 2, 1, input.index)?;
                                     s.start(
-                                        maindevice,
-                                        retry_count,
-                                        timeout,
-                                        tx_entries,
-                                        sock,
-                                        ring,
+                                        ctx,
                                         write_mbx,
                                         read_mbx,
                                         configured_addr,
-                                        Some(1),
+                                        next_identifier(identifier, 1),
                                         idx,
-                                        &write_entry,
-                                        &timeout_entry,
                                     )?;
 
                                     *uinput = PdoMapState::Map(s, 0);
@@ -329,22 +261,13 @@ impl<'a> PdoMappingConfig<'a> {
                                 if w.update(
                                     received,
                                     header,
-                                    maindevice,
-                                    retry_count,
-                                    timeout,
-                                    tx_entries,
-                                    sock,
-                                    ring,
+                                    ctx,
                                     write_mbx,
                                     read_mbx,
                                     configured_addr,
                                     identifier,
                                     idx,
-                                    &write_entry,
-                                    &timeout_entry,
-                                )?
-                                .is_some()
-                                {
+                                )? {
                                     *count += 1;
                                     if let Some(input) = config.inputs.get(*count as usize) {
                                         let mut s = SdoWrite::new(
@@ -352,21 +275,14 @@ impl<'a> PdoMappingConfig<'a> {
                                             0x1c10 + 2 + (*count as u16),
                                             *count + 1,
                                             input.index,
-                                        );
+                                        )?;
                                         s.start(
-                                            maindevice,
-                                            retry_count,
-                                            timeout,
-                                            tx_entries,
-                                            sock,
-                                            ring,
+                                            ctx,
                                             write_mbx,
                                             read_mbx,
                                             configured_addr,
-                                            Some(1),
+                                            next_identifier(identifier, 1),
                                             idx,
-                                            &write_entry,
-                                            &timeout_entry,
                                         )?;
                                         *w = s;
                                     } else {
@@ -375,22 +291,15 @@ impl<'a> PdoMappingConfig<'a> {
                                             0x1c10 + 2,
                                             0,
                                             config.inputs.len() as u8,
-                                        );
+                                        )?;
 
                                         s.start(
-                                            maindevice,
-                                            retry_count,
-                                            timeout,
-                                            tx_entries,
-                                            sock,
-                                            ring,
+                                            ctx,
                                             write_mbx,
                                             read_mbx,
                                             configured_addr,
-                                            Some(1),
+                                            next_identifier(identifier, 1),
                                             idx,
-                                            &write_entry,
-                                            &timeout_entry,
                                         )?;
 
                                         *uinput = PdoMapState::SetCount(s);
@@ -401,40 +310,24 @@ impl<'a> PdoMappingConfig<'a> {
                                 if c.update(
                                     received,
                                     header,
-                                    maindevice,
-                                    retry_count,
-                                    timeout,
-                                    tx_entries,
-                                    sock,
-                                    ring,
+                                    ctx,
                                     write_mbx,
                                     read_mbx,
                                     configured_addr,
                                     identifier,
                                     idx,
-                                    &write_entry,
-                                    &timeout_entry,
-                                )?
-                                .is_some()
-                                {
+                                )? {
                                     *input_idx += 1;
                                     if config.inputs.get(*input_idx as usize).is_some() {
                                         let mut write =
-                                            SdoWrite::new(subdev, 0x1c10 + 2 + *input_idx, 0, 0);
+                                            SdoWrite::new(subdev, 0x1c10 + 2 + *input_idx, 0, 0)?;
                                         write.start(
-                                            maindevice,
-                                            retry_count,
-                                            timeout,
-                                            tx_entries,
-                                            sock,
-                                            ring,
+                                            ctx,
                                             write_mbx,
                                             read_mbx,
                                             configured_addr,
-                                            Some(1),
+                                            next_identifier(identifier, 1),
                                             idx,
-                                            &write_entry,
-                                            &timeout_entry,
                                         )?;
 
                                         *uinput = PdoMapState::Clear(write);
@@ -447,22 +340,15 @@ impl<'a> PdoMappingConfig<'a> {
                                                 0x1c10 + 2 + (config.inputs.len() as u16),
                                                 0,
                                                 0,
-                                            );
+                                            )?;
 
                                             write.start(
-                                                maindevice,
-                                                retry_count,
-                                                timeout,
-                                                tx_entries,
-                                                sock,
-                                                ring,
+                                                ctx,
                                                 write_mbx,
                                                 read_mbx,
                                                 configured_addr,
-                                                Some(2),
+                                                next_identifier(identifier, 2),
                                                 idx,
-                                                &write_entry,
-                                                &timeout_entry,
                                             )?;
                                             Some((PdoMapState::Clear(write), 0))
                                         } else {
@@ -478,50 +364,40 @@ impl<'a> PdoMappingConfig<'a> {
                         }
                     }
                     Some(2) => {
-                        let (uoutput, output_idx) = output.as_mut().unwrap();
+                        let (uoutput, output_idx) = output
+                            .as_mut()
+                            .ok_or(Error::UnexpectedIdentifier(identifier))?;
 
                         match uoutput {
                             PdoMapState::Clear(c) => {
                                 if c.update(
                                     received,
                                     header,
-                                    maindevice,
-                                    retry_count,
-                                    timeout,
-                                    tx_entries,
-                                    sock,
-                                    ring,
+                                    ctx,
                                     write_mbx,
                                     read_mbx,
                                     configured_addr,
                                     identifier,
                                     idx,
-                                    &write_entry,
-                                    &timeout_entry,
-                                )?
-                                .is_some()
-                                {
-                                    let output = config.outputs.first().unwrap();
+                                )? {
+                                    let output =
+                                        config.outputs.first().ok_or(Error::UnexpectedState {
+                                            expected: ethercrab::SubDeviceState::PreOp,
+                                            found: None,
+                                        })?;
                                     let mut s = SdoWrite::new(
                                         subdev,
                                         0x1c10 + 2 + (config.inputs.len() as u16),
                                         1,
                                         output.index,
-                                    );
+                                    )?;
                                     s.start(
-                                        maindevice,
-                                        retry_count,
-                                        timeout,
-                                        tx_entries,
-                                        sock,
-                                        ring,
+                                        ctx,
                                         write_mbx,
                                         read_mbx,
                                         configured_addr,
-                                        Some(2),
+                                        next_identifier(identifier, 2),
                                         idx,
-                                        &write_entry,
-                                        &timeout_entry,
                                     )?;
 
                                     *uoutput = PdoMapState::Map(s, 0);
@@ -531,22 +407,13 @@ impl<'a> PdoMappingConfig<'a> {
                                 if w.update(
                                     received,
                                     header,
-                                    maindevice,
-                                    retry_count,
-                                    timeout,
-                                    tx_entries,
-                                    sock,
-                                    ring,
+                                    ctx,
                                     write_mbx,
                                     read_mbx,
                                     configured_addr,
                                     identifier,
                                     idx,
-                                    &write_entry,
-                                    &timeout_entry,
-                                )?
-                                .is_some()
-                                {
+                                )? {
                                     *count += 1;
                                     if let Some(output) = config.outputs.get(*count as usize) {
                                         let mut s = SdoWrite::new(
@@ -557,21 +424,14 @@ impl<'a> PdoMappingConfig<'a> {
                                                 + (config.inputs.len() as u16),
                                             *count + 1,
                                             output.index,
-                                        );
+                                        )?;
                                         s.start(
-                                            maindevice,
-                                            retry_count,
-                                            timeout,
-                                            tx_entries,
-                                            sock,
-                                            ring,
+                                            ctx,
                                             write_mbx,
                                             read_mbx,
                                             configured_addr,
-                                            Some(2),
+                                            next_identifier(identifier, 2),
                                             idx,
-                                            &write_entry,
-                                            &timeout_entry,
                                         )?;
                                         *w = s;
                                     } else {
@@ -580,22 +440,15 @@ impl<'a> PdoMappingConfig<'a> {
                                             0x1c10 + 2 + (config.inputs.len() as u16),
                                             0,
                                             config.outputs.len() as u8,
-                                        );
+                                        )?;
 
                                         s.start(
-                                            maindevice,
-                                            retry_count,
-                                            timeout,
-                                            tx_entries,
-                                            sock,
-                                            ring,
+                                            ctx,
                                             write_mbx,
                                             read_mbx,
                                             configured_addr,
-                                            Some(2),
+                                            next_identifier(identifier, 2),
                                             idx,
-                                            &write_entry,
-                                            &timeout_entry,
                                         )?;
 
                                         *uoutput = PdoMapState::SetCount(s);
@@ -606,22 +459,13 @@ impl<'a> PdoMappingConfig<'a> {
                                 if c.update(
                                     received,
                                     header,
-                                    maindevice,
-                                    retry_count,
-                                    timeout,
-                                    tx_entries,
-                                    sock,
-                                    ring,
+                                    ctx,
                                     write_mbx,
                                     read_mbx,
                                     configured_addr,
                                     identifier,
                                     idx,
-                                    &write_entry,
-                                    &timeout_entry,
-                                )?
-                                .is_some()
-                                {
+                                )? {
                                     *output_idx += 1;
                                     if config.outputs.get(*output_idx as usize).is_some() {
                                         let mut write = SdoWrite::new(
@@ -629,21 +473,14 @@ impl<'a> PdoMappingConfig<'a> {
                                             0x1c10 + 2 + *output_idx + (config.inputs.len() as u16),
                                             0,
                                             0,
-                                        );
+                                        )?;
                                         write.start(
-                                            maindevice,
-                                            retry_count,
-                                            timeout,
-                                            tx_entries,
-                                            sock,
-                                            ring,
+                                            ctx,
                                             write_mbx,
                                             read_mbx,
                                             configured_addr,
-                                            Some(2),
+                                            next_identifier(identifier, 2),
                                             idx,
-                                            &write_entry,
-                                            &timeout_entry,
                                         )?;
 
                                         *uoutput = PdoMapState::Clear(write);
@@ -658,7 +495,7 @@ impl<'a> PdoMappingConfig<'a> {
                             }
                         }
                     }
-                    _ => unreachable!(),
+                    _ => return Err(Error::UnexpectedIdentifier(identifier)),
                 }
             }
         }
@@ -692,40 +529,23 @@ pub(crate) struct PdoMap<'a> {
 }
 
 impl<'a> PdoMap<'a> {
-    #[allow(clippy::too_many_arguments)]
     fn start(
         &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         write_mbx: &Mailbox,
         read_mbx: &Mailbox,
         configured_addr: u16,
         identifier: Option<u8>,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<(), Error> {
         match &mut self.state {
-            PdoMapState::Clear(c) => c.start(
-                maindevice,
-                retry_count,
-                timeout,
-                tx_entries,
-                sock,
-                ring,
-                write_mbx,
-                read_mbx,
-                configured_addr,
-                identifier,
-                idx,
-                write_entry,
-                timeout_entry,
-            ),
-            _ => unreachable!(),
+            PdoMapState::Clear(c) => {
+                c.start(ctx, write_mbx, read_mbx, configured_addr, identifier, idx)
+            }
+            _ => Err(Error::UnexpectedState {
+                expected: ethercrab::SubDeviceState::PreOp,
+                found: None,
+            }),
         }
     }
 
@@ -734,58 +554,35 @@ impl<'a> PdoMap<'a> {
         &mut self,
         received: ReceivedPdu<'_>,
         header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         write_mbx: &Mailbox,
         read_mbx: &Mailbox,
         configured_addr: u16,
         identifier: Option<u8>,
         idx: u16,
         subdev: &SubDevice,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<bool, Error> {
         match &mut self.state {
             PdoMapState::Clear(c) => {
                 if c.update(
                     received,
                     header,
-                    maindevice,
-                    retry_count,
-                    timeout,
-                    tx_entries,
-                    sock,
-                    ring,
+                    ctx,
                     write_mbx,
                     read_mbx,
                     configured_addr,
                     identifier,
                     idx,
-                    &write_entry,
-                    &timeout_entry,
-                )?
-                .is_some()
-                {
+                )? {
                     if let Some(obj) = self.objects.first() {
-                        let mut s = SdoWrite::new(subdev, self.index, 1, obj.0);
+                        let mut s = SdoWrite::new(subdev, self.index, 1, obj.0)?;
                         s.start(
-                            maindevice,
-                            retry_count,
-                            timeout,
-                            tx_entries,
-                            sock,
-                            ring,
+                            ctx,
                             write_mbx,
                             read_mbx,
                             configured_addr,
                             identifier.map(|id| id >> 2),
                             idx,
-                            &write_entry,
-                            &timeout_entry,
                         )?;
                         self.state = PdoMapState::Map(s, 0);
                     } else {
@@ -797,58 +594,35 @@ impl<'a> PdoMap<'a> {
                 if s.update(
                     received,
                     header,
-                    maindevice,
-                    retry_count,
-                    timeout,
-                    tx_entries,
-                    sock,
-                    ring,
+                    ctx,
                     write_mbx,
                     read_mbx,
                     configured_addr,
                     identifier,
                     idx,
-                    &write_entry,
-                    &timeout_entry,
-                )?
-                .is_some()
-                {
+                )? {
                     *subidx += 1;
                     if let Some(obj) = self.objects.get(*subidx as usize) {
-                        let mut new_s = SdoWrite::new(subdev, self.index, *subidx + 1, obj.0);
+                        let mut new_s = SdoWrite::new(subdev, self.index, *subidx + 1, obj.0)?;
                         new_s.start(
-                            maindevice,
-                            retry_count,
-                            timeout,
-                            tx_entries,
-                            sock,
-                            ring,
+                            ctx,
                             write_mbx,
                             read_mbx,
                             configured_addr,
                             identifier.map(|id| id >> 2),
                             idx,
-                            &write_entry,
-                            &timeout_entry,
                         )?;
                         *s = new_s;
                     } else {
-                        let mut s = SdoWrite::new(subdev, self.index, 0, self.objects.len() as u8);
+                        let mut s = SdoWrite::new(subdev, self.index, 0, self.objects.len() as u8)?;
 
                         s.start(
-                            maindevice,
-                            retry_count,
-                            timeout,
-                            tx_entries,
-                            sock,
-                            ring,
+                            ctx,
                             write_mbx,
                             read_mbx,
                             configured_addr,
                             identifier.map(|id| id >> 2),
                             idx,
-                            &write_entry,
-                            &timeout_entry,
                         )?;
 
                         self.state = PdoMapState::SetCount(s);
@@ -859,22 +633,13 @@ impl<'a> PdoMap<'a> {
                 if s.update(
                     received,
                     header,
-                    maindevice,
-                    retry_count,
-                    timeout,
-                    tx_entries,
-                    sock,
-                    ring,
+                    ctx,
                     write_mbx,
                     read_mbx,
                     configured_addr,
                     identifier,
                     idx,
-                    &write_entry,
-                    &timeout_entry,
-                )?
-                .is_some()
-                {
+                )? {
                     return Ok(true);
                 }
             }
@@ -1,13 +1,11 @@
-use crate::txbuf::TxBuf;
-use ethercrab::{
-    MainDevice, PduHeader, SubDevice, error::Error, received_frame::ReceivedPdu, std::RawSocketDesc,
-};
-use io_uring::{IoUring, types::Timespec};
-use std::collections::BTreeMap;
+use crate::error::Error;
+use crate::io::IoCtx;
+use ethercrab::{MainDevice, PduHeader, SubDevice, received_frame::ReceivedPdu};
 
 use crate::pdo::PdoConfig;
 use crate::state_transition::Transition;
 
+use crate::concurrent::Concurrent;
 use crate::fmmu::ConfigureFmmus;
 use crate::pdo_config::PdoMappingConfig;
 
@@ -15,76 +13,67 @@ use heapless::Deque;
 
 pub struct PreOp<'a, const N: usize, const I: usize, const O: usize, U> {
     subdevices: Deque<(U, &'a PdoConfig<'a, I, O>, PreOpConfigState<'a>), N>,
+    // the pdos are mapped and the sii is read for several subdevices at once
+    concurrent: Concurrent,
+    // while the fmmus are configured one subdevice after another,
+    // as they need sequential access to the pdi
     configured_input_idx: u16,
     configured_output_idx: u16,
+    // how many subdevices are in safeop
+    transitioned: u16,
 }
 
 impl<'a, const N: usize, const I: usize, const O: usize, U: crate::user::UserDevice>
     PreOp<'a, N, I, O, U>
 {
-    #[allow(clippy::too_many_arguments)]
     pub(crate) fn start_new<S>(
         subdevs: Deque<(SubDevice, S), N>,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         mut config: impl FnMut(&MainDevice, ethercrab::SubDevice) -> (U, &'a PdoConfig<'a, I, O>),
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
+        concurrency: u16,
     ) -> Result<Self, Error> {
         let mut devs = Deque::new();
 
         for (subdev, _) in subdevs.into_iter() {
-            let (dev, cfg) = config(maindevice, subdev);
-            let state = PreOpConfigState::new(cfg, dev.subdevice());
+            let (dev, cfg) = config(ctx.maindevice, subdev);
+            let state = PreOpConfigState::new(cfg, dev.subdevice())?;
 
             let _ = devs.push_back((dev, cfg, state));
         }
 
-        let (dev, _, state) = devs.front_mut().unwrap();
-        let subdev = dev.subdevice_mut();
-
-        state.start(
-            maindevice,
-            retry_count,
-            timeout_duration,
-            tx_entries,
-            sock,
-            ring,
-            &subdev.config.mailbox.write.unwrap(),
-            &subdev.config.mailbox.read.unwrap(),
-            subdev.configured_address(),
-            0,
-            write_entry,
-            timeout_entry,
-        )?;
+        if devs.is_empty() {
+            return Err(Error::NoSubDevices);
+        }
+
+        let (concurrent, start) = Concurrent::new(devs.len() as _, concurrency);
+        for idx in start {
+            let (dev, _, state) = devs
+                .get_mut(usize::from(idx))
+                .ok_or(Error::UnknownDevice(idx))?;
+            let subdev = dev.subdevice_mut();
+            let (write_mbx, read_mbx) = crate::mbx::mailboxes(subdev)?;
+
+            state.start(ctx, &write_mbx, &read_mbx, subdev.configured_address(), idx)?;
+        }
 
         Ok(Self {
             subdevices: devs,
+            concurrent,
             configured_input_idx: 0,
             configured_output_idx: 0,
+            transitioned: 0,
         })
     }
 
-    #[allow(clippy::too_many_arguments, clippy::type_complexity)]
+    #[allow(clippy::type_complexity)]
     pub(crate) fn update(
         &mut self,
         received: ReceivedPdu<'_>,
         header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         identifier: Option<u8>,
         idx: Option<u16>,
         pdi_offset: &mut ethercrab::PdiOffset,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<
         Option<(
             Deque<(U, &'a PdoConfig<'a, I, O>, PreOpConfigState<'a>), N>,
@@ -92,104 +81,139 @@ impl<'a, const N: usize, const I: usize, const O: usize, U: crate::user::UserDev
         )>,
         Error,
     > {
-        let idx = idx.unwrap() as usize;
-        let (dev, cfg, state) = self.subdevices.get_mut(idx).unwrap();
+        let idx = idx.ok_or(Error::MissingIndex)? as usize;
+        let (dev, cfg, state) = self
+            .subdevices
+            .get_mut(idx)
+            .ok_or(Error::UnknownDevice(idx as _))?;
         let dev = dev.subdevice_mut();
+        let (write_mbx, read_mbx) = crate::mbx::mailboxes(dev)?;
 
-        if let Some(mapping) = state.update(
+        let Some(configured) = state.update(
             received,
             header,
-            maindevice,
-            retry_count,
-            timeout_duration,
-            tx_entries,
-            sock,
-            ring,
-            &dev.config.mailbox.write.unwrap(),
-            &dev.config.mailbox.read.unwrap(),
+            ctx,
+            &write_mbx,
+            &read_mbx,
             dev.configured_address(),
             idx as u16,
             dev,
             identifier,
             cfg,
             pdi_offset,
-            &write_entry,
-            &timeout_entry,
-        )? {
-            let (configured_idx, start) =
-                if usize::from(self.configured_input_idx) == self.subdevices.len() {
-                    (&mut self.configured_output_idx, false)
-                } else {
-                    (&mut self.configured_input_idx, true)
-                };
-
-            *configured_idx += 1;
-
-            if usize::from(*configured_idx) != self.subdevices.len() {
-                let (dev, _, state) = self.subdevices.get_mut(*configured_idx as _).unwrap();
-                let subdev = dev.subdevice_mut();
-
-                if start {
+        )?
+        else {
+            return Ok(None);
+        };
+
+        match configured {
+            Configured::Ready => {
+                if let Some(next) = self.concurrent.finish() {
+                    let (dev, _, state) = self
+                        .subdevices
+                        .get_mut(usize::from(next))
+                        .ok_or(Error::UnknownDevice(next))?;
+                    let subdev = dev.subdevice_mut();
+                    let (write_mbx, read_mbx) = crate::mbx::mailboxes(subdev)?;
+
                     state.start(
-                        maindevice,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
-                        &subdev.config.mailbox.write.unwrap(),
-                        &subdev.config.mailbox.read.unwrap(),
+                        ctx,
+                        &write_mbx,
+                        &read_mbx,
                         subdev.configured_address(),
-                        *configured_idx as _,
-                        &write_entry,
-                        &timeout_entry,
+                        next,
                     )?;
-                } else {
-                    match state {
-                        PreOpConfigState::Fmmus(f) => f.start_output(
-                            maindevice,
-                            retry_count,
-                            timeout_duration,
-                            tx_entries,
-                            sock,
-                            ring,
-                            subdev.configured_address(),
-                            *configured_idx as _,
-                            &write_entry,
-                            &timeout_entry,
-                        )?,
-                        _ => unreachable!(),
-                    }
                 }
-                return Ok(None);
-            } else if start {
-                let (dev, _, state) = self.subdevices.front_mut().unwrap();
-                let subdev = dev.subdevice_mut();
-                match state {
-                    PreOpConfigState::Fmmus(f) => f.start_output(
-                        maindevice,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
-                        subdev.configured_address(),
-                        0,
-                        &write_entry,
-                        &timeout_entry,
-                    )?,
-                    _ => unreachable!(),
+                self.configure_inputs(ctx, pdi_offset)?;
+            }
+            Configured::Input => {
+                self.configured_input_idx += 1;
+                self.configure_inputs(ctx, pdi_offset)?;
+            }
+            Configured::Output => {
+                self.configured_output_idx += 1;
+                self.configure_outputs(ctx)?;
+            }
+            Configured::SafeOp => {
+                self.transitioned += 1;
+
+                if usize::from(self.transitioned) == self.subdevices.len() {
+                    // the last subdevice in the pdi knows where the inputs / outputs end
+                    let io = match self.subdevices.back() {
+                        Some((_, _, PreOpConfigState::SafeOpTransition(_, io))) => *io,
+                        _ => {
+                            return Err(Error::UnexpectedState {
+                                expected: ethercrab::SubDeviceState::SafeOp,
+                                found: None,
+                            });
+                        }
+                    };
+                    return Ok(Some((core::mem::take(&mut self.subdevices), io)));
                 }
-            } else if let FmmuMapping::Output(io) = mapping {
-                return Ok(Some((core::mem::take(&mut self.subdevices), io)));
             }
         }
         Ok(None)
     }
+
+    // starts configuring the inputs of the subdevice whose turn it is, if its sii was read.
+    // subdevices without inputs are skipped
+    fn configure_inputs(
+        &mut self,
+        ctx: &mut IoCtx,
+        pdi_offset: &ethercrab::PdiOffset,
+    ) -> Result<(), Error> {
+        while let Some((dev, _, state)) = self.subdevices.get_mut(self.configured_input_idx as _) {
+            let PreOpConfigState::Fmmus(fmmus) = state else {
+                return Ok(());
+            };
+            if !fmmus.ready() {
+                return Ok(());
+            }
+
+            let configured_addr = dev.subdevice().configured_address();
+            if fmmus.start_input(ctx, configured_addr, self.configured_input_idx, pdi_offset)? {
+                return Ok(());
+            }
+            self.configured_input_idx += 1;
+        }
+
+        // the outputs come after all of the inputs in the pdi
+        self.configure_outputs(ctx)
+    }
+
+    fn configure_outputs(&mut self, ctx: &mut IoCtx) -> Result<(), Error> {
+        let Some((dev, _, state)) = self.subdevices.get_mut(self.configured_output_idx as _) else {
+            return Ok(());
+        };
+
+        match state {
+            PreOpConfigState::Fmmus(f) => f.start_output(
+                ctx,
+                dev.subdevice().configured_address(),
+                self.configured_output_idx,
+            ),
+            _ => Err(Error::UnexpectedState {
+                expected: ethercrab::SubDeviceState::PreOp,
+                found: None,
+            }),
+        }
+    }
+}
+
+// how far a subdevice got, see `PreOp::update`
+enum Configured {
+    // the pdos were mapped and the sii was read
+    Ready,
+    Input,
+    // the outputs were configured and the transition to safeop was requested
+    Output,
+    SafeOp,
 }
 
 #[derive(Debug)]
 pub(crate) enum FmmuMapping<T> {
+    // the sii was read, the fmmus are configured once it is the subdevice's turn
+    Ready,
     Input,
     Output(T),
 }
@@ -198,6 +222,8 @@ pub(crate) enum FmmuMapping<T> {
 pub struct SendRecvIo {
     input_end: usize,
     output_end: usize,
+    // where the status of the device's read mailbox is in the pdi, if it was mapped
+    mailbox_status: Option<usize>,
 }
 
 impl SendRecvIo {
@@ -208,6 +234,10 @@ impl SendRecvIo {
     pub fn input_len(&self) -> usize {
         self.input_end
     }
+
+    pub(crate) fn mailbox_status(&self) -> Option<usize> {
+        self.mailbox_status
+    }
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -221,43 +251,24 @@ impl<'a> PreOpConfigState<'a> {
     fn new<const I: usize, const O: usize>(
         config: &'a PdoConfig<'a, I, O>,
         subdev: &ethercrab::SubDevice,
-    ) -> Self {
-        Self::Pdos(PdoMappingConfig::new(config, subdev))
+    ) -> Result<Self, Error> {
+        Ok(Self::Pdos(PdoMappingConfig::new(config, subdev)?))
     }
 
-    #[allow(clippy::too_many_arguments)]
     fn start(
         &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         write_mbx: &ethercrab::Mailbox,
         read_mbx: &ethercrab::Mailbox,
         configured_addr: u16,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<(), Error> {
         match self {
-            Self::Pdos(pdos) => pdos.start(
-                maindevice,
-                retry_count,
-                timeout_duration,
-                tx_entries,
-                sock,
-                ring,
-                write_mbx,
-                read_mbx,
-                configured_addr,
-                None,
-                idx,
-                write_entry,
-                timeout_entry,
-            ),
-            _ => unreachable!(),
+            Self::Pdos(pdos) => pdos.start(ctx, write_mbx, read_mbx, configured_addr, None, idx),
+            _ => Err(Error::UnexpectedState {
+                expected: ethercrab::SubDeviceState::PreOp,
+                found: None,
+            }),
         }
     }
 
@@ -266,12 +277,7 @@ impl<'a> PreOpConfigState<'a> {
         &mut self,
         received: ReceivedPdu<'_>,
         header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         write_mbx: &ethercrab::Mailbox,
         read_mbx: &ethercrab::Mailbox,
         configured_addr: u16,
@@ -280,20 +286,13 @@ impl<'a> PreOpConfigState<'a> {
         identifier: Option<u8>,
         config: &'a PdoConfig<'a, I, O>,
         pdi_offset: &mut ethercrab::PdiOffset,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
-    ) -> Result<Option<FmmuMapping<SendRecvIo>>, Error> {
+    ) -> Result<Option<Configured>, Error> {
         match self {
             Self::Pdos(pdos) => {
                 if pdos.update(
                     received,
                     header,
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
+                    ctx,
                     write_mbx,
                     read_mbx,
                     configured_addr,
@@ -301,22 +300,9 @@ impl<'a> PreOpConfigState<'a> {
                     idx,
                     subdev,
                     config,
-                    &write_entry,
-                    &timeout_entry,
                 )? {
                     let mut fmmus = ConfigureFmmus::new();
-                    fmmus.start(
-                        maindevice,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
-                        configured_addr,
-                        idx,
-                        &write_entry,
-                        &timeout_entry,
-                    );
+                    fmmus.start(ctx, configured_addr, idx)?;
 
                     *self = Self::Fmmus(fmmus);
                 }
@@ -325,64 +311,36 @@ impl<'a> PreOpConfigState<'a> {
                 if let Some(res) = fmmus.update(
                     received,
                     header,
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
+                    ctx,
                     configured_addr,
                     idx,
                     subdev,
                     identifier,
                     config,
                     pdi_offset,
-                    &write_entry,
-                    &timeout_entry,
                 )? {
-                    let (input_len, output_len) = match res {
-                        FmmuMapping::Input => return Ok(Some(FmmuMapping::Input)),
+                    let (input_len, output_len, mailbox_status) = match res {
+                        FmmuMapping::Ready => return Ok(Some(Configured::Ready)),
+                        FmmuMapping::Input => return Ok(Some(Configured::Input)),
                         FmmuMapping::Output(len) => len,
                     };
 
                     let mut state = Transition::new(ethercrab::SubDeviceState::SafeOp);
-                    state.start(
-                        maindevice,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
-                        configured_addr,
-                        idx,
-                        &write_entry,
-                        &timeout_entry,
-                    )?;
+                    state.start(ctx, configured_addr, idx)?;
                     *self = Self::SafeOpTransition(
                         state,
                         SendRecvIo {
                             input_end: input_len,
                             output_end: output_len,
+                            mailbox_status,
                         },
                     );
+                    return Ok(Some(Configured::Output));
                 }
             }
-            Self::SafeOpTransition(transition, io) => {
-                if transition.update(
-                    received,
-                    header,
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    configured_addr,
-                    idx,
-                    &write_entry,
-                    &timeout_entry,
-                )? {
-                    return Ok(Some(FmmuMapping::Output(*io)));
+            Self::SafeOpTransition(transition, _) => {
+                if transition.update(received, header, ctx, configured_addr, idx)? {
+                    return Ok(Some(Configured::SafeOp));
                 }
             }
         }
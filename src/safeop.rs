@@ -1,113 +1,117 @@
-use crate::txbuf::TxBuf;
-use ethercrab::{
-    MainDevice, PduHeader, error::Error, received_frame::ReceivedPdu, std::RawSocketDesc,
-};
-use io_uring::{IoUring, types::Timespec};
-use std::collections::BTreeMap;
+use crate::concurrent::Concurrent;
+use crate::error::Error;
+use crate::io::IoCtx;
+use ethercrab::{PduHeader, received_frame::ReceivedPdu};
 
-use crate::state_transition::Transition;
+use crate::pdo::PdoConfig;
+use crate::preop::PreOpConfigState;
+use crate::state_transition::{BroadcastTransition, Transition};
 
 use heapless::Deque;
 
-pub struct SafeOp<const N: usize, U> {
-    subdevices: Deque<(U, Transition), N>,
-    transition_idx: u16,
+pub struct SafeOp<'a, const N: usize, const I: usize, const O: usize, U> {
+    // along with where the status of the read mailbox is in the pdi
+    subdevices: Deque<(U, &'a PdoConfig<'a, I, O>, Transition, Option<usize>), N>,
+    state: SafeOpState<N>,
 }
 
-impl<const N: usize, U: crate::user::UserDevice> SafeOp<N, U> {
-    #[allow(clippy::too_many_arguments)]
-    pub(crate) fn start_new<S1, S2>(
-        subdevs: Deque<(U, S1, S2), N>,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
+enum SafeOpState<const N: usize> {
+    // the transition is requested of each subdevice on its own
+    Concurrent(Concurrent),
+    // or of all of them at once, see `Driver::set_broadcast_transitions`
+    Broadcast(BroadcastTransition<N>),
+}
+
+impl<'a, const N: usize, const I: usize, const O: usize, U: crate::user::UserDevice>
+    SafeOp<'a, N, I, O, U>
+{
+    pub(crate) fn start_new(
+        subdevs: Deque<(U, &'a PdoConfig<'a, I, O>, PreOpConfigState<'_>), N>,
+        ctx: &mut IoCtx,
+        concurrency: u16,
+        broadcast: bool,
     ) -> Result<Self, Error> {
         let mut devs = Deque::new();
-        for (subdev, _, _) in subdevs.into_iter() {
+        for (subdev, config, preop) in subdevs.into_iter() {
+            let mailbox_status = match preop {
+                PreOpConfigState::SafeOpTransition(_, io) => io.mailbox_status(),
+                _ => None,
+            };
+
             let state = Transition::new(ethercrab::SubDeviceState::Op);
-            let _ = devs.push_back((subdev, state));
+            let _ = devs.push_back((subdev, config, state, mailbox_status));
+        }
+
+        if devs.is_empty() {
+            return Err(Error::NoSubDevices);
         }
 
-        let (subdev, state) = devs.front_mut().unwrap();
-
-        state.start(
-            maindevice,
-            retry_count,
-            timeout_duration,
-            tx_entries,
-            sock,
-            ring,
-            subdev.subdevice().configured_address(),
-            0,
-            write_entry,
-            timeout_entry,
-        )?;
+        if broadcast {
+            let addrs = devs
+                .iter()
+                .map(|(subdev, _, _, _)| subdev.subdevice().configured_address());
+            let mut broadcast = BroadcastTransition::new(ethercrab::SubDeviceState::Op, addrs);
+            broadcast.start(ctx)?;
+
+            return Ok(Self {
+                subdevices: devs,
+                state: SafeOpState::Broadcast(broadcast),
+            });
+        }
+
+        let (concurrent, start) = Concurrent::new(devs.len() as _, concurrency);
+        for idx in start {
+            let (subdev, _, state, _) = devs
+                .get_mut(usize::from(idx))
+                .ok_or(Error::UnknownDevice(idx))?;
+            state.start(ctx, subdev.subdevice().configured_address(), idx)?;
+        }
 
         Ok(Self {
             subdevices: devs,
-            transition_idx: 0,
+            state: SafeOpState::Concurrent(concurrent),
         })
     }
 
-    #[allow(clippy::too_many_arguments)]
+    #[allow(clippy::type_complexity)]
     pub(crate) fn update(
         &mut self,
         received: ReceivedPdu<'_>,
         header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         idx: Option<u16>,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
-    ) -> Result<Option<Deque<(U, Transition), N>>, Error> {
-        let idx = idx.unwrap() as usize;
-        let (dev, state) = self.subdevices.get_mut(idx).unwrap();
+    ) -> Result<Option<Deque<(U, &'a PdoConfig<'a, I, O>, Transition, Option<usize>), N>>, Error>
+    {
+        let concurrent = match &mut self.state {
+            SafeOpState::Concurrent(concurrent) => concurrent,
+            SafeOpState::Broadcast(broadcast) => {
+                if broadcast.update(received, header, ctx, idx)? {
+                    return Ok(Some(core::mem::take(&mut self.subdevices)));
+                }
+                return Ok(None);
+            }
+        };
+
+        let idx = idx.ok_or(Error::MissingIndex)? as usize;
+        let (dev, _, state, _) = self
+            .subdevices
+            .get_mut(idx)
+            .ok_or(Error::UnknownDevice(idx as _))?;
         let configured_addr = dev.subdevice().configured_address();
 
-        if state.update(
-            received,
-            header,
-            maindevice,
-            retry_count,
-            timeout_duration,
-            tx_entries,
-            sock,
-            ring,
-            configured_addr,
-            idx as _,
-            &write_entry,
-            &timeout_entry,
-        )? {
-            self.transition_idx += 1;
-
-            if usize::from(self.transition_idx) != self.subdevices.len() {
-                let (subdev, state) = self.subdevices.get_mut(self.transition_idx as _).unwrap();
-
-                state.start(
-                    maindevice,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    subdev.subdevice().configured_address(),
-                    self.transition_idx as _,
-                    &write_entry,
-                    &timeout_entry,
-                )?;
+        if state.update(received, header, ctx, configured_addr, idx as _)? {
+            if let Some(next) = concurrent.finish() {
+                let (subdev, _, state, _) = self
+                    .subdevices
+                    .get_mut(usize::from(next))
+                    .ok_or(Error::UnknownDevice(next))?;
 
-                return Ok(None);
+                state.start(ctx, subdev.subdevice().configured_address(), next)?;
+            }
+
+            if concurrent.done() {
+                return Ok(Some(core::mem::take(&mut self.subdevices)));
             }
-            return Ok(Some(core::mem::take(&mut self.subdevices)));
         }
         Ok(None)
     }
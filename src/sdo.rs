@@ -1,286 +1,1054 @@
-use crate::txbuf::TxBuf;
-use ethercrab::{
-    EtherCrabWireSized, MainDevice, PduHeader, error::Error, received_frame::ReceivedPdu,
-    std::RawSocketDesc,
-};
-use io_uring::{IoUring, types::Timespec};
-use std::collections::BTreeMap;
+use crate::error::Error;
+use crate::io::IoCtx;
+use ethercrab::{PduHeader, received_frame::ReceivedPdu};
 
+use crate::coe::{self, SdoResponse};
 use crate::mbx::MbxWriteRead;
 
+// with complete access, subindex 0 is padded out to 16 bits in front of the entries
+const COMPLETE_HEADER_LEN: usize = 2;
+
+// objects have at most 255 entries after subindex 0
+const MAX_ENTRIES: usize = u8::MAX as usize;
+
+fn upload_request(counter: u8, index: u16, subindex: u8, complete_access: bool) -> coe::MbxBuf {
+    let command = (coe::CCS_INITIATE_UPLOAD << 5) | (u8::from(complete_access) << 4);
+    coe::sdo_request(counter, command, index, subindex, &[0; 4])
+}
+
 pub struct SdoRead<T> {
-    inner: MbxWriteRead<ethercrab::coe::services::SdoNormal>,
-    finished: bool,
+    index: u16,
+    counter: u8,
+    // complete size of the current transfer, given by the device when initiating the upload
+    size: usize,
+    // where the data of the current transfer starts
+    offset: usize,
+    data: smallvec::SmallVec<[u8; 64]>,
+    complete: Option<CompleteRead>,
+    state: SdoReadState,
     ty: core::marker::PhantomData<T>,
 }
 
+enum SdoReadState {
+    Initiate(MbxWriteRead),
+    // the data did not fit into a single mailbox, the rest is requested segment by segment
+    Segment { inner: MbxWriteRead, toggle: bool },
+    Done,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum CompleteRead {
+    // the whole object in a single transfer
+    Access,
+    // no complete access, subindex 0 is read for the number of entries
+    Count,
+    // and then every entry on its own
+    Entry { subindex: u8, count: u8 },
+}
+
 impl<T> SdoRead<T> {
     pub fn finished(&self) -> bool {
-        self.finished
+        matches!(self.state, SdoReadState::Done)
     }
 }
 
 impl<T: ethercrab::EtherCrabWireReadSized> SdoRead<T> {
     pub fn new(mailbox_count: u8, index: u16, subindex: impl Into<ethercrab::SubIndex>) -> Self {
-        let req = ethercrab::coe::services::upload(mailbox_count, index, subindex.into());
-        let inner = MbxWriteRead::new(req);
+        let (subindex, complete_access) = match subindex.into() {
+            ethercrab::SubIndex::Complete => (1, true),
+            ethercrab::SubIndex::Index(subindex) => (subindex, false),
+        };
 
+        let req = upload_request(mailbox_count, index, subindex, complete_access);
+        Self::with_request(mailbox_count, index, &req, None)
+    }
+
+    // reads every entry after subindex 0 of `index` in one go, subindex 0 itself is not part of
+    // the data. if the subdevice does not support complete access, the entries are read one by one
+    pub fn complete(subdev: &ethercrab::SubDevice, index: u16) -> Self {
+        let counter = subdev.mailbox_counter();
+        let complete_access = subdev.config.mailbox.complete_access;
+
+        let req = upload_request(counter, index, 0, complete_access);
+        let complete = if complete_access {
+            CompleteRead::Access
+        } else {
+            CompleteRead::Count
+        };
+        Self::with_request(counter, index, &req, Some(complete))
+    }
+
+    fn with_request(counter: u8, index: u16, req: &[u8], complete: Option<CompleteRead>) -> Self {
         Self {
-            inner,
-            finished: false,
+            index,
+            counter,
+            size: 0,
+            offset: 0,
+            data: smallvec::SmallVec::new(),
+            complete,
+            state: SdoReadState::Initiate(MbxWriteRead::new(req)),
             ty: core::marker::PhantomData,
         }
     }
 
-    #[allow(clippy::too_many_arguments)]
     pub fn start(
         &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         write_mbx: &ethercrab::Mailbox,
         read_mbx: &ethercrab::Mailbox,
         configured_addr: u16,
         identifier: Option<u8>,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<(), Error> {
-        self.inner.start(
-            maindevice,
-            retry_count,
-            timeout,
-            tx_entries,
-            sock,
-            ring,
+        self.initiate()?
+            .start(ctx, write_mbx, read_mbx, configured_addr, identifier, idx)
+    }
+
+    // the request that `start` sends, segments are requested by `update` on its own
+    fn initiate(&mut self) -> Result<&mut MbxWriteRead, Error> {
+        match &mut self.state {
+            SdoReadState::Initiate(inner) => Ok(inner),
+            _ => Err(Error::TransferFinished),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &mut self,
+        received: ReceivedPdu<'_>,
+        header: PduHeader,
+        ctx: &mut IoCtx,
+        write_mbx: &ethercrab::Mailbox,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<Option<T>, Error> {
+        let Some(data) = self.update_raw(
+            received,
+            header,
+            ctx,
             write_mbx,
             read_mbx,
             configured_addr,
             identifier,
             idx,
-            write_entry,
-            timeout_entry,
-        )
+        )?
+        else {
+            return Ok(None);
+        };
+
+        if data.len() > T::PACKED_LEN {
+            return Err(Error::SdoSize {
+                expected: T::PACKED_LEN,
+                received: data.len(),
+            });
+        }
+
+        use ethercrab::EtherCrabWireRead;
+        Ok(Some(T::unpack_from_slice(data)?))
     }
 
+    // same as `update`, but hands back the uploaded bytes as is
     #[allow(clippy::too_many_arguments)]
-    pub fn update(
+    pub fn update_raw(
         &mut self,
         received: ReceivedPdu<'_>,
         header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         write_mbx: &ethercrab::Mailbox,
         read_mbx: &ethercrab::Mailbox,
         configured_addr: u16,
         identifier: Option<u8>,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
-    ) -> Result<Option<T>, Error> {
-        if let Some((header, bytes)) = self.inner.update(
+    ) -> Result<Option<&[u8]>, Error> {
+        if !self.transfer(
             received,
             header,
-            maindevice,
-            retry_count,
-            timeout,
-            tx_entries,
-            sock,
-            ring,
+            ctx,
             write_mbx,
             read_mbx,
             configured_addr,
             identifier,
             idx,
-            write_entry,
-            timeout_entry,
         )? {
-            use ethercrab::EtherCrabWireRead;
-            let payload = if header.sdo_header.expedited_transfer {
-                let len = 4usize.saturating_sub(usize::from(header.sdo_header.size));
-                &bytes[..len]
-            } else {
-                let len = header.header.length.saturating_sub(0x0a);
-                let size = u32::unpack_from_slice(&bytes).unwrap();
-
-                let data = bytes.get(u32::PACKED_LEN..).unwrap();
-                if size > bytes.len() as u32 {
-                    todo!("bad size: {size:?}");
-                } else if size < len as u32 {
-                    todo!("bad len");
-                }
+            return Ok(None);
+        }
 
-                data.get(..(len as usize)).unwrap()
-            };
+        match self.complete {
+            Some(CompleteRead::Access) => {
+                if self.data.len() < COMPLETE_HEADER_LEN {
+                    return Err(Error::InvalidMailbox);
+                }
+                self.data.drain(..COMPLETE_HEADER_LEN);
+            }
+            Some(CompleteRead::Count) => {
+                let count = *self.data.get(self.offset).ok_or(Error::InvalidMailbox)?;
+                self.data.truncate(self.offset);
 
-            let data = T::unpack_from_slice(payload)?;
-            self.finished = true;
-            return Ok(Some(data));
+                if count > 0 {
+                    self.start_entry(
+                        ctx,
+                        write_mbx,
+                        read_mbx,
+                        configured_addr,
+                        identifier,
+                        idx,
+                        1,
+                        count,
+                    )?;
+                    return Ok(None);
+                }
+            }
+            Some(CompleteRead::Entry { subindex, count }) if subindex < count => {
+                self.start_entry(
+                    ctx,
+                    write_mbx,
+                    read_mbx,
+                    configured_addr,
+                    identifier,
+                    idx,
+                    subindex + 1,
+                    count,
+                )?;
+                return Ok(None);
+            }
+            Some(CompleteRead::Entry { .. }) | None => (),
         }
-        Ok(None)
+
+        self.state = SdoReadState::Done;
+        Ok(Some(&self.data))
     }
 
+    // how much of the current transfer has been received so far
+    fn received(&self) -> usize {
+        self.data.len() - self.offset
+    }
+
+    // returns true once the current transfer is complete
     #[allow(clippy::too_many_arguments)]
-    pub fn update_raw<'p>(
+    fn transfer(
         &mut self,
-        received: ReceivedPdu<'p>,
+        received: ReceivedPdu<'_>,
         header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         write_mbx: &ethercrab::Mailbox,
         read_mbx: &ethercrab::Mailbox,
         configured_addr: u16,
         identifier: Option<u8>,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
-    ) -> Result<Option<(ethercrab::coe::services::SdoNormal, ReceivedPdu<'p>)>, Error> {
-        self.inner.update(
-            received,
-            header,
-            maindevice,
-            retry_count,
-            timeout,
-            tx_entries,
-            sock,
-            ring,
+    ) -> Result<bool, Error> {
+        let (bytes, toggle) = match &mut self.state {
+            SdoReadState::Initiate(inner) => (
+                inner.update(
+                    received,
+                    header,
+                    ctx,
+                    write_mbx,
+                    read_mbx,
+                    configured_addr,
+                    identifier,
+                    idx,
+                )?,
+                None,
+            ),
+            SdoReadState::Segment { inner, toggle } => (
+                inner.update(
+                    received,
+                    header,
+                    ctx,
+                    write_mbx,
+                    read_mbx,
+                    configured_addr,
+                    identifier,
+                    idx,
+                )?,
+                Some(*toggle),
+            ),
+            SdoReadState::Done => return Ok(false),
+        };
+
+        let Some(bytes) = bytes else {
+            return Ok(false);
+        };
+        let Some((req, toggle)) = self.receive(&bytes, toggle)? else {
+            return Ok(true);
+        };
+
+        let mut inner = MbxWriteRead::new(&req);
+        inner.start(
+            ctx,
             write_mbx,
             read_mbx,
             configured_addr,
-            identifier,
+            identifier.map(|id| id >> 2),
             idx,
-            write_entry,
-            timeout_entry,
-        )
+        )?;
+        self.state = SdoReadState::Segment { inner, toggle };
+        Ok(false)
+    }
+
+    // takes in the response to the initiate request, or to the segment with `toggle`.
+    // returns the request for the next segment along with its toggle, if there is more to come
+    fn receive(
+        &mut self,
+        bytes: &[u8],
+        toggle: Option<bool>,
+    ) -> Result<Option<(coe::MbxBuf, bool)>, Error> {
+        let res = SdoResponse::parse(bytes)?;
+
+        let last = match toggle {
+            None => {
+                if res.specifier() != coe::SCS_INITIATE_UPLOAD {
+                    return Err(Error::UnexpectedSdoCommand(res.command));
+                }
+
+                // skip over the index and subindex
+                let body = res.body.get(3..).ok_or(Error::InvalidMailbox)?;
+
+                if res.command & 0b10 != 0 {
+                    // expedited, the size is only given if the size indicator is set
+                    let size = if res.command & 0b01 != 0 {
+                        4 - usize::from((res.command >> 2) & 0b11)
+                    } else {
+                        4
+                    };
+                    let data = body.get(..size).ok_or(Error::InvalidMailbox)?;
+
+                    self.size = size;
+                    self.data.extend_from_slice(data);
+                    true
+                } else {
+                    let (size, data) =
+                        body.split_first_chunk::<4>().ok_or(Error::InvalidMailbox)?;
+
+                    self.size = u32::from_le_bytes(*size) as usize;
+                    let len = core::cmp::min(self.size, data.len());
+                    self.data.extend_from_slice(&data[..len]);
+                    self.received() == self.size
+                }
+            }
+            Some(toggle) => {
+                if res.specifier() != coe::SCS_UPLOAD_SEGMENT || res.toggle() != toggle {
+                    return Err(Error::UnexpectedSdoCommand(res.command));
+                }
+
+                let unused = usize::from((res.command >> 1) & 0b111);
+                let len = res.body.len().saturating_sub(unused);
+                let len = core::cmp::min(len, self.size - self.received());
+
+                self.data.extend_from_slice(&res.body[..len]);
+                res.command & 0b1 != 0 || self.received() == self.size
+            }
+        };
+
+        if last {
+            return Ok(None);
+        }
+
+        let toggle = toggle.map(|toggle| !toggle).unwrap_or(false);
+        let command = (coe::CCS_UPLOAD_SEGMENT << 5) | (u8::from(toggle) << 4);
+
+        self.counter = coe::next_counter(self.counter);
+        Ok(Some((coe::sdo_segment(self.counter, command, &[]), toggle)))
+    }
+
+    // reads the next entry of a complete read without complete access
+    #[allow(clippy::too_many_arguments)]
+    fn start_entry(
+        &mut self,
+        ctx: &mut IoCtx,
+        write_mbx: &ethercrab::Mailbox,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+        subindex: u8,
+        count: u8,
+    ) -> Result<(), Error> {
+        self.counter = coe::next_counter(self.counter);
+        self.offset = self.data.len();
+        self.size = 0;
+        self.complete = Some(CompleteRead::Entry { subindex, count });
+
+        let req = upload_request(self.counter, self.index, subindex, false);
+        let mut inner = MbxWriteRead::new(&req);
+        inner.start(
+            ctx,
+            write_mbx,
+            read_mbx,
+            configured_addr,
+            identifier.map(|id| id >> 2),
+            idx,
+        )?;
+        self.state = SdoReadState::Initiate(inner);
+        Ok(())
     }
 }
 
+crate::mbx_queue::mailbox_transfer!(
+    impl[T: ethercrab::EtherCrabWireReadSized] for SdoRead<T>,
+    |res| update_raw => res.is_some(),
+    |this| crate::mbx_queue::MailboxResponse::Data(&this.data),
+);
+
 #[derive(Debug)]
 pub struct SdoWrite<T> {
-    inner: MbxWriteRead<ethercrab::coe::services::SdoExpeditedDownload>,
+    index: u16,
+    // subindex, access and data of the current transfer
+    subindex: u8,
+    complete_access: bool,
+    counter: u8,
+    data: smallvec::SmallVec<[u8; 8]>,
+    complete: Option<CompleteWrite>,
+    state: SdoWriteState,
     ty: core::marker::PhantomData<T>,
 }
 
+#[derive(Debug)]
+enum SdoWriteState {
+    Idle,
+    // expedited or normal download, `offset` is how much of the data fit into the request
+    Initiate(MbxWriteRead, usize),
+    // segmented download for whatever did not fit in the initiate request
+    Segment {
+        inner: MbxWriteRead,
+        offset: usize,
+        toggle: bool,
+    },
+    Done,
+}
+
+// a complete write to a subdevice without complete access, written one subindex at a time
+#[derive(Debug)]
+struct CompleteWrite {
+    entries: smallvec::SmallVec<[u8; 8]>,
+    entry_len: usize,
+    count: u8,
+    step: CompleteStep,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum CompleteStep {
+    // subindex 0 is cleared first so the device accepts the new entries
+    Clear,
+    Entry(u8),
+    SetCount,
+}
+
 impl<T: ethercrab::EtherCrabWireWrite + std::fmt::Debug> SdoWrite<T> {
     pub fn new(
         subdev: &ethercrab::SubDevice,
         index: u16,
         subindex: impl Into<ethercrab::SubIndex>,
         data: T,
-    ) -> Self {
-        if data.packed_len() > 4 {
-            todo!("support for larger writes");
-        }
-
-        let subindex = subindex.into();
+    ) -> Result<Self, Error> {
+        let (subindex, complete_access) = match subindex.into() {
+            ethercrab::SubIndex::Complete => (1, true),
+            ethercrab::SubIndex::Index(subindex) => (subindex, false),
+        };
 
-        let mut buf = [0u8; 4];
-        data.pack_to_slice(&mut buf).unwrap();
+        let mut buf = smallvec::smallvec![0; data.packed_len()];
+        data.pack_to_slice(&mut buf)?;
 
-        let req = ethercrab::coe::services::download(
+        Ok(Self::with_data(
             subdev.mailbox_counter(),
             index,
             subindex,
+            complete_access,
             buf,
-            data.packed_len() as u8,
-        );
-        let inner = MbxWriteRead::new(req);
+            None,
+        ))
+    }
 
+    fn with_data(
+        counter: u8,
+        index: u16,
+        subindex: u8,
+        complete_access: bool,
+        data: smallvec::SmallVec<[u8; 8]>,
+        complete: Option<CompleteWrite>,
+    ) -> Self {
         Self {
-            inner,
+            index,
+            subindex,
+            complete_access,
+            counter,
+            data,
+            complete,
+            state: SdoWriteState::Idle,
             ty: core::marker::PhantomData,
         }
     }
 
-    #[allow(clippy::too_many_arguments)]
+    // writes `entries` from subindex 1 onwards and their count to subindex 0 in one go,
+    // eg. a whole pdo mapping. if the subdevice does not support complete access,
+    // the entries are written one by one instead, so they all have to be the same size
+    pub fn complete(
+        subdev: &ethercrab::SubDevice,
+        index: u16,
+        entries: &[T],
+    ) -> Result<Self, Error> {
+        if entries.len() > MAX_ENTRIES {
+            return Err(Error::SdoEntryCount(entries.len()));
+        }
+        let count = entries.len() as u8;
+        let complete_access = subdev.config.mailbox.complete_access;
+
+        let entry_len = entries.first().map_or(0, |entry| entry.packed_len());
+        if let Some(entry) = entries.iter().find(|entry| entry.packed_len() != entry_len) {
+            return Err(Error::SdoEntrySize {
+                expected: entry_len,
+                received: entry.packed_len(),
+            });
+        }
+
+        let mut buf = smallvec::SmallVec::<[u8; 8]>::new();
+        if complete_access {
+            buf.extend_from_slice(&[count, 0]);
+        }
+
+        for entry in entries {
+            let start = buf.len();
+            buf.resize(start + entry.packed_len(), 0);
+            entry.pack_to_slice(&mut buf[start..])?;
+        }
+
+        let (data, complete) = if complete_access {
+            (buf, None)
+        } else {
+            let complete = CompleteWrite {
+                entry_len,
+                entries: buf,
+                count,
+                step: CompleteStep::Clear,
+            };
+            (smallvec::smallvec![0], Some(complete))
+        };
+
+        Ok(Self::with_data(
+            subdev.mailbox_counter(),
+            index,
+            0,
+            complete_access,
+            data,
+            complete,
+        ))
+    }
+
+    pub fn finished(&self) -> bool {
+        matches!(self.state, SdoWriteState::Done)
+    }
+
     pub fn start(
         &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         write_mbx: &ethercrab::Mailbox,
         read_mbx: &ethercrab::Mailbox,
         configured_addr: u16,
         identifier: Option<u8>,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<(), Error> {
-        self.inner.start(
-            maindevice,
-            retry_count,
-            timeout,
-            tx_entries,
-            sock,
-            ring,
-            write_mbx,
-            read_mbx,
-            configured_addr,
-            identifier,
-            idx,
-            write_entry,
-            timeout_entry,
-        )
+        let (req, offset) = self.initiate_request(write_mbx.len)?;
+
+        let mut inner = MbxWriteRead::new(&req);
+        inner.start(ctx, write_mbx, read_mbx, configured_addr, identifier, idx)?;
+        self.state = SdoWriteState::Initiate(inner, offset);
+        Ok(())
+    }
+
+    // the initiate request of the current transfer, along with how much of the data it holds
+    fn initiate_request(&self, mbx_len: u16) -> Result<(coe::MbxBuf, usize), Error> {
+        if self.finished() {
+            return Err(Error::TransferFinished);
+        }
+
+        let ca = u8::from(self.complete_access) << 4;
+        let len = self.data.len();
+
+        // expedited writes carry 1 to 4 bytes, empty data goes out as a normal download of size 0
+        let (req, offset) = if (1..=4).contains(&len) {
+            // expedited, the data is stored in the request itself
+            let command = (coe::CCS_INITIATE_DOWNLOAD << 5) | ca | ((4 - len as u8) << 2) | 0b11;
+            let mut data = [0; 4];
+            data[..len].copy_from_slice(&self.data);
+
+            let req = coe::sdo_request(self.counter, command, self.index, self.subindex, &data);
+            (req, len)
+        } else {
+            // normal, with the complete size up front and as much data as the mailbox can hold
+            let max_len = usize::from(mbx_len).saturating_sub(
+                coe::MBX_HEADER_LEN + coe::COE_HEADER_LEN + coe::SDO_HEADER_LEN + 4,
+            );
+            if max_len == 0 {
+                return Err(Error::InvalidMailbox);
+            }
+
+            let command = (coe::CCS_INITIATE_DOWNLOAD << 5) | ca | 0b01;
+            let offset = core::cmp::min(len, max_len);
+
+            let mut data = coe::MbxBuf::new();
+            data.extend_from_slice(&(len as u32).to_le_bytes());
+            data.extend_from_slice(&self.data[..offset]);
+
+            let req = coe::sdo_request(self.counter, command, self.index, self.subindex, &data);
+            (req, offset)
+        };
+        Ok((req, offset))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn start_segment(
+        &mut self,
+        ctx: &mut IoCtx,
+        write_mbx: &ethercrab::Mailbox,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+        offset: usize,
+        toggle: bool,
+    ) -> Result<(), Error> {
+        let (req, offset) = self.segment_request(write_mbx.len, offset, toggle)?;
+
+        let mut inner = MbxWriteRead::new(&req);
+        inner.start(ctx, write_mbx, read_mbx, configured_addr, identifier, idx)?;
+        self.state = SdoWriteState::Segment {
+            inner,
+            offset,
+            toggle,
+        };
+        Ok(())
     }
 
+    // the segment starting at `offset`, along with where the next one starts
+    fn segment_request(
+        &mut self,
+        mbx_len: u16,
+        offset: usize,
+        toggle: bool,
+    ) -> Result<(coe::MbxBuf, usize), Error> {
+        let max_len =
+            usize::from(mbx_len).saturating_sub(coe::MBX_HEADER_LEN + coe::COE_HEADER_LEN + 1);
+        if max_len == 0 {
+            return Err(Error::InvalidMailbox);
+        }
+
+        let segment = &self.data[offset..core::cmp::min(self.data.len(), offset + max_len)];
+        let last = offset + segment.len() == self.data.len();
+
+        // unused bytes are only given for segments shorter than the minimum
+        let unused = coe::SDO_SEGMENT_MIN_DATA.saturating_sub(segment.len()) as u8;
+        let command = (coe::CCS_DOWNLOAD_SEGMENT << 5)
+            | (u8::from(toggle) << 4)
+            | (unused << 1)
+            | u8::from(last);
+
+        let end = offset + segment.len();
+        self.counter = coe::next_counter(self.counter);
+        Ok((coe::sdo_segment(self.counter, command, segment), end))
+    }
+
+    // returns true once the device acknowledged the whole write
     #[allow(clippy::too_many_arguments)]
-    pub fn update<'p>(
+    pub fn update(
         &mut self,
-        received: ReceivedPdu<'p>,
+        received: ReceivedPdu<'_>,
         header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         write_mbx: &ethercrab::Mailbox,
         read_mbx: &ethercrab::Mailbox,
         configured_addr: u16,
         identifier: Option<u8>,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
-    ) -> Result<
-        Option<(
-            ethercrab::coe::services::SdoExpeditedDownload,
-            ReceivedPdu<'p>,
-        )>,
-        Error,
-    > {
-        if let Some((header, bytes)) = self.inner.update(
-            received,
-            header,
-            maindevice,
-            retry_count,
-            timeout,
-            tx_entries,
-            sock,
-            ring,
+    ) -> Result<bool, Error> {
+        match &mut self.state {
+            SdoWriteState::Initiate(inner, offset) => {
+                let Some(bytes) = inner.update(
+                    received,
+                    header,
+                    ctx,
+                    write_mbx,
+                    read_mbx,
+                    configured_addr,
+                    identifier,
+                    idx,
+                )?
+                else {
+                    return Ok(false);
+                };
+
+                acknowledged(&bytes, None)?;
+
+                let offset = *offset;
+                if offset == self.data.len() {
+                    return self.next_transfer(
+                        ctx,
+                        write_mbx,
+                        read_mbx,
+                        configured_addr,
+                        identifier,
+                        idx,
+                    );
+                }
+
+                self.start_segment(
+                    ctx,
+                    write_mbx,
+                    read_mbx,
+                    configured_addr,
+                    identifier.map(|id| id >> 2),
+                    idx,
+                    offset,
+                    false,
+                )?;
+            }
+            SdoWriteState::Segment {
+                inner,
+                offset,
+                toggle,
+            } => {
+                let Some(bytes) = inner.update(
+                    received,
+                    header,
+                    ctx,
+                    write_mbx,
+                    read_mbx,
+                    configured_addr,
+                    identifier,
+                    idx,
+                )?
+                else {
+                    return Ok(false);
+                };
+
+                acknowledged(&bytes, Some(*toggle))?;
+
+                let (offset, toggle) = (*offset, !*toggle);
+                if offset == self.data.len() {
+                    return self.next_transfer(
+                        ctx,
+                        write_mbx,
+                        read_mbx,
+                        configured_addr,
+                        identifier,
+                        idx,
+                    );
+                }
+
+                self.start_segment(
+                    ctx,
+                    write_mbx,
+                    read_mbx,
+                    configured_addr,
+                    identifier.map(|id| id >> 2),
+                    idx,
+                    offset,
+                    toggle,
+                )?;
+            }
+            SdoWriteState::Idle | SdoWriteState::Done => (),
+        }
+        Ok(false)
+    }
+
+    // moves on to the next subindex of a complete write without complete access,
+    // returns true once there is nothing left to write
+    fn next_transfer(
+        &mut self,
+        ctx: &mut IoCtx,
+        write_mbx: &ethercrab::Mailbox,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<bool, Error> {
+        let Some(complete) = &mut self.complete else {
+            self.state = SdoWriteState::Done;
+            return Ok(true);
+        };
+
+        complete.step = match complete.step {
+            CompleteStep::Clear if complete.count > 0 => CompleteStep::Entry(1),
+            CompleteStep::Entry(subindex) if subindex < complete.count => {
+                CompleteStep::Entry(subindex + 1)
+            }
+            CompleteStep::Entry(_) => CompleteStep::SetCount,
+            CompleteStep::Clear | CompleteStep::SetCount => {
+                self.state = SdoWriteState::Done;
+                return Ok(true);
+            }
+        };
+
+        self.data.clear();
+        match complete.step {
+            CompleteStep::Entry(subindex) => {
+                let start = usize::from(subindex - 1) * complete.entry_len;
+                self.data
+                    .extend_from_slice(&complete.entries[start..start + complete.entry_len]);
+                self.subindex = subindex;
+            }
+            CompleteStep::Clear | CompleteStep::SetCount => {
+                self.data.push(complete.count);
+                self.subindex = 0;
+            }
+        }
+
+        self.counter = coe::next_counter(self.counter);
+        self.start(
+            ctx,
             write_mbx,
             read_mbx,
             configured_addr,
-            identifier,
+            identifier.map(|id| id >> 2),
             idx,
-            write_entry,
-            timeout_entry,
-        )? {
-            return Ok(Some((header, bytes)));
+        )?;
+        Ok(false)
+    }
+}
+
+// checks that `bytes` answer the initiate download, or the segment with `toggle`
+fn acknowledged(bytes: &[u8], toggle: Option<bool>) -> Result<(), Error> {
+    let res = SdoResponse::parse(bytes)?;
+    let expected = match toggle {
+        None => coe::SCS_INITIATE_DOWNLOAD,
+        Some(_) => coe::SCS_DOWNLOAD_SEGMENT,
+    };
+
+    if res.specifier() != expected || toggle.is_some_and(|toggle| res.toggle() != toggle) {
+        return Err(Error::UnexpectedSdoCommand(res.command));
+    }
+    Ok(())
+}
+
+crate::mbx_queue::mailbox_transfer!(
+    impl[T: ethercrab::EtherCrabWireWrite + std::fmt::Debug] for SdoWrite<T>,
+    |done| update => done,
+    |_| crate::mbx_queue::MailboxResponse::Done,
+);
+
+// sent by the device instead of a response when it refuses an sdo transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SdoAbort {
+    pub index: u16,
+    pub subindex: u8,
+    pub code: u32,
+}
+
+impl SdoAbort {
+    // abort codes as listed in ETG.1000.6
+    pub fn description(&self) -> &'static str {
+        match self.code {
+            0x0503_0000 => "toggle bit not changed",
+            0x0504_0000 => "sdo protocol timeout",
+            0x0504_0001 => "client/server command specifier not valid or unknown",
+            0x0504_0005 => "out of memory",
+            0x0601_0000 => "unsupported access to an object",
+            0x0601_0001 => "attempt to read a write only object",
+            0x0601_0002 => "attempt to write a read only object",
+            0x0601_0003 => "subindex cannot be written, subindex 0 must be 0 for write access",
+            0x0601_0004 => "complete access not supported for objects of variable length",
+            0x0601_0005 => "object length exceeds mailbox size",
+            0x0601_0006 => "object mapped to rxpdo, sdo download blocked",
+            0x0602_0000 => "object does not exist in the object directory",
+            0x0604_0041 => "object cannot be mapped into the pdo",
+            0x0604_0042 => {
+                "number and length of the objects to be mapped would exceed the pdo length"
+            }
+            0x0604_0043 => "general parameter incompatibility",
+            0x0604_0047 => "general internal incompatibility in the device",
+            0x0606_0000 => "access failed due to a hardware error",
+            0x0607_0010 => "data type does not match, length of service parameter does not match",
+            0x0607_0012 => "data type does not match, length of service parameter too high",
+            0x0607_0013 => "data type does not match, length of service parameter too low",
+            0x0609_0011 => "subindex does not exist",
+            0x0609_0030 => "value range of parameter exceeded",
+            0x0609_0031 => "value of parameter written too high",
+            0x0609_0032 => "value of parameter written too low",
+            0x0609_0036 => "maximum value is less than minimum value",
+            0x0800_0000 => "general error",
+            0x0800_0020 => "data cannot be transferred or stored to the application",
+            0x0800_0021 => {
+                "data cannot be transferred or stored to the application because of local control"
+            }
+            0x0800_0022 => {
+                "data cannot be transferred or stored to the application because of the present device state"
+            }
+            0x0800_0023 => {
+                "object dictionary dynamic generation failed or no object dictionary present"
+            }
+            _ => "unknown abort code",
         }
-        Ok(None)
+    }
+}
+
+impl core::fmt::Display for SdoAbort {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "sdo {:#06x}:{} aborted with {:#010x}: {}",
+            self.index,
+            self.subindex,
+            self.code,
+            self.description()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a response from the subdevice at 0x1001, padded out to the size of its read mailbox
+    fn response(coe: &[u8]) -> [u8; 32] {
+        let mut bytes = [0xEE; 32];
+        bytes[..2].copy_from_slice(&(coe.len() as u16).to_le_bytes());
+        bytes[2..4].copy_from_slice(&0x1001u16.to_le_bytes());
+        bytes[4] = 0;
+        bytes[5] = coe::MBX_TYPE_COE | (2 << 4);
+        bytes[6..6 + coe.len()].copy_from_slice(coe);
+        bytes
+    }
+
+    // where the sdo command byte is in a request
+    const COMMAND: usize = coe::MBX_HEADER_LEN + coe::COE_HEADER_LEN;
+
+    #[test]
+    fn read_expedited() {
+        let mut read = SdoRead::<u16>::new(1, 0x6041, 0u8);
+
+        // 2 bytes, size indicated
+        let bytes = response(&[0x00, 0x30, 0x4B, 0x41, 0x60, 0x00, 0x37, 0x06, 0x00, 0x00]);
+        assert!(read.receive(&bytes, None).unwrap().is_none());
+        assert_eq!(read.data.as_slice(), &[0x37, 0x06]);
+    }
+
+    #[test]
+    fn read_segmented() {
+        let mut read = SdoRead::<u32>::new(1, 0x1008, 0u8);
+
+        // normal upload of 10 bytes, none of which are in the initiate response
+        let bytes = response(&[0x00, 0x30, 0x41, 0x08, 0x10, 0x00, 0x0A, 0x00, 0x00, 0x00]);
+        let (req, toggle) = read.receive(&bytes, None).unwrap().unwrap();
+        assert!(!toggle);
+        assert_eq!(req[COMMAND], coe::CCS_UPLOAD_SEGMENT << 5);
+        assert_eq!(coe::counter(&req), Some(2));
+
+        // 7 bytes, more to come
+        let bytes = response(&[0x00, 0x30, 0x00, 1, 2, 3, 4, 5, 6, 7]);
+        let (req, toggle) = read.receive(&bytes, Some(false)).unwrap().unwrap();
+        assert!(toggle);
+        assert_eq!(req[COMMAND], (coe::CCS_UPLOAD_SEGMENT << 5) | 0x10);
+
+        // the last 3 bytes, 4 unused
+        let bytes = response(&[0x00, 0x30, 0x19, 8, 9, 10, 0, 0, 0, 0]);
+        assert!(read.receive(&bytes, Some(true)).unwrap().is_none());
+        assert_eq!(read.data.as_slice(), &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+    }
+
+    #[test]
+    fn read_segment_toggle_mismatch() {
+        let mut read = SdoRead::<u32>::new(1, 0x1008, 0u8);
+        read.size = 10;
+
+        let bytes = response(&[0x00, 0x30, 0x10, 1, 2, 3, 4, 5, 6, 7]);
+        assert!(matches!(
+            read.receive(&bytes, Some(false)),
+            Err(Error::UnexpectedSdoCommand(0x10))
+        ));
+    }
+
+    #[test]
+    fn read_start_after_done() {
+        let mut read = SdoRead::<u16>::new(1, 0x6041, 0u8);
+        assert!(read.initiate().is_ok());
+
+        read.state = SdoReadState::Done;
+        assert!(matches!(read.initiate(), Err(Error::TransferFinished)));
+    }
+
+    #[test]
+    fn write_expedited() {
+        let data = smallvec::smallvec![0x0F, 0x00];
+        let write = SdoWrite::<u16>::with_data(1, 0x6040, 0, false, data, None);
+
+        let (req, offset) = write.initiate_request(32).unwrap();
+        assert_eq!(offset, 2);
+        // 2 of 4 bytes used, expedited, size indicated
+        assert_eq!(req[COMMAND], 0x2B);
+        assert_eq!(req[COMMAND + 1..COMMAND + 4], [0x40, 0x60, 0x00]);
+        assert_eq!(req[COMMAND + 4..], [0x0F, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn write_empty_is_not_expedited() {
+        let write = SdoWrite::<u16>::with_data(1, 0x6040, 0, false, Default::default(), None);
+
+        let (req, offset) = write.initiate_request(32).unwrap();
+        assert_eq!(offset, 0);
+        // normal download, size indicated, no complete access
+        assert_eq!(req[COMMAND], 0x21);
+        assert_eq!(req[COMMAND + 4..], 0u32.to_le_bytes());
+    }
+
+    #[test]
+    fn write_segmented() {
+        let data: smallvec::SmallVec<[u8; 8]> = (1..=20).collect();
+        let mut write = SdoWrite::<u32>::with_data(1, 0x2000, 1, false, data, None);
+
+        // room for 8 bytes after the size
+        let (req, offset) = write.initiate_request(24).unwrap();
+        assert_eq!(offset, 8);
+        assert_eq!(req[COMMAND], 0x21);
+        assert_eq!(req[COMMAND + 4..COMMAND + 8], 20u32.to_le_bytes());
+        assert_eq!(req[COMMAND + 8..], [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        // the rest fits into a single segment, which is the last one
+        let (req, offset) = write.segment_request(24, offset, false).unwrap();
+        assert_eq!(offset, 20);
+        assert_eq!(req[COMMAND], 0x01);
+        assert_eq!(coe::counter(&req), Some(2));
+        assert_eq!(
+            req[COMMAND + 1..],
+            [9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20]
+        );
+    }
+
+    #[test]
+    fn write_short_segment_is_padded() {
+        let data: smallvec::SmallVec<[u8; 8]> = (1..=10).collect();
+        let mut write = SdoWrite::<u32>::with_data(1, 0x2000, 1, false, data, None);
+
+        let (req, offset) = write.segment_request(32, 8, true).unwrap();
+        assert_eq!(offset, 10);
+        // toggle, 5 unused bytes, last
+        assert_eq!(req[COMMAND], 0x10 | (5 << 1) | 0x01);
+        assert_eq!(req[COMMAND + 1..], [9, 10, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn write_acknowledged() {
+        let initiate = response(&[0x00, 0x30, 0x60, 0x40, 0x60, 0x00, 0, 0, 0, 0]);
+        assert!(acknowledged(&initiate, None).is_ok());
+        assert!(acknowledged(&initiate, Some(false)).is_err());
+
+        let segment = response(&[0x00, 0x30, 0x30, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(acknowledged(&segment, Some(true)).is_ok());
+        assert!(matches!(
+            acknowledged(&segment, Some(false)),
+            Err(Error::UnexpectedSdoCommand(0x30))
+        ));
+    }
+
+    #[test]
+    fn write_start_after_done() {
+        let data = smallvec::smallvec![0x0F, 0x00];
+        let mut write = SdoWrite::<u16>::with_data(1, 0x6040, 0, false, data, None);
+
+        write.state = SdoWriteState::Done;
+        assert!(matches!(
+            write.initiate_request(32),
+            Err(Error::TransferFinished)
+        ));
     }
 }
@@ -0,0 +1,294 @@
+use crate::coe;
+use crate::error::Error;
+use crate::io::IoCtx;
+use crate::mbx::MbxWriteRead;
+use crate::sdo::SdoAbort;
+use ethercrab::{PduHeader, received_frame::ReceivedPdu};
+
+use std::borrow::Cow;
+
+// sdo information opcodes, the response to a request is always the request opcode + 1
+const GET_OD_LIST: u8 = 0x01;
+const GET_OBJECT_DESCRIPTION: u8 = 0x03;
+const GET_ENTRY_DESCRIPTION: u8 = 0x05;
+const INFO_ERROR: u8 = 0x07;
+
+// set on every fragment of a response that has more fragments following it
+const INCOMPLETE: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectListType {
+    // the number of objects in each of the lists below, in the same order
+    Lengths = 0,
+    All = 1,
+    RxPdoMappable = 2,
+    TxPdoMappable = 3,
+    // objects that are stored for device replacement
+    Backup = 4,
+    // objects that are used as startup parameters
+    Settings = 5,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Request {
+    ObjectList(ObjectListType),
+    Object(u16),
+    Entry(u16, u8),
+}
+
+impl Request {
+    fn opcode(&self) -> u8 {
+        match self {
+            Self::ObjectList(_) => GET_OD_LIST,
+            Self::Object(_) => GET_OBJECT_DESCRIPTION,
+            Self::Entry(..) => GET_ENTRY_DESCRIPTION,
+        }
+    }
+}
+
+// browses the object dictionary of a subdevice through the coe sdo information service
+pub struct SdoInfo {
+    request: Request,
+    data: smallvec::SmallVec<[u8; 64]>,
+    state: SdoInfoState,
+}
+
+enum SdoInfoState {
+    // waiting on the response, or on the next fragment of it
+    Pending(MbxWriteRead),
+    Done,
+}
+
+impl SdoInfo {
+    pub fn object_list(subdev: &ethercrab::SubDevice, ty: ObjectListType) -> Self {
+        Self::new(subdev, Request::ObjectList(ty))
+    }
+
+    pub fn object(subdev: &ethercrab::SubDevice, index: u16) -> Self {
+        Self::new(subdev, Request::Object(index))
+    }
+
+    pub fn entry(subdev: &ethercrab::SubDevice, index: u16, subindex: u8) -> Self {
+        Self::new(subdev, Request::Entry(index, subindex))
+    }
+
+    fn new(subdev: &ethercrab::SubDevice, request: Request) -> Self {
+        let mut data = coe::MbxBuf::new();
+        match request {
+            Request::ObjectList(ty) => data.extend_from_slice(&(ty as u16).to_le_bytes()),
+            Request::Object(index) => data.extend_from_slice(&index.to_le_bytes()),
+            Request::Entry(index, subindex) => {
+                data.extend_from_slice(&index.to_le_bytes());
+                // no value info, only the description itself is requested
+                data.extend_from_slice(&[subindex, 0]);
+            }
+        }
+
+        let req = coe::sdo_info_request(subdev.mailbox_counter(), request.opcode(), &data);
+
+        Self {
+            request,
+            data: smallvec::SmallVec::new(),
+            state: SdoInfoState::Pending(MbxWriteRead::new(&req)),
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        matches!(self.state, SdoInfoState::Done)
+    }
+
+    pub fn start(
+        &mut self,
+        ctx: &mut IoCtx,
+        write_mbx: &ethercrab::Mailbox,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<(), Error> {
+        match &mut self.state {
+            SdoInfoState::Pending(inner) => {
+                inner.start(ctx, write_mbx, read_mbx, configured_addr, identifier, idx)
+            }
+            SdoInfoState::Done => Err(Error::TransferFinished),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &mut self,
+        received: ReceivedPdu<'_>,
+        header: PduHeader,
+        ctx: &mut IoCtx,
+        write_mbx: &ethercrab::Mailbox,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<Option<SdoInfoResponse<'_>>, Error> {
+        let SdoInfoState::Pending(inner) = &mut self.state else {
+            return Ok(None);
+        };
+
+        let Some(bytes) = inner.update(
+            received,
+            header,
+            ctx,
+            write_mbx,
+            read_mbx,
+            configured_addr,
+            identifier,
+            idx,
+        )?
+        else {
+            return Ok(None);
+        };
+
+        let body = coe::coe_body(&bytes, coe::COE_SDO_INFO)?;
+        let (info_header, body) = body
+            .split_first_chunk::<{ coe::SDO_INFO_HEADER_LEN }>()
+            .ok_or(Error::InvalidMailbox)?;
+
+        let opcode = info_header[0] & !INCOMPLETE;
+        if opcode == INFO_ERROR {
+            let code = body.first_chunk::<4>().ok_or(Error::InvalidMailbox)?;
+            let (index, subindex) = match self.request {
+                Request::ObjectList(_) => (0, 0),
+                Request::Object(index) => (index, 0),
+                Request::Entry(index, subindex) => (index, subindex),
+            };
+
+            return Err(Error::SdoAbort(SdoAbort {
+                index,
+                subindex,
+                code: u32::from_le_bytes(*code),
+            }));
+        }
+
+        if opcode != self.request.opcode() + 1 {
+            return Err(Error::UnexpectedSdoCommand(info_header[0]));
+        }
+
+        self.data.extend_from_slice(body);
+
+        if info_header[0] & INCOMPLETE != 0 {
+            // the following fragments are sent without being asked for
+            let mut inner = MbxWriteRead::read_only();
+            inner.start(
+                ctx,
+                write_mbx,
+                read_mbx,
+                configured_addr,
+                identifier.map(|id| id >> 2),
+                idx,
+            )?;
+            self.state = SdoInfoState::Pending(inner);
+            return Ok(None);
+        }
+
+        self.state = SdoInfoState::Done;
+        self.response().map(Some)
+    }
+
+    fn response(&self) -> Result<SdoInfoResponse<'_>, Error> {
+        match self.request {
+            Request::ObjectList(ty) => {
+                // only the first fragment repeats the list type
+                let data = self.data.get(2..).ok_or(Error::InvalidMailbox)?;
+                Ok(SdoInfoResponse::ObjectList(ObjectList { ty, data }))
+            }
+            Request::Object(_) => {
+                let (head, name) = self
+                    .data
+                    .split_first_chunk::<6>()
+                    .ok_or(Error::InvalidMailbox)?;
+
+                Ok(SdoInfoResponse::Object(ObjectDescription {
+                    index: u16::from_le_bytes([head[0], head[1]]),
+                    data_type: u16::from_le_bytes([head[2], head[3]]),
+                    max_subindex: head[4],
+                    object_code: head[5],
+                    name: String::from_utf8_lossy(name),
+                }))
+            }
+            Request::Entry(..) => {
+                let (head, name) = self
+                    .data
+                    .split_first_chunk::<10>()
+                    .ok_or(Error::InvalidMailbox)?;
+
+                Ok(SdoInfoResponse::Entry(EntryDescription {
+                    index: u16::from_le_bytes([head[0], head[1]]),
+                    subindex: head[2],
+                    data_type: u16::from_le_bytes([head[4], head[5]]),
+                    bit_len: u16::from_le_bytes([head[6], head[7]]),
+                    access: u16::from_le_bytes([head[8], head[9]]),
+                    name: String::from_utf8_lossy(name),
+                }))
+            }
+        }
+    }
+}
+
+// the response was already parsed once when the transfer finished
+crate::mbx_queue::mailbox_transfer!(
+    impl[] for SdoInfo,
+    |res| update => res.is_some(),
+    |this| match SdoInfo::response(this) {
+        Ok(info) => crate::mbx_queue::MailboxResponse::Info(info),
+        Err(_) => crate::mbx_queue::MailboxResponse::Done,
+    },
+);
+
+#[derive(Debug)]
+pub enum SdoInfoResponse<'a> {
+    ObjectList(ObjectList<'a>),
+    Object(ObjectDescription<'a>),
+    Entry(EntryDescription<'a>),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ObjectList<'a> {
+    pub ty: ObjectListType,
+    data: &'a [u8],
+}
+
+impl ObjectList<'_> {
+    // the object indices, or the length of each list for `ObjectListType::Lengths`
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.data
+            .chunks_exact(2)
+            .map(|idx| u16::from_le_bytes([idx[0], idx[1]]))
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len() / 2
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjectDescription<'a> {
+    pub index: u16,
+    // index of the data type in the object dictionary, eg. 0x0007 for an unsigned32
+    pub data_type: u16,
+    pub max_subindex: u8,
+    // 0x07 for variables, 0x08 for arrays and 0x09 for records
+    pub object_code: u8,
+    pub name: Cow<'a, str>,
+}
+
+#[derive(Debug, Clone)]
+pub struct EntryDescription<'a> {
+    pub index: u16,
+    pub subindex: u8,
+    pub data_type: u16,
+    pub bit_len: u16,
+    // bits 0..=2 are read access in preop, safeop and op, bits 3..=5 are write access.
+    // bit 6 and 7 are set for rxpdo and txpdo mappable entries
+    pub access: u16,
+    pub name: Cow<'a, str>,
+}
@@ -1,11 +1,17 @@
+use crate::error::Error;
+use crate::io::TxCtx;
 use crate::txbuf::{TxBuf, TxIndex};
-use ethercrab::error::Error;
-use ethercrab::{PduResponseHandle, SendableFrame, std::RawSocketDesc};
+use ethercrab::{PduResponseHandle, SendableFrame};
 use io_uring::{
     IoUring, opcode,
     types::{TimeoutFlags, Timespec},
 };
-use std::collections::BTreeMap;
+
+// pdus sent with an identifier in this range belong to the crate itself, eg. the state changes
+// and pdo mapping of `ControlFlow`, the reads that op starts on its own or `MailboxQueue`.
+// mailbox transfers carry their identifier shifted up by two, so they have to be started with
+// identifiers below 0x20
+pub const RESERVED_IDENTIFIERS: core::ops::RangeFrom<u8> = 0x80..;
 
 // used for setting up a timeout with io_uring
 pub(crate) fn setup_timeout(
@@ -22,41 +28,38 @@ pub(crate) fn setup_timeout(
         .user_data(timeout_entry(idx));
 
     while unsafe { ring.submission().push(&timeout).is_err() } {
-        ring.submit().expect("could not submit ops");
+        ring.submit()?;
     }
     ring.submit()?;
     Ok(())
 }
 
 // used for setting up a write with io_uring
-#[allow(clippy::too_many_arguments)]
 pub fn setup_write(
     frame: SendableFrame,
     handle: PduResponseHandle,
-    retry_count: usize,
-    timeout_duration: &Timespec,
-    tx_entries: &mut BTreeMap<u64, TxBuf>,
-    sock: &RawSocketDesc,
-    ring: &mut IoUring,
+    tx: &mut TxCtx,
     configured_addr: Option<u16>,
     identifier: Option<u8>,
-    write_entry: impl Fn(u64) -> u64,
-    timeout_entry: impl Fn(u64) -> u64,
 ) -> Result<(), Error> {
-    let mut buf = TxBuf::new(&handle, retry_count, configured_addr, identifier);
+    let mut buf = TxBuf::new(&handle, tx.retry_count, configured_addr, identifier);
 
     frame.send_blocking(|bytes| {
-        let tx_entry = buf.update(bytes, sock, write_entry);
-        setup_timeout(&handle, ring, timeout_duration, timeout_entry)
-            .map_err(|_| Error::Internal)?;
+        let tx_entry = buf.update(bytes, tx.sock, tx.write_entry);
+        setup_timeout(&handle, tx.ring, tx.timeout, tx.timeout_entry)
+            .map_err(|_| ethercrab::error::Error::Internal)?;
 
-        while unsafe { ring.submission().push(tx_entry).is_err() } {
-            ring.submit().expect("could not submit ops");
+        while unsafe { tx.ring.submission().push(tx_entry).is_err() } {
+            tx.ring
+                .submit()
+                .map_err(|_| ethercrab::error::Error::Internal)?;
         }
-        ring.submit().map_err(|_| Error::Internal)?;
+        tx.ring
+            .submit()
+            .map_err(|_| ethercrab::error::Error::Internal)?;
         Ok(bytes.len())
     })?;
 
-    tx_entries.insert(buf.idx(), buf);
+    tx.tx_entries.insert(buf.idx(), buf);
     Ok(())
 }
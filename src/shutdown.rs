@@ -0,0 +1,252 @@
+use crate::error::Error;
+use crate::io::IoCtx;
+use crate::setup::setup_write;
+use ethercrab::{PduHeader, SubDeviceState, received_frame::ReceivedPdu};
+
+use crate::reset::Reset;
+use crate::state_transition::Transition;
+
+use heapless::Deque;
+
+// tags the frame with the cleared outputs, the cyclic frames that were still in flight have none
+const CLEARED_OUTPUTS_IDENTIFIER: u8 = 0;
+
+// brings the subdevices back down to init, see `ControlFlow::Shutdown`. this is best effort,
+// a subdevice that fails to transition is skipped and reported once the rest of the bus is down
+pub struct Shutdown<const N: usize, U> {
+    // along with the state each subdevice is in
+    subdevices: Deque<(U, SubDeviceState, Transition), N>,
+    transition_idx: u16,
+    // the cyclic frame with the outputs zeroed, sent until no subdevice is in safeop anymore so
+    // that their sync manager watchdogs do not run out
+    send_bytes: Vec<u8>,
+    // the configured address of every subdevice that failed to transition and what went wrong
+    failed: Vec<(u16, Error)>,
+    state: ShutdownState,
+}
+
+enum ShutdownState {
+    // waiting for the first frame with the cleared outputs to come back
+    ClearOutputs,
+    // every subdevice is taken to the state one after another before moving on to the next
+    Transition(SubDeviceState),
+    // the bus was not in op, so the subdevices are reset to init instead
+    Reset(Reset),
+}
+
+impl<const N: usize, U: crate::user::UserDevice> Shutdown<N, U> {
+    // zeroes the outputs before leaving op
+    pub(crate) fn start_new(
+        subdevs: Deque<(U, SubDeviceState), N>,
+        ctx: &mut IoCtx,
+        mut send_bytes: Vec<u8>,
+        input_offset: usize,
+    ) -> Result<Self, Error> {
+        send_bytes[input_offset..].fill(0);
+
+        let mut subdevices = Deque::new();
+        for (subdev, state) in subdevs.into_iter() {
+            let transition = Transition::new(SubDeviceState::SafeOp);
+            let _ = subdevices.push_back((subdev, state, transition));
+        }
+
+        let shutdown = Self {
+            subdevices,
+            transition_idx: 0,
+            send_bytes,
+            failed: Vec::new(),
+            state: ShutdownState::ClearOutputs,
+        };
+        shutdown.clear_outputs(ctx)?;
+        Ok(shutdown)
+    }
+
+    // for when the bus is still being brought up
+    pub(crate) fn start_reset(ctx: &mut IoCtx) -> Result<Self, Error> {
+        let mut reset = Reset::new();
+        reset.start(ctx)?;
+
+        Ok(Self {
+            subdevices: Deque::new(),
+            transition_idx: 0,
+            send_bytes: Vec::new(),
+            failed: Vec::new(),
+            state: ShutdownState::Reset(reset),
+        })
+    }
+
+    // what the shutdown ended with once `update` / `timeout` returned true
+    pub(crate) fn result(&mut self) -> Result<(), Error> {
+        if self.failed.is_empty() {
+            return Ok(());
+        }
+        Err(Error::Shutdown(core::mem::take(&mut self.failed)))
+    }
+
+    fn clear_outputs(&self, ctx: &mut IoCtx) -> Result<(), Error> {
+        let (frame, handle) =
+            unsafe { ctx.maindevice.prep_rx_tx(0, &self.send_bytes) }?.ok_or(Error::NoFrame)?;
+        setup_write(
+            frame,
+            handle,
+            &mut ctx.tx,
+            Some(0),
+            Some(CLEARED_OUTPUTS_IDENTIFIER),
+        )
+    }
+
+    // the frame with the cleared outputs came back or ran out of retries
+    fn cleared_outputs(&mut self, ctx: &mut IoCtx) -> Result<bool, Error> {
+        let done = match self.state {
+            ShutdownState::ClearOutputs => self.transition(ctx, SubDeviceState::SafeOp)?,
+            _ => false,
+        };
+
+        let in_safeop = self
+            .subdevices
+            .iter()
+            .any(|(_, state, _)| crate::state_transition::above(*state, SubDeviceState::PreOp));
+        if !done && in_safeop {
+            self.clear_outputs(ctx)?;
+        }
+        Ok(done)
+    }
+
+    // the subdevice that is being transitioned failed to, the shutdown goes on with the next one
+    fn skip(
+        &mut self,
+        ctx: &mut IoCtx,
+        requested: SubDeviceState,
+        configured_addr: u16,
+        err: Error,
+    ) -> Result<bool, Error> {
+        self.failed.push((configured_addr, err));
+        self.transition_idx += 1;
+        self.transition(ctx, requested)
+    }
+
+    // returns true once every subdevice is in init
+    pub(crate) fn update(
+        &mut self,
+        received: ReceivedPdu<'_>,
+        header: PduHeader,
+        ctx: &mut IoCtx,
+        identifier: Option<u8>,
+        idx: Option<u16>,
+    ) -> Result<bool, Error> {
+        let requested = match &mut self.state {
+            ShutdownState::Reset(reset) => {
+                return Ok(reset.update(received, header, ctx)?.is_some());
+            }
+            _ if header.command_code == 12 => {
+                if identifier != Some(CLEARED_OUTPUTS_IDENTIFIER) {
+                    return Ok(false);
+                }
+                return self.cleared_outputs(ctx);
+            }
+            ShutdownState::Transition(requested) => *requested,
+            ShutdownState::ClearOutputs => return Ok(false),
+        };
+
+        // anything else is left over from op, eg. mailbox transfers
+        if identifier.is_some()
+            || idx != Some(self.transition_idx)
+            || !Transition::is_response(&header)
+        {
+            return Ok(false);
+        }
+
+        let (dev, state, transition) = self
+            .subdevices
+            .get_mut(self.transition_idx as _)
+            .ok_or(Error::UnknownDevice(self.transition_idx))?;
+        let configured_addr = dev.subdevice().configured_address();
+
+        match transition.update(received, header, ctx, configured_addr, self.transition_idx) {
+            Ok(false) => Ok(false),
+            Ok(true) => {
+                *state = requested;
+                self.transition_idx += 1;
+                self.transition(ctx, requested)
+            }
+            Err(err) => self.skip(ctx, requested, configured_addr, err),
+        }
+    }
+
+    // a pdu ran out of retries, returns true once every subdevice is in init like `update`.
+    // the ones that were not sent by the shutdown are left over from op
+    pub(crate) fn timeout(
+        &mut self,
+        ctx: &mut IoCtx,
+        command_code: u8,
+        idx: Option<u16>,
+        identifier: Option<u8>,
+    ) -> Result<bool, Error> {
+        let requested = match self.state {
+            // nothing to skip while the bus is reset as a whole
+            ShutdownState::Reset(_) if command_code != 12 && identifier.is_none() => {
+                return Err(Error::Timeout { idx, identifier });
+            }
+            ShutdownState::Reset(_) => return Ok(false),
+            _ if command_code == 12 => {
+                if identifier != Some(CLEARED_OUTPUTS_IDENTIFIER) {
+                    return Ok(false);
+                }
+                return self.cleared_outputs(ctx);
+            }
+            ShutdownState::Transition(requested) => requested,
+            ShutdownState::ClearOutputs => return Ok(false),
+        };
+
+        if identifier.is_some() || idx != Some(self.transition_idx) {
+            return Ok(false);
+        }
+
+        let (dev, ..) = self
+            .subdevices
+            .get(self.transition_idx as _)
+            .ok_or(Error::UnknownDevice(self.transition_idx))?;
+        let configured_addr = dev.subdevice().configured_address();
+
+        self.skip(
+            ctx,
+            requested,
+            configured_addr,
+            Error::Timeout { idx, identifier },
+        )
+    }
+
+    // starts the next transition down to `requested`, moving on to the next state once every
+    // subdevice is there. subdevices that already are at or below it are skipped.
+    // returns true once there is nothing left to transition
+    fn transition(
+        &mut self,
+        ctx: &mut IoCtx,
+        mut requested: SubDeviceState,
+    ) -> Result<bool, Error> {
+        loop {
+            while let Some((dev, state, transition)) =
+                self.subdevices.get_mut(self.transition_idx as _)
+            {
+                if crate::state_transition::above(*state, requested) {
+                    *transition = Transition::new(requested);
+                    transition.start(
+                        ctx,
+                        dev.subdevice().configured_address(),
+                        self.transition_idx,
+                    )?;
+                    self.state = ShutdownState::Transition(requested);
+                    return Ok(false);
+                }
+                self.transition_idx += 1;
+            }
+
+            requested = match requested {
+                SubDeviceState::SafeOp => SubDeviceState::PreOp,
+                SubDeviceState::PreOp => SubDeviceState::Init,
+                _ => return Ok(true),
+            };
+            self.transition_idx = 0;
+        }
+    }
+}
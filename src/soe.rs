@@ -0,0 +1,556 @@
+use crate::coe;
+use crate::error::Error;
+use crate::io::IoCtx;
+use crate::mbx::MbxWriteRead;
+use ethercrab::{PduHeader, received_frame::ReceivedPdu};
+
+// ETG.1000.6 servo profile over ethercat, idns are read and written through the same
+// mailbox flow as coe, only with a different header in front of the data
+
+// opcode / flags / drive + elements + idn or fragments left
+const SOE_HEADER_LEN: usize = 4;
+
+const OP_READ_REQUEST: u8 = 0x01;
+const OP_READ_RESPONSE: u8 = 0x02;
+const OP_WRITE_REQUEST: u8 = 0x03;
+const OP_WRITE_RESPONSE: u8 = 0x04;
+
+// set on every fragment that has more fragments following it
+const INCOMPLETE: u8 = 1 << 3;
+const ERROR: u8 = 1 << 4;
+
+// the parts of an idn that can be read or written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoeElement {
+    DataState = 0x01,
+    Name = 0x02,
+    Attribute = 0x04,
+    Unit = 0x08,
+    Min = 0x10,
+    Max = 0x20,
+    Value = 0x40,
+    Default = 0x80,
+}
+
+fn request(
+    counter: u8,
+    opcode: u8,
+    drive: u8,
+    element: SoeElement,
+    idn: u16,
+    data: &[u8],
+) -> coe::MbxBuf {
+    let mut buf = coe::MbxBuf::new();
+    coe::mailbox_header(
+        &mut buf,
+        SOE_HEADER_LEN + data.len(),
+        coe::MBX_TYPE_SOE,
+        counter,
+    );
+    buf.push(opcode | ((drive & 0b111) << 5));
+    buf.push(element as u8);
+    buf.extend_from_slice(&idn.to_le_bytes());
+    buf.extend_from_slice(data);
+    buf
+}
+
+// the header byte and the data of a response to `opcode`
+fn parse(bytes: &[u8], opcode: u8, idn: u16) -> Result<(u8, &[u8]), Error> {
+    let body = coe::mailbox_body(bytes, coe::MBX_TYPE_SOE)?;
+    let (header, data) = body
+        .split_first_chunk::<SOE_HEADER_LEN>()
+        .ok_or(Error::InvalidMailbox)?;
+
+    if header[0] & ERROR != 0 {
+        let code = data.first_chunk::<2>().ok_or(Error::InvalidMailbox)?;
+        return Err(Error::Soe {
+            idn,
+            code: u16::from_le_bytes(*code),
+        });
+    }
+
+    if header[0] & 0b111 != opcode {
+        return Err(Error::UnexpectedSoeOpcode(header[0]));
+    }
+    Ok((header[0], data))
+}
+
+pub struct SoeRead<T> {
+    idn: u16,
+    data: smallvec::SmallVec<[u8; 64]>,
+    state: SoeReadState,
+    ty: core::marker::PhantomData<T>,
+}
+
+enum SoeReadState {
+    // waiting on the response, or on the next fragment of it
+    Pending(MbxWriteRead),
+    Done,
+}
+
+impl<T> SoeRead<T> {
+    pub fn finished(&self) -> bool {
+        matches!(self.state, SoeReadState::Done)
+    }
+}
+
+impl<T: ethercrab::EtherCrabWireReadSized> SoeRead<T> {
+    pub fn new(subdev: &ethercrab::SubDevice, drive: u8, idn: u16, element: SoeElement) -> Self {
+        Self::with_counter(subdev.mailbox_counter(), drive, idn, element)
+    }
+
+    fn with_counter(counter: u8, drive: u8, idn: u16, element: SoeElement) -> Self {
+        let req = request(counter, OP_READ_REQUEST, drive, element, idn, &[]);
+
+        Self {
+            idn,
+            data: smallvec::SmallVec::new(),
+            state: SoeReadState::Pending(MbxWriteRead::new(&req)),
+            ty: core::marker::PhantomData,
+        }
+    }
+
+    pub fn start(
+        &mut self,
+        ctx: &mut IoCtx,
+        write_mbx: &ethercrab::Mailbox,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<(), Error> {
+        match &mut self.state {
+            SoeReadState::Pending(inner) => {
+                inner.start(ctx, write_mbx, read_mbx, configured_addr, identifier, idx)
+            }
+            SoeReadState::Done => Err(Error::TransferFinished),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &mut self,
+        received: ReceivedPdu<'_>,
+        header: PduHeader,
+        ctx: &mut IoCtx,
+        write_mbx: &ethercrab::Mailbox,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<Option<T>, Error> {
+        let idn = self.idn;
+        let Some(data) = self.update_raw(
+            received,
+            header,
+            ctx,
+            write_mbx,
+            read_mbx,
+            configured_addr,
+            identifier,
+            idx,
+        )?
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self::unpack(idn, data)?))
+    }
+
+    fn unpack(idn: u16, data: &[u8]) -> Result<T, Error> {
+        if data.len() > T::PACKED_LEN {
+            return Err(Error::SoeSize {
+                idn,
+                expected: T::PACKED_LEN,
+                received: data.len(),
+            });
+        }
+
+        use ethercrab::EtherCrabWireRead;
+        Ok(T::unpack_from_slice(data)?)
+    }
+
+    // same as `update`, but hands back the element as is, eg. for names and units
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_raw(
+        &mut self,
+        received: ReceivedPdu<'_>,
+        header: PduHeader,
+        ctx: &mut IoCtx,
+        write_mbx: &ethercrab::Mailbox,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<Option<&[u8]>, Error> {
+        let SoeReadState::Pending(inner) = &mut self.state else {
+            return Ok(None);
+        };
+
+        let Some(bytes) = inner.update(
+            received,
+            header,
+            ctx,
+            write_mbx,
+            read_mbx,
+            configured_addr,
+            identifier,
+            idx,
+        )?
+        else {
+            return Ok(None);
+        };
+
+        if !self.receive(&bytes)? {
+            // the following fragments are sent without being asked for
+            let mut inner = MbxWriteRead::read_only();
+            inner.start(
+                ctx,
+                write_mbx,
+                read_mbx,
+                configured_addr,
+                identifier.map(|id| id >> 2),
+                idx,
+            )?;
+            self.state = SoeReadState::Pending(inner);
+            return Ok(None);
+        }
+
+        self.state = SoeReadState::Done;
+        Ok(Some(&self.data))
+    }
+
+    // takes in a fragment of the response, returns true once it was the last one
+    fn receive(&mut self, bytes: &[u8]) -> Result<bool, Error> {
+        let (flags, data) = parse(bytes, OP_READ_RESPONSE, self.idn)?;
+        self.data.extend_from_slice(data);
+        Ok(flags & INCOMPLETE == 0)
+    }
+}
+
+crate::mbx_queue::mailbox_transfer!(
+    impl[T: ethercrab::EtherCrabWireReadSized] for SoeRead<T>,
+    |res| update_raw => res.is_some(),
+    |this| crate::mbx_queue::MailboxResponse::Data(&this.data),
+);
+
+#[derive(Debug)]
+pub struct SoeWrite<T> {
+    counter: u8,
+    drive: u8,
+    idn: u16,
+    element: SoeElement,
+    data: smallvec::SmallVec<[u8; 8]>,
+    // how much of the data was sent so far
+    offset: usize,
+    state: SoeWriteState,
+    ty: core::marker::PhantomData<T>,
+}
+
+#[derive(Debug)]
+enum SoeWriteState {
+    Idle,
+    // fragments that do not hold the end of the data are not answered
+    Fragment(MbxWriteRead),
+    Last(MbxWriteRead),
+    Done,
+}
+
+impl<T: ethercrab::EtherCrabWireWrite + std::fmt::Debug> SoeWrite<T> {
+    pub fn new(
+        subdev: &ethercrab::SubDevice,
+        drive: u8,
+        idn: u16,
+        element: SoeElement,
+        data: T,
+    ) -> Result<Self, Error> {
+        Self::with_counter(subdev.mailbox_counter(), drive, idn, element, data)
+    }
+
+    fn with_counter(
+        counter: u8,
+        drive: u8,
+        idn: u16,
+        element: SoeElement,
+        data: T,
+    ) -> Result<Self, Error> {
+        let mut buf = smallvec::smallvec![0; data.packed_len()];
+        data.pack_to_slice(&mut buf)?;
+
+        Ok(Self {
+            counter,
+            drive,
+            idn,
+            element,
+            data: buf,
+            offset: 0,
+            state: SoeWriteState::Idle,
+            ty: core::marker::PhantomData,
+        })
+    }
+
+    pub fn finished(&self) -> bool {
+        matches!(self.state, SoeWriteState::Done)
+    }
+
+    pub fn start(
+        &mut self,
+        ctx: &mut IoCtx,
+        write_mbx: &ethercrab::Mailbox,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<(), Error> {
+        if self.finished() {
+            return Err(Error::TransferFinished);
+        }
+
+        let (req, last) = self.next_request(write_mbx.len)?;
+
+        self.state = if last {
+            let mut inner = MbxWriteRead::new(&req);
+            inner.start(ctx, write_mbx, read_mbx, configured_addr, identifier, idx)?;
+            SoeWriteState::Last(inner)
+        } else {
+            let mut inner = MbxWriteRead::write_only(&req);
+            inner.start(ctx, write_mbx, read_mbx, configured_addr, identifier, idx)?;
+            SoeWriteState::Fragment(inner)
+        };
+        Ok(())
+    }
+
+    // the next fragment for a write mailbox that is `mbx_len` long, along with whether it holds the
+    // end of the data
+    fn next_request(&mut self, mbx_len: u16) -> Result<(coe::MbxBuf, bool), Error> {
+        let max_len = usize::from(mbx_len).saturating_sub(coe::MBX_HEADER_LEN + SOE_HEADER_LEN);
+        if max_len == 0 {
+            return Err(Error::InvalidMailbox);
+        }
+
+        let remaining = self.data.len() - self.offset;
+        let end = self.offset + core::cmp::min(remaining, max_len);
+
+        let (req, last) = if remaining > max_len {
+            // fragments carry how many fragments are left instead of the idn
+            let left = remaining.div_ceil(max_len) - 1;
+            let req = request(
+                self.counter,
+                OP_WRITE_REQUEST | INCOMPLETE,
+                self.drive,
+                self.element,
+                left as u16,
+                &self.data[self.offset..end],
+            );
+            (req, false)
+        } else {
+            let req = request(
+                self.counter,
+                OP_WRITE_REQUEST,
+                self.drive,
+                self.element,
+                self.idn,
+                &self.data[self.offset..end],
+            );
+            (req, true)
+        };
+        self.offset = end;
+        Ok((req, last))
+    }
+
+    // returns true once the subdevice acknowledged the write
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &mut self,
+        received: ReceivedPdu<'_>,
+        header: PduHeader,
+        ctx: &mut IoCtx,
+        write_mbx: &ethercrab::Mailbox,
+        read_mbx: &ethercrab::Mailbox,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<bool, Error> {
+        match &mut self.state {
+            SoeWriteState::Fragment(inner) => {
+                if inner
+                    .update(
+                        received,
+                        header,
+                        ctx,
+                        write_mbx,
+                        read_mbx,
+                        configured_addr,
+                        identifier,
+                        idx,
+                    )?
+                    .is_some()
+                {
+                    self.counter = coe::next_counter(self.counter);
+                    self.start(
+                        ctx,
+                        write_mbx,
+                        read_mbx,
+                        configured_addr,
+                        identifier.map(|id| id >> 2),
+                        idx,
+                    )?;
+                }
+            }
+            SoeWriteState::Last(inner) => {
+                let Some(bytes) = inner.update(
+                    received,
+                    header,
+                    ctx,
+                    write_mbx,
+                    read_mbx,
+                    configured_addr,
+                    identifier,
+                    idx,
+                )?
+                else {
+                    return Ok(false);
+                };
+
+                parse(&bytes, OP_WRITE_RESPONSE, self.idn)?;
+                self.state = SoeWriteState::Done;
+                return Ok(true);
+            }
+            SoeWriteState::Idle | SoeWriteState::Done => (),
+        }
+        Ok(false)
+    }
+}
+
+crate::mbx_queue::mailbox_transfer!(
+    impl[T: ethercrab::EtherCrabWireWrite + std::fmt::Debug] for SoeWrite<T>,
+    |done| update => done,
+    |_| crate::mbx_queue::MailboxResponse::Done,
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(opcode: u8, idn: u16, data: &[u8]) -> coe::MbxBuf {
+        request(2, opcode, 0, SoeElement::Value, idn, data)
+    }
+
+    // the header byte, the idn / fragments left and the data of a request
+    fn fields(req: &[u8]) -> (u8, u16, &[u8]) {
+        let header = &req[coe::MBX_HEADER_LEN..];
+        (
+            header[0],
+            u16::from_le_bytes([header[2], header[3]]),
+            &header[SOE_HEADER_LEN..],
+        )
+    }
+
+    #[test]
+    fn request_layout() {
+        let buf = request(1, OP_READ_REQUEST, 2, SoeElement::Value, 0x1234, &[]);
+
+        // length, station address, channel + priority, type + counter
+        assert_eq!(buf[..6], [0x04, 0x00, 0x00, 0x00, 0x00, 0x15]);
+        // opcode + drive, element, idn
+        assert_eq!(buf[6..], [0x41, 0x40, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn parse_error() {
+        let bytes = response(OP_READ_RESPONSE | ERROR, 0x0020, &[0x01, 0x70]);
+        assert!(matches!(
+            parse(&bytes, OP_READ_RESPONSE, 0x0020),
+            Err(Error::Soe {
+                idn: 0x0020,
+                code: 0x7001
+            })
+        ));
+    }
+
+    #[test]
+    fn parse_wrong_opcode() {
+        let bytes = response(OP_WRITE_RESPONSE, 0x0020, &[]);
+        assert!(matches!(
+            parse(&bytes, OP_READ_RESPONSE, 0x0020),
+            Err(Error::UnexpectedSoeOpcode(OP_WRITE_RESPONSE))
+        ));
+    }
+
+    #[test]
+    fn read_fragments() {
+        let mut read = SoeRead::<u32>::with_counter(1, 0, 0x0020, SoeElement::Value);
+
+        let first = response(OP_READ_RESPONSE | INCOMPLETE, 1, &[0x01, 0x02]);
+        assert!(!read.receive(&first).unwrap());
+
+        let last = response(OP_READ_RESPONSE, 0x0020, &[0x03, 0x04]);
+        assert!(read.receive(&last).unwrap());
+
+        assert_eq!(read.data[..], [0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(
+            SoeRead::<u32>::unpack(read.idn, &read.data).unwrap(),
+            0x0403_0201
+        );
+    }
+
+    #[test]
+    fn read_too_large() {
+        assert!(matches!(
+            SoeRead::<u32>::unpack(0x0020, &[0; 5]),
+            Err(Error::SoeSize {
+                idn: 0x0020,
+                expected: 4,
+                received: 5
+            })
+        ));
+    }
+
+    #[test]
+    fn write_fragments() {
+        let data = 0x0807_0605_0403_0201u64;
+        let mut write = SoeWrite::with_counter(1, 0, 0x0020, SoeElement::Value, data).unwrap();
+
+        // room for 3 bytes of data per fragment
+        let mbx_len = (coe::MBX_HEADER_LEN + SOE_HEADER_LEN + 3) as u16;
+
+        // every fragment but the last carries the number of fragments after it
+        let (req, last) = write.next_request(mbx_len).unwrap();
+        assert!(!last);
+        assert_eq!(
+            fields(&req),
+            (OP_WRITE_REQUEST | INCOMPLETE, 2, &[0x01, 0x02, 0x03][..])
+        );
+
+        let (req, last) = write.next_request(mbx_len).unwrap();
+        assert!(!last);
+        assert_eq!(
+            fields(&req),
+            (OP_WRITE_REQUEST | INCOMPLETE, 1, &[0x04, 0x05, 0x06][..])
+        );
+
+        let (req, last) = write.next_request(mbx_len).unwrap();
+        assert!(last);
+        assert_eq!(fields(&req), (OP_WRITE_REQUEST, 0x0020, &[0x07, 0x08][..]));
+    }
+
+    #[test]
+    fn write_single() {
+        let mut write = SoeWrite::with_counter(1, 0, 0x0020, SoeElement::Value, 0x1234u16).unwrap();
+
+        let (req, last) = write.next_request(32).unwrap();
+        assert!(last);
+        assert_eq!(fields(&req), (OP_WRITE_REQUEST, 0x0020, &[0x34, 0x12][..]));
+    }
+
+    #[test]
+    fn write_mailbox_too_small() {
+        let mut write = SoeWrite::with_counter(1, 0, 0x0020, SoeElement::Value, 0x1234u16).unwrap();
+
+        let mbx_len = (coe::MBX_HEADER_LEN + SOE_HEADER_LEN) as u16;
+        assert!(matches!(
+            write.next_request(mbx_len),
+            Err(Error::InvalidMailbox)
+        ));
+    }
+}
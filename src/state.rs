@@ -1,27 +1,36 @@
-use crate::txbuf::TxBuf;
-use ethercrab::{
-    MainDevice, PduHeader, error::Error, received_frame::ReceivedPdu, std::RawSocketDesc,
-};
-use io_uring::{IoUring, types::Timespec};
-use std::collections::BTreeMap;
+use crate::error::Error;
+use crate::io::IoCtx;
+use ethercrab::{MainDevice, PduHeader, received_frame::ReceivedPdu};
 
 use crate::pdo::PdoConfig;
 
 pub enum InitState<'a, const MAX_SUBDEVICES: usize, const I: usize, const O: usize, U> {
     Idle,
 
-    //TODO: all of the earlier stuff (resetting, configuration, etc) needs to be done in
-    //series.
+    // resetting and the dc setup address the whole bus, the other stages configure several
+    // subdevices at once, see `Driver::set_concurrency`
     Reset(crate::reset::Reset),
     Init(crate::init::Init<MAX_SUBDEVICES>),
     Dc(crate::dc::Dc<MAX_SUBDEVICES>),
     Mbx(crate::mbx_config::MailboxConfig<MAX_SUBDEVICES>),
     PreOp(crate::preop::PreOp<'a, MAX_SUBDEVICES, I, O, U>),
     SafeOp(
-        crate::safeop::SafeOp<MAX_SUBDEVICES, U>,
+        crate::safeop::SafeOp<'a, MAX_SUBDEVICES, I, O, U>,
         crate::preop::SendRecvIo,
     ),
-    Op(crate::op::Op<MAX_SUBDEVICES, U>, SendCtx),
+    Op(crate::op::Op<'a, MAX_SUBDEVICES, I, O, U>, SendCtx),
+    Shutdown(crate::shutdown::Shutdown<MAX_SUBDEVICES, U>),
+    // every subdevice was brought back down to init, or the bus was stopped where it was
+    Stopped,
+}
+
+// how the bus is brought up, see `Driver::set_concurrency` / `Driver::set_broadcast_transitions`
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Settings {
+    // how many subdevices are configured at once
+    pub(crate) concurrency: u16,
+    // whether the subdevices are taken to op all at once
+    pub(crate) broadcast_transitions: bool,
 }
 
 pub struct SendCtx {
@@ -48,243 +57,128 @@ impl<const N: usize, const I: usize, const O: usize, U> InitState<'_, N, I, O, U
     pub fn new() -> Self {
         Self::Idle
     }
+
+    // whether a shutdown has completed or the bus was stopped, see `ControlFlow`
+    pub fn is_stopped(&self) -> bool {
+        matches!(self, Self::Stopped)
+    }
 }
 
 impl<'a, const N: usize, const I: usize, const O: usize, U: crate::user::UserDevice>
     InitState<'a, N, I, O, U>
 {
-    #[allow(clippy::too_many_arguments)]
-    pub fn start(
-        &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
-    ) -> Result<(), Error> {
+    pub fn start(&mut self, ctx: &mut IoCtx) -> Result<(), Error> {
+        // a gateway exchange in flight would never be answered
+        if let Some(gateway) = ctx.gateway.as_deref_mut() {
+            gateway.reset();
+        }
+        ctx.mailbox_counters.clear();
+        ctx.mailbox_status.clear();
+
         let mut reset = crate::reset::Reset::new();
-        reset.start(
-            maindevice,
-            retry_count,
-            timeout,
-            tx_entries,
-            sock,
-            ring,
-            write_entry,
-            timeout_entry,
-        )?;
+        reset.start(ctx)?;
         *self = Self::Reset(reset);
         Ok(())
     }
 
+    // zeroes the outputs and then takes every subdevice from op down to init, one state at a
+    // time. if the bus is still being brought up the subdevices are reset instead
+    pub fn shutdown(&mut self, ctx: &mut IoCtx) -> Result<(), Error> {
+        // nothing is forwarded or read on the side anymore
+        if let Some(gateway) = ctx.gateway.as_deref_mut() {
+            gateway.reset();
+        }
+        ctx.mailbox_status.clear();
+
+        match core::mem::replace(self, Self::Idle) {
+            Self::Op(op, io) => {
+                let shutdown = crate::shutdown::Shutdown::start_new(
+                    op.into_subdevices(),
+                    ctx,
+                    io.send_bytes,
+                    io.input_offset,
+                )?;
+                *self = Self::Shutdown(shutdown);
+            }
+            Self::Idle | Self::Stopped => *self = Self::Stopped,
+            Self::Shutdown(shutdown) => *self = Self::Shutdown(shutdown),
+            _ => *self = Self::Shutdown(crate::shutdown::Shutdown::start_reset(ctx)?),
+        }
+        Ok(())
+    }
+
     #[allow(clippy::too_many_arguments)]
-    pub fn update(
+    pub(crate) fn update(
         &mut self,
         received: ReceivedPdu<'_>,
         header: PduHeader,
-        maindevice: &mut MainDevice,
-        retry_count: usize,
-        timeout: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         index: Option<u16>,
         identifier: Option<u8>,
         pdi_offset: &mut ethercrab::PdiOffset,
+        settings: Settings,
         config: impl FnMut(&MainDevice, ethercrab::SubDevice) -> (U, &'a PdoConfig<'a, I, O>),
         user_cb: impl FnMut(
-            &mut MainDevice,
+            &mut IoCtx,
             &mut U,
             Option<crate::op::DeviceResponse<'_, '_>>,
-            &mut BTreeMap<u64, TxBuf>,
-            &mut IoUring,
             u16,
             Option<u8>,
             &mut [u8],
         ) -> std::io::Result<Option<crate::user::ControlFlow>>,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<(), Error> {
         match self {
             Self::Reset(r) => {
-                if let Some(count) = r.update(
-                    received,
-                    header,
-                    maindevice,
-                    retry_count,
-                    timeout,
-                    tx_entries,
-                    sock,
-                    ring,
-                    &write_entry,
-                    &timeout_entry,
-                )? {
-                    let init = crate::init::Init::start_new(
-                        count,
-                        maindevice,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
-                        &write_entry,
-                        &timeout_entry,
-                    )?;
+                if let Some(count) = r.update(received, header, ctx)? {
+                    let init = crate::init::Init::start_new(count, ctx, settings.concurrency)?;
                     *self = Self::Init(init);
                 }
             }
             Self::Init(i) => {
-                if let Some(devs) = i.update(
-                    received,
-                    header,
-                    maindevice,
-                    retry_count,
-                    timeout,
-                    tx_entries,
-                    sock,
-                    ring,
-                    index,
-                    identifier,
-                    &write_entry,
-                    &timeout_entry,
-                )? {
+                if let Some(devs) = i.update(received, header, ctx, index, identifier)? {
                     let mut dc = crate::dc::Dc::new(devs);
 
-                    dc.start(
-                        maindevice,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
-                        &write_entry,
-                        &timeout_entry,
-                    )?;
+                    dc.start(ctx)?;
                     *self = Self::Dc(dc);
                 }
             }
             Self::Dc(dc) => {
-                if let Some(devs) = dc.update(
-                    received,
-                    header,
-                    maindevice,
-                    retry_count,
-                    timeout,
-                    tx_entries,
-                    sock,
-                    ring,
-                    index,
-                    &write_entry,
-                    &timeout_entry,
-                )? {
+                if let Some(devs) = dc.update(received, header, ctx, index)? {
                     let mbx_config = crate::mbx_config::MailboxConfig::start_new(
                         devs,
-                        maindevice,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
-                        &write_entry,
-                        &timeout_entry,
+                        ctx,
+                        settings.concurrency,
                     )?;
                     *self = Self::Mbx(mbx_config);
                 }
             }
             Self::Mbx(m) => {
-                if let Some(devs) = m.update(
-                    received,
-                    header,
-                    maindevice,
-                    retry_count,
-                    timeout,
-                    tx_entries,
-                    sock,
-                    ring,
-                    identifier,
-                    index,
-                    &write_entry,
-                    &timeout_entry,
-                )? {
-                    let preop = crate::preop::PreOp::start_new(
-                        devs,
-                        maindevice,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
-                        config,
-                        &write_entry,
-                        &timeout_entry,
-                    )?;
+                if let Some(devs) = m.update(received, header, ctx, identifier, index)? {
+                    let preop =
+                        crate::preop::PreOp::start_new(devs, ctx, config, settings.concurrency)?;
 
                     *self = Self::PreOp(preop);
                 }
             }
             Self::PreOp(p) => {
-                if let Some((devs, io)) = p.update(
-                    received,
-                    header,
-                    maindevice,
-                    retry_count,
-                    timeout,
-                    tx_entries,
-                    sock,
-                    ring,
-                    identifier,
-                    index,
-                    pdi_offset,
-                    &write_entry,
-                    &timeout_entry,
-                )? {
+                if let Some((devs, io)) =
+                    p.update(received, header, ctx, identifier, index, pdi_offset)?
+                {
                     let safeop = crate::safeop::SafeOp::start_new(
                         devs,
-                        maindevice,
-                        retry_count,
-                        timeout,
-                        tx_entries,
-                        sock,
-                        ring,
-                        &write_entry,
-                        &timeout_entry,
+                        ctx,
+                        settings.concurrency,
+                        settings.broadcast_transitions,
                     )?;
 
                     *self = Self::SafeOp(safeop, io);
                 }
             }
             Self::SafeOp(o, io) => {
-                if let Some(devs) = o.update(
-                    received,
-                    header,
-                    maindevice,
-                    retry_count,
-                    timeout,
-                    tx_entries,
-                    sock,
-                    ring,
-                    index,
-                    &write_entry,
-                    &timeout_entry,
-                )? {
+                if let Some(devs) = o.update(received, header, ctx, index)? {
                     let mut io: SendCtx = (*io).into();
 
-                    let op = crate::op::Op::start_new(
-                        devs,
-                        maindevice,
-                        tx_entries,
-                        ring,
-                        user_cb,
-                        &mut io.send_bytes,
-                        retry_count,
-                        timeout,
-                        sock,
-                        &write_entry,
-                        &timeout_entry,
-                    )?;
+                    let op = crate::op::Op::start_new(devs, ctx, user_cb, &mut io.send_bytes)?;
 
                     *self = Self::Op(op, io);
                 }
@@ -293,39 +187,96 @@ impl<'a, const N: usize, const I: usize, const O: usize, U: crate::user::UserDev
                 if let Some(flow) = o.update(
                     received,
                     header,
-                    maindevice,
-                    tx_entries,
-                    ring,
+                    ctx,
                     identifier,
                     index,
                     user_cb,
                     io.input_offset,
                     &mut io.send_bytes,
-                    retry_count,
-                    timeout,
-                    sock,
-                    &write_entry,
-                    &timeout_entry,
                 )? {
-                    use crate::user::ControlFlow;
-                    match flow {
-                        ControlFlow::Restart => {
-                            *self = Self::Idle;
-                            self.start(
-                                maindevice,
-                                retry_count,
-                                timeout,
-                                tx_entries,
-                                sock,
-                                ring,
-                                &write_entry,
-                                &timeout_entry,
-                            )?;
-                        }
-                    }
+                    self.control(flow, ctx)?;
+                }
+            }
+            Self::Shutdown(s) => {
+                if s.update(received, header, ctx, identifier, index)? {
+                    let res = s.result();
+                    *self = Self::Stopped;
+                    res?;
+                }
+            }
+            // stale responses from before the last (re)start / shutdown
+            Self::Idle | Self::Stopped => (),
+        }
+        Ok(())
+    }
+
+    // a pdu ran out of retries without a response. in op the user callback is told and the
+    // cyclic frame keeps going, while the bus is brought up / shut down there is nothing to go on
+    // with
+    pub fn timeout(
+        &mut self,
+        ctx: &mut IoCtx,
+        command_code: u8,
+        index: Option<u16>,
+        identifier: Option<u8>,
+        user_cb: impl FnMut(
+            &mut IoCtx,
+            &mut U,
+            Option<crate::op::DeviceResponse<'_, '_>>,
+            u16,
+            Option<u8>,
+            &mut [u8],
+        ) -> std::io::Result<Option<crate::user::ControlFlow>>,
+    ) -> Result<(), Error> {
+        match self {
+            Self::Op(o, io) => {
+                if let Some(flow) = o.timeout(
+                    ctx,
+                    command_code,
+                    index,
+                    identifier,
+                    user_cb,
+                    &mut io.send_bytes,
+                )? {
+                    self.control(flow, ctx)?;
+                }
+                Ok(())
+            }
+            Self::Shutdown(s) => {
+                if s.timeout(ctx, command_code, index, identifier)? {
+                    let res = s.result();
+                    *self = Self::Stopped;
+                    res?;
+                }
+                Ok(())
+            }
+            Self::Idle | Self::Stopped => Ok(()),
+            _ => Err(Error::Timeout {
+                idx: index,
+                identifier,
+            }),
+        }
+    }
+
+    // the flows that op hands back as they concern the whole bus
+    fn control(&mut self, flow: crate::user::ControlFlow, ctx: &mut IoCtx) -> Result<(), Error> {
+        use crate::user::ControlFlow;
+        match flow {
+            ControlFlow::Restart => {
+                *self = Self::Idle;
+                self.start(ctx)?;
+            }
+            ControlFlow::Shutdown => self.shutdown(ctx)?,
+            ControlFlow::Stop => {
+                // nothing is forwarded or read on the side anymore
+                if let Some(gateway) = ctx.gateway.as_deref_mut() {
+                    gateway.reset();
                 }
+                ctx.mailbox_status.clear();
+                *self = Self::Stopped;
             }
-            _ => todo!(),
+            // handled by op itself
+            _ => (),
         }
         Ok(())
     }
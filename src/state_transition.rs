@@ -1,17 +1,24 @@
+use crate::error::Error;
+use crate::io::IoCtx;
 use crate::setup::setup_write;
-use crate::txbuf::TxBuf;
-use ethercrab::EtherCrabWireRead;
-use ethercrab::{
-    AlControl, MainDevice, PduHeader, SubDeviceState, error::Error, received_frame::ReceivedPdu,
-    std::RawSocketDesc,
-};
-use io_uring::{IoUring, types::Timespec};
-use std::collections::BTreeMap;
+use ethercrab::{AlControl, AlStatusCode, PduHeader, SubDeviceState, received_frame::ReceivedPdu};
+use ethercrab::{EtherCrabWireRead, EtherCrabWireWrite};
+
+use heapless::Deque;
+
+// al control register, written to request a state / acknowledge an error
+const AL_CONTROL: u16 = 0x0120;
+// al status register, polled while waiting for the subdevice to reach the state
+const AL_STATUS: u16 = 0x0130;
+// al status code register, set by the subdevice when it refuses a transition
+const AL_STATUS_CODE: u16 = 0x0134;
 
 // request transition from one state to another
 // eg, init -> preop
 pub struct Transition {
     requested: SubDeviceState,
+    acknowledge: bool,
+    identifier: Option<u8>,
     state: TransitionState,
 }
 
@@ -19,117 +26,459 @@ impl Transition {
     pub fn new(requested: SubDeviceState) -> Self {
         Self {
             requested,
+            acknowledge: true,
+            identifier: None,
             state: TransitionState::Transition,
         }
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub fn start(
+    // whether to acknowledge the error after a failed transition.
+    // acknowledging clears the error flag so the transition can be retried with `start`.
+    pub fn acknowledge_errors(mut self, acknowledge: bool) -> Self {
+        self.acknowledge = acknowledge;
+        self
+    }
+
+    // the identifier the pdus are sent with, so that the responses can be told apart from
+    // the other ones of the subdevice, eg. from the user callback in op
+    pub fn identifier(mut self, identifier: Option<u8>) -> Self {
+        self.identifier = identifier;
+        self
+    }
+
+    // whether `header` is for one of the registers a transition reads / writes
+    pub(crate) fn is_response(header: &PduHeader) -> bool {
+        let addr = (u32::from_le_bytes(header.command_raw) >> 16) as u16;
+        matches!(addr, AL_CONTROL | AL_STATUS | AL_STATUS_CODE)
+    }
+
+    pub fn start(&mut self, ctx: &mut IoCtx, configured_addr: u16, idx: u16) -> Result<(), Error> {
+        let (frame, handle) = ctx
+            .maindevice
+            .prep_request_subdevice_state(configured_addr, self.requested)?
+            .ok_or(Error::NoFrame)?;
+
+        setup_write(frame, handle, &mut ctx.tx, Some(idx), self.identifier)?;
+        self.state = TransitionState::Transition;
+        Ok(())
+    }
+
+    // the subdevice flagged an error, find out why
+    fn read_status_code(
         &mut self,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         configured_addr: u16,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
+        current: SubDeviceState,
     ) -> Result<(), Error> {
-        let (frame, handle) = maindevice
-            .prep_request_subdevice_state(configured_addr, self.requested)?
-            .unwrap();
-
-        setup_write(
-            frame,
-            handle,
-            retry_count,
-            timeout_duration,
-            tx_entries,
-            sock,
-            ring,
-            Some(idx),
-            None,
-            write_entry,
-            timeout_entry,
-        )?;
+        let (frame, handle) = unsafe {
+            ctx.maindevice
+                .prep_read(configured_addr, AL_STATUS_CODE, 2)?
+                .ok_or(Error::NoFrame)?
+        };
+        setup_write(frame, handle, &mut ctx.tx, Some(idx), self.identifier)?;
+        self.state = TransitionState::StatusCode(current);
         Ok(())
     }
 
-    #[allow(clippy::too_many_arguments)]
     pub fn update(
         &mut self,
         received: ReceivedPdu<'_>,
         _header: PduHeader,
-        maindevice: &MainDevice,
-        retry_count: usize,
-        timeout_duration: &Timespec,
-        tx_entries: &mut BTreeMap<u64, TxBuf>,
-        sock: &RawSocketDesc,
-        ring: &mut IoUring,
+        ctx: &mut IoCtx,
         configured_addr: u16,
         idx: u16,
-        write_entry: impl Fn(u64) -> u64,
-        timeout_entry: impl Fn(u64) -> u64,
     ) -> Result<bool, Error> {
         match &mut self.state {
             TransitionState::Transition => {
-                let res = AlControl::unpack_from_slice(&received).unwrap();
+                let res = AlControl::unpack_from_slice(&received)?;
 
                 if res.error {
-                    todo!("error transitioning to {:?}", self.requested);
+                    self.read_status_code(ctx, configured_addr, idx, res.state)?;
+                    return Ok(false);
                 }
 
-                let (frame, handle) = maindevice
+                let (frame, handle) = ctx
+                    .maindevice
                     .prep_wait_subdevice_state(configured_addr, self.requested)?
-                    .unwrap();
-                setup_write(
-                    frame,
-                    handle,
-                    retry_count,
-                    timeout_duration,
-                    tx_entries,
-                    sock,
-                    ring,
-                    Some(idx),
-                    None,
-                    write_entry,
-                    timeout_entry,
-                )?;
+                    .ok_or(Error::NoFrame)?;
+                setup_write(frame, handle, &mut ctx.tx, Some(idx), self.identifier)?;
                 self.state = TransitionState::WaitForAck;
             }
             TransitionState::WaitForAck => {
-                let res = AlControl::unpack_from_slice(&received).unwrap();
+                let res = AlControl::unpack_from_slice(&received)?;
+
+                if res.error {
+                    self.read_status_code(ctx, configured_addr, idx, res.state)?;
+                    return Ok(false);
+                }
 
                 if res.state != self.requested {
-                    let (frame, handle) = maindevice
+                    let (frame, handle) = ctx
+                        .maindevice
                         .prep_wait_subdevice_state(configured_addr, self.requested)?
-                        .unwrap();
-
-                    setup_write(
-                        frame,
-                        handle,
-                        retry_count,
-                        timeout_duration,
-                        tx_entries,
-                        sock,
-                        ring,
-                        Some(idx),
-                        None,
-                        write_entry,
-                        timeout_entry,
-                    )?;
+                        .ok_or(Error::NoFrame)?;
+
+                    setup_write(frame, handle, &mut ctx.tx, Some(idx), self.identifier)?;
                     return Ok(false);
                 }
                 return Ok(true);
             }
+            TransitionState::StatusCode(current) => {
+                let code = AlStatusCode::unpack_from_slice(&received)?;
+
+                if !self.acknowledge {
+                    return Err(self.error(configured_addr, code));
+                }
+
+                // writing back the current state with the error bit set acknowledges the error
+                let ack = AlControl {
+                    error: true,
+                    ..AlControl::new(*current)
+                };
+                let mut buf = [0u8; 2];
+                ack.pack_to_slice(&mut buf)?;
+
+                let (frame, handle) = unsafe {
+                    ctx.maindevice
+                        .prep_write(configured_addr, AL_CONTROL, 2, &buf)?
+                        .ok_or(Error::NoFrame)?
+                };
+                setup_write(frame, handle, &mut ctx.tx, Some(idx), self.identifier)?;
+                self.state = TransitionState::Acknowledge(code);
+            }
+            TransitionState::Acknowledge(code) => {
+                let code = *code;
+                self.state = TransitionState::Transition;
+                return Err(self.error(configured_addr, code));
+            }
         }
         Ok(false)
     }
+
+    fn error(&self, configured_addr: u16, code: AlStatusCode) -> Error {
+        Error::Transition {
+            configured_addr,
+            requested: self.requested,
+            code,
+        }
+    }
 }
 
 enum TransitionState {
     Transition,
     WaitForAck,
+    // reading the al status code, holding the state the subdevice is currently in
+    StatusCode(SubDeviceState),
+    // acknowledging the error, holding the code to report once done
+    Acknowledge(AlStatusCode),
+}
+
+// how often the al status of the whole bus is polled before the subdevices are checked one by one
+const BROADCAST_POLLS: u16 = 1000;
+
+// requests a state of every subdevice at once, then waits for the whole bus to get there,
+// see `Driver::set_broadcast_transitions`.
+// subdevices that did not make it are checked one by one and the transition is requested of them
+// again, the ones that still fail are reported together
+pub(crate) struct BroadcastTransition<const N: usize> {
+    requested: SubDeviceState,
+    // the configured address of every subdevice, along with how far it got once they are checked
+    subdevices: Deque<(u16, Verify), N>,
+    polls: u16,
+    // the configured address and al status code of every subdevice that refused the transition
+    failed: Vec<(u16, AlStatusCode)>,
+    state: BroadcastState,
+}
+
+enum BroadcastState {
+    Request,
+    WaitForAck,
+    // the subdevices are checked one by one
+    Verify,
+}
+
+enum Verify {
+    // waiting for the subdevice's al status
+    Status,
+    // it was not in the requested state, so the transition is requested of it alone
+    Transition(Transition),
+    Done,
+}
+
+impl<const N: usize> BroadcastTransition<N> {
+    pub(crate) fn new(requested: SubDeviceState, addrs: impl IntoIterator<Item = u16>) -> Self {
+        let mut subdevices = Deque::new();
+        for addr in addrs {
+            let _ = subdevices.push_back((addr, Verify::Done));
+        }
+
+        Self {
+            requested,
+            subdevices,
+            polls: 0,
+            failed: Vec::new(),
+            state: BroadcastState::Request,
+        }
+    }
+
+    pub(crate) fn start(&mut self, ctx: &mut IoCtx) -> Result<(), Error> {
+        let (frame, handle) = ctx
+            .maindevice
+            .prep_request_state(self.requested)?
+            .ok_or(Error::NoFrame)?;
+        setup_write(frame, handle, &mut ctx.tx, None, None)?;
+        self.state = BroadcastState::Request;
+        Ok(())
+    }
+
+    fn wait(&mut self, ctx: &mut IoCtx) -> Result<(), Error> {
+        let (frame, handle) = ctx
+            .maindevice
+            .prep_wait_for_state(self.requested)?
+            .ok_or(Error::NoFrame)?;
+        setup_write(frame, handle, &mut ctx.tx, None, None)?;
+        self.polls += 1;
+        self.state = BroadcastState::WaitForAck;
+        Ok(())
+    }
+
+    // reads the al status of every subdevice, as the one of the bus can not tell them apart
+    fn verify(&mut self, ctx: &mut IoCtx) -> Result<(), Error> {
+        for (idx, (addr, verify)) in self.subdevices.iter_mut().enumerate() {
+            let (frame, handle) = ctx
+                .maindevice
+                .prep_wait_subdevice_state(*addr, self.requested)?
+                .ok_or(Error::NoFrame)?;
+            setup_write(frame, handle, &mut ctx.tx, Some(idx as _), None)?;
+            *verify = Verify::Status;
+        }
+        self.state = BroadcastState::Verify;
+        Ok(())
+    }
+
+    // returns true once every subdevice is in the requested state
+    pub(crate) fn update(
+        &mut self,
+        received: ReceivedPdu<'_>,
+        header: PduHeader,
+        ctx: &mut IoCtx,
+        idx: Option<u16>,
+    ) -> Result<bool, Error> {
+        let device_count = self.subdevices.len() as u16;
+
+        match self.state {
+            BroadcastState::Request => {
+                if header.command_code != 8 {
+                    return Err(Error::UnexpectedCommand(header.command_code));
+                }
+
+                // not every subdevice took the request
+                if received.working_counter != device_count {
+                    self.verify(ctx)?;
+                } else {
+                    self.wait(ctx)?;
+                }
+            }
+            BroadcastState::WaitForAck => {
+                if header.command_code != 7 {
+                    return Err(Error::UnexpectedCommand(header.command_code));
+                }
+
+                // the states of the subdevices are or'ed together, so a mix of them may not parse
+                let res = AlControl::unpack_from_slice(&received).ok();
+                let responded = received.working_counter == device_count;
+
+                match res {
+                    Some(res) if responded && !res.error && res.state == self.requested => {
+                        return Ok(true);
+                    }
+                    Some(res) if responded && !res.error && self.polls < BROADCAST_POLLS => {
+                        self.wait(ctx)?;
+                    }
+                    None if responded && self.polls < BROADCAST_POLLS => self.wait(ctx)?,
+                    _ => self.verify(ctx)?,
+                }
+            }
+            BroadcastState::Verify => {
+                let idx = idx.ok_or(Error::MissingIndex)?;
+                let (addr, verify) = self
+                    .subdevices
+                    .get_mut(usize::from(idx))
+                    .ok_or(Error::UnknownDevice(idx))?;
+
+                match verify {
+                    Verify::Status => {
+                        let res = AlControl::unpack_from_slice(&received)?;
+
+                        if !res.error && res.state == self.requested {
+                            *verify = Verify::Done;
+                        } else {
+                            let mut transition = Transition::new(self.requested);
+                            transition.start(ctx, *addr, idx)?;
+                            *verify = Verify::Transition(transition);
+                        }
+                    }
+                    Verify::Transition(transition) => {
+                        match transition.update(received, header, ctx, *addr, idx) {
+                            Ok(false) => return Ok(false),
+                            Ok(true) => (),
+                            Err(Error::Transition {
+                                configured_addr,
+                                code,
+                                ..
+                            }) => self.failed.push((configured_addr, code)),
+                            Err(e) => return Err(e),
+                        }
+                        *verify = Verify::Done;
+                    }
+                    Verify::Done => return Err(Error::UnknownDevice(idx)),
+                }
+
+                if !self
+                    .subdevices
+                    .iter()
+                    .all(|(_, verify)| matches!(verify, Verify::Done))
+                {
+                    return Ok(false);
+                }
+
+                if !self.failed.is_empty() {
+                    return Err(Error::BroadcastTransition {
+                        requested: self.requested,
+                        failed: core::mem::take(&mut self.failed),
+                    });
+                }
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+// the states that are stepped through, in order
+const STATES: [SubDeviceState; 4] = [
+    SubDeviceState::Init,
+    SubDeviceState::PreOp,
+    SubDeviceState::SafeOp,
+    SubDeviceState::Op,
+];
+
+// where `state` is in `STATES`, bootstrap has no place in it
+fn position(state: SubDeviceState) -> Option<usize> {
+    STATES.iter().position(|s| *s == state)
+}
+
+// whether `state` has to be left to get down to `requested`
+pub(crate) fn above(state: SubDeviceState, requested: SubDeviceState) -> bool {
+    match (position(state), position(requested)) {
+        (Some(state), Some(requested)) => state > requested,
+        // eg. bootstrap, which is only left to init
+        _ => state != requested && requested == SubDeviceState::Init,
+    }
+}
+
+// moves a single subdevice to another state one transition at a time, eg. from the user callback
+// in op to take a drive down to preop after a fault and back up to op, while the rest of the bus
+// keeps cycling.
+// the sync manager and fmmu configuration is kept, so the subdevice has to come back up with the
+// same mapping. going through init may need the mailboxes to be configured again.
+pub struct StateChange {
+    current: SubDeviceState,
+    target: SubDeviceState,
+    transition: Option<Transition>,
+}
+
+impl StateChange {
+    pub fn new(current: SubDeviceState, target: SubDeviceState) -> Self {
+        Self {
+            current,
+            target,
+            transition: None,
+        }
+    }
+
+    // the state the subdevice was last brought to
+    pub fn state(&self) -> SubDeviceState {
+        self.current
+    }
+
+    pub fn finished(&self) -> bool {
+        self.current == self.target
+    }
+
+    // the state after `current` on the way to `target`, stepping through all of the ones in between
+    fn next(&self) -> Option<SubDeviceState> {
+        if self.finished() {
+            return None;
+        }
+
+        let (Some(current), Some(target)) = (position(self.current), position(self.target)) else {
+            // eg. bootstrap, which is only entered from / left to init
+            return Some(self.target);
+        };
+
+        let next = if current < target {
+            current + 1
+        } else {
+            current - 1
+        };
+        Some(STATES[next])
+    }
+
+    fn start_next(
+        &mut self,
+        ctx: &mut IoCtx,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<bool, Error> {
+        let Some(next) = self.next() else {
+            self.transition = None;
+            return Ok(true);
+        };
+
+        let mut transition = Transition::new(next).identifier(identifier);
+        transition.start(ctx, configured_addr, idx)?;
+        self.transition = Some(transition);
+        Ok(false)
+    }
+
+    // returns true if the subdevice is already in the target state
+    pub fn start(
+        &mut self,
+        ctx: &mut IoCtx,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<bool, Error> {
+        self.start_next(ctx, configured_addr, identifier, idx)
+    }
+
+    // returns true once the subdevice is in the target state.
+    // responses for anything other than the al registers are ignored
+    pub fn update(
+        &mut self,
+        received: ReceivedPdu<'_>,
+        header: PduHeader,
+        ctx: &mut IoCtx,
+        configured_addr: u16,
+        identifier: Option<u8>,
+        idx: u16,
+    ) -> Result<bool, Error> {
+        if !Transition::is_response(&header) {
+            return Ok(false);
+        }
+
+        let Some(transition) = &mut self.transition else {
+            return Ok(self.finished());
+        };
+
+        if !transition.update(received, header, ctx, configured_addr, idx)? {
+            return Ok(false);
+        }
+
+        self.current = transition.requested;
+        self.start_next(ctx, configured_addr, identifier, idx)
+    }
 }
@@ -2,10 +2,31 @@
 #[derive(Clone, Copy)]
 pub enum ControlFlow {
     Restart,
+    // zeroes the outputs and brings every subdevice back down to init, see `InitState::shutdown`
+    Shutdown,
+    // stops sending the cyclic frame right away, the subdevices are left in the state they are in
+    Stop,
+    // takes the subdevice at the index down to init and back up to op, mapping its pdos again
+    // like `Reconfigure`
+    RestartDevice(u16),
+    // takes the subdevice at the index down to preop to map its pdos again and back up to op.
+    // the change is sent with identifiers from `RESERVED_IDENTIFIERS`
+    Reconfigure(u16),
+    // takes every subdevice down to safeop, where the inputs keep updating but the outputs are
+    // ignored. the rest of the bus keeps cycling
+    EnterSafeOp,
+    // brings every subdevice that is not in op back up to it
+    EnterOp,
 }
 
 pub trait UserDevice {
     fn subdevice_mut(&mut self) -> &mut ethercrab::SubDevice;
     fn subdevice(&self) -> &ethercrab::SubDevice;
     fn into_subdevice(self) -> ethercrab::SubDevice;
+
+    // the queue that runs the mailbox transfers of the subdevice, if there is one, so that op
+    // can fail the transfer in flight when it times out instead of leaving the queue stuck
+    fn mailbox_queue(&mut self) -> Option<&mut crate::MailboxQueue> {
+        None
+    }
 }